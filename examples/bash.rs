@@ -98,14 +98,16 @@ async fn main() -> anyhow::Result<()> {
                             let mut ptyout = unsafe { PipeRead::from_raw_fd(dup(master_fd)?) };
                             let mut ptyin = unsafe { PipeWrite::from_raw_fd(master_fd) };
                             tokio::spawn(async move {
-                                let r = io::copy(&mut stdin, &mut ptyin).await;
+                                let r = stdin.copy_to(&mut ptyin).await;
                                 log::info!("### END1 {:?}", r);
                             });
-                            tokio::spawn(async move {
-                                let r = io::copy(&mut ptyout, &mut stdout).await;
-                                log::info!("### END2 {:?}", r); // May be IO Error at EOF.
+                            let output = tokio::spawn(async move {
+                                let r = stdout.copy_from(&mut ptyout).await;
+                                log::info!("### END2 {:?}", r);
                             });
                             let status = child.wait().await?;
+                            // rest of output precedes exit status.
+                            output.await?;
 
                             return Ok(status.code().unwrap_or(255) as u32);
                         }
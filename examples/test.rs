@@ -55,7 +55,7 @@ async fn main() -> anyhow::Result<()> {
                     .boxed()
                 });
 
-                handlers.on_channel_direct_tcpip(|_, mut stdout: SshOutput| {
+                handlers.on_channel_direct_tcpip(|_, _, mut stdout: SshOutput| {
                     async move {
                         stdout.write(b"Hello, World!").await?;
                         stdout.shutdown().await?;
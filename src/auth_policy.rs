@@ -0,0 +1,112 @@
+//! Required combination of user authentication methods.
+
+/// Methods a user must complete before `SSH_MSG_USERAUTH_SUCCESS`.
+///
+/// Until satisfied, a succeeded method is answered with `SSH_MSG_USERAUTH_FAILURE`
+/// with partial success, listing methods still needed. (RFC 4252 5.1)
+///
+/// # Example
+///
+/// ```
+/// use ssssh::{AuthPolicy, ServerBuilder};
+///
+/// // publickey AND (password OR keyboard-interactive)
+/// let policy = AuthPolicy::all(vec![
+///     AuthPolicy::method("publickey"),
+///     AuthPolicy::any(vec![
+///         AuthPolicy::method("password"),
+///         AuthPolicy::method("keyboard-interactive"),
+///     ]),
+/// ]);
+/// let mut builder = ServerBuilder::default();
+/// builder.auth_policy(policy);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthPolicy {
+    /// Method name. (e.g. `publickey`)
+    Method(String),
+
+    /// All of policies.
+    All(Vec<AuthPolicy>),
+
+    /// Any of policies.
+    Any(Vec<AuthPolicy>),
+}
+
+impl AuthPolicy {
+    pub fn method(name: &str) -> Self {
+        Self::Method(name.into())
+    }
+
+    pub fn all(policies: Vec<AuthPolicy>) -> Self {
+        Self::All(policies)
+    }
+
+    pub fn any(policies: Vec<AuthPolicy>) -> Self {
+        Self::Any(policies)
+    }
+
+    pub(crate) fn is_satisfied(&self, completed: &[String]) -> bool {
+        match self {
+            Self::Method(name) => completed.contains(name),
+            Self::All(policies) => policies.iter().all(|p| p.is_satisfied(completed)),
+            Self::Any(policies) => policies.iter().any(|p| p.is_satisfied(completed)),
+        }
+    }
+
+    /// Methods that can still make progress, in order of appearance.
+    pub(crate) fn needed(&self, completed: &[String]) -> Vec<String> {
+        let mut needed = vec![];
+        self.collect_needed(completed, &mut needed);
+        needed
+    }
+
+    fn collect_needed(&self, completed: &[String], needed: &mut Vec<String>) {
+        if self.is_satisfied(completed) {
+            return;
+        }
+        match self {
+            Self::Method(name) => {
+                if !needed.contains(name) {
+                    needed.push(name.clone());
+                }
+            }
+            Self::All(policies) | Self::Any(policies) => {
+                for policy in policies {
+                    policy.collect_needed(completed, needed);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let policy = AuthPolicy::all(vec![
+            AuthPolicy::method("publickey"),
+            AuthPolicy::any(vec![
+                AuthPolicy::method("password"),
+                AuthPolicy::method("hostbased"),
+            ]),
+        ]);
+
+        let completed = vec![];
+        assert!(!policy.is_satisfied(&completed));
+        assert_eq!(
+            policy.needed(&completed),
+            vec!["publickey", "password", "hostbased"]
+        );
+
+        let completed = vec!["password".to_string()];
+        assert!(!policy.is_satisfied(&completed));
+        assert_eq!(policy.needed(&completed), vec!["publickey"]);
+
+        let completed = vec!["password".to_string(), "publickey".to_string()];
+        assert!(policy.is_satisfied(&completed));
+        assert!(policy.needed(&completed).is_empty());
+    }
+}
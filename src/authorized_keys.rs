@@ -1,8 +1,10 @@
 //! OpenSSH `authorized_keys` parser.
 use std::iter::IntoIterator;
+use std::net::IpAddr;
 use std::str::FromStr;
 
 use authorized_keys::openssh::v2::{KeysFile, KeysFileLine};
+use ipnet::IpNet;
 use tokio::io::{self, AsyncRead, AsyncReadExt};
 
 use crate::PublicKey;
@@ -18,7 +20,7 @@ pub enum ParseError {
 }
 
 /// represent `authorized_keys` line.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AuthorizedKey {
     options: Vec<(String, Option<String>)>,
     key_type: String,
@@ -42,6 +44,175 @@ impl AuthorizedKey {
     pub fn comment(&self) -> &str {
         &self.comment
     }
+
+    /// Unescaped values of option `name`. (case insensitive)
+    fn option_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = String> + 'a {
+        self.options
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .filter_map(|(_, v)| v.as_deref().map(unescape))
+    }
+
+    fn has_option(&self, name: &str) -> bool {
+        self.options
+            .iter()
+            .any(|(n, _)| n.eq_ignore_ascii_case(name))
+    }
+
+    /// Forced command by `command="..."`.
+    pub fn command(&self) -> Option<String> {
+        self.option_values("command").next()
+    }
+
+    /// Whether `restrict` is specified.
+    ///
+    /// All forwarding, pty and `~/.ssh/rc` are disabled unless enabled by another option.
+    /// (e.g. `restrict,pty`)
+    pub fn is_restricted(&self) -> bool {
+        self.has_option("restrict")
+    }
+
+    /// Destinations allowed by `permitopen="host:port"`. Port `*` is `None`, meaning any port.
+    ///
+    /// IPv6 address is enclosed in square brackets. (e.g. `[2001:db8::1]:80`)
+    /// Invalid values are skipped.
+    pub fn permitopen(&self) -> Vec<(String, Option<u16>)> {
+        self.option_values("permitopen")
+            .filter_map(|value| {
+                let parsed = parse_permitopen(&value);
+                if parsed.is_none() {
+                    log::warn!("invalid permitopen {:?}", value);
+                }
+                parsed
+            })
+            .collect()
+    }
+
+    /// Source address patterns by `from="pattern-list"`. Negated patterns start with `!`.
+    pub fn from(&self) -> Vec<String> {
+        self.option_values("from")
+            .flat_map(|value| value.split(',').map(ToOwned::to_owned).collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// Whether pty allocation is permitted. (`no-pty`, or `restrict` without `pty`)
+    pub fn permits_pty(&self) -> bool {
+        !self.has_option("no-pty") && (!self.is_restricted() || self.has_option("pty"))
+    }
+
+    /// Whether port forwarding is permitted. (`no-port-forwarding`, or `restrict` without `port-forwarding`)
+    pub fn permits_port_forwarding(&self) -> bool {
+        !self.has_option("no-port-forwarding")
+            && (!self.is_restricted() || self.has_option("port-forwarding"))
+    }
+
+    /// Whether agent forwarding is permitted. (`no-agent-forwarding`, or `restrict` without `agent-forwarding`)
+    pub fn permits_agent_forwarding(&self) -> bool {
+        !self.has_option("no-agent-forwarding")
+            && (!self.is_restricted() || self.has_option("agent-forwarding"))
+    }
+
+    /// Whether X11 forwarding is permitted. (`no-X11-forwarding`, or `restrict` without `X11-forwarding`)
+    pub fn permits_x11_forwarding(&self) -> bool {
+        !self.has_option("no-X11-forwarding")
+            && (!self.is_restricted() || self.has_option("X11-forwarding"))
+    }
+
+    /// Whether local forwarding (direct-tcpip) to `host:port` is permitted.
+    ///
+    /// If `permitopen` is specified, the destination must match one of them.
+    /// Host is compared literally, without name resolution.
+    /// If all `permitopen` values are invalid, nothing is permitted.
+    pub fn permits_open(&self, host: &str, port: u16) -> bool {
+        if !self.permits_port_forwarding() {
+            return false;
+        }
+        if !self.has_option("permitopen") {
+            return true;
+        }
+        self.permitopen()
+            .iter()
+            .any(|(h, p)| (h == "*" || h.eq_ignore_ascii_case(host)) && p.is_none_or(|p| p == port))
+    }
+
+    /// Whether remote forwarding (tcpip-forward) is permitted.
+    ///
+    /// `permitlisten` is not supported, so a key with it is not permitted at all.
+    pub fn permits_tcpip_forward(&self) -> bool {
+        self.permits_port_forwarding() && !self.has_option("permitlisten")
+    }
+
+    /// Whether `from="pattern-list"` permits `addr`. Unknown address is not permitted.
+    ///
+    /// Patterns are matched against the address only, with `*` / `?` wildcards or CIDR.
+    /// Host names are not resolved.
+    pub fn permits_from(&self, addr: Option<IpAddr>) -> bool {
+        let patterns = self.from();
+        if patterns.is_empty() {
+            return true;
+        }
+        let addr = match addr {
+            Some(addr) => addr,
+            None => return false,
+        };
+
+        let text = addr.to_string();
+        let mut matched = false;
+        for pattern in &patterns {
+            let (negated, pattern) = match pattern.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, &pattern[..]),
+            };
+            let hit = if pattern.contains('/') {
+                pattern
+                    .parse::<IpNet>()
+                    .is_ok_and(|net| net.contains(&addr))
+            } else {
+                wildcard_match(pattern.as_bytes(), text.as_bytes())
+            };
+            match (hit, negated) {
+                (true, true) => return false,
+                (true, false) => matched = true,
+                _ => {}
+            }
+        }
+        matched
+    }
+}
+
+/// Match `text` against `pattern` with `*` and `?`. (case insensitive)
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.split_first(), text.split_first()) {
+        (None, _) => text.is_empty(),
+        (Some((b'*', rest)), _) => (0..=text.len()).any(|n| wildcard_match(rest, &text[n..])),
+        (Some((b'?', rest)), Some((_, text))) => wildcard_match(rest, text),
+        (Some((p, rest)), Some((c, text))) if p.eq_ignore_ascii_case(c) => {
+            wildcard_match(rest, text)
+        }
+        _ => false,
+    }
+}
+
+/// Unescape `\"` in quoted option value. Other backslashes are kept as is, as OpenSSH does.
+fn unescape(value: &str) -> String {
+    value.replace("\\\"", "\"")
+}
+
+fn parse_permitopen(value: &str) -> Option<(String, Option<u16>)> {
+    let (host, port) = if let Some(rest) = value.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        (host, rest.strip_prefix(':')?)
+    } else {
+        value.split_once(':')?
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let port = match port {
+        "*" => None,
+        port => Some(port.parse().ok().filter(|port| *port != 0)?),
+    };
+    Some((host.to_string(), port))
 }
 
 /// OpenSSH represent `authorized_keys`.
@@ -131,4 +302,102 @@ restrict,pty,command="nethack" ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQCwgKJ9qbRMm
             assert_eq!(key.publickey(), &expect);
         }
     }
+
+    #[tokio::test]
+    async fn test_options() {
+        let authorized_keys = br#"command="dump /home",no-pty ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBGr/hiKoT+ED6BGl0rYM8Ai96O/2lbnGM++zAbz578V
+restrict,COMMAND="echo \"a,b\" \\n" ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBGr/hiKoT+ED6BGl0rYM8Ai96O/2lbnGM++zAbz578V
+permitopen="192.0.2.1:80",permitopen="[2001:db8::1]:*",permitopen="bad",from="*.example.net,!pc.example.net" ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBGr/hiKoT+ED6BGl0rYM8Ai96O/2lbnGM++zAbz578V"#;
+
+        let authorized_keys = AuthorizedKeys::parse(&authorized_keys[..]).await.unwrap();
+        let keys = authorized_keys.into_iter().collect::<Vec<_>>();
+
+        assert_eq!(keys[0].command().as_deref(), Some("dump /home"));
+        assert!(!keys[0].is_restricted());
+        assert!(keys[0].permitopen().is_empty());
+        assert!(keys[0].from().is_empty());
+
+        assert_eq!(keys[1].command().as_deref(), Some(r#"echo "a,b" \\n"#));
+        assert!(keys[1].is_restricted());
+
+        assert_eq!(keys[2].command(), None);
+        assert_eq!(
+            keys[2].permitopen(),
+            vec![
+                ("192.0.2.1".to_string(), Some(80)),
+                ("2001:db8::1".to_string(), None)
+            ]
+        );
+        assert_eq!(keys[2].from(), vec!["*.example.net", "!pc.example.net"]);
+
+        assert!(keys[0].permits_open("192.0.2.1", 22));
+        assert!(!keys[1].permits_open("192.0.2.1", 22));
+        assert!(keys[2].permits_open("192.0.2.1", 80));
+        assert!(!keys[2].permits_open("192.0.2.1", 81));
+        assert!(keys[2].permits_open("2001:DB8::1", 22));
+        assert!(!keys[2].permits_open("192.0.2.2", 80));
+    }
+
+    #[tokio::test]
+    async fn test_permits() {
+        let authorized_keys = br#"no-pty,from="192.0.2.*,!192.0.2.1,2001:db8::/32" ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBGr/hiKoT+ED6BGl0rYM8Ai96O/2lbnGM++zAbz578V
+restrict ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBGr/hiKoT+ED6BGl0rYM8Ai96O/2lbnGM++zAbz578V
+restrict,pty ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBGr/hiKoT+ED6BGl0rYM8Ai96O/2lbnGM++zAbz578V
+restrict,port-forwarding,agent-forwarding,x11-forwarding ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBGr/hiKoT+ED6BGl0rYM8Ai96O/2lbnGM++zAbz578V
+no-port-forwarding,no-agent-forwarding,no-X11-forwarding ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBGr/hiKoT+ED6BGl0rYM8Ai96O/2lbnGM++zAbz578V
+permitopen="bad",permitlisten="8080" ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBGr/hiKoT+ED6BGl0rYM8Ai96O/2lbnGM++zAbz578V"#;
+
+        let authorized_keys = AuthorizedKeys::parse(&authorized_keys[..]).await.unwrap();
+        let keys = authorized_keys.into_iter().collect::<Vec<_>>();
+
+        assert!(!keys[0].permits_pty());
+        assert!(!keys[1].permits_pty());
+        assert!(keys[2].permits_pty());
+
+        let forwardings = |key: &AuthorizedKey| {
+            (
+                key.permits_port_forwarding(),
+                key.permits_agent_forwarding(),
+                key.permits_x11_forwarding(),
+            )
+        };
+        assert_eq!(forwardings(&keys[0]), (true, true, true));
+        assert_eq!(forwardings(&keys[1]), (false, false, false));
+        assert_eq!(forwardings(&keys[3]), (true, true, true));
+        assert_eq!(forwardings(&keys[4]), (false, false, false));
+        assert!(keys[0].permits_tcpip_forward());
+        assert!(!keys[1].permits_tcpip_forward());
+        assert!(!keys[5].permits_tcpip_forward());
+        assert!(!keys[5].permits_open("192.0.2.1", 80));
+
+        let permits = |addr: &str| keys[0].permits_from(Some(addr.parse().unwrap()));
+        assert!(permits("192.0.2.2"));
+        assert!(!permits("192.0.2.1"));
+        assert!(!permits("198.51.100.1"));
+        assert!(permits("2001:db8::1"));
+        assert!(!keys[0].permits_from(None));
+        assert!(keys[1].permits_from(None));
+    }
+
+    #[test]
+    fn test_parse_permitopen() {
+        assert_eq!(
+            parse_permitopen("localhost:22"),
+            Some(("localhost".into(), Some(22)))
+        );
+        assert_eq!(
+            parse_permitopen("localhost:*"),
+            Some(("localhost".into(), None))
+        );
+        for value in &[
+            "localhost",
+            ":22",
+            "localhost:0",
+            "localhost:x",
+            "::1:22",
+            "[::1]22",
+        ] {
+            assert_eq!(parse_permitopen(value), None, "{}", value);
+        }
+    }
 }
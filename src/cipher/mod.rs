@@ -99,6 +99,15 @@ impl Cipher {
         Self::None(none::None::new())
     }
 
+    pub(crate) fn algorithm(&self) -> Algorithm {
+        match self {
+            Self::None(..) => Algorithm::None,
+            Self::Aes128Ctr(..) => Algorithm::Aes128Ctr,
+            Self::Aes192Ctr(..) => Algorithm::Aes192Ctr,
+            Self::Aes256Ctr(..) => Algorithm::Aes256Ctr,
+        }
+    }
+
     /// Create new instance for encrypt by name
     pub(crate) fn new_for_encrypt(
         name: &Algorithm,
@@ -0,0 +1,27 @@
+use futures::channel::{mpsc, oneshot};
+
+use super::ssh_stream::{SshInput, SshOutput};
+use super::StreamOpenRequest;
+use crate::msg::channel_open::Type;
+use crate::SshError;
+
+/// Opens forwarded agent connections to the client.
+#[derive(Debug, Clone)]
+pub struct AgentForward {
+    tx: mpsc::UnboundedSender<StreamOpenRequest>,
+}
+
+impl AgentForward {
+    pub(crate) fn new(tx: mpsc::UnboundedSender<StreamOpenRequest>) -> Self {
+        Self { tx }
+    }
+
+    /// Open new `auth-agent@openssh.com` channel to the client.
+    pub async fn connect(&self) -> Result<(SshInput, SshOutput), SshError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .unbounded_send((Type::AuthAgent(()), tx))
+            .map_err(mpsc::TrySendError::into_send_error)?;
+        rx.await.map_err(SshError::any)?
+    }
+}
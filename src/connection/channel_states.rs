@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Default)]
+struct ChannelState {
+    running: bool,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl ChannelState {
+    fn released(&self) -> bool {
+        self.close_sent && self.close_received && !self.running
+    }
+}
+
+/// Close state of channels, shared by the message loop and the task loop.
+///
+/// Local channel id is in use until both sides sent close and the handler completed.
+/// The lock is never held across `.await`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ChannelStates {
+    inner: Arc<Mutex<HashMap<u32, ChannelState>>>,
+}
+
+impl ChannelStates {
+    pub(crate) fn open(&self, chid: u32) {
+        self.inner
+            .lock()
+            .unwrap()
+            .insert(chid, ChannelState::default());
+    }
+
+    pub(crate) fn in_use(&self, chid: u32) -> bool {
+        self.inner.lock().unwrap().contains_key(&chid)
+    }
+
+    /// Whether channel is opened and close is not sent by server yet.
+    pub(crate) fn is_open(&self, chid: u32) -> bool {
+        matches!(self.inner.lock().unwrap().get(&chid), Some(state) if !state.close_sent)
+    }
+
+    pub(crate) fn spawned(&self, chid: u32) {
+        if let Some(state) = self.inner.lock().unwrap().get_mut(&chid) {
+            state.running = true;
+        }
+    }
+
+    /// Mark close sent by server. Returns `false` if already sent.
+    pub(crate) fn close(&self, chid: u32) -> bool {
+        self.update(chid, |_| {})
+    }
+
+    /// Mark handler completed. Returns `true` if close must be sent.
+    pub(crate) fn completed(&self, chid: u32) -> bool {
+        self.update(chid, |state| state.running = false)
+    }
+
+    /// Mark close received from client. Returns `true` if close must be sent in reply.
+    pub(crate) fn close_received(&self, chid: u32) -> bool {
+        self.update(chid, |state| state.close_received = true)
+    }
+
+    /// Release channel never opened to client.
+    pub(crate) fn release(&self, chid: u32) {
+        self.inner.lock().unwrap().remove(&chid);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.inner.lock().unwrap().clear()
+    }
+
+    fn update<F>(&self, chid: u32, f: F) -> bool
+    where
+        F: FnOnce(&mut ChannelState),
+    {
+        let mut states = self.inner.lock().unwrap();
+        let state = match states.get_mut(&chid) {
+            Some(state) => state,
+            None => return false,
+        };
+        f(state);
+        let send_close = !state.close_sent;
+        state.close_sent = true;
+        if state.released() {
+            states.remove(&chid);
+        }
+        send_close
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_once() {
+        let states = ChannelStates::default();
+        states.open(0);
+        states.spawned(0);
+
+        assert!(states.close(0));
+        assert!(!states.completed(0));
+        assert!(!states.close_received(0));
+        assert!(!states.in_use(0));
+    }
+
+    #[test]
+    fn test_completed_then_close_received() {
+        let states = ChannelStates::default();
+        states.open(0);
+        states.spawned(0);
+
+        assert!(states.completed(0));
+        assert!(states.in_use(0));
+        assert!(!states.close(0));
+        assert!(!states.close_received(0));
+        assert!(!states.in_use(0));
+    }
+
+    #[test]
+    fn test_close_received_while_running() {
+        let states = ChannelStates::default();
+        states.open(0);
+        states.spawned(0);
+
+        assert!(states.close_received(0));
+        // id is kept until the handler completes.
+        assert!(states.in_use(0));
+        assert!(!states.completed(0));
+        assert!(!states.in_use(0));
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Waker};
+
+#[derive(Debug, Default)]
+struct Window {
+    size: u32,
+    waker: Option<Waker>,
+}
+
+/// Window of channels on the client side, shared by the message loop and the output loop.
+///
+/// Channel data is not sent beyond the window until `SSH_MSG_CHANNEL_WINDOW_ADJUST`.
+/// (RFC 4254 5.2) Unknown channels are not limited.
+/// The lock is never held across `.await`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ChannelWindows {
+    inner: Arc<Mutex<HashMap<u32, Window>>>,
+}
+
+impl ChannelWindows {
+    pub(crate) fn open(&self, chid: u32, size: u32) {
+        let window = Window { size, waker: None };
+        self.inner.lock().unwrap().insert(chid, window);
+    }
+
+    /// Add bytes to window, and wake the output loop waiting for it.
+    pub(crate) fn adjust(&self, chid: u32, bytes_to_add: u32) {
+        if let Some(window) = self.inner.lock().unwrap().get_mut(&chid) {
+            window.size = window.size.saturating_add(bytes_to_add);
+            if let Some(waker) = window.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Bytes allowed to send. Wakes `cx` on window adjust if none.
+    pub(crate) fn poll_available(&self, chid: u32, cx: &mut Context<'_>) -> usize {
+        match self.inner.lock().unwrap().get_mut(&chid) {
+            Some(window) if window.size == 0 => {
+                window.waker = Some(cx.waker().clone());
+                0
+            }
+            Some(window) => window.size as usize,
+            None => usize::MAX,
+        }
+    }
+
+    pub(crate) fn consume(&self, chid: u32, bytes: usize) {
+        if let Some(window) = self.inner.lock().unwrap().get_mut(&chid) {
+            window.size = window.size.saturating_sub(bytes as u32);
+        }
+    }
+
+    pub(crate) fn remove(&self, chid: u32) {
+        self.inner.lock().unwrap().remove(&chid);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.inner.lock().unwrap().clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    #[test]
+    fn test_window() {
+        let windows = ChannelWindows::default();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        windows.open(0, 10);
+
+        assert_eq!(windows.poll_available(0, &mut cx), 10);
+        windows.consume(0, 10);
+        assert_eq!(windows.poll_available(0, &mut cx), 0);
+        windows.adjust(0, 5);
+        assert_eq!(windows.poll_available(0, &mut cx), 5);
+        assert_eq!(windows.poll_available(1, &mut cx), usize::MAX);
+    }
+}
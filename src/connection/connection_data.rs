@@ -0,0 +1,82 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Per-connection values shared by handlers, one per type.
+///
+/// Obtained by [`Connection::data`](crate::Connection::data) before running the connection and
+/// captured by auth handlers, then read by channel handlers through
+/// [`SessionContext::get_data`](crate::SessionContext::get_data).
+#[derive(Clone, Default)]
+pub struct ConnectionData {
+    values: Arc<Mutex<HashMap<TypeId, Box<dyn Any + Send + Sync>>>>,
+}
+
+impl ConnectionData {
+    /// Store `value`, replacing the previous value of the same type.
+    pub fn insert<T>(&self, value: T) -> Option<T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.values
+            .lock()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+
+    /// Copy of stored value of type `T`.
+    pub fn get<T>(&self) -> Option<T>
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        self.values
+            .lock()
+            .unwrap()
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+            .cloned()
+    }
+
+    pub fn remove<T>(&self) -> Option<T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.values
+            .lock()
+            .unwrap()
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+}
+
+impl fmt::Debug for ConnectionData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = self.values.lock().unwrap().len();
+        f.debug_struct("ConnectionData").field("len", &len).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data() {
+        let data = ConnectionData::default();
+        assert_eq!(data.insert(1u32), None);
+        assert_eq!(data.insert(2u32), Some(1));
+        assert_eq!(data.insert("user".to_string()), None);
+
+        let cloned = data.clone();
+        assert_eq!(cloned.get::<u32>(), Some(2));
+        assert_eq!(cloned.get::<String>().as_deref(), Some("user"));
+        assert_eq!(cloned.get::<u64>(), None);
+
+        assert_eq!(cloned.remove::<u32>(), Some(2));
+        assert_eq!(data.get::<u32>(), None);
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// Kind of open channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelKind {
+    /// `session`
+    Session,
+    /// `direct-tcpip`
+    DirectTcpip,
+    /// Opened by server. (`forwarded-tcpip`, `x11` or `auth-agent@openssh.com`)
+    Forwarded,
+}
+
+/// Shared view of a connection, readable outside handlers.
+///
+/// Obtained by [`Connection::state`](crate::Connection::state) before running the connection.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionState {
+    channels: Arc<Mutex<BTreeMap<u32, ChannelKind>>>,
+    peer_addr: Option<SocketAddr>,
+}
+
+impl ConnectionState {
+    pub(crate) fn new(peer_addr: Option<SocketAddr>) -> Self {
+        Self {
+            channels: Default::default(),
+            peer_addr,
+        }
+    }
+
+    /// Remote address, if accepted by [`Server`](crate::Server).
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Snapshot of open channels, by local channel id.
+    pub fn channels(&self) -> Vec<(u32, ChannelKind)> {
+        self.channels
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(chid, kind)| (*chid, *kind))
+            .collect()
+    }
+
+    /// Number of open channels.
+    pub fn channel_count(&self) -> usize {
+        self.channels.lock().unwrap().len()
+    }
+
+    pub(crate) fn opened(&self, chid: u32, kind: ChannelKind) {
+        self.channels.lock().unwrap().insert(chid, kind);
+    }
+
+    pub(crate) fn closed(&self, chid: u32) {
+        self.channels.lock().unwrap().remove(&chid);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.channels.lock().unwrap().clear();
+    }
+}
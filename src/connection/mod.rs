@@ -1,20 +1,44 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use futures::channel::oneshot;
+use futures::sink::SinkExt as _;
 use tokio::io::{self, AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 
+use crate::events::ServerEvent;
 use crate::handlers::{HandlerError, Handlers};
+use crate::msg::channel_open;
 use crate::preference::Preference;
+use crate::server::HandshakePermit;
 use crate::stream::msg::MsgStream;
 use crate::SshError;
+pub use agent_forward::AgentForward;
+pub use connection_data::ConnectionData;
+pub use connection_state::{ChannelKind, ConnectionState};
 pub use ssh_stream::{SshInput, SshOutput};
+pub use tcpip_forward::TcpipForward;
+pub use x11_forward::X11Forward;
 
+mod agent_forward;
+mod channel_states;
+mod channel_windows;
 mod completion_stream;
+mod connection_data;
+mod connection_state;
 mod reader_map;
 mod run;
 mod ssh_stream;
+mod tcpip_forward;
 mod version_ex;
+mod x11_forward;
+
+/// Reply to server-initiated channel open request.
+pub(crate) type StreamOpenReply = oneshot::Sender<Result<(SshInput, SshOutput), SshError>>;
+
+/// Server-initiated channel open request from handlers.
+pub(crate) type StreamOpenRequest = (channel_open::Type, StreamOpenReply);
 
 /// Protocol Version Exchange
 ///
@@ -26,14 +50,27 @@ where
 {
     io: IO,
     preference: Arc<Preference>,
+    /// Released after version exchange.
+    permit: Option<HandshakePermit>,
+    peer_addr: Option<SocketAddr>,
 }
 
 impl<IO> Accept<IO>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
-    pub(crate) fn new(io: IO, preference: Arc<Preference>) -> Self {
-        Accept { io, preference }
+    pub(crate) fn new(
+        io: IO,
+        preference: Arc<Preference>,
+        permit: Option<HandshakePermit>,
+        peer_addr: Option<SocketAddr>,
+    ) -> Self {
+        Accept {
+            io,
+            preference,
+            permit,
+            peer_addr,
+        }
     }
 }
 
@@ -46,18 +83,28 @@ where
     c_version: String,
     s_version: String,
     preference: Arc<Preference>,
+    state: ConnectionState,
+    data: ConnectionData,
 }
 
 impl<IO> Established<IO>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
-    fn new(io: IO, c_version: String, s_version: String, preference: Arc<Preference>) -> Self {
+    fn new(
+        io: IO,
+        c_version: String,
+        s_version: String,
+        preference: Arc<Preference>,
+        peer_addr: Option<SocketAddr>,
+    ) -> Self {
         Self {
-            io: MsgStream::new(io),
+            io: MsgStream::with_rng(io, preference.rng().clone()),
             c_version,
             s_version,
             preference,
+            state: ConnectionState::new(peer_addr),
+            data: ConnectionData::default(),
         }
     }
 }
@@ -82,19 +129,89 @@ impl<IO> Connection<Accept<IO>>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
-    pub(crate) fn new(io: IO, preference: Arc<Preference>) -> Self {
-        let state = Accept::new(io, preference);
+    pub(crate) fn new(
+        io: IO,
+        preference: Arc<Preference>,
+        permit: Option<HandshakePermit>,
+        peer_addr: Option<SocketAddr>,
+    ) -> Self {
+        let state = Accept::new(io, preference, permit, peer_addr);
         Self { state }
     }
 
+    #[cfg(test)]
+    pub(crate) fn preference(&self) -> &Arc<Preference> {
+        &self.state.preference
+    }
+
     /// Performe SSH version exchange.
     pub async fn accept(self) -> Result<Connection<Established<IO>>, SshError> {
-        let Accept { mut io, preference } = self.state;
-        let (c_version, s_version) = version_ex::vex(&mut io, preference.name()).await?;
+        let Accept {
+            mut io,
+            preference,
+            permit,
+            peer_addr,
+        } = self.state;
+        let (c_version, s_version) = version_ex::vex(
+            &mut io,
+            preference.name(),
+            preference.pre_banner(),
+            *preference.banner_timeout(),
+        )
+        .await?;
+        drop(permit);
+        preference.events().emit(ServerEvent::VersionExchanged {
+            client_version: c_version.clone(),
+        });
         Ok(Connection {
-            state: Established::new(io, c_version, s_version, preference),
+            state: Established::new(io, c_version, s_version, preference, peer_addr),
         })
     }
+
+    /// Performe SSH version exchange, failing with [`SshError::Timeout`] after `timeout`.
+    ///
+    /// Bounds the handshake at the call site, in addition to `banner_timeout`.
+    pub async fn accept_timeout(
+        self,
+        timeout: Duration,
+    ) -> Result<Connection<Established<IO>>, SshError> {
+        tokio::time::timeout(timeout, self.accept())
+            .await
+            .map_err(|_| SshError::Timeout)?
+    }
+
+    /// Reject connection with `reason`, instead of closing silently.
+    ///
+    /// Performs version exchange, then sends `SSH_MSG_DISCONNECT`
+    /// (`SSH_DISCONNECT_HOST_NOT_ALLOWED_TO_CONNECT`) and closes.
+    pub async fn reject(self, reason: &str) -> Result<(), SshError> {
+        use crate::msg::disconnect::{Disconnect, ReasonCode};
+
+        let Accept {
+            mut io,
+            preference,
+            permit,
+            ..
+        } = self.state;
+        version_ex::vex(
+            &mut io,
+            preference.name(),
+            preference.pre_banner(),
+            *preference.banner_timeout(),
+        )
+        .await?;
+        drop(permit);
+
+        let mut io = MsgStream::with_rng(io, preference.rng().clone());
+        let t = ReasonCode::HostNotAllowedToConnect;
+        let language_tag = preference.language_tag().clone();
+        io.send(Disconnect::new(t.clone(), reason.into(), language_tag).into())
+            .await?;
+        preference
+            .events()
+            .emit(ServerEvent::Disconnected { reason: Some(t) });
+        io.close().await
+    }
 }
 
 impl<IO> Connection<Established<IO>>
@@ -105,6 +222,18 @@ where
         &self.state.c_version
     }
 
+    /// Shared view of this connection, updated while running.
+    pub fn state(&self) -> ConnectionState {
+        self.state.state.clone()
+    }
+
+    /// Values shared by handlers of this connection.
+    ///
+    /// e.g. user record stored by an auth handler, read by channel handlers.
+    pub fn data(&self) -> ConnectionData {
+        self.state.data.clone()
+    }
+
     /// Run with [`Handlers`]
     pub async fn run<E, Pty>(self, handler: Handlers<E, Pty>) -> Result<(), SshError>
     where
@@ -115,10 +244,29 @@ where
             c_version,
             s_version,
             preference,
+            state,
+            data,
         } = self.state;
 
-        run::Runner::new(io, c_version, s_version, preference, handler)
+        run::Runner::new(io, c_version, s_version, preference, state, data, handler)
             .run()
             .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preference::PreferenceBuilder;
+
+    #[tokio::test]
+    async fn test_accept_timeout() {
+        let (io, _client) = tokio::io::duplex(1024);
+        let preference = Arc::new(PreferenceBuilder::default().build().await.unwrap());
+        let connection = Connection::new(io, preference, None, None);
+
+        // the client never sends its banner.
+        let result = connection.accept_timeout(Duration::from_millis(100)).await;
+        assert!(matches!(result, Err(SshError::Timeout)));
+    }
+}
@@ -8,9 +8,13 @@ use futures::channel::oneshot;
 use futures::stream::Stream;
 use tokio::io::{self, AsyncRead, ReadBuf};
 
+/// Read data of key, or `None` on EOF.
+type ReadItem<K> = io::Result<(K, Option<Bytes>)>;
+
 #[derive(Debug)]
 pub(crate) struct ReaderMap<K, V> {
-    entries: Vec<(K, V, oneshot::Sender<()>)>,
+    /// Key, reader, close notify and data read but not taken yet.
+    entries: Vec<(K, V, oneshot::Sender<()>, Bytes)>,
     buf: BytesMut,
 }
 
@@ -27,49 +31,91 @@ impl<K, V> ReaderMap<K, V> {
         K: Hash + Eq,
     {
         let (tx, rx) = oneshot::channel();
-        self.entries.push((k, reader, tx));
+        self.entries.push((k, reader, tx, Bytes::new()));
         rx
     }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn remove_by<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K) -> bool,
+    {
+        let mut n = 0;
+        while n < self.entries.len() {
+            if f(&self.entries[n].0) {
+                let (_, _, close_notify, _) = self.entries.swap_remove(n);
+                close_notify.send(()).ok();
+            } else {
+                n += 1;
+            }
+        }
+    }
 }
 
-impl<K, V> Stream for ReaderMap<K, V>
+impl<K, V> ReaderMap<K, V>
 where
-    K: Clone + Unpin,
+    K: Clone,
     V: AsyncRead + Unpin,
 {
-    type Item = io::Result<(K, Option<Bytes>)>;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    /// Take at most `limit(key)` bytes from any reader.
+    ///
+    /// Readers limited to zero are read ahead up to one buffer, so that EOF is still detected.
+    pub(crate) fn poll_next_limited<F>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut limit: F,
+    ) -> Poll<Option<ReadItem<K>>>
+    where
+        F: FnMut(&K, &mut Context<'_>) -> usize,
+    {
         let Self {
             ref mut entries,
             ref mut buf,
-        } = self.get_mut();
+        } = self;
 
         for n in 0..entries.len() {
-            let (k, reader, _) = &mut entries[n];
-            buf.clear();
+            let (k, reader, _, pending) = &mut entries[n];
+            let limit = limit(k, cx);
+
+            if pending.is_empty() {
+                buf.clear();
 
-            let dst = buf.chunk_mut();
-            let dst = unsafe { &mut *(dst as *mut _ as *mut [MaybeUninit<u8>]) };
-            let mut buf = ReadBuf::uninit(dst);
-            match Pin::new(reader).poll_read(cx, &mut buf)? {
-                Poll::Ready(()) => {
-                    if buf.filled().is_empty() {
-                        let (k, _, close_notify) = entries.swap_remove(n);
+                let dst = buf.chunk_mut();
+                let dst = unsafe { &mut *(dst as *mut _ as *mut [MaybeUninit<u8>]) };
+                let mut buf = ReadBuf::uninit(dst);
+                match Pin::new(reader).poll_read(cx, &mut buf)? {
+                    Poll::Ready(()) if buf.filled().is_empty() => {
+                        let (k, _, close_notify, _) = entries.swap_remove(n);
                         close_notify.send(()).ok();
                         return Poll::Ready(Some(Ok((k, None))));
-                    } else {
-                        let buf = buf.filled();
-                        return Poll::Ready(Some(Ok((
-                            k.clone(),
-                            Some(Bytes::copy_from_slice(buf)),
-                        ))));
                     }
+                    Poll::Ready(()) => *pending = Bytes::copy_from_slice(buf.filled()),
+                    Poll::Pending => continue,
                 }
-                Poll::Pending => {}
+            }
+
+            if limit > 0 {
+                let data = pending.split_to(pending.len().min(limit));
+                return Poll::Ready(Some(Ok((k.clone(), Some(data)))));
             }
         }
 
         Poll::Pending
     }
 }
+
+impl<K, V> Stream for ReaderMap<K, V>
+where
+    K: Clone + Unpin,
+    V: AsyncRead + Unpin,
+{
+    type Item = ReadItem<K>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_next_limited(cx, |_, _| usize::MAX)
+    }
+}
@@ -1,38 +1,60 @@
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use std::collections::HashMap;
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
 use std::future::Future;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::channel::{mpsc, oneshot};
-use futures::future::{Either, TryFutureExt as _};
+use futures::future::{self, Either, FutureExt as _, TryFutureExt as _};
 use futures::lock::Mutex;
 use futures::sink::SinkExt as _;
 use futures::stream::Stream;
 use futures::stream::StreamExt as _;
+use getset::Getters;
 use log::{debug, error, warn};
+use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::time;
 use tokio_pipe::{PipeRead, PipeWrite};
 
-use crate::handlers::{HandlerError, Handlers};
+use crate::events::ServerEvent;
+use crate::handlers::{HandlerError, Handlers, SessionState};
 use crate::msg::channel_extended_data::DataTypeCode;
+use crate::msg::channel_open_failure::ReasonCode as OpenFailureReasonCode;
+use crate::msg::channel_request::X11Req;
 use crate::msg::{self, Msg};
 use crate::preference::Preference;
+use crate::quirks::QuirkSet;
 use crate::stream::msg::MsgStream;
-use crate::SshError;
+use crate::{Signal, SshError};
 
+use super::agent_forward::AgentForward;
+use super::channel_states::ChannelStates;
+use super::channel_windows::ChannelWindows;
 use super::completion_stream::CompletionStream;
+use super::connection_data::ConnectionData;
+use super::connection_state::{ChannelKind, ConnectionState};
 use super::reader_map::ReaderMap;
 use super::ssh_stream::{SshInput, SshOutput};
+use super::tcpip_forward::TcpipForward;
+use super::x11_forward::X11Forward;
+use super::{StreamOpenReply, StreamOpenRequest};
 
 mod on_channel_close;
 mod on_channel_data;
 mod on_channel_eof;
+mod on_channel_failure;
 mod on_channel_open;
+mod on_channel_open_confirmation;
+mod on_channel_open_failure;
 mod on_channel_request;
+mod on_channel_success;
 mod on_channel_window_adjust;
+mod on_disconnect;
 mod on_global_request;
 mod on_kexinit;
 mod on_service_request;
@@ -41,13 +63,35 @@ mod on_userauth_request;
 type TaskStream = Arc<
     Mutex<
         CompletionStream<
-            (u32, bool, Vec<oneshot::Receiver<()>>),
-            Result<Option<u32>, HandlerError>,
+            (u32, u32, bool, Vec<oneshot::Receiver<()>>),
+            Result<Option<Exit>, HandlerError>,
         >,
     >,
 >;
 
-type OutputReaderMap = Arc<Mutex<ReaderMap<(u32, Option<DataTypeCode>), PipeRead>>>;
+/// How a session handler terminated.
+#[derive(Debug)]
+enum Exit {
+    Status(u32),
+    Signal(Signal),
+}
+
+const INITIAL_WINDOW_SIZE: u32 = 2 * 1024 * 1024;
+const MAXIMUM_PACKET_SIZE: u32 = 32 * 1024;
+
+/// Server initiated channel open rejected by client.
+#[derive(Debug, Clone, Error, Getters)]
+#[error("channel open failed: {reason_code:?} {description}")]
+pub(super) struct ChannelOpenError {
+    #[get = "pub(super)"]
+    reason_code: OpenFailureReasonCode,
+
+    #[get = "pub(super)"]
+    description: String,
+}
+
+/// Keyed by local channel id, client channel id and data type.
+type OutputReaderMap = Arc<Mutex<ReaderMap<(u32, u32, Option<DataTypeCode>), PipeRead>>>;
 
 struct LockNext<'a, S> {
     inner: &'a mut S,
@@ -84,24 +128,101 @@ impl<S> MutexStream for Arc<Mutex<S>> {
 
 #[derive(Debug)]
 enum Channel<Pty> {
-    Session(
-        u32,
-        Option<PipeWrite>,
-        Option<SshInput>,
-        HashMap<String, String>,
-        Option<Pty>,
-    ),
-    DirectTcpip(u32, Option<PipeWrite>),
+    Session {
+        stdin: Option<PipeWrite>,
+        input: Option<SshInput>,
+        state: SessionState<Pty>,
+    },
+    DirectTcpip {
+        stdin: Option<PipeWrite>,
+    },
+    Forwarded {
+        stdin: Option<PipeWrite>,
+    },
+}
+
+impl<Pty> Channel<Pty> {
+    fn stdin_mut(&mut self) -> &mut Option<PipeWrite> {
+        match self {
+            Self::Session { stdin, .. }
+            | Self::DirectTcpip { stdin }
+            | Self::Forwarded { stdin } => stdin,
+        }
+    }
+
+    fn kind(&self) -> ChannelKind {
+        match self {
+            Self::Session { .. } => ChannelKind::Session,
+            Self::DirectTcpip { .. } => ChannelKind::DirectTcpip,
+            Self::Forwarded { .. } => ChannelKind::Forwarded,
+        }
+    }
 }
 
-fn maybe_timeout(preference: &Preference) -> impl Future<Output = ()> {
-    if let Some(timeout) = preference.timeout() {
+fn maybe_timeout(timeout: &Option<Duration>) -> impl Future<Output = ()> {
+    if let Some(timeout) = timeout {
         Either::Left(time::sleep(*timeout))
     } else {
         Either::Right(futures::future::pending())
     }
 }
 
+fn maybe_deadline(deadline: Option<time::Instant>) -> impl Future<Output = ()> {
+    if let Some(deadline) = deadline {
+        Either::Left(time::sleep_until(deadline))
+    } else {
+        Either::Right(futures::future::pending())
+    }
+}
+
+/// Fail with [`SshError::KexTimeout`] if `fut` is not completed before `deadline`.
+async fn within_deadline<F, T>(deadline: Option<time::Instant>, fut: F) -> Result<T, SshError>
+where
+    F: Future<Output = Result<T, SshError>>,
+{
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline, fut)
+            .await
+            .map_err(|_| SshError::KexTimeout)?,
+        None => fut.await,
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+/// Catch panic of handler future.
+///
+/// Panic completes as `Ok(None)` (exit status 255), or as error if `abort` is set.
+async fn catch_handler_panic<F>(
+    channel: u32,
+    abort: bool,
+    fut: F,
+) -> Result<Option<Exit>, HandlerError>
+where
+    F: Future<Output = Result<Option<Exit>, HandlerError>>,
+{
+    match AssertUnwindSafe(fut).catch_unwind().await {
+        Ok(result) => result,
+        Err(panic) => {
+            let msg = format!("handler panicked: {}", panic_message(&*panic));
+            error!("channel {}: {}", channel, msg);
+            if abort {
+                Err(msg.into())
+            } else {
+                Ok(None)
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(super) struct Runner<IO, E, Pty>
 where
@@ -112,14 +233,38 @@ where
     c_version: String,
     s_version: String,
     preference: Arc<Preference>,
+    quirks: QuirkSet,
+    state: ConnectionState,
+    data: ConnectionData,
     handlers: Handlers<E, Pty>,
     channels: HashMap<u32, Channel<Pty>>,
+    /// Local channel id to client channel id.
+    remote_channels: HashMap<u32, u32>,
+    pending_channel_requests: HashMap<u32, VecDeque<oneshot::Sender<bool>>>,
+    pending_channel_opens: HashMap<u32, oneshot::Sender<Result<u32, ChannelOpenError>>>,
+    pending_stream_opens: HashMap<u32, StreamOpenReply>,
     output_readers: OutputReaderMap,
     completions: TaskStream,
-    msg_queue_tx: mpsc::UnboundedSender<Msg>,
-    msg_queue_rx: mpsc::UnboundedReceiver<Msg>,
+    msg_queue_tx: mpsc::Sender<Msg>,
+    msg_queue_rx: mpsc::Receiver<Msg>,
+    close_channel_tx: mpsc::UnboundedSender<u32>,
+    close_channel_rx: mpsc::UnboundedReceiver<u32>,
+    stream_open_tx: mpsc::UnboundedSender<StreamOpenRequest>,
+    stream_open_rx: mpsc::UnboundedReceiver<StreamOpenRequest>,
+    channel_states: ChannelStates,
+    channel_windows: ChannelWindows,
     first_kexinit: Option<msg::kexinit::Kexinit>,
+    kex_deadline: Option<time::Instant>,
+    service_request_deadline: Option<time::Instant>,
+    /// Completion of the last key exchange.
+    last_kex: Option<time::Instant>,
+    userauth_requested: bool,
+    no_more_sessions: bool,
+    total_channels: u32,
     auth_state: on_userauth_request::AuthState,
+    /// Reason of `SSH_MSG_DISCONNECT` sent or received.
+    disconnect_reason: Option<msg::disconnect::ReasonCode>,
+    byte_quota_warned: bool,
 }
 
 impl<IO, E, Pty> Runner<IO, E, Pty>
@@ -132,23 +277,49 @@ where
         c_version: String,
         s_version: String,
         preference: Arc<Preference>,
+        state: ConnectionState,
+        data: ConnectionData,
         handlers: Handlers<E, Pty>,
     ) -> Self {
-        let (msg_queue_tx, msg_queue_rx) = mpsc::unbounded();
+        let (msg_queue_tx, msg_queue_rx) = mpsc::channel(*preference.outbound_queue_len());
+        let (close_channel_tx, close_channel_rx) = mpsc::unbounded();
+        let (stream_open_tx, stream_open_rx) = mpsc::unbounded();
+        let quirks = preference.quirks_for(&c_version);
 
         Self {
             io,
             c_version,
             s_version,
             preference,
+            quirks,
+            state,
+            data,
             handlers,
             channels: Default::default(),
+            remote_channels: Default::default(),
+            pending_channel_requests: Default::default(),
+            pending_channel_opens: Default::default(),
+            pending_stream_opens: Default::default(),
             output_readers: Arc::new(Mutex::new(ReaderMap::new())),
             completions: Arc::new(Mutex::new(CompletionStream::new())),
             msg_queue_tx,
             msg_queue_rx,
+            close_channel_tx,
+            close_channel_rx,
+            stream_open_tx,
+            stream_open_rx,
+            channel_states: Default::default(),
+            channel_windows: Default::default(),
             first_kexinit: None,
+            kex_deadline: None,
+            service_request_deadline: None,
+            last_kex: None,
+            userauth_requested: false,
+            no_more_sessions: false,
+            total_channels: 0,
             auth_state: on_userauth_request::AuthState::new(),
+            disconnect_reason: None,
+            byte_quota_warned: false,
         }
     }
 
@@ -156,11 +327,166 @@ where
         self.io.send(msg.into()).await
     }
 
+    /// Send channel request with `want_reply`.
+    ///
+    /// Returned receiver resolves with `true` on `SSH_MSG_CHANNEL_SUCCESS`,
+    /// `false` on `SSH_MSG_CHANNEL_FAILURE`.
+    #[cfg(test)]
+    async fn send_channel_request(
+        &mut self,
+        channel: u32,
+        typ: msg::channel_request::Type,
+    ) -> Result<oneshot::Receiver<bool>, SshError> {
+        use msg::channel_request::ChannelRequest;
+
+        let (tx, rx) = oneshot::channel();
+        self.pending_channel_requests
+            .entry(channel)
+            .or_default()
+            .push_back(tx);
+        let msg = ChannelRequest::new(self.remote_channel(channel), true, typ);
+        self.send(msg).await?;
+        Ok(rx)
+    }
+
+    fn resolve_channel_request(&mut self, channel: u32, success: bool) {
+        let pending = self
+            .pending_channel_requests
+            .get_mut(&channel)
+            .and_then(VecDeque::pop_front);
+        if let Some(tx) = pending {
+            tx.send(success).ok();
+        } else {
+            warn!("unexpected channel request reply for channel {}", channel);
+        }
+    }
+
+    /// Lowest local channel id not in use.
+    ///
+    /// An id is in use until both sides closed the channel and its handler completed.
+    fn allocate_channel_id(&self) -> u32 {
+        (0..)
+            .find(|chid| {
+                !self.channel_states.in_use(*chid) && !self.pending_channel_opens.contains_key(chid)
+            })
+            .unwrap()
+    }
+
+    /// Register opened channel with client's initial window size.
+    fn open_channel(&mut self, chid: u32, remote: u32, window: u32, channel: Channel<Pty>) {
+        self.channel_states.open(chid);
+        self.channel_windows.open(chid, window);
+        self.remote_channels.insert(chid, remote);
+        self.state.opened(chid, channel.kind());
+        let event = ServerEvent::ChannelOpened {
+            id: chid,
+            kind: channel.kind(),
+        };
+        self.preference.events().emit(event);
+        self.channels.insert(chid, channel);
+    }
+
+    /// Forget opened channel.
+    fn remove_channel(&mut self, chid: u32) -> Option<Channel<Pty>> {
+        self.state.closed(chid);
+        self.channel_windows.remove(chid);
+        let channel = self.channels.remove(&chid);
+        if channel.is_some() {
+            let event = ServerEvent::ChannelClosed { id: chid };
+            self.preference.events().emit(event);
+        }
+        channel
+    }
+
+    /// Client channel id of local channel. Unknown channel is answered as is.
+    fn remote_channel(&self, chid: u32) -> u32 {
+        self.remote_channels.get(&chid).copied().unwrap_or(chid)
+    }
+
+    /// Open channel from server side.
+    ///
+    /// Returns reserved local channel id and receiver that resolves with the client's
+    /// channel id on `SSH_MSG_CHANNEL_OPEN_CONFIRMATION`, or [`ChannelOpenError`] on
+    /// `SSH_MSG_CHANNEL_OPEN_FAILURE`.
+    async fn send_channel_open(
+        &mut self,
+        typ: msg::channel_open::Type,
+    ) -> Result<(u32, oneshot::Receiver<Result<u32, ChannelOpenError>>), SshError> {
+        use msg::channel_open::ChannelOpen;
+
+        let chid = self.allocate_channel_id();
+        let (tx, rx) = oneshot::channel();
+        self.pending_channel_opens.insert(chid, tx);
+        let msg = ChannelOpen::new(chid, INITIAL_WINDOW_SIZE, MAXIMUM_PACKET_SIZE, typ);
+        self.send(msg).await?;
+        Ok((chid, rx))
+    }
+
+    fn resolve_channel_open(&mut self, channel: u32, result: Result<u32, ChannelOpenError>) {
+        if let Some(tx) = self.pending_channel_opens.remove(&channel) {
+            tx.send(result).ok();
+        } else {
+            warn!("unexpected channel open reply for channel {}", channel);
+        }
+    }
+
+    fn agent_forward(&self) -> AgentForward {
+        AgentForward::new(self.stream_open_tx.clone())
+    }
+
+    fn tcpip_forward(&self, address: String, port: u32) -> TcpipForward {
+        TcpipForward::new(address, port, self.stream_open_tx.clone())
+    }
+
+    fn x11_forward(&self, req: &X11Req) -> X11Forward {
+        X11Forward::new(
+            *req.single_connection(),
+            req.x11_auth_protocol().clone(),
+            req.x11_auth_cookie().clone(),
+            *req.x11_screen_number(),
+            self.stream_open_tx.clone(),
+        )
+    }
+
+    async fn open_stream_channel(&mut self, request: StreamOpenRequest) -> Result<(), SshError> {
+        let (typ, reply) = request;
+        let (chid, _) = self.send_channel_open(typ).await?;
+        self.pending_stream_opens.insert(chid, reply);
+        Ok(())
+    }
+
+    /// Attach streams to confirmed server-initiated channel.
+    ///
+    /// EOF / close are sent to the client when the output is closed.
+    async fn attach_stream_channel(
+        &mut self,
+        chid: u32,
+        remote: u32,
+        window: u32,
+    ) -> Result<(SshInput, SshOutput), SshError> {
+        let (input_r, input_w) = tokio_pipe::pipe()?;
+        let channel = Channel::Forwarded {
+            stdin: Some(input_w),
+        };
+        self.open_channel(chid, remote, window, channel);
+        let (output, output_closed) = self.new_output(chid, None).await?;
+        self.channel_states.spawned(chid);
+
+        let completions = self.completions.clone();
+        let mut completions = completions.lock().await;
+        completions.push((chid, remote, false, vec![output_closed]), async {
+            Ok(None)
+        });
+
+        Ok((SshInput::new(input_r), output))
+    }
+
     async fn new_output(
         &mut self,
         channel: u32,
         type_code: Option<DataTypeCode>,
     ) -> Result<(SshOutput, oneshot::Receiver<()>), SshError> {
+        let remote = self.remote_channel(channel);
         let output_readers = self.output_readers.clone();
         let mut output_readers = output_readers.lock().await;
 
@@ -170,7 +496,7 @@ where
             "channel: {}, type: {:?} output: {:?} opened.",
             channel, &type_code, output
         );
-        let closed = output_readers.insert((channel, type_code), r);
+        let closed = output_readers.insert((channel, remote, type_code), r);
 
         Ok((output, closed))
     }
@@ -180,6 +506,7 @@ where
         channel: u32,
         stdout_closed: oneshot::Receiver<()>,
         stderr_closed: oneshot::Receiver<()>,
+        exit_signal: Arc<std::sync::Mutex<Option<Signal>>>,
         fut: F,
     ) where
         F: Future<Output = Result<u32, ERR>> + Send + 'static,
@@ -192,9 +519,20 @@ where
             debug!("spawn handler {}", channel);
             let r = fut.map_err(Into::into).await?;
             debug!("done spawn handler {}", channel);
-            Ok::<_, HandlerError>(Some(r))
+            let exit = match exit_signal.lock().unwrap().take() {
+                Some(signal) => Exit::Signal(signal),
+                None => Exit::Status(r),
+            };
+            Ok::<_, HandlerError>(Some(exit))
         };
-        completions.push((channel, true, vec![stdout_closed, stderr_closed]), fut);
+        let abort = *self.preference.abort_connection_on_panic();
+        let fut = catch_handler_panic(channel, abort, fut);
+        let remote = self.remote_channel(channel);
+        self.channel_states.spawned(channel);
+        completions.push(
+            (channel, remote, true, vec![stdout_closed, stderr_closed]),
+            fut,
+        );
     }
 
     async fn spawn_handler<F, ERR>(
@@ -215,7 +553,11 @@ where
             debug!("done spawn handler {}", channel);
             Ok(None)
         };
-        completions.push((channel, true, vec![output_closed]), fut);
+        let abort = *self.preference.abort_connection_on_panic();
+        let fut = catch_handler_panic(channel, abort, fut);
+        let remote = self.remote_channel(channel);
+        self.channel_states.spawned(channel);
+        completions.push((channel, remote, true, vec![output_closed]), fut);
     }
 
     pub(super) async fn run(mut self) -> Result<(), SshError> {
@@ -226,66 +568,265 @@ where
         if let Err(e) = &result {
             error!("error ocurred {}", e);
             let t = e.reason_code().unwrap_or(ReasonCode::ProtocolError);
-            let msg = Disconnect::new(t, "error occurred".into(), "".into());
+            let description = match e {
+                SshError::Timeout => self.preference.timeout_description().clone(),
+                _ => "error occurred".into(),
+            };
+            let language_tag = self.preference.language_tag().clone();
+            let msg = Disconnect::new(t.clone(), description, language_tag);
             if let Err(e) = self.send(msg).await {
                 error!("failed to send disconnect: {}", e)
             }
+            self.disconnect_reason = Some(t);
         }
+        self.close_channels().await;
+        let reason = self.disconnect_reason.take();
+        self.preference
+            .events()
+            .emit(ServerEvent::Disconnected { reason });
         debug!("connection done.");
         self.io.close().await.ok();
         result
     }
 
+    /// Wait `fut` while sending queued messages.
+    ///
+    /// Message loop is the only consumer of the queue. Waiting for anything which depends on
+    /// handler progress (e.g. handler stdin) must keep the queue moving.
+    async fn with_queue_flushing<F>(&mut self, fut: F) -> Result<F::Output, SshError>
+    where
+        F: Future,
+    {
+        tokio::pin!(fut);
+        loop {
+            tokio::select! {
+                result = &mut fut => return Ok(result),
+                Some(msg) = self.msg_queue_rx.next() => self.send(msg).await?,
+                Some(request) = self.stream_open_rx.next() => self.open_stream_channel(request).await?,
+            }
+        }
+    }
+
+    /// Wait handler future while sending queued messages.
+    ///
+    /// Every handler awaited by the message loop goes through here, since it may wait for
+    /// channel output to be sent (e.g. another task writing to a full queue).
+    /// Channel opened to the client is not confirmed until the handler completes, so stream
+    /// open requests fail with [`SshError::ChannelOpenInHandler`] meanwhile instead of deadlock.
+    async fn wait_handler<F>(&mut self, fut: F) -> Result<F::Output, SshError>
+    where
+        F: Future,
+    {
+        tokio::pin!(fut);
+        loop {
+            tokio::select! {
+                result = &mut fut => return Ok(result),
+                Some(msg) = self.msg_queue_rx.next() => self.send(msg).await?,
+                Some((_, reply)) = self.stream_open_rx.next() => {
+                    reply.send(Err(SshError::ChannelOpenInHandler)).ok();
+                }
+            }
+        }
+    }
+
+    async fn close_channels(&mut self) {
+        let channels = self
+            .channels
+            .drain()
+            .map(|(chid, _)| chid)
+            .collect::<Vec<_>>();
+        self.remote_channels.clear();
+        self.channel_states.clear();
+        self.channel_windows.clear();
+        self.state.clear();
+        self.output_readers.lock().await.remove_by(|_| true);
+        for chid in channels {
+            let event = ServerEvent::ChannelClosed { id: chid };
+            self.preference.events().emit(event);
+            if let Some(fut) = self.handlers.dispatch_channel_close(chid) {
+                if let Err(e) = fut.await {
+                    error!("failed to close channel {}: {}", chid, e.into());
+                }
+            }
+        }
+    }
+
+    /// Drop stdout / stderr readers of channel. Output not read yet is discarded.
+    async fn drop_output(&mut self, chid: u32) {
+        self.output_readers
+            .lock()
+            .await
+            .remove_by(|(channel, _, _)| *channel == chid);
+    }
+
+    /// Send queued messages without waiting.
+    ///
+    /// Queued channel data must precede EOF / close sent directly.
+    /// This loop is the queue consumer, so must not wait for the queue.
+    async fn flush_queue(&mut self) -> Result<(), SshError> {
+        while let Some(Some(msg)) = self.msg_queue_rx.next().now_or_never() {
+            self.send(msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Close channel requested by handler.
+    ///
+    /// Nothing is sent if the channel is already closed from the server side.
+    async fn close_channel(&mut self, chid: u32) -> Result<(), SshError> {
+        use msg::channel_close::ChannelClose;
+        use msg::channel_eof::ChannelEof;
+
+        if !self.channel_states.close(chid) {
+            return Ok(());
+        }
+        let channel = self.remove_channel(chid);
+        self.pending_channel_requests.remove(&chid);
+        // drop stdout / stderr readers, then stdin writer.
+        self.drop_output(chid).await;
+        drop(channel);
+
+        self.flush_queue().await?;
+        let remote = self.remote_channel(chid);
+        self.send(ChannelEof::new(remote)).await?;
+        self.send(ChannelClose::new(remote)).await?;
+
+        if let Some(fut) = self.handlers.dispatch_channel_close(chid) {
+            self.wait_handler(fut)
+                .await?
+                .map_err(|e| SshError::HandlerError(e.into()))?;
+        }
+        Ok(())
+    }
+
     async fn r#loop(&mut self) -> Result<(), SshError> {
-        let first_kexinit = self.preference.to_kexinit();
+        let first_kexinit = self.preference.to_kexinit_for(&self.quirks);
         self.send(first_kexinit.clone()).await?;
         self.first_kexinit = Some(first_kexinit);
+        self.start_kex_deadline();
 
         let reader = self.output_readers.clone();
         let tasks = self.completions.clone();
+        let channel_states = self.channel_states.clone();
+        let channel_windows = self.channel_windows.clone();
         let msg_queue_tx = self.msg_queue_tx.clone();
+        let session_timeout = maybe_timeout(self.preference.max_session_duration());
 
         tokio::select! {
             result = self.msg_loop() => result,
-            result = Self::data_output_loop(reader, msg_queue_tx.clone()) => result,
-            result = Self::task_loop(tasks, msg_queue_tx) => result,
+            _ = session_timeout => Err(SshError::SessionDurationExceeded),
+            result = Self::data_output_loop(reader, channel_windows, msg_queue_tx.clone()) => result,
+            result = Self::task_loop(tasks, channel_states, msg_queue_tx) => result,
         }
     }
 
+    /// Start key exchange deadline if configured. Cleared on `SSH_MSG_NEWKEYS`.
+    fn start_kex_deadline(&mut self) {
+        self.kex_deadline = self
+            .preference
+            .kex_timeout()
+            .map(|timeout| time::Instant::now() + timeout);
+    }
+
+    /// Start service request deadline if configured. Cleared on `SSH_MSG_SERVICE_REQUEST`.
+    fn start_service_request_deadline(&mut self) {
+        if self.userauth_requested {
+            return;
+        }
+        self.service_request_deadline = self
+            .preference
+            .service_request_timeout()
+            .map(|timeout| time::Instant::now() + timeout);
+    }
+
     async fn msg_loop(&mut self) -> Result<(), SshError> {
         loop {
-            let timeout = maybe_timeout(&self.preference);
+            let timeout = maybe_timeout(self.preference.timeout());
             tokio::pin!(timeout);
+            let kex_deadline = maybe_deadline(self.kex_deadline);
+            tokio::pin!(kex_deadline);
+            let service_request_deadline = maybe_deadline(self.service_request_deadline);
+            tokio::pin!(service_request_deadline);
 
             tokio::select! {
                 msg = self.io.next() => {match msg {
-                    Some(msg) => self.handle_msg(&msg?).await?,
+                    Some(msg) => {
+                        let msg = msg?;
+                        self.handle_msg(&msg).await?;
+                        if let Msg::Disconnect(..) = msg {
+                            return Ok(());
+                        }
+                    }
                     None => return Ok(()),
                 }}
                 Some(msg) = self.msg_queue_rx.next() => self.send(msg).await?,
-                _ = &mut timeout => return Err(SshError::Timeout)
+                Some(chid) = self.close_channel_rx.next() => self.close_channel(chid).await?,
+                Some(request) = self.stream_open_rx.next() => self.open_stream_channel(request).await?,
+                _ = &mut timeout => return Err(SshError::Timeout),
+                _ = &mut kex_deadline => return Err(SshError::KexTimeout),
+                _ = &mut service_request_deadline => return Err(SshError::ServiceRequestTimeout),
             }
+            self.check_byte_quota()?;
         }
     }
 
+    /// Fail when bytes in and out exceed `byte_quota`. Warns once at 90%.
+    fn check_byte_quota(&mut self) -> Result<(), SshError> {
+        let quota = match self.preference.byte_quota() {
+            Some(quota) => *quota,
+            None => return Ok(()),
+        };
+        let state = self.io.get_ref().state();
+        let total = state.ctos().bytes().saturating_add(state.stoc().bytes());
+        if total > quota {
+            return Err(SshError::ByteQuotaExceeded(quota));
+        }
+        if !self.byte_quota_warned && total >= quota / 10 * 9 {
+            warn!("{} of byte quota {} used", total, quota);
+            self.byte_quota_warned = true;
+        }
+        Ok(())
+    }
+
     async fn data_output_loop(
-        mut read: OutputReaderMap,
-        mut queue: mpsc::UnboundedSender<Msg>,
+        read: OutputReaderMap,
+        windows: ChannelWindows,
+        mut queue: mpsc::Sender<Msg>,
     ) -> Result<(), SshError> {
         use msg::channel_data::ChannelData;
         use msg::channel_extended_data::ChannelExtendedData;
 
-        while let Some(result) = read.lock_next().await {
-            let ((channel_id, type_code), buf) = result?;
+        loop {
+            // Reserve queue slot before reading, so that read data is queued without waiting.
+            // Output is not read while the queue is full, which blocks handler writes.
+            future::poll_fn(|cx| queue.poll_ready(cx)).await?;
+            // Channels without client window are not read until window adjust.
+            let mut lock = None;
+            let result = future::poll_fn(|cx| {
+                let locking = lock.get_or_insert_with(|| read.lock());
+                let mut read = futures::ready!(Pin::new(locking).poll(cx));
+                lock = None;
+                read.poll_next_limited(cx, |(chid, _, _), cx| windows.poll_available(*chid, cx))
+            })
+            .await;
+            let result = match result {
+                Some(result) => result,
+                None => break,
+            };
+            let ((chid, channel_id, type_code), buf) = result?;
+            if let Some(buf) = &buf {
+                windows.consume(chid, buf.len());
+            }
 
             match (type_code, buf) {
+                (_, Some(buf)) if buf.is_empty() => {}
                 (Some(data_type), Some(buf)) => {
                     let msg = ChannelExtendedData::new(channel_id, data_type, buf).into();
-                    queue.send(msg).await?;
+                    queue.start_send(msg)?;
                 }
                 (None, Some(buf)) => {
                     let msg = ChannelData::new(channel_id, buf).into();
-                    queue.send(msg).await?;
+                    queue.start_send(msg)?;
                 }
                 (type_code, None) => {
                     debug!("channel: {}, type: {:?} reach eof.", channel_id, type_code)
@@ -297,33 +838,45 @@ where
 
     async fn task_loop(
         mut tasks: TaskStream,
-        mut queue: mpsc::UnboundedSender<Msg>,
+        channel_states: ChannelStates,
+        mut queue: mpsc::Sender<Msg>,
     ) -> Result<(), SshError> {
         use msg::channel_close::ChannelClose;
         use msg::channel_eof::ChannelEof;
-        use msg::channel_request::{ChannelRequest, Type};
+        use msg::channel_request::{ChannelRequest, ExitSignal, Type};
 
         while let Some(completed) = tasks.lock_next().await {
-            let ((channel_id, notify_status, output_closed), status) = completed;
+            let ((channel_id, remote, notify_status, output_closed), status) = completed;
 
+            // Output readers notify close on EOF, after all preceding data was queued.
+            // So stdout / stderr data always precede exit-status and close.
             for f in output_closed {
                 f.await.ok();
             }
 
-            let msg = ChannelEof::new(channel_id).into();
+            if !channel_states.completed(channel_id) {
+                // already closed.
+                status.map_err(SshError::HandlerError)?;
+                continue;
+            }
+
+            let msg = ChannelEof::new(remote).into();
             queue.send(msg).await?;
 
             if notify_status {
-                let status = match status {
-                    Ok(Some(status)) => status,
-                    Err(_) | Ok(None) => 255,
+                let typ = match &status {
+                    Ok(Some(Exit::Status(status))) => Type::ExitStatus(*status),
+                    Ok(Some(Exit::Signal(signal))) => {
+                        let name = signal.name().to_string();
+                        Type::ExitSignal(ExitSignal::new(name, false, "".into(), "".into()))
+                    }
+                    Err(_) | Ok(None) => Type::ExitStatus(255),
                 };
-                let typ = Type::ExitStatus(status);
-                let msg = ChannelRequest::new(channel_id, false, typ).into();
+                let msg = ChannelRequest::new(remote, false, typ).into();
                 queue.send(msg).await?;
             }
 
-            let msg = ChannelClose::new(channel_id).into();
+            let msg = ChannelClose::new(remote).into();
             queue.send(msg).await?;
 
             status.map_err(SshError::HandlerError)?;
@@ -338,12 +891,16 @@ where
             Msg::UserauthRequest(msg) => self.on_userauth_request(msg).await?,
             Msg::GlobalRequest(msg) => self.on_global_request(msg).await?,
             Msg::ChannelOpen(msg) => self.on_channel_open(msg).await?,
+            Msg::ChannelOpenConfirmation(msg) => self.on_channel_open_confirmation(msg).await?,
+            Msg::ChannelOpenFailure(msg) => self.on_channel_open_failure(msg).await?,
             Msg::ChannelData(msg) => self.on_channel_data(msg).await?,
             Msg::ChannelEof(msg) => self.on_channel_eof(msg).await?,
             Msg::ChannelClose(msg) => self.on_channel_close(msg).await?,
             Msg::ChannelWindowAdjust(msg) => self.on_channel_window_adjust(msg).await?,
             Msg::ChannelRequest(msg) => self.on_channel_request(msg).await?,
-            Msg::Disconnect(..) => {}
+            Msg::ChannelSuccess(msg) => self.on_channel_success(msg).await?,
+            Msg::ChannelFailure(msg) => self.on_channel_failure(msg).await?,
+            Msg::Disconnect(msg) => self.on_disconnect(msg).await?,
             Msg::Ignore(..) => {}
             Msg::Unimplemented(..) => {}
             x => {
@@ -358,3 +915,1086 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::msg::channel_failure::ChannelFailure;
+    use crate::msg::channel_open::{self, ChannelOpen};
+    use crate::msg::channel_request::{ChannelRequest, Type};
+    use crate::msg::channel_success::ChannelSuccess;
+    use crate::msg::disconnect::ReasonCode;
+    use crate::msg::ignore::Ignore;
+    use crate::preference::PreferenceBuilder;
+
+    pub(super) async fn new_runner(
+        preference: &PreferenceBuilder,
+    ) -> (
+        Runner<tokio::io::DuplexStream, HandlerError, ()>,
+        MsgStream<tokio::io::DuplexStream>,
+    ) {
+        new_runner_with_handlers(preference, Handlers::new()).await
+    }
+
+    pub(super) async fn new_runner_with_handlers(
+        preference: &PreferenceBuilder,
+        handlers: Handlers<HandlerError>,
+    ) -> (
+        Runner<tokio::io::DuplexStream, HandlerError, ()>,
+        MsgStream<tokio::io::DuplexStream>,
+    ) {
+        let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+        let preference = Arc::new(preference.build().await.unwrap());
+        let runner = Runner::new(
+            MsgStream::new(server_io),
+            "".into(),
+            "".into(),
+            preference,
+            Default::default(),
+            Default::default(),
+            handlers,
+        );
+        (runner, MsgStream::new(client_io))
+    }
+
+    #[tokio::test]
+    async fn test_channel_request_reply() {
+        let (mut runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+
+        let success = runner
+            .send_channel_request(0, Type::Signal("TERM".into()))
+            .await
+            .unwrap();
+        let failure = runner
+            .send_channel_request(0, Type::Signal("KILL".into()))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            match client.next().await {
+                Some(Ok(Msg::ChannelRequest(msg))) => assert!(*msg.want_reply()),
+                x => panic!("{:?}", x),
+            }
+        }
+
+        client.send(ChannelSuccess::new(0).into()).await.unwrap();
+        client.send(ChannelFailure::new(0).into()).await.unwrap();
+        for _ in 0..2 {
+            let msg = runner.io.next().await.unwrap().unwrap();
+            runner.handle_msg(&msg).await.unwrap();
+        }
+
+        assert!(success.await.unwrap());
+        assert!(!failure.await.unwrap());
+    }
+
+    fn panicking_shell_handlers() -> Handlers<HandlerError> {
+        use futures::future::FutureExt as _;
+
+        let mut handlers = Handlers::new();
+        handlers.on_channel_shell(|_| async { panic!("boom") }.boxed());
+        handlers
+    }
+
+    #[tokio::test]
+    async fn test_handler_panic() {
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), panicking_shell_handlers())
+                .await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let msg = ChannelOpen::new(0, 1024, 1024, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        client
+            .send(ChannelRequest::new(0, true, Type::Shell(())).into())
+            .await
+            .unwrap();
+
+        let mut status = None;
+        loop {
+            match client.next().await {
+                Some(Ok(Msg::ChannelRequest(msg))) => {
+                    if let Type::ExitStatus(s) = msg.typ() {
+                        status = Some(*s)
+                    }
+                }
+                Some(Ok(Msg::ChannelClose(..))) => break,
+                Some(Ok(..)) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+        assert_eq!(status, Some(255));
+
+        // connection survives for other channels.
+        let msg = ChannelOpen::new(1, 1024, 1024, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(msg))) => {
+                assert_eq!(*msg.recipient_channel(), 1)
+            }
+            x => panic!("{:?}", x),
+        }
+        drop(client);
+        runner.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handler_panic_abort() {
+        let mut preference = PreferenceBuilder::default();
+        preference.abort_connection_on_panic(true);
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&preference, panicking_shell_handlers()).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        let msg = ChannelOpen::new(0, 1024, 1024, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        client
+            .send(ChannelRequest::new(0, true, Type::Shell(())).into())
+            .await
+            .unwrap();
+
+        loop {
+            match client.next().await {
+                Some(Ok(Msg::Disconnect(msg))) => {
+                    assert!(matches!(msg.reason_code(), ReasonCode::ByApplication));
+                    break;
+                }
+                Some(Ok(..)) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+        assert!(matches!(
+            runner.await.unwrap(),
+            Err(SshError::HandlerError(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        use futures::future::{ok, FutureExt as _};
+
+        use crate::msg::channel_close::ChannelClose;
+        use crate::msg::disconnect::Disconnect;
+        use crate::msg::userauth_request::{Method, UserauthRequest};
+        use crate::ServerEvent;
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_auth_none(|_| ok(true).boxed());
+        let mut preference = PreferenceBuilder::default();
+        let events = preference.events();
+        let (mut runner, mut client) = new_runner_with_handlers(&preference, handlers).await;
+        runner.userauth_requested = true;
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let msg = UserauthRequest::new("alice".into(), "ssh-connection".into(), Method::None);
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::UserauthSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let msg = ChannelOpen::new(3, 1024, 1024, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        let chid = match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(msg))) => *msg.sender_channel(),
+            x => panic!("{:?}", x),
+        };
+        client.send(ChannelClose::new(chid).into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelClose(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let msg = Disconnect::new(ReasonCode::ByApplication, "".into(), "".into());
+        client.send(msg.into()).await.unwrap();
+        runner.await.unwrap().unwrap();
+
+        let events = events.take(4).collect::<Vec<_>>().await;
+        assert!(matches!(
+            &events[0],
+            ServerEvent::Authenticated { user, method } if user == "alice" && method == "none"
+        ));
+        assert!(matches!(
+            &events[1],
+            ServerEvent::ChannelOpened { id, kind: ChannelKind::Session } if *id == chid
+        ));
+        assert!(matches!(&events[2], ServerEvent::ChannelClosed { id } if *id == chid));
+        assert!(matches!(
+            &events[3],
+            ServerEvent::Disconnected {
+                reason: Some(ReasonCode::ByApplication)
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_channel_open_before_auth() {
+        let (runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let msg = ChannelOpen::new(0, 1024, 1024, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+
+        match client.next().await {
+            Some(Ok(Msg::Disconnect(msg))) => {
+                assert!(matches!(msg.reason_code(), ReasonCode::ProtocolError))
+            }
+            x => panic!("{:?}", x),
+        }
+        assert!(matches!(
+            runner.await.unwrap(),
+            Err(SshError::NotAuthenticated)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_userauth_before_service_request() {
+        use crate::msg::userauth_request::{Method, UserauthRequest};
+
+        let (runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let msg = UserauthRequest::new("user".into(), "ssh-connection".into(), Method::None);
+        client.send(msg.into()).await.unwrap();
+
+        match client.next().await {
+            Some(Ok(Msg::Disconnect(msg))) => {
+                assert!(matches!(msg.reason_code(), ReasonCode::ProtocolError))
+            }
+            x => panic!("{:?}", x),
+        }
+        assert!(matches!(
+            runner.await.unwrap(),
+            Err(SshError::ServiceNotRequested(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_max_session_duration() {
+        let mut preference = PreferenceBuilder::default();
+        preference.max_session_duration(Duration::from_millis(300));
+        let (runner, mut client) = new_runner(&preference).await;
+        let started = time::Instant::now();
+        let runner = tokio::spawn(runner.run());
+
+        loop {
+            client.send(Ignore::new("".into()).into()).await.unwrap();
+            let msg = time::timeout(Duration::from_millis(50), client.next()).await;
+            match msg {
+                Ok(Some(Ok(Msg::Kexinit(..)))) | Err(..) => {}
+                Ok(Some(Ok(Msg::Disconnect(msg)))) => {
+                    assert!(matches!(msg.reason_code(), ReasonCode::ByApplication));
+                    break;
+                }
+                x => panic!("{:?}", x),
+            }
+        }
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(matches!(
+            runner.await.unwrap(),
+            Err(SshError::SessionDurationExceeded)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_byte_quota() {
+        use bytes::Bytes;
+        use futures::future::FutureExt as _;
+
+        use crate::msg::channel_data::ChannelData;
+        use crate::SessionContext;
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_shell(|mut ctx: SessionContext| {
+            async move {
+                let (mut stdin, mut stdout, _) = ctx.take_stdio().unwrap();
+                tokio::io::copy(&mut stdin, &mut stdout).await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+        let quota = 16 * 1024;
+        let mut preference = PreferenceBuilder::default();
+        preference.byte_quota(quota);
+        let (mut runner, mut client) = new_runner_with_handlers(&preference, handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let msg = ChannelOpen::new(0, 1024 * 1024, 32768, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        let server_id = match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(msg))) => *msg.sender_channel(),
+            x => panic!("{:?}", x),
+        };
+        let msg = ChannelRequest::new(server_id, true, Type::Shell(()));
+        client.send(msg.into()).await.unwrap();
+
+        // echoed data counts in both directions.
+        let chunk = Bytes::from(vec![b'x'; 1024]);
+        let mut pushed = 0;
+        'push: loop {
+            assert!(pushed < quota, "not disconnected");
+            let msg = ChannelData::new(server_id, chunk.clone());
+            if client.send(msg.into()).await.is_ok() {
+                pushed += chunk.len() as u64;
+            }
+            while let Ok(msg) = time::timeout(Duration::from_millis(20), client.next()).await {
+                match msg {
+                    Some(Ok(Msg::Disconnect(msg))) => {
+                        assert!(matches!(msg.reason_code(), ReasonCode::ByApplication));
+                        break 'push;
+                    }
+                    Some(Ok(..)) => {}
+                    x => panic!("{:?}", x),
+                }
+            }
+        }
+        // half of the quota each way, give or take one chunk and packet overhead.
+        let slack = chunk.len() as u64 + 1024;
+        assert!(
+            pushed + slack >= quota / 2 && pushed <= quota / 2 + slack,
+            "{}",
+            pushed
+        );
+        assert!(matches!(
+            runner.await.unwrap(),
+            Err(SshError::ByteQuotaExceeded(q)) if q == quota
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_byte_quota_warning() {
+        let quota = 16 * 1024;
+        let mut preference = PreferenceBuilder::default();
+        preference.byte_quota(quota);
+        let (mut runner, mut client) = new_runner(&preference).await;
+
+        let mut warned_before_exceeded = false;
+        loop {
+            let msg = Ignore::new(vec![b'x'; 512].into());
+            client.send(msg.into()).await.unwrap();
+            runner.io.next().await.unwrap().unwrap();
+
+            let state = runner.io.get_ref().state();
+            let total = state.ctos().bytes() + state.stoc().bytes();
+            match runner.check_byte_quota() {
+                Ok(()) => {
+                    assert!(total <= quota);
+                    assert_eq!(runner.byte_quota_warned, total >= quota / 10 * 9);
+                    warned_before_exceeded = runner.byte_quota_warned;
+                }
+                Err(SshError::ByteQuotaExceeded(q)) => {
+                    assert_eq!(q, quota);
+                    assert!(total > quota);
+                    break;
+                }
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        assert!(warned_before_exceeded);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_description() {
+        let mut preference = PreferenceBuilder::default();
+        preference
+            .timeout(Duration::from_millis(100))
+            .timeout_description("idle too long");
+        let (runner, mut client) = new_runner(&preference).await;
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        match client.next().await {
+            Some(Ok(Msg::Disconnect(msg))) => {
+                assert!(matches!(msg.reason_code(), ReasonCode::ConnectionLost));
+                assert_eq!(msg.description(), "idle too long");
+            }
+            x => panic!("{:?}", x),
+        }
+        assert!(matches!(runner.await.unwrap(), Err(SshError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_close_channel_from_handler() {
+        use std::ffi::OsString;
+
+        use bytes::Bytes;
+        use futures::future::FutureExt as _;
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        use crate::msg::channel_close::ChannelClose;
+        use crate::msg::channel_data::ChannelData;
+        use crate::msg::channel_eof::ChannelEof;
+        use crate::msg::channel_request::ChannelRequest;
+        use crate::SessionContext;
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_exec(|mut ctx: SessionContext, prog: OsString| {
+            let (mut stdin, mut stdout, _) = ctx.take_stdio().unwrap();
+            async move {
+                if prog == "close" {
+                    ctx.close();
+                    let mut buf = vec![];
+                    stdin.read_to_end(&mut buf).await?;
+                    assert!(stdout.write_all(b"closed").await.is_err());
+                } else {
+                    tokio::io::copy(&mut stdin, &mut stdout).await?;
+                }
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        // client channel id to server channel id.
+        let mut server_ids = HashMap::new();
+        for (chid, prog) in &[(1, "cat"), (0, "close")] {
+            let msg = ChannelOpen::new(*chid, 1024 * 1024, 32768, channel_open::Type::Session(()));
+            client.send(msg.into()).await.unwrap();
+            let server_id = match client.next().await {
+                Some(Ok(Msg::ChannelOpenConfirmation(msg))) => *msg.sender_channel(),
+                x => panic!("{:?}", x),
+            };
+            server_ids.insert(*chid, server_id);
+
+            let typ = Type::Exec(Bytes::from(*prog));
+            client
+                .send(ChannelRequest::new(server_id, true, typ).into())
+                .await
+                .unwrap();
+            match client.next().await {
+                Some(Ok(Msg::ChannelSuccess(..))) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+
+        match client.next().await {
+            Some(Ok(Msg::ChannelEof(msg))) => assert_eq!(*msg.recipient_channel(), 0),
+            x => panic!("{:?}", x),
+        }
+        match client.next().await {
+            Some(Ok(Msg::ChannelClose(msg))) => assert_eq!(*msg.recipient_channel(), 0),
+            x => panic!("{:?}", x),
+        }
+        client
+            .send(ChannelClose::new(server_ids[&0]).into())
+            .await
+            .unwrap();
+
+        let data = Bytes::from("hello");
+        client
+            .send(ChannelData::new(server_ids[&1], data.clone()).into())
+            .await
+            .unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelData(msg))) => {
+                assert_eq!(*msg.recipient_channel(), 1);
+                assert_eq!(msg.data(), &data);
+            }
+            x => panic!("{:?}", x),
+        }
+
+        client
+            .send(ChannelEof::new(server_ids[&1]).into())
+            .await
+            .unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelEof(msg))) => assert_eq!(*msg.recipient_channel(), 1),
+            x => panic!("{:?}", x),
+        }
+        match client.next().await {
+            Some(Ok(Msg::ChannelRequest(msg))) => {
+                assert!(matches!(msg.typ(), Type::ExitStatus(0)))
+            }
+            x => panic!("{:?}", x),
+        }
+        match client.next().await {
+            Some(Ok(Msg::ChannelClose(msg))) => assert_eq!(*msg.recipient_channel(), 1),
+            x => panic!("{:?}", x),
+        }
+
+        drop(client);
+        runner.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_state_channels() {
+        use crate::msg::channel_close::ChannelClose;
+
+        let (mut runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+        runner.auth_state.done();
+        let state = runner.state.clone();
+        assert_eq!(state.channel_count(), 0);
+
+        for id in 0..2 {
+            let msg = ChannelOpen::new(id, 1024, 1024, channel_open::Type::Session(()));
+            runner.on_channel_open(&msg).await.unwrap();
+            match client.next().await {
+                Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+        assert_eq!(
+            state.channels(),
+            vec![(0, ChannelKind::Session), (1, ChannelKind::Session)]
+        );
+
+        runner
+            .on_channel_close(&ChannelClose::new(0))
+            .await
+            .unwrap();
+        assert_eq!(state.channels(), vec![(1, ChannelKind::Session)]);
+
+        runner.close_channels().await;
+        assert_eq!(state.channel_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_close_channel_then_reuse_id() {
+        use std::ffi::OsString;
+
+        use bytes::Bytes;
+        use futures::future::FutureExt as _;
+
+        use crate::msg::channel_close::ChannelClose;
+        use crate::msg::channel_request::ChannelRequest;
+        use crate::SessionContext;
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_exec(|ctx: SessionContext, prog: OsString| {
+            async move {
+                if prog == "close" {
+                    ctx.close();
+                }
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        for prog in &["close", "true"] {
+            let msg = ChannelOpen::new(0, 1024 * 1024, 32768, channel_open::Type::Session(()));
+            client.send(msg.into()).await.unwrap();
+            let server_id = match client.next().await {
+                Some(Ok(Msg::ChannelOpenConfirmation(msg))) => *msg.sender_channel(),
+                x => panic!("{:?}", x),
+            };
+            let typ = Type::Exec(Bytes::from(*prog));
+            client
+                .send(ChannelRequest::new(server_id, true, typ).into())
+                .await
+                .unwrap();
+
+            let mut msgs = vec![];
+            loop {
+                match client.next().await {
+                    Some(Ok(Msg::ChannelSuccess(..))) => {}
+                    Some(Ok(Msg::ChannelClose(msg))) => {
+                        assert_eq!(*msg.recipient_channel(), 0);
+                        break;
+                    }
+                    Some(Ok(msg)) => msgs.push(msg),
+                    x => panic!("{:?}", x),
+                }
+            }
+            client
+                .send(ChannelClose::new(server_id).into())
+                .await
+                .unwrap();
+
+            // exactly one EOF, and exit status unless closed by handler.
+            let eof = msgs
+                .iter()
+                .filter(|msg| matches!(msg, Msg::ChannelEof(..)))
+                .count();
+            assert_eq!(eof, 1, "{:?}", msgs);
+            let exit_status = msgs.iter().any(|msg| match msg {
+                Msg::ChannelRequest(msg) => matches!(msg.typ(), Type::ExitStatus(0)),
+                _ => false,
+            });
+            assert_eq!(exit_status, *prog == "true", "{:?}", msgs);
+        }
+
+        drop(client);
+        runner.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_empty_output() {
+        use tokio::io::AsyncWriteExt as _;
+
+        let (mut runner, _client) = new_runner(&PreferenceBuilder::default()).await;
+        let (mut output, _closed) = runner.new_output(0, None).await.unwrap();
+        output.write_all(b"").await.unwrap();
+        output.write_all(b"hello").await.unwrap();
+        drop(output);
+
+        let reader = runner.output_readers.clone();
+        let output_loop = Runner::<tokio::io::DuplexStream, HandlerError, ()>::data_output_loop(
+            reader,
+            runner.channel_windows.clone(),
+            runner.msg_queue_tx.clone(),
+        );
+        tokio::pin!(output_loop);
+        let msg = tokio::select! {
+            _ = &mut output_loop => panic!(),
+            msg = runner.msg_queue_rx.next() => msg,
+        };
+        match msg {
+            Some(Msg::ChannelData(msg)) => assert_eq!(msg.data().as_ref(), b"hello"),
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_output_flushed_before_exit_status() {
+        use std::ffi::OsString;
+
+        use bytes::Bytes;
+        use futures::future::FutureExt as _;
+        use tokio::io::AsyncWriteExt as _;
+
+        use crate::msg::channel_request::ChannelRequest;
+        use crate::SessionContext;
+
+        let stderr_data = vec![b'e'; 20 * 1024];
+        let expected = stderr_data.clone();
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_exec(move |mut ctx: SessionContext, _: OsString| {
+            let (_, _, mut stderr) = ctx.take_stdio().unwrap();
+            let data = stderr_data.clone();
+            async move {
+                stderr.write_all(&data).await?;
+                Ok(3)
+            }
+            .boxed()
+        });
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let msg = ChannelOpen::new(0, 1024 * 1024, 32768, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let typ = Type::Exec(Bytes::from("err"));
+        client
+            .send(ChannelRequest::new(0, true, typ).into())
+            .await
+            .unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let mut stderr = vec![];
+        let mut eof = false;
+        loop {
+            match client.next().await {
+                Some(Ok(Msg::ChannelExtendedData(msg))) if !eof => {
+                    stderr.extend_from_slice(msg.data())
+                }
+                Some(Ok(Msg::ChannelEof(..))) => eof = true,
+                Some(Ok(Msg::ChannelRequest(msg))) => {
+                    assert!(eof);
+                    assert!(matches!(msg.typ(), Type::ExitStatus(3)));
+                    break;
+                }
+                x => panic!("{:?}", x),
+            }
+        }
+        assert_eq!(stderr, expected);
+        match client.next().await {
+            Some(Ok(Msg::ChannelClose(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        drop(client);
+        runner.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_output_shutdown() {
+        use std::ffi::OsString;
+
+        use bytes::Bytes;
+        use futures::future::FutureExt as _;
+        use tokio::io::AsyncWriteExt as _;
+
+        use crate::msg::channel_request::ChannelRequest;
+        use crate::SessionContext;
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_exec(|mut ctx: SessionContext, _: OsString| {
+            let (_, mut stdout, mut stderr) = ctx.take_stdio().unwrap();
+            async move {
+                stdout.write_all(b"out").await?;
+                stdout.shutdown().await?;
+                let err = stdout.write_all(b"more").await.unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+                // stdout is kept, and stderr still delivers data.
+                stderr.write_all(b"err").await?;
+                drop(stdout);
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let msg = ChannelOpen::new(0, 1024 * 1024, 32768, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let typ = Type::Exec(Bytes::from("half-close"));
+        client
+            .send(ChannelRequest::new(0, true, typ).into())
+            .await
+            .unwrap();
+
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        loop {
+            match client.next().await {
+                Some(Ok(Msg::ChannelData(msg))) => stdout.extend_from_slice(msg.data()),
+                Some(Ok(Msg::ChannelExtendedData(msg))) => stderr.extend_from_slice(msg.data()),
+                Some(Ok(Msg::ChannelClose(..))) => break,
+                Some(Ok(..)) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+        assert_eq!(stdout, b"out");
+        assert_eq!(stderr, b"err");
+
+        runner.abort();
+    }
+
+    #[tokio::test]
+    async fn test_exit_signal() {
+        use std::ffi::OsString;
+
+        use bytes::Bytes;
+        use futures::future::FutureExt as _;
+
+        use crate::msg::channel_request::ChannelRequest;
+        use crate::SessionContext;
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_exec(|ctx: SessionContext, _: OsString| {
+            ctx.set_exit_signal("SIGINT".parse().unwrap());
+            async move { Ok(130) }.boxed()
+        });
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let msg = ChannelOpen::new(0, 1024 * 1024, 32768, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let typ = Type::Exec(Bytes::from("sleep"));
+        client
+            .send(ChannelRequest::new(0, true, typ).into())
+            .await
+            .unwrap();
+
+        loop {
+            match client.next().await {
+                Some(Ok(Msg::ChannelSuccess(..))) | Some(Ok(Msg::ChannelEof(..))) => {}
+                Some(Ok(Msg::ChannelRequest(msg))) => {
+                    match msg.typ() {
+                        Type::ExitSignal(signal) => assert_eq!(signal.name(), "INT"),
+                        x => panic!("{:?}", x),
+                    }
+                    break;
+                }
+                x => panic!("{:?}", x),
+            }
+        }
+        match client.next().await {
+            Some(Ok(Msg::ChannelClose(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        drop(client);
+        runner.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_outbound_queue_backpressure() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use futures::future::FutureExt as _;
+        use tokio::io::AsyncWriteExt as _;
+
+        use crate::SessionContext;
+
+        const TOTAL: usize = 8 * 1024 * 1024;
+
+        let written = Arc::new(AtomicUsize::new(0));
+        let mut handlers = Handlers::<HandlerError>::new();
+        let w = written.clone();
+        handlers.on_channel_shell(move |mut ctx: SessionContext| {
+            let (_, mut stdout, _) = ctx.take_stdio().unwrap();
+            let written = w.clone();
+            async move {
+                let chunk = vec![b'x'; 8 * 1024];
+                while written.load(Ordering::SeqCst) < TOTAL {
+                    stdout.write_all(&chunk).await?;
+                    written.fetch_add(chunk.len(), Ordering::SeqCst);
+                }
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let mut preference = PreferenceBuilder::default();
+        preference.outbound_queue_len(4);
+        let (mut runner, mut client) = new_runner_with_handlers(&preference, handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        let msg = ChannelOpen::new(0, TOTAL as u32, 1024, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        client
+            .send(ChannelRequest::new(0, true, Type::Shell(())).into())
+            .await
+            .unwrap();
+
+        // client does not read, so handler writes block.
+        time::sleep(Duration::from_millis(500)).await;
+        assert!(written.load(Ordering::SeqCst) < 1024 * 1024);
+
+        let mut received = 0;
+        loop {
+            match client.next().await {
+                Some(Ok(Msg::ChannelData(msg))) => received += msg.data().len(),
+                Some(Ok(Msg::ChannelClose(..))) => break,
+                Some(Ok(..)) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+        assert_eq!(received, TOTAL);
+
+        drop(client);
+        runner.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handler_waits_for_output() {
+        use futures::future::FutureExt as _;
+        use tokio::io::AsyncWriteExt as _;
+        use tokio::sync::Notify;
+
+        use crate::msg::channel_close::ChannelClose;
+        use crate::SessionContext;
+
+        const TOTAL: usize = 1024 * 1024;
+
+        let written = Arc::new(Notify::new());
+        let mut handlers = Handlers::<HandlerError>::new();
+        let w = written.clone();
+        handlers.on_channel_shell(move |mut ctx: SessionContext| {
+            let (_, mut stdout, _) = ctx.take_stdio().unwrap();
+            let written = w.clone();
+            async move {
+                stdout.write_all(&vec![b'x'; TOTAL]).await?;
+                written.notify_one();
+                Ok(0)
+            }
+            .boxed()
+        });
+        // waits for output of the other channel while the message loop waits for it.
+        handlers.on_channel_close(move |channel| {
+            let written = written.clone();
+            async move {
+                if channel == 1 {
+                    written.notified().await;
+                }
+                Ok(())
+            }
+            .boxed()
+        });
+
+        let mut preference = PreferenceBuilder::default();
+        preference.outbound_queue_len(1);
+        let (mut runner, mut client) = new_runner_with_handlers(&preference, handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        for chid in 0..2 {
+            let msg = ChannelOpen::new(chid, TOTAL as u32, 1024, channel_open::Type::Session(()));
+            client.send(msg.into()).await.unwrap();
+        }
+        client
+            .send(ChannelRequest::new(0, true, Type::Shell(())).into())
+            .await
+            .unwrap();
+        client.send(ChannelClose::new(1).into()).await.unwrap();
+
+        let mut received = 0;
+        let receive = async {
+            loop {
+                match client.next().await {
+                    Some(Ok(Msg::ChannelData(msg))) => received += msg.data().len(),
+                    Some(Ok(Msg::ChannelRequest(msg))) => {
+                        assert!(matches!(msg.typ(), Type::ExitStatus(0)));
+                        break;
+                    }
+                    Some(Ok(..)) => {}
+                    x => panic!("{:?}", x),
+                }
+            }
+        };
+        time::timeout(Duration::from_secs(10), receive)
+            .await
+            .unwrap();
+        assert_eq!(received, TOTAL);
+
+        drop(client);
+        runner.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_after_client_close() {
+        use futures::channel::oneshot;
+        use futures::future::FutureExt as _;
+        use std::sync::Mutex as StdMutex;
+        use tokio::io::AsyncWriteExt as _;
+
+        use crate::msg::channel_close::ChannelClose;
+        use crate::SessionContext;
+
+        let (tx, rx) = oneshot::channel();
+        let tx = Arc::new(StdMutex::new(Some(tx)));
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_shell(move |mut ctx: SessionContext| {
+            let (_, mut stdout, _) = ctx.take_stdio().unwrap();
+            let tx = tx.lock().unwrap().take().unwrap();
+            async move {
+                let err = loop {
+                    if let Err(e) = stdout.write_all(&[b'x'; 1024]).await {
+                        break e;
+                    }
+                };
+                tx.send(err.kind()).ok();
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        let msg = ChannelOpen::new(0, 1024, 1024, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        client
+            .send(ChannelRequest::new(0, true, Type::Shell(())).into())
+            .await
+            .unwrap();
+
+        let mut closed = false;
+        let receive = async {
+            loop {
+                match client.next().await {
+                    Some(Ok(Msg::ChannelData(..))) if !closed => {
+                        // close while the handler is writing.
+                        client.send(ChannelClose::new(0).into()).await.unwrap();
+                        closed = true;
+                    }
+                    Some(Ok(Msg::ChannelClose(..))) => break,
+                    Some(Ok(..)) => {}
+                    x => panic!("{:?}", x),
+                }
+            }
+        };
+        time::timeout(Duration::from_secs(10), receive)
+            .await
+            .unwrap();
+
+        let kind = time::timeout(Duration::from_secs(10), rx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(kind, std::io::ErrorKind::BrokenPipe);
+
+        drop(client);
+        runner.await.unwrap().unwrap();
+    }
+}
@@ -14,8 +14,111 @@ where
         &mut self,
         channel_close: &ChannelClose,
     ) -> Result<(), SshError> {
-        let chid = channel_close.recipient_channel();
-        self.channels.remove(chid);
+        let chid = *channel_close.recipient_channel();
+        self.pending_channel_requests.remove(&chid);
+        let reply = self.channel_states.close_received(chid);
+        // output after close is never sent, and handler writes fail.
+        // readers left by a handler still holding output are dropped here too.
+        self.drop_output(chid).await;
+        if reply {
+            self.flush_queue().await?;
+            self.send(ChannelClose::new(self.remote_channel(chid)))
+                .await?;
+        }
+        self.remote_channels.remove(&chid);
+        if self.remove_channel(chid).is_some() {
+            if let Some(fut) = self.handlers.dispatch_channel_close(chid) {
+                self.wait_handler(fut)
+                    .await?
+                    .map_err(|e| SshError::HandlerError(e.into()))?;
+            }
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::future::FutureExt as _;
+    use futures::sink::SinkExt as _;
+    use futures::stream::StreamExt as _;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    use super::super::tests::new_runner_with_handlers;
+    use super::*;
+    use crate::msg::channel_open::{self, ChannelOpen};
+    use crate::msg::channel_request::{ChannelRequest, Type};
+    use crate::msg::Msg;
+    use crate::preference::PreferenceBuilder;
+    use crate::{Handlers, SessionContext};
+
+    #[tokio::test]
+    async fn test_no_reader_left() {
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_exec(|mut ctx: SessionContext, prog| {
+            async move {
+                let (mut stdin, mut stdout, _) = ctx.take_stdio().unwrap();
+                if prog == "wait" {
+                    // until closed by client.
+                    stdin.read_to_end(&mut vec![]).await?;
+                } else {
+                    stdout.write_all(b"hello").await?;
+                }
+                Ok(0)
+            }
+            .boxed()
+        });
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+        let readers = runner.output_readers.clone();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        for n in 0..16 {
+            let msg = ChannelOpen::new(0, 1024 * 1024, 32768, channel_open::Type::Session(()));
+            client.send(msg.into()).await.unwrap();
+            let chid = match client.next().await {
+                Some(Ok(Msg::ChannelOpenConfirmation(msg))) => *msg.sender_channel(),
+                x => panic!("{:?}", x),
+            };
+
+            let prog = if n % 2 == 0 { "wait" } else { "hello" };
+            let msg = ChannelRequest::new(chid, true, Type::Exec(prog.into()));
+            client.send(msg.into()).await.unwrap();
+            match client.next().await {
+                Some(Ok(Msg::ChannelSuccess(..))) => {}
+                x => panic!("{:?}", x),
+            }
+
+            if prog == "wait" {
+                client.send(ChannelClose::new(chid).into()).await.unwrap();
+            }
+            loop {
+                match client.next().await {
+                    Some(Ok(Msg::ChannelClose(..))) => break,
+                    Some(Ok(..)) => {}
+                    x => panic!("{:?}", x),
+                }
+            }
+            if prog != "wait" {
+                client.send(ChannelClose::new(chid).into()).await.unwrap();
+            }
+        }
+
+        // previous close is processed once open is confirmed.
+        let msg = ChannelOpen::new(0, 1024, 1024, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        assert_eq!(readers.lock().await.len(), 0);
+
+        runner.abort();
+    }
+}
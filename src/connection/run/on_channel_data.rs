@@ -1,10 +1,10 @@
-use log::warn;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use log::{debug, warn};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
 
 use crate::msg::channel_data::ChannelData;
 use crate::HandlerError;
 
-use super::{Channel, Runner, SshError};
+use super::{Runner, SshError};
 
 impl<IO, E, Pty> Runner<IO, E, Pty>
 where
@@ -17,17 +17,175 @@ where
     ) -> Result<(), SshError> {
         let chid = channel_data.recipient_channel();
         let data = channel_data.data().as_ref();
-        if let Some(channel) = self.channels.get_mut(chid) {
-            match channel {
-                Channel::Session(_, stdin, _, _, _) | Channel::DirectTcpip(_, stdin) => match stdin
-                {
-                    Some(stdin) => {
-                        stdin.write_all(&data).await?;
-                    }
-                    None => warn!("closed channel {}", chid),
-                },
+        if data.is_empty() {
+            return Ok(());
+        }
+        let mut stdin = match self.channels.get_mut(chid) {
+            Some(channel) => match channel.stdin_mut().take() {
+                Some(stdin) => stdin,
+                None => {
+                    warn!("closed channel {}", chid);
+                    return Ok(());
+                }
+            },
+            // already removed. (e.g. sent by client before receiving close)
+            None => return Ok(()),
+        };
+
+        // handler may be blocked on output while not reading stdin.
+        match self.with_queue_flushing(stdin.write_all(data)).await? {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                // input dropped by handler (e.g. completed). drop stdin, and discard further data.
+                debug!("channel {} input closed.", chid);
+                return Ok(());
             }
+            Err(e) => return Err(e.into()),
+        }
+
+        if let Some(channel) = self.channels.get_mut(chid) {
+            *channel.stdin_mut() = Some(stdin);
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::io::AsyncReadExt as _;
+
+    use super::super::tests::new_runner;
+    use super::super::Channel;
+    use super::*;
+    use crate::msg::channel_eof::ChannelEof;
+    use crate::msg::channel_open::{ChannelOpen, Type};
+    use crate::preference::PreferenceBuilder;
+
+    #[tokio::test]
+    async fn test_empty_channel_data() {
+        let (mut runner, _client) = new_runner(&PreferenceBuilder::default()).await;
+        runner.auth_state.done();
+
+        let msg = ChannelOpen::new(0, 1024, 1024, Type::Session(()));
+        runner.on_channel_open(&msg).await.unwrap();
+
+        for data in &["", "hello", ""] {
+            let msg = ChannelData::new(0, Bytes::from(*data));
+            runner.on_channel_data(&msg).await.unwrap();
+        }
+        runner.on_channel_eof(&ChannelEof::new(0)).await.unwrap();
+
+        let mut stdin = match runner.channels.get_mut(&0) {
+            Some(Channel::Session { input, .. }) => input.take().unwrap(),
+            x => panic!("{:?}", x),
+        };
+        let mut buf = vec![];
+        stdin.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_handler_exit_while_sending() {
+        use std::time::Duration;
+
+        use futures::future::{ok, FutureExt as _};
+        use futures::sink::SinkExt as _;
+        use futures::stream::StreamExt as _;
+
+        use super::super::tests::new_runner_with_handlers;
+        use crate::msg::channel_close::ChannelClose;
+        use crate::msg::channel_request::{self, ChannelRequest};
+        use crate::msg::Msg;
+        use crate::Handlers;
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_shell(|_| ok(0).boxed());
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        let msg = ChannelOpen::new(0, 1024, 1024, Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        let msg = ChannelRequest::new(0, false, channel_request::Type::Shell(()));
+        client.send(msg.into()).await.unwrap();
+
+        // far beyond pipe buffer, never read by handler.
+        let send = async {
+            for _ in 0..64 {
+                let msg = ChannelData::new(0, Bytes::from(vec![0; 16 * 1024]));
+                client.send(msg.into()).await.unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), send)
+            .await
+            .unwrap();
+
+        loop {
+            match client.next().await {
+                Some(Ok(Msg::ChannelClose(..))) => break,
+                Some(Ok(..)) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+        client.send(ChannelClose::new(0).into()).await.unwrap();
+        drop(client);
+        runner.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_data_after_close() {
+        use futures::future::{ok, FutureExt as _};
+        use futures::sink::SinkExt as _;
+        use futures::stream::StreamExt as _;
+
+        use super::super::tests::new_runner_with_handlers;
+        use crate::msg::channel_close::ChannelClose;
+        use crate::msg::channel_request::{self, ChannelRequest};
+        use crate::msg::channel_window_adjust::ChannelWindowAdjust;
+        use crate::msg::global_request::{self, GlobalRequest};
+        use crate::msg::Msg;
+        use crate::Handlers;
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_shell(|_| ok(0).boxed());
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        let msg = ChannelOpen::new(0, 1024, 1024, Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        let msg = ChannelRequest::new(0, false, channel_request::Type::Shell(()));
+        client.send(msg.into()).await.unwrap();
+        loop {
+            match client.next().await {
+                Some(Ok(Msg::ChannelClose(..))) => break,
+                Some(Ok(..)) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+
+        // sent by client before receiving close. channel 1 is never opened.
+        for chid in &[0, 1] {
+            let msg = ChannelData::new(*chid, Bytes::from("late"));
+            client.send(msg.into()).await.unwrap();
+            let msg = ChannelWindowAdjust::new(*chid, 1024);
+            client.send(msg.into()).await.unwrap();
+        }
+        client.send(ChannelClose::new(0).into()).await.unwrap();
+        let typ = global_request::Type::Unknown("sync".into(), Bytes::new());
+        let msg = GlobalRequest::new(true, typ);
+        client.send(msg.into()).await.unwrap();
+
+        match client.next().await {
+            Some(Ok(Msg::RequestFailure(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        drop(client);
+        runner.await.unwrap().unwrap();
+    }
+}
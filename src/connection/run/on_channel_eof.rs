@@ -3,7 +3,7 @@ use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt as _};
 use crate::msg::channel_eof::ChannelEof;
 use crate::HandlerError;
 
-use super::{Channel, Runner, SshError};
+use super::{Runner, SshError};
 
 impl<IO, E, Pty> Runner<IO, E, Pty>
 where
@@ -16,14 +16,75 @@ where
     ) -> Result<(), SshError> {
         let chid = channel_eof.recipient_channel();
         if let Some(channel) = self.channels.get_mut(chid) {
-            match channel {
-                Channel::Session(_, stdin, _, _, _) | Channel::DirectTcpip(_, stdin) => {
-                    if let Some(mut stdin) = stdin.take() {
-                        stdin.shutdown().await?;
+            if let Some(mut stdin) = channel.stdin_mut().take() {
+                stdin.shutdown().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::FutureExt as _;
+    use futures::sink::SinkExt as _;
+    use futures::stream::StreamExt as _;
+    use tokio::io::AsyncReadExt as _;
+
+    use super::super::tests::new_runner_with_handlers;
+    use super::*;
+    use crate::msg::channel_open::{ChannelOpen, Type};
+    use crate::msg::channel_request::{self, ChannelRequest};
+    use crate::msg::Msg;
+    use crate::preference::PreferenceBuilder;
+    use crate::{Handlers, SessionContext};
+
+    #[tokio::test]
+    async fn test_eof_before_request() {
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_shell(|mut ctx: SessionContext| {
+            let (mut stdin, _, _) = ctx.take_stdio().unwrap();
+            async move {
+                let mut buf = vec![];
+                let n = stdin.read_to_end(&mut buf).await?;
+                Ok(n as u32 + 7)
+            }
+            .boxed()
+        });
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let msg = ChannelOpen::new(0, 1024, 1024, Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        client.send(ChannelEof::new(0).into()).await.unwrap();
+        let msg = ChannelRequest::new(0, true, channel_request::Type::Shell(()));
+        client.send(msg.into()).await.unwrap();
+
+        let mut status = None;
+        loop {
+            match client.next().await {
+                Some(Ok(Msg::ChannelRequest(msg))) => {
+                    if let channel_request::Type::ExitStatus(s) = msg.typ() {
+                        status = Some(*s)
                     }
                 }
+                Some(Ok(Msg::ChannelClose(..))) => break,
+                Some(Ok(..)) => {}
+                x => panic!("{:?}", x),
             }
         }
-        Ok(())
+        // stdin is already at EOF.
+        assert_eq!(status, Some(7));
+
+        drop(client);
+        runner.await.unwrap().unwrap();
     }
 }
@@ -0,0 +1,21 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::msg::channel_failure::ChannelFailure;
+use crate::HandlerError;
+
+use super::{Runner, SshError};
+
+impl<IO, E, Pty> Runner<IO, E, Pty>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    pub(super) async fn on_channel_failure(
+        &mut self,
+        channel_failure: &ChannelFailure,
+    ) -> Result<(), SshError> {
+        let chid = *channel_failure.recipient_channel();
+        self.resolve_channel_request(chid, false);
+        Ok(())
+    }
+}
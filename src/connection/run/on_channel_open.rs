@@ -1,13 +1,13 @@
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::convert::TryFrom;
 
 use log::debug;
 use tokio::io::{AsyncRead, AsyncWrite};
 
+use crate::handlers::SessionState;
 use crate::msg::channel_open::{ChannelOpen, DirectTcpip, Type};
 use crate::msg::channel_open_confirmation::ChannelOpenConfirmation;
 use crate::msg::channel_open_failure::{ChannelOpenFailure, ReasonCode};
-use crate::HandlerError;
+use crate::{DirectTcpIpContext, HandlerError};
 
 use super::{Channel, Runner, SshError, SshInput};
 
@@ -20,6 +20,15 @@ where
         &mut self,
         channel_open: &ChannelOpen,
     ) -> Result<(), SshError> {
+        if !self.auth_state.authenticated() {
+            return Err(SshError::NotAuthenticated);
+        }
+        if let Some(max) = self.preference.max_total_channels() {
+            if self.total_channels >= *max {
+                return Err(SshError::TooManyChannels(*max));
+            }
+        }
+
         match channel_open.typ() {
             Type::Session(..) => self.on_channel_open_session(channel_open).await,
             Type::DirectTcpip(item) => self.on_channel_open_direct_tcpip(channel_open, item).await,
@@ -30,7 +39,7 @@ where
                     *channel_open.sender_channel(),
                     ReasonCode::UnknownChannelType,
                     "unknown channel".into(),
-                    "en-US".into(),
+                    self.preference.language_tag().clone(),
                 );
                 self.send(msg).await?;
                 Ok(())
@@ -42,82 +51,422 @@ where
         &mut self,
         channel_open: &ChannelOpen,
     ) -> Result<(), SshError> {
-        let chid = *channel_open.sender_channel();
-        let (r, w) = tokio_pipe::pipe()?;
-        let stdin_rx = SshInput::new(r);
-
-        let env = HashMap::new();
-        let channel = Channel::Session(chid, Some(w), Some(stdin_rx), env, None);
-        if let Entry::Vacant(entry) = self.channels.entry(chid) {
-            entry.insert(channel);
-
-            let ok = ChannelOpenConfirmation::new(
-                *channel_open.sender_channel(),
-                *channel_open.sender_channel(),
-                *channel_open.initial_window_size(),
-                *channel_open.maximum_packet_size(),
-                "".into(),
-            );
-            self.send(ok).await?;
-        } else {
-            // already exists
+        if self.no_more_sessions {
             let msg = ChannelOpenFailure::new(
                 *channel_open.sender_channel(),
                 ReasonCode::AdministrativeryProhibited,
-                "already opened".into(),
-                "en-US".into(),
+                "no more sessions".into(),
+                self.preference.language_tag().clone(),
             );
             self.send(msg).await?;
+            return Ok(());
+        }
+
+        if self.already_opened(channel_open) {
+            return self.send_already_opened(channel_open).await;
         }
+
+        let remote = *channel_open.sender_channel();
+        let chid = self.allocate_channel_id();
+        let (r, w) = tokio_pipe::pipe()?;
+        let stdin_rx = SshInput::new(r);
+
+        let channel = Channel::Session {
+            stdin: Some(w),
+            input: Some(stdin_rx),
+            state: SessionState::default(),
+        };
+        self.open_channel(chid, remote, *channel_open.initial_window_size(), channel);
+
+        let (window_size, maximum_packet_size) =
+            window_for(channel_open, *self.preference.session_window());
+        let ok =
+            ChannelOpenConfirmation::new(remote, chid, window_size, maximum_packet_size, "".into());
+        self.send(ok).await?;
+        self.total_channels += 1;
         Ok(())
     }
 
     async fn on_channel_open_direct_tcpip(
         &mut self,
         channel_open: &ChannelOpen,
-        _item: &DirectTcpip,
+        item: &DirectTcpip,
     ) -> Result<(), SshError> {
-        let chid = *channel_open.sender_channel();
+        if self.already_opened(channel_open) {
+            return self.send_already_opened(channel_open).await;
+        }
+
+        let permitted = match u16::try_from(*item.port()) {
+            Ok(port) => self
+                .auth_state
+                .authorized_key()
+                .is_none_or(|key| key.permits_open(item.host(), port)),
+            Err(..) => false,
+        };
+        if !permitted {
+            debug!("direct-tcpip {}:{} not permitted", item.host(), item.port());
+            let failure = ChannelOpenFailure::new(
+                *channel_open.sender_channel(),
+                ReasonCode::AdministrativeryProhibited,
+                "forwarding not permitted".into(),
+                self.preference.language_tag().clone(),
+            );
+            self.send(failure).await?;
+            return Ok(());
+        }
+
+        let ctx = DirectTcpIpContext::new(
+            item.host().clone(),
+            *item.port(),
+            item.originator_address().clone(),
+            *item.originator_port(),
+        );
+        debug!(
+            "direct-tcpip {}:{} from {}:{}",
+            ctx.host(),
+            ctx.port(),
+            ctx.originator_address(),
+            ctx.originator_port()
+        );
+
+        let remote = *channel_open.sender_channel();
+        let chid = self.allocate_channel_id();
 
         let (input_r, input_w) = tokio_pipe::pipe()?;
         let input = SshInput::new(input_r);
 
-        let (output, output_closed) = self.new_output(chid, None).await?;
+        let channel = Channel::DirectTcpip {
+            stdin: Some(input_w),
+        };
+        self.open_channel(chid, remote, *channel_open.initial_window_size(), channel);
 
-        let channel = Channel::DirectTcpip(chid, Some(input_w));
-        if let Entry::Vacant(entry) = self.channels.entry(chid) {
-            entry.insert(channel);
+        let (output, output_closed) = self.new_output(chid, None).await?;
 
-            if let Some(fut) = self.handlers.dispatch_direct_tcpip(input, output) {
-                self.spawn_handler(chid, output_closed, fut).await;
-                let msg = ChannelOpenConfirmation::new(
-                    *channel_open.sender_channel(),
-                    *channel_open.sender_channel(),
-                    *channel_open.initial_window_size(),
-                    *channel_open.maximum_packet_size(),
-                    "".into(),
-                );
-                self.send(msg).await?;
-            } else {
-                // FIXME unimplemented
-                let msg = ChannelOpenFailure::new(
-                    *channel_open.sender_channel(),
-                    ReasonCode::AdministrativeryProhibited,
-                    "already opened".into(),
-                    "en-US".into(),
-                );
-                self.send(msg).await?;
-            }
+        if let Some(fut) = self.handlers.dispatch_direct_tcpip(ctx, input, output) {
+            self.spawn_handler(chid, output_closed, fut).await;
+            let (window_size, maximum_packet_size) =
+                window_for(channel_open, *self.preference.direct_tcpip_window());
+            let msg = ChannelOpenConfirmation::new(
+                remote,
+                chid,
+                window_size,
+                maximum_packet_size,
+                "".into(),
+            );
+            self.send(msg).await?;
+            self.total_channels += 1;
         } else {
-            // already exists
+            self.remove_channel(chid);
+            self.remote_channels.remove(&chid);
+            self.channel_states.release(chid);
+            self.drop_output(chid).await;
             let msg = ChannelOpenFailure::new(
-                *channel_open.sender_channel(),
+                remote,
                 ReasonCode::AdministrativeryProhibited,
-                "already opened".into(),
-                "en-US".into(),
+                "direct-tcpip not supported".into(),
+                self.preference.language_tag().clone(),
             );
             self.send(msg).await?;
         }
         Ok(())
     }
+
+    /// Whether the client channel id is still in use.
+    fn already_opened(&self, channel_open: &ChannelOpen) -> bool {
+        self.remote_channels
+            .values()
+            .any(|remote| remote == channel_open.sender_channel())
+    }
+
+    async fn send_already_opened(&mut self, channel_open: &ChannelOpen) -> Result<(), SshError> {
+        let msg = ChannelOpenFailure::new(
+            *channel_open.sender_channel(),
+            ReasonCode::AdministrativeryProhibited,
+            "already opened".into(),
+            self.preference.language_tag().clone(),
+        );
+        self.send(msg).await
+    }
+}
+
+/// Window size and maximum packet size to advertise. Same as the client's if not configured.
+fn window_for(channel_open: &ChannelOpen, configured: Option<(u32, u32)>) -> (u32, u32) {
+    configured.unwrap_or((
+        *channel_open.initial_window_size(),
+        *channel_open.maximum_packet_size(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::stream::StreamExt as _;
+
+    use super::super::tests::{new_runner, new_runner_with_handlers};
+    use super::*;
+    use crate::msg::Msg;
+    use crate::preference::PreferenceBuilder;
+
+    #[tokio::test]
+    async fn test_language_tag() {
+        let mut preference = PreferenceBuilder::default();
+        preference.language_tag("ja-JP");
+        let (mut runner, mut client) = new_runner(&preference).await;
+        runner.auth_state.done();
+
+        let typ = Type::Unknown("unknown".into(), Bytes::new());
+        let msg = ChannelOpen::new(0, 1024, 1024, typ);
+        runner.on_channel_open(&msg).await.unwrap();
+
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenFailure(msg))) => assert_eq!(msg.language_tag(), "ja-JP"),
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_total_channels() {
+        use futures::sink::SinkExt as _;
+
+        use crate::msg::channel_close::ChannelClose;
+        use crate::msg::disconnect::ReasonCode;
+
+        let mut preference = PreferenceBuilder::default();
+        preference.max_total_channels(2);
+        let (mut runner, mut client) = new_runner(&preference).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        for _ in 0..2 {
+            let msg = ChannelOpen::new(0, 1024, 1024, Type::Session(()));
+            client.send(msg.into()).await.unwrap();
+            match client.next().await {
+                Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+                x => panic!("{:?}", x),
+            }
+            client.send(ChannelClose::new(0).into()).await.unwrap();
+            match client.next().await {
+                Some(Ok(Msg::ChannelClose(msg))) => assert_eq!(*msg.recipient_channel(), 0),
+                x => panic!("{:?}", x),
+            }
+        }
+
+        let msg = ChannelOpen::new(0, 1024, 1024, Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::Disconnect(msg))) => {
+                assert!(matches!(msg.reason_code(), ReasonCode::ProtocolError))
+            }
+            x => panic!("{:?}", x),
+        }
+        assert!(matches!(
+            runner.await.unwrap(),
+            Err(SshError::TooManyChannels(2))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_no_more_sessions() {
+        use crate::msg::global_request::{self, GlobalRequest};
+
+        let (mut runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+        runner.auth_state.done();
+
+        let msg = ChannelOpen::new(0, 1024, 1024, Type::Session(()));
+        runner.on_channel_open(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let msg = GlobalRequest::new(true, global_request::Type::NoMoreSessions(()));
+        runner.on_global_request(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::RequestSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let msg = ChannelOpen::new(1, 1024, 1024, Type::Session(()));
+        runner.on_channel_open(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenFailure(msg))) => assert_eq!(*msg.recipient_channel(), 1),
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_direct_tcpip_originator() {
+        use futures::channel::mpsc;
+        use futures::future::{ok, FutureExt as _};
+
+        use crate::Handlers;
+
+        let (tx, mut rx) = mpsc::unbounded();
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_direct_tcpip(move |ctx: DirectTcpIpContext, _, _| {
+            tx.unbounded_send(ctx).unwrap();
+            ok(()).boxed()
+        });
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+
+        let item = DirectTcpip::new("example.com".into(), 80, "1.2.3.4".into(), 5678);
+        let msg = ChannelOpen::new(0, 1024, 1024, Type::DirectTcpip(item));
+        runner.on_channel_open(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let ctx = rx.next().await.unwrap();
+        assert_eq!(ctx.host(), "example.com");
+        assert_eq!(ctx.port(), 80);
+        assert_eq!(ctx.originator(), Some("1.2.3.4:5678".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_local_channel_id() {
+        use futures::channel::oneshot;
+        use tokio::io::AsyncWriteExt as _;
+
+        use crate::msg::channel_open_confirmation::ChannelOpenConfirmation;
+
+        let (mut runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+        runner.auth_state.done();
+
+        // server initiated channel takes local id 0, client id 5.
+        let (tx, rx) = oneshot::channel();
+        runner
+            .open_stream_channel((Type::AuthAgent(()), tx))
+            .await
+            .unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpen(msg))) => assert_eq!(*msg.sender_channel(), 0),
+            x => panic!("{:?}", x),
+        }
+        let msg = ChannelOpenConfirmation::new(0, 5, 1024, 1024, Bytes::new());
+        runner.on_channel_open_confirmation(&msg).await.unwrap();
+        let (_input, mut output) = rx.await.unwrap().unwrap();
+
+        // client id 0 is free on the client side.
+        let msg = ChannelOpen::new(0, 1024, 1024, Type::Session(()));
+        runner.on_channel_open(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(msg))) => {
+                assert_eq!(*msg.recipient_channel(), 0);
+                assert_eq!(*msg.sender_channel(), 1);
+            }
+            x => panic!("{:?}", x),
+        }
+
+        // client id 5 is in use.
+        let msg = ChannelOpen::new(5, 1024, 1024, Type::Session(()));
+        runner.on_channel_open(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenFailure(msg))) => assert_eq!(*msg.recipient_channel(), 5),
+            x => panic!("{:?}", x),
+        }
+
+        output.write_all(b"hello").await.unwrap();
+        let reader = runner.output_readers.clone();
+        let windows = runner.channel_windows.clone();
+        let (queue_tx, mut queue_rx) = futures::channel::mpsc::channel(1);
+        tokio::spawn(
+            Runner::<tokio::io::DuplexStream, HandlerError, ()>::data_output_loop(
+                reader, windows, queue_tx,
+            ),
+        );
+        match queue_rx.next().await {
+            Some(Msg::ChannelData(msg)) => {
+                assert_eq!(*msg.recipient_channel(), 5);
+                assert_eq!(msg.data().as_ref(), b"hello");
+            }
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_sender_channel() {
+        let (mut runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+        runner.auth_state.done();
+
+        let msg = ChannelOpen::new(3, 1024, 1024, Type::Session(()));
+        runner.on_channel_open(&msg).await.unwrap();
+        let chid = match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(msg))) => *msg.sender_channel(),
+            x => panic!("{:?}", x),
+        };
+
+        let msg = ChannelOpen::new(3, 1024, 1024, Type::Session(()));
+        runner.on_channel_open(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenFailure(msg))) => {
+                assert_eq!(*msg.recipient_channel(), 3);
+                assert_eq!(msg.description(), "already opened");
+            }
+            x => panic!("{:?}", x),
+        }
+
+        // first channel is untouched.
+        assert_eq!(runner.channels.len(), 1);
+        assert!(runner.channels.contains_key(&chid));
+        assert_eq!(runner.remote_channel(chid), 3);
+        assert_eq!(runner.total_channels, 1);
+    }
+
+    #[tokio::test]
+    async fn test_window_per_type() {
+        use futures::future::{ok, FutureExt as _};
+
+        use crate::Handlers;
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_direct_tcpip(|_, _, _| ok(()).boxed());
+        let mut preference = PreferenceBuilder::default();
+        preference
+            .session_window(64 * 1024, 1024)
+            .direct_tcpip_window(4 * 1024 * 1024, 32 * 1024);
+        let (mut runner, mut client) = new_runner_with_handlers(&preference, handlers).await;
+        runner.auth_state.done();
+
+        let msg = ChannelOpen::new(0, 1024, 512, Type::Session(()));
+        runner.on_channel_open(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(msg))) => {
+                assert_eq!(*msg.initial_window_size(), 64 * 1024);
+                assert_eq!(*msg.maximum_packet_size(), 1024);
+            }
+            x => panic!("{:?}", x),
+        }
+
+        let item = DirectTcpip::new("example.com".into(), 80, "1.2.3.4".into(), 5678);
+        let msg = ChannelOpen::new(1, 1024, 512, Type::DirectTcpip(item));
+        runner.on_channel_open(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(msg))) => {
+                assert_eq!(*msg.initial_window_size(), 4 * 1024 * 1024);
+                assert_eq!(*msg.maximum_packet_size(), 32 * 1024);
+            }
+            x => panic!("{:?}", x),
+        }
+
+        // client's window when not configured.
+        let (mut runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+        runner.auth_state.done();
+        let msg = ChannelOpen::new(0, 1024, 512, Type::Session(()));
+        runner.on_channel_open(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(msg))) => {
+                assert_eq!(*msg.initial_window_size(), 1024);
+                assert_eq!(*msg.maximum_packet_size(), 512);
+            }
+            x => panic!("{:?}", x),
+        }
+    }
 }
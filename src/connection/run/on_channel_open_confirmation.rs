@@ -0,0 +1,27 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::msg::channel_open_confirmation::ChannelOpenConfirmation;
+use crate::HandlerError;
+
+use super::{Runner, SshError};
+
+impl<IO, E, Pty> Runner<IO, E, Pty>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    pub(super) async fn on_channel_open_confirmation(
+        &mut self,
+        channel_open_confirmation: &ChannelOpenConfirmation,
+    ) -> Result<(), SshError> {
+        let chid = *channel_open_confirmation.recipient_channel();
+        let remote = *channel_open_confirmation.sender_channel();
+        let window = *channel_open_confirmation.initial_window_size();
+        self.resolve_channel_open(chid, Ok(remote));
+        if let Some(reply) = self.pending_stream_opens.remove(&chid) {
+            let result = self.attach_stream_channel(chid, remote, window).await;
+            reply.send(result).ok();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::msg::channel_open_failure::ChannelOpenFailure;
+use crate::HandlerError;
+
+use super::{ChannelOpenError, Runner, SshError};
+
+impl<IO, E, Pty> Runner<IO, E, Pty>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    pub(super) async fn on_channel_open_failure(
+        &mut self,
+        channel_open_failure: &ChannelOpenFailure,
+    ) -> Result<(), SshError> {
+        let chid = *channel_open_failure.recipient_channel();
+        let err = ChannelOpenError {
+            reason_code: channel_open_failure.reason_code().clone(),
+            description: channel_open_failure.description().clone(),
+        };
+        if let Some(reply) = self.pending_stream_opens.remove(&chid) {
+            reply.send(Err(SshError::any(err.clone()))).ok();
+        }
+        self.resolve_channel_open(chid, Err(err));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::sink::SinkExt as _;
+    use futures::stream::StreamExt as _;
+
+    use super::super::tests::new_runner;
+    use super::*;
+    use crate::msg::channel_open::{ForwardedTcpip, Type};
+    use crate::msg::channel_open_failure::ReasonCode;
+    use crate::msg::Msg;
+    use crate::preference::PreferenceBuilder;
+
+    #[tokio::test]
+    async fn test_server_initiated_open_rejected() {
+        let (mut runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+        runner.auth_state.done();
+
+        let typ = Type::ForwardedTcpip(ForwardedTcpip::new(
+            "localhost".into(),
+            8080,
+            "127.0.0.1".into(),
+            12345,
+        ));
+        let (chid, rx) = runner.send_channel_open(typ).await.unwrap();
+
+        let msg = match client.next().await {
+            Some(Ok(Msg::ChannelOpen(msg))) => msg,
+            x => panic!("{:?}", x),
+        };
+        assert_eq!(*msg.sender_channel(), chid);
+
+        let msg =
+            ChannelOpenFailure::new(chid, ReasonCode::ConnectFailed, "refused".into(), "".into());
+        client.send(msg.into()).await.unwrap();
+        let msg = runner.io.next().await.unwrap().unwrap();
+        runner.handle_msg(&msg).await.unwrap();
+
+        let err = rx.await.unwrap().unwrap_err();
+        assert_eq!(err.reason_code(), &ReasonCode::ConnectFailed);
+        assert_eq!(err.description(), "refused");
+        assert!(runner.pending_channel_opens.is_empty());
+    }
+}
@@ -1,12 +1,16 @@
 use std::os::unix::ffi::OsStringExt;
+use std::sync::{Arc, Mutex};
 
-use tokio::io::{AsyncRead, AsyncWrite};
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt as _};
 
+use crate::authorized_keys::AuthorizedKey;
 use crate::msg::channel_extended_data::DataTypeCode;
 use crate::msg::channel_failure::ChannelFailure;
-use crate::msg::channel_request::{ChannelRequest, PtyReq, Type};
+use crate::msg::channel_request::{ChannelRequest, PtyReq, Type, X11Req};
 use crate::msg::channel_success::ChannelSuccess;
 
+use crate::handlers::{RequestKind, SessionContext};
 use crate::HandlerError;
 
 use super::{Channel, Runner, SshError};
@@ -20,88 +24,125 @@ where
         &mut self,
         channel_request: &ChannelRequest,
     ) -> Result<(), SshError> {
+        if !self.auth_state.authenticated() {
+            return Err(SshError::NotAuthenticated);
+        }
+
         match channel_request.typ() {
-            Type::Shell(..) => self.on_channel_request_shell(channel_request).await,
-            Type::Exec(prog) => self.on_channel_request_exec(channel_request, prog).await,
+            Type::Shell(..) => {
+                self.on_channel_request_session(channel_request, RequestKind::Shell)
+                    .await
+            }
+            Type::Exec(prog) => {
+                let prog = std::ffi::OsString::from_vec(prog.to_vec());
+                let kind = RequestKind::Exec(prog);
+                self.on_channel_request_session(channel_request, kind).await
+            }
+            Type::Subsystem(name) => {
+                let kind = RequestKind::Subsystem(name.clone());
+                self.on_channel_request_session(channel_request, kind).await
+            }
             Type::Env(env) => {
                 self.on_channel_request_env(channel_request, env.name(), env.value())
                     .await
             }
             Type::PtyReq(pty) => self.on_channel_request_pty(channel_request, pty).await,
+            Type::XonXoff(client_can_do) => {
+                self.on_channel_request_xon_xoff(channel_request, *client_can_do)
+                    .await
+            }
+            Type::Eow(..) => self.on_channel_request_eow(channel_request).await,
+            Type::AuthAgentReq(..) => self.on_channel_request_auth_agent(channel_request).await,
+            Type::X11Req(req) => self.on_channel_request_x11(channel_request, req).await,
             _ => {
-                let r = ChannelFailure::new(*channel_request.recipient_channel());
-                self.send(r).await?;
+                let channel = *channel_request.recipient_channel();
+                if !*self.preference.lenient_channel_requests() {
+                    let r = ChannelFailure::new(self.remote_channel(channel));
+                    self.send(r).await?;
+                } else if *channel_request.want_reply() {
+                    let r = ChannelSuccess::new(self.remote_channel(channel));
+                    self.send(r).await?;
+                }
                 Ok(())
             }
         }
     }
 
-    pub(super) async fn on_channel_request_shell(
+    /// Start session handler for `shell`, `exec` or `subsystem`.
+    async fn on_channel_request_session(
         &mut self,
         channel_request: &ChannelRequest,
+        kind: RequestKind,
     ) -> Result<(), SshError> {
         let channel = *channel_request.recipient_channel();
 
-        if let Some(Channel::Session(_, _, stdin, env, pty)) = self.channels.get_mut(&channel) {
-            let env = env.clone();
-            let pty = pty.take();
-            let stdin = stdin.take().unwrap();
-
-            let (stdout, stdout_closed) = self.new_output(channel, None).await?;
-            let (stderr, stderr_closed) =
-                self.new_output(channel, Some(DataTypeCode::Stderr)).await?;
-
-            if let Some(fut) = self
-                .handlers
-                .dispatch_channel_shell(stdin, stdout, stderr, env, pty)
-            {
-                self.spawn_shell_handler(channel, stdout_closed, stderr_closed, fut)
-                    .await;
-                let r = ChannelSuccess::new(*channel_request.recipient_channel());
-                self.send(r).await?;
-            } else {
-                let r = ChannelFailure::new(*channel_request.recipient_channel());
-                self.send(r).await?;
+        let session = match self.channels.get_mut(&channel) {
+            Some(Channel::Session { input, state, .. }) => {
+                input.take().map(|input| (input, std::mem::take(state)))
             }
-        } else {
-            let r = ChannelFailure::new(*channel_request.recipient_channel());
-            self.send(r).await?;
-        }
-        Ok(())
-    }
-
-    pub(super) async fn on_channel_request_exec(
-        &mut self,
-        channel_request: &ChannelRequest,
-        prog: &[u8],
-    ) -> Result<(), SshError> {
-        let channel = *channel_request.recipient_channel();
-
-        if let Some(Channel::Session(_, _, stdin, env, pty)) = self.channels.get_mut(&channel) {
-            let env = env.clone();
-            let pty = pty.take();
-            let stdin = stdin.take().unwrap();
+            _ => None,
+        };
+        if let Some((stdin, mut state)) = session {
+            let kind = match self
+                .auth_state
+                .authorized_key()
+                .and_then(AuthorizedKey::command)
+            {
+                Some(command) => {
+                    state.env.remove("SSH_ORIGINAL_COMMAND");
+                    if let RequestKind::Exec(prog) = &kind {
+                        let prog = prog.to_string_lossy().into_owned();
+                        state.env.insert("SSH_ORIGINAL_COMMAND".into(), prog);
+                    }
+                    RequestKind::Exec(command.into())
+                }
+                None => kind,
+            };
 
             let (stdout, stdout_closed) = self.new_output(channel, None).await?;
             let (stderr, stderr_closed) =
                 self.new_output(channel, Some(DataTypeCode::Stderr)).await?;
+            let motd = match (&kind, self.preference.motd()) {
+                (RequestKind::Shell, Some(motd)) => Some((stdout.try_clone()?, motd.clone())),
+                _ => None,
+            };
 
-            let prog = std::ffi::OsString::from_vec(prog.to_vec());
-
-            if let Some(fut) = self
-                .handlers
-                .dispatch_channel_exec(stdin, stdout, stderr, prog, env, pty)
-            {
-                self.spawn_shell_handler(channel, stdout_closed, stderr_closed, fut)
+            let close_tx = self.close_channel_tx.clone();
+            let exit_signal = Arc::new(Mutex::new(None));
+            let ctx = SessionContext::new(
+                (stdin, stdout, stderr),
+                state,
+                kind.clone(),
+                channel,
+                close_tx,
+                exit_signal.clone(),
+                self.data.clone(),
+            );
+            let fut = match kind {
+                RequestKind::Shell => self.handlers.dispatch_channel_shell(ctx),
+                RequestKind::Exec(prog) => self.handlers.dispatch_channel_exec(ctx, prog),
+                RequestKind::Subsystem(name) => self.handlers.dispatch_channel_subsystem(ctx, name),
+            };
+            if let Some(fut) = fut {
+                // the handler is not polled until the message is written.
+                let fut = async move {
+                    if let Some((mut stdout, motd)) = motd {
+                        if let Err(e) = stdout.write_all(motd.as_bytes()).await {
+                            debug!("failed to write motd: {}", e);
+                        }
+                    }
+                    fut.await
+                };
+                self.spawn_shell_handler(channel, stdout_closed, stderr_closed, exit_signal, fut)
                     .await;
-                let r = ChannelSuccess::new(*channel_request.recipient_channel());
+                let r = ChannelSuccess::new(self.remote_channel(channel));
                 self.send(r).await?;
             } else {
-                let r = ChannelFailure::new(*channel_request.recipient_channel());
+                let r = ChannelFailure::new(self.remote_channel(channel));
                 self.send(r).await?;
             }
         } else {
-            let r = ChannelFailure::new(*channel_request.recipient_channel());
+            let r = ChannelFailure::new(self.remote_channel(channel));
             self.send(r).await?;
         }
         Ok(())
@@ -115,12 +156,19 @@ where
     ) -> Result<(), SshError> {
         let channel = *channel_request.recipient_channel();
 
-        if let Some(Channel::Session(_, _, _, ref mut env, _)) = self.channels.get_mut(&channel) {
-            env.insert(name.to_owned(), value.to_owned());
-            let r = ChannelSuccess::new(*channel_request.recipient_channel());
+        if !self.preference.accepts_env(name) {
+            log::debug!("env {} not accepted", name);
+            let r = ChannelFailure::new(self.remote_channel(channel));
+            self.send(r).await?;
+            return Ok(());
+        }
+
+        if let Some(Channel::Session { state, .. }) = self.channels.get_mut(&channel) {
+            state.env.insert(name.to_owned(), value.to_owned());
+            let r = ChannelSuccess::new(self.remote_channel(channel));
             self.send(r).await?;
         } else {
-            let r = ChannelFailure::new(*channel_request.recipient_channel());
+            let r = ChannelFailure::new(self.remote_channel(channel));
             self.send(r).await?;
         }
         Ok(())
@@ -139,7 +187,19 @@ where
         let height_px = ptyreq.height_px();
         let modes = ptyreq.modes();
 
-        if let Some(Channel::Session(_, _, _, _, ref mut pty)) = self.channels.get_mut(&channel) {
+        let permitted = self
+            .auth_state
+            .authorized_key()
+            .is_none_or(AuthorizedKey::permits_pty);
+        if !permitted {
+            log::debug!("pty not permitted by authorized key");
+            let r = ChannelFailure::new(self.remote_channel(channel));
+            self.send(r).await?;
+            return Ok(());
+        }
+
+        if let Some(Channel::Session { state, .. }) = self.channels.get_mut(&channel) {
+            state.term.replace(term.to_owned());
             if let Some(fut) = self.handlers.dispatch_channel_pty_req(
                 term.to_owned(),
                 *width,
@@ -148,26 +208,988 @@ where
                 *height_px,
                 modes.into_iter().cloned().collect(),
             ) {
-                match fut.await {
+                match self.wait_handler(fut).await? {
                     Ok(p) => {
-                        pty.replace(p);
-                        let r = ChannelSuccess::new(*channel_request.recipient_channel());
+                        if let Some(Channel::Session { state, .. }) =
+                            self.channels.get_mut(&channel)
+                        {
+                            state.pty.replace(p);
+                        }
+                        let r = ChannelSuccess::new(self.remote_channel(channel));
                         self.send(r).await?;
                     }
                     Err(err) => {
                         log::warn!("{}", err.into());
-                        let r = ChannelFailure::new(*channel_request.recipient_channel());
+                        let r = ChannelFailure::new(self.remote_channel(channel));
                         self.send(r).await?;
                     }
                 }
+            } else if *self.preference.allow_pty_without_handler() {
+                // term only. session runs without pty.
+                let r = ChannelSuccess::new(self.remote_channel(channel));
+                self.send(r).await?;
             } else {
-                let r = ChannelFailure::new(*channel_request.recipient_channel());
+                let r = ChannelFailure::new(self.remote_channel(channel));
                 self.send(r).await?;
             }
         } else {
-            let r = ChannelFailure::new(*channel_request.recipient_channel());
+            let r = ChannelFailure::new(self.remote_channel(channel));
+            self.send(r).await?;
+        }
+        Ok(())
+    }
+
+    async fn on_channel_request_xon_xoff(
+        &mut self,
+        channel_request: &ChannelRequest,
+        client_can_do: bool,
+    ) -> Result<(), SshError> {
+        let channel = *channel_request.recipient_channel();
+
+        let found = if let Some(Channel::Session { state, .. }) = self.channels.get_mut(&channel) {
+            state.client_can_do_flow_control = client_can_do;
+            true
+        } else {
+            false
+        };
+
+        if *channel_request.want_reply() {
+            if found {
+                self.send(ChannelSuccess::new(self.remote_channel(channel)))
+                    .await?;
+            } else {
+                self.send(ChannelFailure::new(self.remote_channel(channel)))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn on_channel_request_eow(
+        &mut self,
+        channel_request: &ChannelRequest,
+    ) -> Result<(), SshError> {
+        let channel = *channel_request.recipient_channel();
+        log::debug!("eow {}", channel);
+        if *channel_request.want_reply() {
+            let r = ChannelSuccess::new(self.remote_channel(channel));
             self.send(r).await?;
         }
         Ok(())
     }
+
+    async fn on_channel_request_auth_agent(
+        &mut self,
+        channel_request: &ChannelRequest,
+    ) -> Result<(), SshError> {
+        let channel = *channel_request.recipient_channel();
+        let permitted = self
+            .auth_state
+            .authorized_key()
+            .is_none_or(AuthorizedKey::permits_agent_forwarding);
+        if !permitted {
+            log::debug!("agent forwarding not permitted by authorized key");
+            if *channel_request.want_reply() {
+                let r = ChannelFailure::new(self.remote_channel(channel));
+                self.send(r).await?;
+            }
+            return Ok(());
+        }
+
+        let agent = self.agent_forward();
+        let r = if let Some(fut) = self.handlers.dispatch_agent_forward(agent) {
+            self.wait_handler(fut)
+                .await?
+                .map_err(|e| SshError::HandlerError(e.into()))?
+        } else {
+            false
+        };
+
+        if *channel_request.want_reply() {
+            if r {
+                self.send(ChannelSuccess::new(self.remote_channel(channel)))
+                    .await?;
+            } else {
+                self.send(ChannelFailure::new(self.remote_channel(channel)))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn on_channel_request_x11(
+        &mut self,
+        channel_request: &ChannelRequest,
+        req: &X11Req,
+    ) -> Result<(), SshError> {
+        let channel = *channel_request.recipient_channel();
+        let permitted = self
+            .auth_state
+            .authorized_key()
+            .is_none_or(AuthorizedKey::permits_x11_forwarding);
+        if !permitted {
+            log::debug!("X11 forwarding not permitted by authorized key");
+            if *channel_request.want_reply() {
+                let r = ChannelFailure::new(self.remote_channel(channel));
+                self.send(r).await?;
+            }
+            return Ok(());
+        }
+
+        let forward = self.x11_forward(req);
+        let r = if let Some(fut) = self.handlers.dispatch_x11_forward(forward) {
+            self.wait_handler(fut)
+                .await?
+                .map_err(|e| SshError::HandlerError(e.into()))?
+        } else {
+            false
+        };
+
+        if *channel_request.want_reply() {
+            if r {
+                self.send(ChannelSuccess::new(self.remote_channel(channel)))
+                    .await?;
+            } else {
+                self.send(ChannelFailure::new(self.remote_channel(channel)))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream::StreamExt as _;
+
+    use super::super::tests::{new_runner, new_runner_with_handlers};
+    use super::*;
+    use crate::msg::ignore::Ignore;
+    use crate::msg::Msg;
+    use crate::preference::PreferenceBuilder;
+
+    #[tokio::test]
+    async fn test_eow() {
+        let (mut runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+        runner.auth_state.done();
+
+        let msg = ChannelRequest::new(0, false, Type::Eow(()));
+        runner.on_channel_request(&msg).await.unwrap();
+        let msg = ChannelRequest::new(0, true, Type::Eow(()));
+        runner.on_channel_request(&msg).await.unwrap();
+
+        // mark end of replies.
+        runner.send(Ignore::new("".into())).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        match client.next().await {
+            Some(Ok(Msg::Ignore(..))) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_request() {
+        use bytes::Bytes;
+
+        let unknown = || {
+            ChannelRequest::new(
+                0,
+                true,
+                Type::Unknown("vendor@example.com".into(), Bytes::new()),
+            )
+        };
+
+        let (mut runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+        runner.auth_state.done();
+        runner.on_channel_request(&unknown()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelFailure(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let mut preference = PreferenceBuilder::default();
+        preference.lenient_channel_requests(true);
+        let (mut runner, mut client) = new_runner(&preference).await;
+        runner.auth_state.done();
+        runner.on_channel_request(&unknown()).await.unwrap();
+        let msg = ChannelRequest::new(
+            0,
+            false,
+            Type::Unknown("vendor@example.com".into(), Bytes::new()),
+        );
+        runner.on_channel_request(&msg).await.unwrap();
+
+        // mark end of replies.
+        runner.send(Ignore::new("".into())).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        match client.next().await {
+            Some(Ok(Msg::Ignore(..))) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_term_without_pty_handler() {
+        use std::sync::{Arc, Mutex};
+
+        use bytes::Bytes;
+        use futures::future::{ok, FutureExt as _};
+
+        use crate::msg::channel_open::{self, ChannelOpen};
+        use crate::{Handlers, SessionContext};
+
+        let term = Arc::new(Mutex::new(None));
+        let mut handlers = Handlers::<HandlerError>::new();
+        let t = term.clone();
+        handlers.on_channel_shell(move |ctx: SessionContext| {
+            *t.lock().unwrap() = ctx.term().map(ToOwned::to_owned);
+            ok(0).boxed()
+        });
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+
+        let msg = ChannelOpen::new(0, 1024, 1024, channel_open::Type::Session(()));
+        runner.on_channel_open(&msg).await.unwrap();
+        let pty = PtyReq::new("xterm-256color".into(), 80, 24, 0, 0, Bytes::new());
+        let msg = ChannelRequest::new(0, true, Type::PtyReq(pty));
+        runner.on_channel_request(&msg).await.unwrap();
+        let msg = ChannelRequest::new(0, true, Type::Shell(()));
+        runner.on_channel_request(&msg).await.unwrap();
+
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        match client.next().await {
+            Some(Ok(Msg::ChannelFailure(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        match client.next().await {
+            Some(Ok(Msg::ChannelSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        assert_eq!(term.lock().unwrap().as_deref(), Some("xterm-256color"));
+    }
+
+    #[tokio::test]
+    async fn test_allow_pty_without_handler() {
+        use std::sync::{Arc, Mutex};
+
+        use bytes::Bytes;
+        use futures::future::{ok, FutureExt as _};
+
+        use crate::msg::channel_open::{self, ChannelOpen};
+        use crate::{Handlers, SessionContext};
+
+        let started = Arc::new(Mutex::new(None));
+        let mut handlers = Handlers::<HandlerError>::new();
+        let s = started.clone();
+        handlers.on_channel_shell(move |mut ctx: SessionContext| {
+            *s.lock().unwrap() = Some((ctx.term().map(ToOwned::to_owned), ctx.take_pty()));
+            ok(0).boxed()
+        });
+
+        let mut preference = PreferenceBuilder::default();
+        preference.allow_pty_without_handler(true);
+        let (mut runner, mut client) = new_runner_with_handlers(&preference, handlers).await;
+        runner.auth_state.done();
+
+        let msg = ChannelOpen::new(0, 1024, 1024, channel_open::Type::Session(()));
+        runner.on_channel_open(&msg).await.unwrap();
+        let pty = PtyReq::new("xterm-256color".into(), 80, 24, 0, 0, Bytes::new());
+        let msg = ChannelRequest::new(0, true, Type::PtyReq(pty));
+        runner.on_channel_request(&msg).await.unwrap();
+        let msg = ChannelRequest::new(0, true, Type::Shell(()));
+        runner.on_channel_request(&msg).await.unwrap();
+
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        for _ in 0..2 {
+            match client.next().await {
+                Some(Ok(Msg::ChannelSuccess(..))) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+        assert_eq!(
+            *started.lock().unwrap(),
+            Some((Some("xterm-256color".into()), None))
+        );
+
+        // no exec handler.
+        let msg = ChannelOpen::new(1, 1024, 1024, channel_open::Type::Session(()));
+        runner.on_channel_open(&msg).await.unwrap();
+        let msg = ChannelRequest::new(1, true, Type::Exec(Bytes::from("ls")));
+        runner.on_channel_request(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        match client.next().await {
+            Some(Ok(Msg::ChannelFailure(..))) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_xon_xoff() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        use futures::future::{ok, FutureExt as _};
+
+        use crate::msg::channel_open::{self, ChannelOpen};
+        use crate::{Handlers, SessionContext};
+
+        let flow_control = Arc::new(AtomicBool::new(false));
+        let mut handlers = Handlers::<HandlerError>::new();
+        let f = flow_control.clone();
+        handlers.on_channel_shell(move |ctx: SessionContext| {
+            f.store(ctx.client_can_do_flow_control(), Ordering::SeqCst);
+            ok(0).boxed()
+        });
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+
+        let msg = ChannelOpen::new(0, 1024, 1024, channel_open::Type::Session(()));
+        runner.on_channel_open(&msg).await.unwrap();
+        let msg = ChannelRequest::new(0, false, Type::XonXoff(true));
+        runner.on_channel_request(&msg).await.unwrap();
+        let msg = ChannelRequest::new(0, true, Type::Shell(()));
+        runner.on_channel_request(&msg).await.unwrap();
+
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        match client.next().await {
+            Some(Ok(Msg::ChannelSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        assert!(flow_control.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_request_kind() {
+        use std::ffi::OsString;
+
+        use bytes::Bytes;
+        use futures::future::{ok, FutureExt as _};
+
+        use crate::msg::channel_open::{self, ChannelOpen};
+        use crate::{Handlers, RequestKind, SessionContext};
+
+        let kinds = Arc::new(Mutex::new(vec![]));
+        let mut handlers = Handlers::<HandlerError>::new();
+        let k = kinds.clone();
+        handlers.on_channel_shell(move |ctx: SessionContext| {
+            k.lock().unwrap().push(ctx.request_kind().clone());
+            ok(0).boxed()
+        });
+        let k = kinds.clone();
+        handlers.on_channel_exec(move |ctx: SessionContext, _| {
+            k.lock().unwrap().push(ctx.request_kind().clone());
+            ok(0).boxed()
+        });
+        let k = kinds.clone();
+        handlers.on_channel_subsystem(move |ctx: SessionContext, _| {
+            k.lock().unwrap().push(ctx.request_kind().clone());
+            ok(0).boxed()
+        });
+
+        let (mut runner, _client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+
+        let requests = vec![
+            Type::Shell(()),
+            Type::Exec(Bytes::from("ls -l")),
+            Type::Subsystem("sftp".into()),
+        ];
+        for (chid, typ) in requests.into_iter().enumerate() {
+            let msg = ChannelOpen::new(chid as u32, 1024, 1024, channel_open::Type::Session(()));
+            runner.on_channel_open(&msg).await.unwrap();
+            let msg = ChannelRequest::new(chid as u32, true, typ);
+            runner.on_channel_request(&msg).await.unwrap();
+        }
+
+        assert_eq!(
+            *kinds.lock().unwrap(),
+            vec![
+                RequestKind::Shell,
+                RequestKind::Exec(OsString::from("ls -l")),
+                RequestKind::Subsystem("sftp".into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_request_without_input() {
+        use bytes::Bytes;
+        use futures::future::{ok, FutureExt as _};
+
+        use crate::msg::channel_open::{self, ChannelOpen};
+        use crate::Handlers;
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_shell(|_| ok(0).boxed());
+        handlers.on_channel_exec(|_, _| ok(0).boxed());
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+
+        // never opened.
+        let msg = ChannelRequest::new(42, true, Type::Shell(()));
+        runner.on_channel_request(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelFailure(msg))) => assert_eq!(*msg.recipient_channel(), 42),
+            x => panic!("{:?}", x),
+        }
+
+        // input already consumed by the shell.
+        let msg = ChannelOpen::new(0, 1024, 1024, channel_open::Type::Session(()));
+        runner.on_channel_open(&msg).await.unwrap();
+        let msg = ChannelRequest::new(0, true, Type::Shell(()));
+        runner.on_channel_request(&msg).await.unwrap();
+        let msg = ChannelRequest::new(0, true, Type::Exec(Bytes::from("ls")));
+        runner.on_channel_request(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        match client.next().await {
+            Some(Ok(Msg::ChannelSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        match client.next().await {
+            Some(Ok(Msg::ChannelFailure(..))) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_env_per_channel() {
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex};
+
+        use futures::future::{ok, FutureExt as _};
+
+        use crate::msg::channel_open::{self, ChannelOpen};
+        use crate::msg::channel_request::Env;
+        use crate::{Handlers, SessionContext};
+
+        let envs = Arc::new(Mutex::new(vec![]));
+        let mut handlers = Handlers::<HandlerError>::new();
+        let e = envs.clone();
+        handlers.on_channel_shell(move |ctx: SessionContext| {
+            e.lock().unwrap().push(ctx.env().clone());
+            ok(0).boxed()
+        });
+
+        let mut preference = PreferenceBuilder::default();
+        preference.accept_env(vec!["FOO", "BAR"]);
+        let (mut runner, _client) = new_runner_with_handlers(&preference, handlers).await;
+        runner.auth_state.done();
+
+        for chid in 0..2 {
+            let msg = ChannelOpen::new(chid, 1024, 1024, channel_open::Type::Session(()));
+            runner.on_channel_open(&msg).await.unwrap();
+        }
+        // interleaved, and the second channel only.
+        for (chid, name, value) in &[(0, "FOO", "a"), (1, "FOO", "b"), (1, "BAR", "c")] {
+            let env = Env::new(name.to_string(), value.to_string());
+            let msg = ChannelRequest::new(*chid, false, Type::Env(env));
+            runner.on_channel_request(&msg).await.unwrap();
+        }
+        for chid in 0..2 {
+            let msg = ChannelRequest::new(chid, false, Type::Shell(()));
+            runner.on_channel_request(&msg).await.unwrap();
+        }
+
+        let env = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        assert_eq!(
+            *envs.lock().unwrap(),
+            vec![env(&[("FOO", "a")]), env(&[("FOO", "b"), ("BAR", "c")])]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_env_spawn_process() {
+        use bytes::Bytes;
+        use futures::future::{FutureExt as _, TryFutureExt as _};
+        use futures::sink::SinkExt as _;
+        use tokio::process::Command;
+
+        use crate::msg::channel_open::{self, ChannelOpen};
+        use crate::msg::channel_request::{Env, PtyReq};
+        use crate::util::spawn_process;
+        use crate::Handlers;
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_exec(|ctx, _| {
+            let mut command = Command::new("printenv");
+            command
+                .args(&["FOO", "TERM", "BAZ", "BAR"])
+                .env("BAZ", "server");
+            spawn_process::<_, std::io::Error>(ctx, command)
+                .map_err(Into::into)
+                .boxed()
+        });
+        let mut preference = PreferenceBuilder::default();
+        preference
+            .accept_env(vec!["FOO", "BAZ"])
+            .allow_pty_without_handler(true);
+        let (mut runner, mut client) = new_runner_with_handlers(&preference, handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let msg = ChannelOpen::new(0, 1024 * 1024, 32768, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let pty = PtyReq::new("xterm".into(), 80, 24, 0, 0, Default::default());
+        let requests = vec![
+            (Type::PtyReq(pty), true),
+            (Type::Env(Env::new("FOO".into(), "bar".into())), true),
+            (Type::Env(Env::new("BAZ".into(), "client".into())), true),
+            // not accepted.
+            (Type::Env(Env::new("BAR".into(), "x".into())), false),
+        ];
+        for (typ, accepted) in requests {
+            client
+                .send(ChannelRequest::new(0, true, typ).into())
+                .await
+                .unwrap();
+            match client.next().await {
+                Some(Ok(Msg::ChannelSuccess(..))) if accepted => {}
+                Some(Ok(Msg::ChannelFailure(..))) if !accepted => {}
+                x => panic!("{:?}", x),
+            }
+        }
+        let typ = Type::Exec(Bytes::from("printenv"));
+        client
+            .send(ChannelRequest::new(0, true, typ).into())
+            .await
+            .unwrap();
+
+        let mut stdout = vec![];
+        let mut exit_status = None;
+        loop {
+            match client.next().await {
+                Some(Ok(Msg::ChannelData(msg))) => stdout.extend_from_slice(msg.data()),
+                Some(Ok(Msg::ChannelRequest(msg))) => {
+                    if let Type::ExitStatus(status) = msg.typ() {
+                        exit_status = Some(*status);
+                    }
+                }
+                Some(Ok(Msg::ChannelClose(..))) => break,
+                Some(Ok(..)) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+        // `BAR` is unset, so printenv fails.
+        assert_eq!(stdout, b"bar\nxterm\nserver\n");
+        assert_eq!(exit_status, Some(1));
+
+        runner.abort();
+    }
+
+    #[tokio::test]
+    async fn test_auth_agent() {
+        use bytes::Bytes;
+        use futures::channel::mpsc;
+        use futures::future::FutureExt as _;
+        use futures::sink::SinkExt as _;
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        use crate::msg::channel_data::ChannelData;
+        use crate::msg::channel_open::{self, ChannelOpen};
+        use crate::msg::channel_open_confirmation::ChannelOpenConfirmation;
+        use crate::Handlers;
+
+        let (streams_tx, mut streams_rx) = mpsc::unbounded();
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_agent_forward(move |agent: crate::AgentForward| {
+            let streams_tx = streams_tx.clone();
+            tokio::spawn(async move {
+                let streams = agent.connect().await.unwrap();
+                streams_tx.unbounded_send(streams).unwrap();
+            });
+            futures::future::ok(true).boxed()
+        });
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let msg = ChannelOpen::new(0, 1024 * 1024, 32768, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let msg = ChannelRequest::new(0, true, Type::AuthAgentReq(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let chid = match client.next().await {
+            Some(Ok(Msg::ChannelOpen(msg))) => {
+                assert!(matches!(msg.typ(), channel_open::Type::AuthAgent(..)));
+                *msg.sender_channel()
+            }
+            x => panic!("{:?}", x),
+        };
+        let msg = ChannelOpenConfirmation::new(chid, 7, 1024 * 1024, 32768, Bytes::new());
+        client.send(msg.into()).await.unwrap();
+        let (mut input, mut output) = streams_rx.next().await.unwrap();
+
+        output.write_all(b"request").await.unwrap();
+        drop(output);
+        match client.next().await {
+            Some(Ok(Msg::ChannelData(msg))) => {
+                assert_eq!(*msg.recipient_channel(), 7);
+                assert_eq!(msg.data().as_ref(), b"request");
+            }
+            x => panic!("{:?}", x),
+        }
+        match client.next().await {
+            Some(Ok(Msg::ChannelEof(msg))) => assert_eq!(*msg.recipient_channel(), 7),
+            x => panic!("{:?}", x),
+        }
+        match client.next().await {
+            Some(Ok(Msg::ChannelClose(msg))) => assert_eq!(*msg.recipient_channel(), 7),
+            x => panic!("{:?}", x),
+        }
+
+        let msg = ChannelData::new(chid, Bytes::from("response"));
+        client.send(msg.into()).await.unwrap();
+        client
+            .send(crate::msg::channel_eof::ChannelEof::new(chid).into())
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        input.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"response");
+
+        drop(client);
+        runner.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auth_agent_connect_in_handler() {
+        use futures::future::FutureExt as _;
+        use futures::sink::SinkExt as _;
+
+        use crate::msg::channel_open::{self, ChannelOpen};
+        use crate::Handlers;
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_agent_forward(|agent: crate::AgentForward| {
+            async move {
+                let result = agent.connect().await;
+                Ok(matches!(result, Err(SshError::ChannelOpenInHandler)))
+            }
+            .boxed()
+        });
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let msg = ChannelOpen::new(0, 1024, 1024, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let msg = ChannelRequest::new(0, true, Type::AuthAgentReq(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        drop(client);
+        runner.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_x11() {
+        use bytes::Bytes;
+        use futures::channel::mpsc;
+        use futures::future::FutureExt as _;
+        use futures::sink::SinkExt as _;
+
+        use crate::msg::channel_open::{self, ChannelOpen};
+        use crate::msg::channel_open_confirmation::ChannelOpenConfirmation;
+        use crate::Handlers;
+
+        let (streams_tx, mut streams_rx) = mpsc::unbounded();
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_x11_forward(move |forward: crate::X11Forward| {
+            assert!(!forward.single_connection());
+            assert_eq!(forward.auth_protocol(), "MIT-MAGIC-COOKIE-1");
+            assert_eq!(forward.auth_cookie(), b"0123abcd");
+            assert_eq!(forward.screen_number(), 0);
+            let streams_tx = streams_tx.clone();
+            tokio::spawn(async move {
+                let streams = forward.connect("127.0.0.1", 6010).await.unwrap();
+                streams_tx.unbounded_send(streams).unwrap();
+            });
+            futures::future::ok(true).boxed()
+        });
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let msg = ChannelOpen::new(0, 1024 * 1024, 32768, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let req = X11Req::new(
+            false,
+            "MIT-MAGIC-COOKIE-1".into(),
+            Bytes::from("0123abcd"),
+            0,
+        );
+        let msg = ChannelRequest::new(0, true, Type::X11Req(req));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let chid = match client.next().await {
+            Some(Ok(Msg::ChannelOpen(msg))) => match msg.typ() {
+                channel_open::Type::X11(x11) => {
+                    assert_eq!(x11.originator_address(), "127.0.0.1");
+                    assert_eq!(*x11.originator_port(), 6010);
+                    *msg.sender_channel()
+                }
+                x => panic!("{:?}", x),
+            },
+            x => panic!("{:?}", x),
+        };
+        let msg = ChannelOpenConfirmation::new(chid, 7, 1024 * 1024, 32768, Bytes::new());
+        client.send(msg.into()).await.unwrap();
+        streams_rx.next().await.unwrap();
+
+        drop(client);
+        runner.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_x11_not_registered() {
+        use bytes::Bytes;
+
+        let (mut runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+        runner.auth_state.done();
+
+        let req = X11Req::new(true, "MIT-MAGIC-COOKIE-1".into(), Bytes::new(), 0);
+        let msg = ChannelRequest::new(0, true, Type::X11Req(req));
+        runner.on_channel_request(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelFailure(..))) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_data() {
+        use bytes::BytesMut;
+        use futures::future::{ok, FutureExt as _};
+        use futures::sink::SinkExt as _;
+        use tokio::io::AsyncWriteExt as _;
+
+        use crate::msg::channel_open::{self, ChannelOpen};
+        use crate::msg::userauth_request::{Method, Password, UserauthRequest};
+        use crate::pack::{Pack, Unpack};
+        use crate::{ConnectionData, Handlers, PasswordResult};
+
+        #[derive(Debug, Clone)]
+        struct User {
+            name: String,
+            admin: bool,
+        }
+
+        let data = ConnectionData::default();
+        let mut handlers = Handlers::<HandlerError>::new();
+        let d = data.clone();
+        handlers.on_auth_password(move |name, _| {
+            d.insert(User { name, admin: true });
+            ok(PasswordResult::Ok).boxed()
+        });
+        handlers.on_channel_shell(|mut ctx: SessionContext| {
+            async move {
+                let user = ctx.get_data::<User>().unwrap();
+                let (_, mut stdout, _) = ctx.take_stdio().unwrap();
+                let line = format!("{} {}", user.name, user.admin);
+                stdout.write_all(line.as_bytes()).await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.data = data;
+        runner.userauth_requested = true;
+
+        let mut buf = BytesMut::new();
+        false.pack(&mut buf);
+        "secret".pack(&mut buf);
+        let password = Password::unpack(&mut buf.freeze()).unwrap();
+        let msg = UserauthRequest::new(
+            "alice".into(),
+            "ssh-connection".into(),
+            Method::Password(password),
+        );
+        runner.on_userauth_request(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::UserauthSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let runner = tokio::spawn(runner.run());
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let msg = ChannelOpen::new(0, 1024, 1024, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        client
+            .send(ChannelRequest::new(0, true, Type::Shell(())).into())
+            .await
+            .unwrap();
+        let mut output = vec![];
+        loop {
+            match client.next().await {
+                Some(Ok(Msg::ChannelData(msg))) => output.extend_from_slice(msg.data()),
+                Some(Ok(Msg::ChannelClose(..))) => break,
+                Some(Ok(..)) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+        assert_eq!(output, b"alice true");
+
+        runner.abort();
+    }
+
+    #[tokio::test]
+    async fn test_motd() {
+        use futures::future::FutureExt as _;
+        use futures::sink::SinkExt as _;
+        use tokio::io::AsyncWriteExt as _;
+
+        use crate::msg::channel_open::{self, ChannelOpen};
+        use crate::{Handlers, SessionContext};
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_shell(|mut ctx: SessionContext| {
+            async move {
+                let (_, mut stdout, _) = ctx.take_stdio().unwrap();
+                stdout.write_all(b"$ ").await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+        handlers.on_channel_exec(|mut ctx: SessionContext, _| {
+            async move {
+                let (_, mut stdout, _) = ctx.take_stdio().unwrap();
+                stdout.write_all(b"ok").await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+        let mut pref = PreferenceBuilder::default();
+        pref.motd("Welcome!\r\n".into());
+        let (mut runner, mut client) = new_runner_with_handlers(&pref, handlers).await;
+        runner.auth_state.done();
+
+        let runner = tokio::spawn(runner.run());
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let expects = [&b"Welcome!\r\n$ "[..], b"ok"];
+        for (chid, expect) in expects.iter().enumerate() {
+            let chid = chid as u32;
+            let typ = match chid {
+                0 => Type::Shell(()),
+                _ => Type::Exec(bytes::Bytes::from("true")),
+            };
+            let msg = ChannelOpen::new(chid, 1024, 1024, channel_open::Type::Session(()));
+            client.send(msg.into()).await.unwrap();
+            match client.next().await {
+                Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+                x => panic!("{:?}", x),
+            }
+            client
+                .send(ChannelRequest::new(chid, false, typ).into())
+                .await
+                .unwrap();
+            let mut output = vec![];
+            loop {
+                match client.next().await {
+                    Some(Ok(Msg::ChannelData(msg))) => output.extend_from_slice(msg.data()),
+                    Some(Ok(Msg::ChannelClose(..))) => break,
+                    Some(Ok(..)) => {}
+                    x => panic!("{:?}", x),
+                }
+            }
+            assert_eq!(output, *expect);
+        }
+
+        runner.abort();
+    }
 }
@@ -0,0 +1,21 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::msg::channel_success::ChannelSuccess;
+use crate::HandlerError;
+
+use super::{Runner, SshError};
+
+impl<IO, E, Pty> Runner<IO, E, Pty>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    pub(super) async fn on_channel_success(
+        &mut self,
+        channel_success: &ChannelSuccess,
+    ) -> Result<(), SshError> {
+        let chid = *channel_success.recipient_channel();
+        self.resolve_channel_request(chid, true);
+        Ok(())
+    }
+}
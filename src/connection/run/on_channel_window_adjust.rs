@@ -14,11 +14,105 @@ where
         &mut self,
         channel_window_adjust: &ChannelWindowAdjust,
     ) -> Result<(), SshError> {
-        // FIXME window adjust management
+        let chid = *channel_window_adjust.recipient_channel();
+        self.channel_windows
+            .adjust(chid, *channel_window_adjust.bytes_to_add());
+        if !self.channel_states.is_open(chid) {
+            // unknown or closing channel. nothing is sent after close.
+            return Ok(());
+        }
+        // FIXME receive window management
         let m = ChannelWindowAdjust::new(
-            *channel_window_adjust.recipient_channel(),
+            self.remote_channel(chid),
             *channel_window_adjust.bytes_to_add(),
         );
         self.send(m).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future::FutureExt as _;
+    use futures::sink::SinkExt as _;
+    use futures::stream::StreamExt as _;
+    use tokio::io::AsyncWriteExt as _;
+
+    use super::super::tests::new_runner_with_handlers;
+    use super::*;
+    use crate::msg::channel_open::{self, ChannelOpen};
+    use crate::msg::channel_request::{ChannelRequest, Type};
+    use crate::msg::Msg;
+    use crate::preference::PreferenceBuilder;
+    use crate::{Handlers, SessionContext};
+
+    #[tokio::test]
+    async fn test_zero_initial_window() {
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_shell(|mut ctx: SessionContext| {
+            async move {
+                let (_, mut stdout, _) = ctx.take_stdio().unwrap();
+                stdout.write_all(b"hello").await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let msg = ChannelOpen::new(0, 0, 32768, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let msg = ChannelRequest::new(0, true, Type::Shell(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        // nothing goes on the wire until window adjust.
+        let r = tokio::time::timeout(Duration::from_millis(100), client.next()).await;
+        assert!(r.is_err(), "{:?}", r);
+
+        let msg = ChannelWindowAdjust::new(0, 3);
+        client.send(msg.into()).await.unwrap();
+        let mut data = vec![];
+        while data.len() < 3 {
+            match client.next().await {
+                Some(Ok(Msg::ChannelWindowAdjust(..))) => {}
+                Some(Ok(Msg::ChannelData(msg))) => data.extend_from_slice(msg.data()),
+                x => panic!("{:?}", x),
+            }
+        }
+        assert_eq!(data, b"hel");
+
+        let r = tokio::time::timeout(Duration::from_millis(100), client.next()).await;
+        assert!(r.is_err(), "{:?}", r);
+
+        let msg = ChannelWindowAdjust::new(0, 1024);
+        client.send(msg.into()).await.unwrap();
+        loop {
+            match client.next().await {
+                Some(Ok(Msg::ChannelWindowAdjust(..))) | Some(Ok(Msg::ChannelEof(..))) => {}
+                Some(Ok(Msg::ChannelData(msg))) => data.extend_from_slice(msg.data()),
+                Some(Ok(Msg::ChannelRequest(..))) => break,
+                x => panic!("{:?}", x),
+            }
+        }
+        assert_eq!(data, b"hello");
+
+        runner.abort();
+    }
+}
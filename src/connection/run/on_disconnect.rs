@@ -0,0 +1,75 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::msg::disconnect::Disconnect;
+use crate::HandlerError;
+
+use super::{Runner, SshError};
+
+impl<IO, E, Pty> Runner<IO, E, Pty>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    pub(super) async fn on_disconnect(&mut self, disconnect: &Disconnect) -> Result<(), SshError> {
+        log::debug!(
+            "disconnected by client {:?} {}",
+            disconnect.reason_code(),
+            disconnect.description()
+        );
+
+        let reason_code = disconnect.reason_code().clone();
+        self.disconnect_reason = Some(reason_code.clone());
+        let description = disconnect.description().clone();
+        if let Some(fut) = self.handlers.dispatch_disconnect(reason_code, description) {
+            self.wait_handler(fut)
+                .await?
+                .map_err(|e| SshError::HandlerError(e.into()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::future::{ok, FutureExt as _};
+    use futures::sink::SinkExt as _;
+    use futures::stream::StreamExt as _;
+
+    use super::super::tests::new_runner_with_handlers;
+    use super::*;
+    use crate::msg::disconnect::ReasonCode;
+    use crate::msg::Msg;
+    use crate::preference::PreferenceBuilder;
+    use crate::Handlers;
+
+    #[tokio::test]
+    async fn test_disconnect() {
+        let observed = Arc::new(Mutex::new(None));
+        let mut handlers = Handlers::<HandlerError>::new();
+        let o = observed.clone();
+        handlers.on_disconnect(move |reason_code, description| {
+            *o.lock().unwrap() = Some((reason_code, description));
+            ok(()).boxed()
+        });
+
+        let (runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let msg = Disconnect::new(ReasonCode::ByApplication, "bye".into(), "".into());
+        client.send(msg.into()).await.unwrap();
+
+        // terminates without waiting for client EOF.
+        runner.await.unwrap().unwrap();
+        assert_eq!(
+            *observed.lock().unwrap(),
+            Some((ReasonCode::ByApplication, "bye".to_string()))
+        );
+    }
+}
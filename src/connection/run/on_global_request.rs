@@ -1,7 +1,10 @@
+use bytes::Bytes;
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::msg::global_request::{GlobalRequest, Type};
+use crate::authorized_keys::AuthorizedKey;
+use crate::msg::global_request::{GlobalRequest, TcpipForward, Type};
 use crate::msg::request_failure::RequestFailure;
+use crate::msg::request_success::RequestSuccess;
 
 use crate::HandlerError;
 
@@ -17,16 +20,22 @@ where
         global_request: &GlobalRequest,
     ) -> Result<(), SshError> {
         match global_request.typ() {
-            Type::TcpipForward(..) => {
-                log::debug!("not implemented for tcpip forward.");
-                let r = RequestFailure::new();
-                self.send(r).await?;
+            Type::TcpipForward(item) => {
+                self.on_global_request_tcpip_forward(global_request, item)
+                    .await?
             }
             Type::CancelTcpipForward(..) => {
                 log::debug!("not implemented for cancel tcpip forward.");
                 let r = RequestFailure::new();
                 self.send(r).await?;
             }
+            Type::NoMoreSessions(..) => {
+                log::debug!("no more sessions.");
+                self.no_more_sessions = true;
+                if *global_request.want_reply() {
+                    self.send(RequestSuccess::new(Bytes::new())).await?;
+                }
+            }
             Type::Unknown(..) => {
                 log::debug!("unknown request.");
                 let r = RequestFailure::new();
@@ -35,4 +44,105 @@ where
         }
         Ok(())
     }
+
+    async fn on_global_request_tcpip_forward(
+        &mut self,
+        global_request: &GlobalRequest,
+        item: &TcpipForward,
+    ) -> Result<(), SshError> {
+        let permitted = self
+            .auth_state
+            .authorized_key()
+            .is_none_or(AuthorizedKey::permits_tcpip_forward);
+        if !permitted {
+            log::debug!("tcpip forward not permitted by authorized key");
+            if *global_request.want_reply() {
+                self.send(RequestFailure::new()).await?;
+            }
+            return Ok(());
+        }
+
+        let requested = *item.port_number_to_bind();
+        let forward = self.tcpip_forward(item.address_to_bind().clone(), requested);
+        let bound = if let Some(fut) = self.handlers.dispatch_tcpip_forward(forward) {
+            self.wait_handler(fut)
+                .await?
+                .map_err(|e| SshError::HandlerError(e.into()))?
+        } else {
+            log::debug!("no tcpip forward handler.");
+            None
+        };
+
+        if *global_request.want_reply() {
+            match bound {
+                // RFC 4254 7.1: reply bound port only if port 0 was requested.
+                Some(port) if requested == 0 => {
+                    let data = Bytes::copy_from_slice(&port.to_be_bytes());
+                    self.send(RequestSuccess::new(data)).await?;
+                }
+                Some(..) => self.send(RequestSuccess::new(Bytes::new())).await?,
+                None => self.send(RequestFailure::new()).await?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{ok, FutureExt as _};
+    use futures::stream::StreamExt as _;
+
+    use super::super::tests::new_runner_with_handlers;
+    use super::*;
+    use crate::msg::Msg;
+    use crate::preference::PreferenceBuilder;
+    use crate::Handlers;
+
+    #[tokio::test]
+    async fn test_tcpip_forward_bound_port() {
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_tcpip_forward(|forward: crate::TcpipForward| {
+            let port = if forward.port() == 0 {
+                12345
+            } else {
+                forward.port()
+            };
+            ok(Some(port)).boxed()
+        });
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.done();
+
+        for (port, expected) in &[(0, &12345u32.to_be_bytes()[..]), (8080, &[][..])] {
+            let typ = Type::TcpipForward(TcpipForward::new("localhost".into(), *port));
+            runner
+                .on_global_request(&GlobalRequest::new(true, typ))
+                .await
+                .unwrap();
+            match client.next().await {
+                Some(Ok(Msg::RequestSuccess(msg))) => {
+                    assert_eq!(msg.additional_data().as_ref(), *expected)
+                }
+                x => panic!("{:?}", x),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcpip_forward_without_handler() {
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), Handlers::new()).await;
+        runner.auth_state.done();
+
+        let typ = Type::TcpipForward(TcpipForward::new("localhost".into(), 0));
+        runner
+            .on_global_request(&GlobalRequest::new(true, typ))
+            .await
+            .unwrap();
+        match client.next().await {
+            Some(Ok(Msg::RequestFailure(..))) => {}
+            x => panic!("{:?}", x),
+        }
+    }
 }
@@ -1,15 +1,17 @@
+use bytes::Bytes;
 use futures::stream::TryStreamExt as _;
 use log::debug;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time;
 
 use crate::kex::Kex;
 use crate::msg::kexinit::Kexinit;
 use crate::msg::new_keys::NewKeys;
 use crate::msg::Msg;
 use crate::negotiate::negotiate;
-use crate::HandlerError;
+use crate::{HandlerError, KexSummary};
 
-use super::{Runner, SshError};
+use super::{within_deadline, Runner, SshError};
 
 impl<IO, E, Pty> Runner<IO, E, Pty>
 where
@@ -21,43 +23,208 @@ where
         let s_kexinit = if self.first_kexinit.is_some() {
             self.first_kexinit.take().unwrap()
         } else {
-            let s_kexinit = self.preference.to_kexinit();
+            // rekey by client. channel data is queued until it completes.
+            self.check_rekey_interval()?;
+            let s_kexinit = self.preference.to_kexinit_for(&self.quirks);
             self.send(s_kexinit.clone()).await?;
+            self.start_kex_deadline();
             s_kexinit
         };
+        let deadline = self.kex_deadline;
 
-        let algorithm = negotiate(&c_kexinit, &self.preference)?;
+        let algorithm = negotiate(&c_kexinit, &self.preference, &self.quirks)?;
         debug!("algorithm: {:?}", algorithm);
 
         let hostkey = self
             .preference
             .hostkeys()
-            .lookup(algorithm.server_host_key_algorithm())
-            .unwrap();
-        let kex = Kex::new(algorithm.kex_algorithm());
+            .lookup(algorithm.server_host_key_algorithm())?;
+        let mut kex = Kex::new(algorithm.kex_algorithm());
+        if let Some(bits) = self.preference.max_dh_group_size() {
+            kex.set_max_dh_group_size(*bits);
+        }
 
         debug!("Begin kex.. {:?}", kex);
-        let (hash, key) = kex
-            .kex(
-                &mut self.io,
-                &self.c_version,
-                &self.s_version,
-                &c_kexinit,
-                &s_kexinit,
-                hostkey,
-            )
-            .await?;
+        let exchange = kex.kex(
+            &mut self.io,
+            &self.c_version,
+            &self.s_version,
+            &c_kexinit,
+            &s_kexinit,
+            hostkey,
+        );
+        let (hash, key, signature) = within_deadline(deadline, exchange).await?;
         debug!("Done kex. {:?}", kex);
 
-        match self.io.try_next().await? {
-            Some(Msg::NewKeys(..)) => {}
-            Some(msg) => return Err(SshError::UnexpectedMsg(format!("{:?}", msg))),
-            None => return Err(SshError::NoPacketReceived),
-        };
+        within_deadline(deadline, self.recv_new_keys()).await?;
+        self.kex_deadline = None;
+        if self.last_kex.is_none() {
+            self.start_service_request_deadline();
+        }
+        self.last_kex = Some(time::Instant::now());
         self.send(NewKeys::new()).await?;
 
         let state = self.io.get_mut().state_mut();
         state.change_key(&hash, &key, &kex, &algorithm)?;
+        #[cfg(feature = "debug_keylog")]
+        {
+            let keys = state.take_keys();
+            if let (Some(callback), Some(keys)) = (self.preference.on_keys_derived(), keys) {
+                callback.call(&keys);
+            }
+        }
+        let session_id = Bytes::copy_from_slice(state.session_id());
+
+        let summary = KexSummary::new(algorithm, hash, session_id, signature.to_bytes());
+        if let Some(fut) = self.handlers.dispatch_kex_done(summary) {
+            self.wait_handler(fut)
+                .await?
+                .map_err(|e| SshError::HandlerError(e.into()))?;
+        }
         Ok(())
     }
+
+    fn check_rekey_interval(&self) -> Result<(), SshError> {
+        match (self.last_kex, self.preference.min_rekey_interval()) {
+            (Some(last), Some(interval)) if last.elapsed() < *interval => {
+                Err(SshError::RekeyTooFrequent)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn recv_new_keys(&mut self) -> Result<(), SshError> {
+        match self.io.try_next().await? {
+            Some(Msg::NewKeys(..)) => Ok(()),
+            Some(msg) => Err(SshError::KexUnexpectedMsg(format!("{:?}", msg))),
+            None => Err(SshError::KexUnexpectedEof),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::sink::SinkExt as _;
+    use futures::stream::StreamExt as _;
+
+    use super::super::tests::new_runner;
+    use super::*;
+    use crate::msg::disconnect::ReasonCode;
+    use crate::msg::ignore::Ignore;
+    use crate::msg::kexinit::KexinitBuilder;
+    use crate::msg::Msg;
+    use crate::pack::NameList;
+    use crate::preference::PreferenceBuilder;
+
+    fn list<'a, V: AsRef<[&'a str]>>(v: V) -> NameList {
+        v.as_ref().iter().map(|s| s.to_string()).collect()
+    }
+
+    fn client_kexinit(hostkey: &str) -> Kexinit {
+        KexinitBuilder::default()
+            .cookie(0)
+            .kex_algorithms(list(["curve25519-sha256"]))
+            .server_host_key_algorithms(list([hostkey]))
+            .cipher_algorithms_c2s(list(["aes256-ctr"]))
+            .cipher_algorithms_s2c(list(["aes256-ctr"]))
+            .mac_algorithms_c2s(list(["hmac-sha2-256"]))
+            .mac_algorithms_s2c(list(["hmac-sha2-256"]))
+            .compression_algorithms_c2s(list(["none"]))
+            .compression_algorithms_s2c(list(["none"]))
+            .languages_c2s(list([""]))
+            .languages_s2c(list([""]))
+            .first_kex_packet_follows(false)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_missing_new_keys() {
+        let (mut runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+
+        client.send(Ignore::new("".into()).into()).await.unwrap();
+        assert!(matches!(
+            runner.recv_new_keys().await,
+            Err(SshError::KexUnexpectedMsg(..))
+        ));
+
+        drop(client);
+        assert!(matches!(
+            runner.recv_new_keys().await,
+            Err(SshError::KexUnexpectedEof)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_kex_timeout() {
+        use std::time::Duration;
+
+        let mut preference = PreferenceBuilder::default();
+        preference.kex_timeout(Duration::from_millis(100));
+        let (runner, mut client) = new_runner(&preference).await;
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        // never sends SSH_MSG_KEX_ECDH_INIT.
+        client
+            .send(client_kexinit("ssh-ed25519").into())
+            .await
+            .unwrap();
+
+        match client.next().await {
+            Some(Ok(Msg::Disconnect(msg))) => {
+                assert!(matches!(msg.reason_code(), ReasonCode::KeyExchangeFailed));
+            }
+            x => panic!("{:?}", x),
+        }
+        assert!(matches!(runner.await.unwrap(), Err(SshError::KexTimeout)));
+    }
+
+    #[tokio::test]
+    async fn test_service_request_timeout() {
+        use std::time::Duration;
+
+        let mut preference = PreferenceBuilder::default();
+        preference.service_request_timeout(Duration::from_millis(100));
+        let (mut runner, mut client) = new_runner(&preference).await;
+        // as if the first key exchange completed.
+        runner.start_service_request_deadline();
+        let started = time::Instant::now();
+        let runner = tokio::spawn(runner.run());
+
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        // never sends SSH_MSG_SERVICE_REQUEST.
+        match client.next().await {
+            Some(Ok(Msg::Disconnect(msg))) => {
+                assert!(matches!(msg.reason_code(), ReasonCode::ByApplication));
+            }
+            x => panic!("{:?}", x),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(matches!(
+            runner.await.unwrap(),
+            Err(SshError::ServiceRequestTimeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rekey_too_frequent() {
+        use std::time::Duration;
+
+        let mut preference = PreferenceBuilder::default();
+        preference.min_rekey_interval(Duration::from_secs(60));
+        let (mut runner, _client) = new_runner(&preference).await;
+        runner.last_kex = Some(time::Instant::now());
+
+        assert!(matches!(
+            runner.on_kexinit(&client_kexinit("ssh-ed25519")).await,
+            Err(SshError::RekeyTooFrequent)
+        ));
+    }
 }
@@ -25,6 +25,8 @@ where
     async fn on_userauth(&mut self) -> Result<(), SshError> {
         let accept = ServiceAccept::new(SSH_USERAUTH.into());
         self.send(accept).await?;
+        self.userauth_requested = true;
+        self.service_request_deadline = None;
         Ok(())
     }
 
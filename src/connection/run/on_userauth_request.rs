@@ -1,13 +1,19 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
 use futures::sink::SinkExt as _;
 use tokio::io::{AsyncRead, AsyncWrite};
 
+use crate::authorized_keys::AuthorizedKey;
+use crate::events::ServerEvent;
+use crate::msg::service_request::SSH_USERAUTH;
 use crate::msg::userauth_failure::UserauthFailure;
 use crate::msg::userauth_passwd_changereq::UserauthPasswdChangereq;
 use crate::msg::userauth_pk_ok::UserauthPkOk;
 use crate::msg::userauth_request::{Hostbased, Method, Password, Publickey, UserauthRequest};
 use crate::msg::userauth_success::UserauthSuccess;
 use crate::msg::UserauthPkMsg;
-use crate::pack::Pack;
+use crate::pack::{NameList, Pack};
 use crate::{HandlerError, PasswordResult};
 use bytes::Bytes;
 use log::debug;
@@ -16,17 +22,30 @@ use super::{Runner, SshError};
 
 const SUPPORTED_METHODS: &[&str] = &["publickey", "password", "hostbased"];
 
+/// Number of publickey probe results kept per connection.
+const MAX_PUBLICKEY_RESULTS: usize = 16;
+
 #[derive(Debug)]
 pub(super) struct AuthState {
     remaining: Vec<&'static str>,
-    accepted_publickey: Option<(String, crate::PublicKey)>,
+    publickey_results: VecDeque<((String, crate::PublicKey), bool, Option<AuthorizedKey>)>,
+    authenticated: bool,
+    last_result: Option<bool>,
+    /// `authorized_keys` entry of the authenticated key, whose options are enforced.
+    authorized_key: Option<AuthorizedKey>,
+    /// Methods succeeded toward `auth_policy`, by the user name.
+    completed: Option<(String, Vec<String>)>,
 }
 
 impl AuthState {
     pub(super) fn new() -> Self {
         Self {
             remaining: Vec::from(SUPPORTED_METHODS),
-            accepted_publickey: None,
+            publickey_results: VecDeque::new(),
+            authenticated: false,
+            last_result: None,
+            authorized_key: None,
+            completed: None,
         }
     }
 
@@ -38,8 +57,68 @@ impl AuthState {
         &self.remaining
     }
 
-    fn done(&mut self) {
+    fn publickey_result(&self, user_name: &str, publickey: &crate::PublicKey) -> Option<bool> {
+        self.publickey_results
+            .iter()
+            .find(|((u, k), _, _)| u == user_name && k == publickey)
+            .map(|(_, r, _)| *r)
+    }
+
+    fn publickey_entry(
+        &self,
+        user_name: &str,
+        publickey: &crate::PublicKey,
+    ) -> Option<&AuthorizedKey> {
+        self.publickey_results
+            .iter()
+            .find(|((u, k), _, _)| u == user_name && k == publickey)
+            .and_then(|(_, _, entry)| entry.as_ref())
+    }
+
+    /// Cache probe result. Oldest result is dropped when full.
+    fn cache_publickey_result(
+        &mut self,
+        user_name: &str,
+        publickey: &crate::PublicKey,
+        r: bool,
+        entry: Option<AuthorizedKey>,
+    ) {
+        if self.publickey_results.len() >= MAX_PUBLICKEY_RESULTS {
+            self.publickey_results.pop_front();
+        }
+        self.publickey_results
+            .push_back(((user_name.into(), publickey.clone()), r, entry));
+    }
+
+    /// Record succeeded method. Methods of another user name are discarded.
+    fn complete(&mut self, user_name: &str, method: &str) -> &[String] {
+        match &mut self.completed {
+            Some((user, methods)) if user == user_name => {
+                if !methods.iter().any(|m| m == method) {
+                    methods.push(method.into());
+                }
+            }
+            completed => *completed = Some((user_name.into(), vec![method.into()])),
+        }
+        self.completed.as_ref().map_or(&[], |(_, methods)| methods)
+    }
+
+    fn completed(&self) -> &[String] {
+        self.completed.as_ref().map_or(&[], |(_, methods)| methods)
+    }
+
+    pub(super) fn done(&mut self) {
         self.remaining.clear();
+        self.completed = None;
+        self.authenticated = true;
+    }
+
+    pub(super) fn authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    pub(super) fn authorized_key(&self) -> Option<&AuthorizedKey> {
+        self.authorized_key.as_ref()
     }
 }
 
@@ -52,8 +131,24 @@ where
         &mut self,
         userauth_request: &UserauthRequest,
     ) -> Result<(), SshError> {
+        if !self.userauth_requested {
+            return Err(SshError::ServiceNotRequested(SSH_USERAUTH.into()));
+        }
+
+        let reauth = self.auth_state.authenticated();
+        if reauth && !*self.preference.allow_reauthentication() {
+            debug!("ignore userauth request after success");
+            return Ok(());
+        }
+        self.auth_state.last_result = None;
+
         let user_name = userauth_request.user_name();
         match userauth_request.method() {
+            _ if !self.acceptable_names(userauth_request) => {
+                debug!("invalid user name or service name");
+                self.send_failure(None).await
+            }
+
             Method::None => self.on_userauth_none(user_name).await,
 
             Method::Publickey(item) if item.signature().is_none() => {
@@ -80,11 +175,51 @@ where
                 debug!("unknown auth method {:?}", x);
                 self.send_failure(None).await
             }
+        }?;
+
+        if self.auth_state.last_result == Some(true) {
+            let event = ServerEvent::Authenticated {
+                user: user_name.into(),
+                method: userauth_request.method().name().into(),
+            };
+            self.preference.events().emit(event);
+        }
+
+        if let (true, Some(success)) = (reauth, self.auth_state.last_result) {
+            if let Some(fut) = self.handlers.dispatch_reauth(user_name.into(), success) {
+                self.wait_handler(fut)
+                    .await?
+                    .map_err(|e| SshError::HandlerError(e.into()))?;
+            }
         }
+        Ok(())
+    }
+
+    /// Whether user name and service name can be passed to handlers and logs as is.
+    fn acceptable_names(&self, userauth_request: &UserauthRequest) -> bool {
+        let max = *self.preference.max_user_name_length();
+        [
+            userauth_request.user_name(),
+            userauth_request.service_name(),
+        ]
+        .iter()
+        .all(|name| name.len() <= max && !name.chars().any(char::is_control))
     }
 
-    async fn send_success(&mut self) -> Result<(), SshError> {
+    async fn send_success(&mut self, user_name: &str, method: &str) -> Result<(), SshError> {
+        let preference = self.preference.clone();
+        if let Some(policy) = preference.auth_policy() {
+            let completed = self.auth_state.complete(user_name, method);
+            if !policy.is_satisfied(completed) {
+                debug!("partial success by {}", method);
+                let msg = UserauthFailure::new(self.continuable_methods(), true);
+                self.send(msg).await?;
+                return Ok(());
+            }
+        }
+
         self.auth_state.done();
+        self.auth_state.last_result = Some(true);
         self.send(UserauthSuccess::new()).await?;
         Ok(())
     }
@@ -93,23 +228,57 @@ where
         if let Some(consume) = consume {
             self.auth_state.consume(consume);
         }
-        let methods = self.auth_state.remaining();
-        let msg = UserauthFailure::new(methods.iter().cloned().collect(), false);
+        self.auth_state.last_result = Some(false);
+        let msg = UserauthFailure::new(self.continuable_methods(), false);
+        if let Some(delay) = self.failure_delay()? {
+            tokio::time::sleep(delay).await;
+        }
         self.send(msg).await?;
         Ok(())
     }
 
+    /// Methods listed in `SSH_MSG_USERAUTH_FAILURE`, narrowed by `auth_policy`.
+    fn continuable_methods(&self) -> NameList {
+        let methods = if self.auth_state.authenticated() {
+            SUPPORTED_METHODS
+        } else {
+            self.auth_state.remaining()
+        };
+        match self.preference.auth_policy() {
+            Some(policy) => policy
+                .needed(self.auth_state.completed())
+                .into_iter()
+                .filter(|m| methods.contains(&m.as_str()))
+                .collect(),
+            None => methods.iter().cloned().collect(),
+        }
+    }
+
+    /// `auth_failure_delay` plus random jitter up to `auth_failure_jitter`.
+    fn failure_delay(&self) -> Result<Option<Duration>, SshError> {
+        let mut delay = self.preference.auth_failure_delay().unwrap_or_default();
+        if let Some(jitter) = self.preference.auth_failure_jitter() {
+            let mut buf = [0; 8];
+            self.preference.rng().fill(&mut buf)?;
+            let nanos = u64::from_be_bytes(buf) % (jitter.as_nanos() as u64).saturating_add(1);
+            delay += Duration::from_nanos(nanos);
+        }
+        Ok(Some(delay).filter(|delay| !delay.is_zero()))
+    }
+
     async fn on_userauth_none(&mut self, user_name: &str) -> Result<(), SshError> {
-        let user_name = user_name.into();
+        let username = user_name.into();
 
-        let r = if let Some(fut) = self.handlers.dispatch_auth_none(user_name) {
-            fut.await.map_err(|e| SshError::HandlerError(e.into()))?
+        let r = if let Some(fut) = self.handlers.dispatch_auth_none(username) {
+            self.wait_handler(fut)
+                .await?
+                .map_err(|e| SshError::HandlerError(e.into()))?
         } else {
             false
         };
 
         if r {
-            self.send_success().await
+            self.send_success(user_name, "none").await
         } else {
             self.send_failure(None).await
         }
@@ -129,17 +298,7 @@ where
             ));
         }
 
-        let r = if let Some(fut) = self
-            .handlers
-            .dispatch_auth_publickey(user_name.into(), publickey.clone())
-        {
-            fut.await.map_err(|e| SshError::HandlerError(e.into()))?
-        } else {
-            false
-        };
-
-        if r {
-            self.auth_state.accepted_publickey = Some((user_name.into(), publickey.clone()));
+        if self.check_publickey(user_name, publickey).await? {
             let m = UserauthPkOk::new(item.algorithm().into(), item.blob().clone()).into();
             self.io.context::<UserauthPkMsg>().send(m).await?;
         } else {
@@ -185,10 +344,9 @@ where
                 ));
             }
 
-            let r = if let Some((accepted_username, accepted_publickey)) =
-                self.auth_state.accepted_publickey.take()
-            {
-                if accepted_username == user_name && &accepted_publickey == publickey {
+            let cached = self.auth_state.publickey_result(user_name, publickey);
+            let r = match cached {
+                Some(true) => {
                     if let Some(fut) = self
                         .handlers
                         .dispatch_auth_publickey_signature_verified_after_accepted(
@@ -196,29 +354,23 @@ where
                             publickey.clone(),
                         )
                     {
-                        fut.await.map_err(|e| SshError::HandlerError(e.into()))?
+                        self.wait_handler(fut)
+                            .await?
+                            .map_err(|e| SshError::HandlerError(e.into()))?
                     } else {
                         true
                     }
-                } else if let Some(fut) = self
-                    .handlers
-                    .dispatch_auth_publickey(user_name.into(), publickey.clone())
-                {
-                    fut.await.map_err(|e| SshError::HandlerError(e.into()))?
-                } else {
-                    false
                 }
-            } else if let Some(fut) = self
-                .handlers
-                .dispatch_auth_publickey(user_name.into(), publickey.clone())
-            {
-                fut.await.map_err(|e| SshError::HandlerError(e.into()))?
-            } else {
-                false
+                Some(false) => false,
+                None => self.check_publickey(user_name, publickey).await?,
             };
 
             if r {
-                self.send_success().await
+                // kept over reauthentication by other methods, not to lift restrictions.
+                if let Some(entry) = self.auth_state.publickey_entry(user_name, publickey) {
+                    self.auth_state.authorized_key = Some(entry.clone());
+                }
+                self.send_success(user_name, "publickey").await
             } else {
                 self.send_failure(Some("publickey")).await
             }
@@ -227,6 +379,54 @@ where
         }
     }
 
+    /// Ask handler whether publickey is acceptable. Result is cached per connection.
+    async fn check_publickey(
+        &mut self,
+        user_name: &str,
+        publickey: &crate::PublicKey,
+    ) -> Result<bool, SshError> {
+        if let Some(r) = self.auth_state.publickey_result(user_name, publickey) {
+            return Ok(r);
+        }
+
+        let (r, entry) = if let Some(fut) = self
+            .handlers
+            .dispatch_auth_authorized_key(user_name.into(), publickey.clone())
+        {
+            let entry = self
+                .wait_handler(fut)
+                .await?
+                .map_err(|e| SshError::HandlerError(e.into()))?;
+            let peer = self.state.peer_addr().map(|addr| addr.ip());
+            let entry = match entry {
+                Some(entry) if entry.publickey() != publickey => {
+                    debug!("authorized key does not match publickey");
+                    None
+                }
+                Some(entry) if !entry.permits_from(peer) => {
+                    debug!("authorized key not permitted from {:?}", peer);
+                    None
+                }
+                entry => entry,
+            };
+            (entry.is_some(), entry)
+        } else if let Some(fut) = self
+            .handlers
+            .dispatch_auth_publickey(user_name.into(), publickey.clone())
+        {
+            let r = self
+                .wait_handler(fut)
+                .await?
+                .map_err(|e| SshError::HandlerError(e.into()))?;
+            (r, None)
+        } else {
+            (false, None)
+        };
+        self.auth_state
+            .cache_publickey_result(user_name, publickey, r, entry);
+        Ok(r)
+    }
+
     async fn on_userauth_password(
         &mut self,
         user_name: &str,
@@ -236,15 +436,18 @@ where
         let password = item.password().into();
 
         let r = if let Some(fut) = self.handlers.dispatch_auth_password(username, password) {
-            fut.await.map_err(|e| SshError::HandlerError(e.into()))?
+            self.wait_handler(fut)
+                .await?
+                .map_err(|e| SshError::HandlerError(e.into()))?
         } else {
             PasswordResult::Failure
         };
 
         match r {
-            PasswordResult::Ok => self.send_success().await,
+            PasswordResult::Ok => self.send_success(user_name, "password").await,
             PasswordResult::PasswordChangeRequired(message) => {
-                let m = UserauthPasswdChangereq::new(message, "".into());
+                let m =
+                    UserauthPasswdChangereq::new(message, self.preference.language_tag().clone());
                 self.send(m).await
             }
             PasswordResult::Failure => self.send_failure(Some("password")).await,
@@ -264,15 +467,18 @@ where
             self.handlers
                 .dispatch_auth_change_password(username, oldpassword, newpassword)
         {
-            fut.await.map_err(|e| SshError::HandlerError(e.into()))?
+            self.wait_handler(fut)
+                .await?
+                .map_err(|e| SshError::HandlerError(e.into()))?
         } else {
             PasswordResult::Failure
         };
 
         match r {
-            PasswordResult::Ok => self.send_success().await,
+            PasswordResult::Ok => self.send_success(user_name, "password").await,
             PasswordResult::PasswordChangeRequired(message) => {
-                let m = UserauthPasswdChangereq::new(message, "".into());
+                let m =
+                    UserauthPasswdChangereq::new(message, self.preference.language_tag().clone());
                 self.send(m).await
             }
             PasswordResult::Failure => self.send_failure(Some("password")).await,
@@ -323,13 +529,15 @@ where
                 self.handlers
                     .dispatch_auth_hostbased(username, hostname, publickey.clone())
             {
-                fut.await.map_err(|e| SshError::HandlerError(e.into()))?
+                self.wait_handler(fut)
+                    .await?
+                    .map_err(|e| SshError::HandlerError(e.into()))?
             } else {
                 false
             };
 
             if r {
-                self.send_success().await
+                self.send_success(user_name, "hostbased").await
             } else {
                 self.send_failure(Some("hostbased")).await
             }
@@ -338,3 +546,556 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+    use futures::future::{ok, FutureExt as _};
+
+    use super::super::tests::{new_runner, new_runner_with_handlers};
+    use super::*;
+    use crate::key::{Algorithm, Key};
+    use crate::preference::PreferenceBuilder;
+    use crate::Handlers;
+
+    #[tokio::test]
+    async fn test_publickey_probe_cached() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut handlers = Handlers::<HandlerError>::new();
+        let c = count.clone();
+        handlers.on_auth_publickey(move |_, _| {
+            c.fetch_add(1, Ordering::SeqCst);
+            ok(true).boxed()
+        });
+        let (mut runner, _client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.userauth_requested = true;
+        let session_id = Bytes::from("session");
+        runner
+            .io
+            .get_mut()
+            .state_mut()
+            .set_session_id(session_id.clone());
+
+        let key = Key::gen(&Algorithm::SshEd25519).unwrap();
+        let algorithm = key.name().as_ref().to_string();
+
+        let probe = Publickey::new(algorithm.clone(), key.publickey(), None);
+        let msg = UserauthRequest::new(
+            "user".into(),
+            "ssh-connection".into(),
+            Method::Publickey(probe),
+        );
+        runner.on_userauth_request(&msg).await.unwrap();
+        assert!(!runner.auth_state.authenticated());
+
+        let mut target = BytesMut::new();
+        session_id.pack(&mut target);
+        50u8.pack(&mut target);
+        "user".pack(&mut target);
+        "ssh-connection".pack(&mut target);
+        "publickey".pack(&mut target);
+        true.pack(&mut target);
+        algorithm.pack(&mut target);
+        key.publickey().pack(&mut target);
+        let signature = key.sign(&target.freeze());
+
+        let signed = Publickey::new(algorithm, key.publickey(), Some(signature));
+        let msg = UserauthRequest::new(
+            "user".into(),
+            "ssh-connection".into(),
+            Method::Publickey(signed),
+        );
+        runner.on_userauth_request(&msg).await.unwrap();
+        assert!(runner.auth_state.authenticated());
+
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_publickey_results_bounded() {
+        let mut state = AuthState::new();
+        let keys = (0..=MAX_PUBLICKEY_RESULTS)
+            .map(|_| Key::gen(&Algorithm::SshEd25519).unwrap().publickey())
+            .collect::<Vec<_>>();
+        for key in &keys {
+            state.cache_publickey_result("user", key, true, None);
+        }
+        assert_eq!(state.publickey_results.len(), MAX_PUBLICKEY_RESULTS);
+        assert_eq!(state.publickey_result("user", &keys[0]), None);
+        assert_eq!(
+            state.publickey_result("user", &keys[MAX_PUBLICKEY_RESULTS]),
+            Some(true)
+        );
+        assert_eq!(state.publickey_result("other", &keys[1]), None);
+    }
+
+    #[tokio::test]
+    async fn test_reauth() {
+        use futures::stream::StreamExt as _;
+        use std::sync::Mutex;
+
+        use crate::msg::Msg;
+
+        let results = Arc::new(Mutex::new(vec![]));
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_auth_none(|username| ok(username == "alice").boxed());
+        let r = results.clone();
+        handlers.on_reauth(move |username, success| {
+            r.lock().unwrap().push((username, success));
+            ok(()).boxed()
+        });
+        let mut preference = PreferenceBuilder::default();
+        preference.allow_reauthentication(true);
+        let (mut runner, mut client) = new_runner_with_handlers(&preference, handlers).await;
+        runner.userauth_requested = true;
+
+        for (user, expect) in &[("alice", true), ("mallory", false), ("alice", true)] {
+            let msg = UserauthRequest::new((*user).into(), "ssh-connection".into(), Method::None);
+            runner.on_userauth_request(&msg).await.unwrap();
+            match client.next().await {
+                Some(Ok(Msg::UserauthSuccess(..))) if *expect => {}
+                Some(Ok(Msg::UserauthFailure(..))) if !*expect => {}
+                x => panic!("{:?}", x),
+            }
+            // failed re-authentication does not revoke the session.
+            assert!(runner.auth_state.authenticated());
+        }
+
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![("mallory".to_string(), false), ("alice".to_string(), true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reauth_ignored() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut handlers = Handlers::<HandlerError>::new();
+        let c = count.clone();
+        handlers.on_auth_none(move |_| {
+            c.fetch_add(1, Ordering::SeqCst);
+            ok(true).boxed()
+        });
+        let (mut runner, _client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.userauth_requested = true;
+
+        for _ in 0..2 {
+            let msg = UserauthRequest::new("user".into(), "ssh-connection".into(), Method::None);
+            runner.on_userauth_request(&msg).await.unwrap();
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_user_name() {
+        use futures::stream::StreamExt as _;
+
+        use crate::msg::Msg;
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut handlers = Handlers::<HandlerError>::new();
+        let c = count.clone();
+        handlers.on_auth_none(move |_| {
+            c.fetch_add(1, Ordering::SeqCst);
+            ok(true).boxed()
+        });
+        let mut preference = PreferenceBuilder::default();
+        preference.max_user_name_length(8);
+        let (mut runner, mut client) = new_runner_with_handlers(&preference, handlers).await;
+        runner.userauth_requested = true;
+
+        let requests = [
+            ("root\nfake log", "ssh-connection"),
+            ("longusername", "ssh-connection"),
+            ("user", "ssh-\x1b[0m"),
+        ];
+        for (user_name, service_name) in &requests {
+            let msg =
+                UserauthRequest::new((*user_name).into(), (*service_name).into(), Method::None);
+            runner.on_userauth_request(&msg).await.unwrap();
+            match client.next().await {
+                Some(Ok(Msg::UserauthFailure(..))) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        assert!(!runner.auth_state.authenticated());
+    }
+
+    #[tokio::test]
+    async fn test_none_probe() {
+        use futures::stream::StreamExt as _;
+
+        use crate::msg::Msg;
+
+        let (mut runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+        runner.userauth_requested = true;
+
+        let msg = UserauthRequest::new("user".into(), "ssh-connection".into(), Method::None);
+        runner.on_userauth_request(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::UserauthFailure(msg))) => {
+                let methods = msg.authentications().iter().collect::<Vec<_>>();
+                assert_eq!(methods, SUPPORTED_METHODS);
+                assert!(!methods.iter().any(|m| *m == "none"));
+                assert!(!msg.partial_success());
+            }
+            x => panic!("{:?}", x),
+        }
+        assert!(!runner.auth_state.authenticated());
+    }
+
+    #[tokio::test]
+    async fn test_failure_delay() {
+        use futures::stream::StreamExt as _;
+        use tokio::time::Instant;
+
+        use crate::msg::Msg;
+
+        let mut preference = PreferenceBuilder::default();
+        preference.auth_failure_delay(Duration::from_millis(200));
+        let (mut runner, mut client) = new_runner(&preference).await;
+        runner.userauth_requested = true;
+
+        let msg = UserauthRequest::new("user".into(), "ssh-connection".into(), Method::None);
+        let start = Instant::now();
+        runner.on_userauth_request(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::UserauthFailure(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_too_large_publickey() {
+        use futures::stream::StreamExt as _;
+
+        use crate::key::PublicKey;
+        use crate::msg::disconnect::ReasonCode;
+        use crate::msg::Msg;
+        use crate::pack::{Unpack, UnpackError};
+
+        let (runner, mut client) = new_runner(&PreferenceBuilder::default()).await;
+        let runner = tokio::spawn(runner.run());
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let mut blob = BytesMut::new();
+        "ssh-ed25519".pack(&mut blob);
+        blob.extend_from_slice(&vec![0; 20 * 1024]);
+        let mut buf = BytesMut::new();
+        blob.freeze().pack(&mut buf);
+        let blob = PublicKey::unpack(&mut buf.freeze()).unwrap();
+
+        let method = Method::Publickey(Publickey::new("ssh-ed25519".into(), blob, None));
+        let msg = UserauthRequest::new("user".into(), "ssh-connection".into(), method);
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::Disconnect(msg))) => {
+                assert!(matches!(msg.reason_code(), ReasonCode::ProtocolError))
+            }
+            x => panic!("{:?}", x),
+        }
+        assert!(matches!(
+            runner.await.unwrap(),
+            Err(SshError::UnpackError(UnpackError::TooLarge(..)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_authorized_key_options() {
+        use futures::sink::SinkExt as _;
+        use futures::stream::StreamExt as _;
+        use tokio::io::AsyncWriteExt as _;
+
+        use crate::authorized_keys::AuthorizedKeys;
+        use crate::msg::channel_open::{self, ChannelOpen};
+        use crate::msg::channel_request::{ChannelRequest, PtyReq, Type};
+        use crate::msg::Msg;
+        use crate::{RequestKind, SessionContext};
+
+        let key = Key::gen(&Algorithm::SshEd25519).unwrap();
+        let algorithm = key.name().as_ref().to_string();
+        let line = format!(
+            "command=\"whoami\",no-pty {} {}",
+            algorithm,
+            key.publickey()
+        );
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_auth_authorized_key(move |_, _| {
+            let line = line.clone();
+            async move {
+                let keys = AuthorizedKeys::parse(line.as_bytes()).await?;
+                Ok(keys.into_iter().next())
+            }
+            .boxed()
+        });
+        handlers.on_channel_exec(|mut ctx: SessionContext, _| {
+            async move {
+                let prog = match ctx.request_kind() {
+                    RequestKind::Exec(prog) => prog.to_string_lossy().into_owned(),
+                    x => panic!("{:?}", x),
+                };
+                let original = ctx.env().get("SSH_ORIGINAL_COMMAND").cloned();
+                let (_, mut stdout, _) = ctx.take_stdio().unwrap();
+                let line = format!("{} {}", prog, original.unwrap_or_default());
+                stdout.write_all(line.as_bytes()).await?;
+                Ok(0)
+            }
+            .boxed()
+        });
+        let mut preference = PreferenceBuilder::default();
+        preference.allow_pty_without_handler(true);
+        let (mut runner, mut client) = new_runner_with_handlers(&preference, handlers).await;
+        runner.userauth_requested = true;
+        let session_id = Bytes::from("session");
+        runner
+            .io
+            .get_mut()
+            .state_mut()
+            .set_session_id(session_id.clone());
+
+        let mut target = BytesMut::new();
+        session_id.pack(&mut target);
+        50u8.pack(&mut target);
+        "user".pack(&mut target);
+        "ssh-connection".pack(&mut target);
+        "publickey".pack(&mut target);
+        true.pack(&mut target);
+        algorithm.pack(&mut target);
+        key.publickey().pack(&mut target);
+        let signature = key.sign(&target.freeze());
+
+        let signed = Publickey::new(algorithm, key.publickey(), Some(signature));
+        let msg = UserauthRequest::new(
+            "user".into(),
+            "ssh-connection".into(),
+            Method::Publickey(signed),
+        );
+        runner.on_userauth_request(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::UserauthSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let runner = tokio::spawn(runner.run());
+        match client.next().await {
+            Some(Ok(Msg::Kexinit(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        let msg = ChannelOpen::new(0, 1024, 1024, channel_open::Type::Session(()));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenConfirmation(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let pty = PtyReq::new("xterm".into(), 80, 24, 0, 0, Bytes::new());
+        let msg = ChannelRequest::new(0, true, Type::PtyReq(pty));
+        client.send(msg.into()).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelFailure(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let msg = ChannelRequest::new(0, true, Type::Exec(Bytes::from("ls")));
+        client.send(msg.into()).await.unwrap();
+        let mut output = vec![];
+        loop {
+            match client.next().await {
+                Some(Ok(Msg::ChannelData(msg))) => output.extend_from_slice(msg.data()),
+                Some(Ok(Msg::ChannelClose(..))) => break,
+                Some(Ok(..)) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+        assert_eq!(output, b"whoami ls");
+
+        runner.abort();
+    }
+
+    #[tokio::test]
+    async fn test_authorized_key_forwarding_options() {
+        use futures::stream::StreamExt as _;
+
+        use crate::authorized_keys::AuthorizedKeys;
+        use crate::msg::channel_open::{ChannelOpen, DirectTcpip, Type as OpenType};
+        use crate::msg::channel_request::{ChannelRequest, Type, X11Req};
+        use crate::msg::global_request::{GlobalRequest, TcpipForward, Type as GlobalType};
+        use crate::msg::Msg;
+
+        let line = b"restrict ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBGr/hiKoT+ED6BGl0rYM8Ai96O/2lbnGM++zAbz578V";
+        let key = AuthorizedKeys::parse(&line[..])
+            .await
+            .unwrap()
+            .into_iter()
+            .next()
+            .unwrap();
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_channel_direct_tcpip(|_, _, _| ok(()).boxed());
+        handlers.on_tcpip_forward(|forward: crate::TcpipForward| ok(Some(forward.port())).boxed());
+        handlers.on_agent_forward(|_| ok(true).boxed());
+        handlers.on_x11_forward(|_| ok(true).boxed());
+        let (mut runner, mut client) =
+            new_runner_with_handlers(&PreferenceBuilder::default(), handlers).await;
+        runner.auth_state.authorized_key = Some(key);
+        runner.auth_state.done();
+
+        let item = DirectTcpip::new("example.com".into(), 80, "1.2.3.4".into(), 5678);
+        let msg = ChannelOpen::new(0, 1024, 1024, OpenType::DirectTcpip(item));
+        runner.on_channel_open(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::ChannelOpenFailure(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let typ = GlobalType::TcpipForward(TcpipForward::new("localhost".into(), 8080));
+        runner
+            .on_global_request(&GlobalRequest::new(true, typ))
+            .await
+            .unwrap();
+        match client.next().await {
+            Some(Ok(Msg::RequestFailure(..))) => {}
+            x => panic!("{:?}", x),
+        }
+
+        let x11 = X11Req::new(true, "MIT-MAGIC-COOKIE-1".into(), Bytes::new(), 0);
+        for typ in vec![Type::AuthAgentReq(()), Type::X11Req(x11)] {
+            let msg = ChannelRequest::new(0, true, typ);
+            runner.on_channel_request(&msg).await.unwrap();
+            match client.next().await {
+                Some(Ok(Msg::ChannelFailure(..))) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+    }
+
+    fn password_request(user_name: &str, password: &str) -> UserauthRequest {
+        use crate::pack::Unpack;
+
+        let mut buf = BytesMut::new();
+        false.pack(&mut buf);
+        password.pack(&mut buf);
+        let password = Password::unpack(&mut buf.freeze()).unwrap();
+        UserauthRequest::new(
+            user_name.into(),
+            "ssh-connection".into(),
+            Method::Password(password),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_auth_policy_all() {
+        use futures::stream::StreamExt as _;
+
+        use crate::msg::Msg;
+        use crate::AuthPolicy;
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_auth_publickey(|_, _| ok(true).boxed());
+        handlers.on_auth_password(|_, _| ok(PasswordResult::Ok).boxed());
+        let mut preference = PreferenceBuilder::default();
+        preference.auth_policy(AuthPolicy::all(vec![
+            AuthPolicy::method("publickey"),
+            AuthPolicy::method("password"),
+        ]));
+        let (mut runner, mut client) = new_runner_with_handlers(&preference, handlers).await;
+        runner.userauth_requested = true;
+        let session_id = Bytes::from("session");
+        runner
+            .io
+            .get_mut()
+            .state_mut()
+            .set_session_id(session_id.clone());
+
+        runner
+            .on_userauth_request(&password_request("user", "secret"))
+            .await
+            .unwrap();
+        match client.next().await {
+            Some(Ok(Msg::UserauthFailure(msg))) => {
+                let methods = msg.authentications().iter().collect::<Vec<_>>();
+                assert_eq!(methods, vec!["publickey"]);
+                assert!(msg.partial_success());
+            }
+            x => panic!("{:?}", x),
+        }
+        assert!(!runner.auth_state.authenticated());
+
+        let key = Key::gen(&Algorithm::SshEd25519).unwrap();
+        let algorithm = key.name().as_ref().to_string();
+        let mut target = BytesMut::new();
+        session_id.pack(&mut target);
+        50u8.pack(&mut target);
+        "user".pack(&mut target);
+        "ssh-connection".pack(&mut target);
+        "publickey".pack(&mut target);
+        true.pack(&mut target);
+        algorithm.pack(&mut target);
+        key.publickey().pack(&mut target);
+        let signature = key.sign(&target.freeze());
+        let signed = Publickey::new(algorithm, key.publickey(), Some(signature));
+        let msg = UserauthRequest::new(
+            "user".into(),
+            "ssh-connection".into(),
+            Method::Publickey(signed),
+        );
+        runner.on_userauth_request(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::UserauthSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        assert!(runner.auth_state.authenticated());
+    }
+
+    #[tokio::test]
+    async fn test_auth_policy_any() {
+        use futures::stream::StreamExt as _;
+
+        use crate::msg::Msg;
+        use crate::AuthPolicy;
+
+        let mut handlers = Handlers::<HandlerError>::new();
+        handlers.on_auth_password(|_, _| ok(PasswordResult::Ok).boxed());
+        let mut preference = PreferenceBuilder::default();
+        preference.auth_policy(AuthPolicy::any(vec![
+            AuthPolicy::method("hostbased"),
+            AuthPolicy::method("password"),
+        ]));
+        let (mut runner, mut client) = new_runner_with_handlers(&preference, handlers).await;
+        runner.userauth_requested = true;
+
+        let msg = UserauthRequest::new("user".into(), "ssh-connection".into(), Method::None);
+        runner.on_userauth_request(&msg).await.unwrap();
+        match client.next().await {
+            Some(Ok(Msg::UserauthFailure(msg))) => {
+                let methods = msg.authentications().iter().collect::<Vec<_>>();
+                assert_eq!(methods, vec!["hostbased", "password"]);
+                assert!(!msg.partial_success());
+            }
+            x => panic!("{:?}", x),
+        }
+
+        runner
+            .on_userauth_request(&password_request("user", "secret"))
+            .await
+            .unwrap();
+        match client.next().await {
+            Some(Ok(Msg::UserauthSuccess(..))) => {}
+            x => panic!("{:?}", x),
+        }
+        assert!(runner.auth_state.authenticated());
+    }
+}
@@ -1,17 +1,71 @@
-use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, BorrowedFd, IntoRawFd, RawFd};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use futures::ready;
+use tokio::io::{
+    self, AsyncBufRead, AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, ReadBuf,
+};
 use tokio_pipe::{PipeRead, PipeWrite};
 
 /// SSH data input.
+///
+/// Also [`AsyncBufRead`]. Data buffered by it but not consumed is lost on [`IntoRawFd`].
 #[derive(Debug)]
-pub struct SshInput(PipeRead);
+pub struct SshInput {
+    inner: PipeRead,
+    /// Allocated on first [`AsyncBufRead::poll_fill_buf`].
+    buf: Box<[u8]>,
+    pos: usize,
+    filled: usize,
+}
+
+const COPY_BUF_SIZE: usize = 8 * 1024;
+
+fn is_broken_pipe(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::BrokenPipe
+}
+
+/// PTY master read fails with `EIO` once every slave is closed.
+fn is_pty_closed(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EIO)
+}
 
 impl SshInput {
     pub(crate) fn new(inner: PipeRead) -> Self {
-        Self(inner)
+        Self {
+            inner,
+            buf: Box::new([]),
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    /// Copy input to `writer` until the client sends EOF, then shut `writer` down.
+    ///
+    /// Unlike [`tokio::io::copy`], completes without error when the reader side of `writer`
+    /// is gone (e.g. the process exited). Returns the number of bytes written.
+    pub async fn copy_to<W>(&mut self, writer: &mut W) -> io::Result<u64>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let mut buf = vec![0; COPY_BUF_SIZE];
+        let mut total = 0;
+        loop {
+            let n = self.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            match writer.write_all(&buf[..n]).await {
+                Ok(()) => total += n as u64,
+                Err(e) if is_broken_pipe(&e) => return Ok(total),
+                Err(e) => return Err(e),
+            }
+        }
+        match writer.shutdown().await {
+            Err(e) if !is_broken_pipe(&e) => Err(e),
+            _ => Ok(total),
+        }
     }
 }
 
@@ -21,29 +75,115 @@ impl AsyncRead for SshInput {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.0).poll_read(cx, buf)
+        if self.pos < self.filled {
+            let n = buf.remaining().min(self.filled - self.pos);
+            buf.put_slice(&self.buf[self.pos..(self.pos + n)]);
+            self.consume(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for SshInput {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.pos >= this.filled {
+            if this.buf.is_empty() {
+                this.buf = vec![0; COPY_BUF_SIZE].into_boxed_slice();
+            }
+            let mut buf = ReadBuf::new(&mut this.buf);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf))?;
+            this.filled = buf.filled().len();
+            this.pos = 0;
+        }
+        Poll::Ready(Ok(&this.buf[this.pos..this.filled]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.pos = (self.pos + amt).min(self.filled);
     }
 }
 
 impl AsRawFd for SshInput {
     fn as_raw_fd(&self) -> RawFd {
-        self.0.as_raw_fd()
+        self.inner.as_raw_fd()
     }
 }
 
 impl IntoRawFd for SshInput {
     fn into_raw_fd(self) -> RawFd {
-        self.0.into_raw_fd()
+        self.inner.into_raw_fd()
     }
 }
 
 /// SSH data output.
+///
+/// Writes fail with `BrokenPipe` once the channel is closed, or after
+/// [`AsyncWriteExt::shutdown`](tokio::io::AsyncWriteExt::shutdown).
 #[derive(Debug)]
-pub struct SshOutput(PipeWrite);
+pub struct SshOutput {
+    inner: PipeWrite,
+    shut_down: bool,
+}
+
+fn output_shutdown() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "output already shut down")
+}
 
 impl SshOutput {
     pub(crate) fn new(inner: PipeWrite) -> Self {
-        Self(inner)
+        Self {
+            inner,
+            shut_down: false,
+        }
+    }
+
+    /// Another handle to the same pipe. The output ends when all handles are dropped.
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        if self.shut_down {
+            return Err(output_shutdown());
+        }
+        // SAFETY: `inner` owns the fd and keeps it open during this call.
+        let fd = unsafe { BorrowedFd::borrow_raw(self.inner.as_raw_fd()) }.try_clone_to_owned()?;
+        let inner = PipeWrite::from_raw_fd_checked(fd.into_raw_fd())?;
+        Ok(Self::new(inner))
+    }
+
+    /// Close the pipe in place, by putting `/dev/null` on its fd.
+    fn close_pipe(&self) -> io::Result<()> {
+        let null = std::fs::OpenOptions::new().write(true).open("/dev/null")?;
+        // SAFETY: both fds are open; `dup2` closes the pipe and reuses the fd number.
+        if unsafe { libc::dup2(null.as_raw_fd(), self.inner.as_raw_fd()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Copy `reader` to output until EOF.
+    ///
+    /// Unlike [`tokio::io::copy`], completes without error when the channel is closed.
+    /// `EIO` from `reader`, as read from a PTY master after the process exited, is taken as EOF.
+    /// Returns the number of bytes written.
+    pub async fn copy_from<R>(&mut self, reader: &mut R) -> io::Result<u64>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let mut buf = vec![0; COPY_BUF_SIZE];
+        let mut total = 0;
+        loop {
+            let n = match reader.read(&mut buf).await {
+                Ok(0) => return Ok(total),
+                Ok(n) => n,
+                Err(e) if is_pty_closed(&e) => return Ok(total),
+                Err(e) => return Err(e),
+            };
+            match self.write_all(&buf[..n]).await {
+                Ok(()) => total += n as u64,
+                Err(e) if is_broken_pipe(&e) => return Ok(total),
+                Err(e) => return Err(e),
+            }
+        }
     }
 }
 
@@ -53,30 +193,48 @@ impl AsyncWrite for SshOutput {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        Pin::new(&mut self.0).poll_write(cx, buf)
+        if self.shut_down {
+            return Poll::Ready(Err(output_shutdown()));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        Pin::new(&mut self.0).poll_flush(cx)
+        if self.shut_down {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_flush(cx)
     }
 
+    /// Ends this output, as if dropped, keeping the handle.
+    ///
+    /// Data written so far is still sent. e.g. end stdout, then keep writing to stderr.
+    /// SSH has a single EOF per channel, so `SSH_MSG_CHANNEL_EOF` is sent once all outputs
+    /// of the channel are ended and the handler returns.
     fn poll_shutdown(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), io::Error>> {
-        Pin::new(&mut self.0).poll_shutdown(cx)
+        if !self.shut_down {
+            ready!(Pin::new(&mut self.inner).poll_shutdown(cx))?;
+            self.close_pipe()?;
+            self.shut_down = true;
+        }
+        Poll::Ready(Ok(()))
     }
 }
 
+/// After shutdown, the fd refers to `/dev/null`.
 impl AsRawFd for SshOutput {
     fn as_raw_fd(&self) -> RawFd {
-        self.0.as_raw_fd()
+        self.inner.as_raw_fd()
     }
 }
 
+/// After shutdown, the fd refers to `/dev/null`.
 impl IntoRawFd for SshOutput {
     fn into_raw_fd(self) -> RawFd {
-        self.0.into_raw_fd()
+        self.inner.into_raw_fd()
     }
 }
 
@@ -100,4 +258,128 @@ mod tests {
         let n = tokio::io::copy(&mut rx, &mut b).await.unwrap();
         assert_eq!(b"Hello, World!".len(), n as usize);
     }
+
+    #[tokio::test]
+    async fn test_ssh_input_lines() {
+        use tokio::io::AsyncBufReadExt as _;
+        use tokio_pipe::pipe;
+
+        let (rx, mut tx) = pipe().unwrap();
+        let mut input = SshInput::new(rx);
+
+        tx.write_all(b"first\nsecond\r\n\nthird\nrest")
+            .await
+            .unwrap();
+        drop(tx);
+
+        let mut line = String::new();
+        input.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "first\n");
+
+        let mut lines = (&mut input).lines();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("second"));
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some(""));
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("third"));
+
+        // buffered data precedes plain read.
+        let mut b = vec![];
+        input.read_to_end(&mut b).await.unwrap();
+        assert_eq!(b, b"rest");
+    }
+
+    #[tokio::test]
+    async fn test_copy_to_closed_writer() {
+        use tokio_pipe::pipe;
+
+        let (rx, mut tx) = pipe().unwrap();
+        let mut input = SshInput::new(rx);
+        let (process_stdin, mut writer) = pipe().unwrap();
+        drop(process_stdin);
+
+        tx.write_all(b"Hello, World!").await.unwrap();
+        drop(tx);
+        assert_eq!(input.copy_to(&mut writer).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_copy_to_eof() {
+        use tokio_pipe::pipe;
+
+        let (rx, mut tx) = pipe().unwrap();
+        let mut input = SshInput::new(rx);
+
+        tx.write_all(b"Hello, World!").await.unwrap();
+        drop(tx);
+        let mut b = vec![];
+        assert_eq!(input.copy_to(&mut b).await.unwrap(), 13);
+        assert_eq!(b, b"Hello, World!");
+    }
+
+    #[tokio::test]
+    async fn test_copy_from_closed_channel() {
+        use tokio_pipe::pipe;
+
+        let (rx, tx) = pipe().unwrap();
+        let mut output = SshOutput::new(tx);
+        drop(rx);
+
+        let mut reader = &b"Hello, World!"[..];
+        assert_eq!(output.copy_from(&mut reader).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_output_shutdown_keeps_fd() {
+        use tokio_pipe::pipe;
+
+        let (mut rx, tx) = pipe().unwrap();
+        let mut output = SshOutput::new(tx);
+        output.write_all(b"hello").await.unwrap();
+        output.shutdown().await.unwrap();
+
+        // the reader reaches EOF while the handle is alive.
+        let mut b = vec![];
+        rx.read_to_end(&mut b).await.unwrap();
+        assert_eq!(b, b"hello");
+
+        let err = output.write_all(b"more").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        let fd = unsafe { BorrowedFd::borrow_raw(output.as_raw_fd()) };
+        assert!(fd.try_clone_to_owned().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_copy_from_closed_pty() {
+        use std::io::Write as _;
+        use std::os::unix::io::{FromRawFd as _, IntoRawFd as _};
+
+        use nix::fcntl::{fcntl, FcntlArg, OFlag};
+        use nix::pty::{grantpt, posix_openpt, ptsname, unlockpt};
+        use tokio_pipe::pipe;
+
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+        grantpt(&master).unwrap();
+        unlockpt(&master).unwrap();
+        let slave_name = unsafe { ptsname(&master) }.unwrap();
+        let mut slave = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(slave_name)
+            .unwrap();
+        slave.write_all(b"hello").unwrap();
+        // the process exited.
+        drop(slave);
+
+        let master = master.into_raw_fd();
+        fcntl(master, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap();
+        let mut master = unsafe { PipeRead::from_raw_fd(master) };
+
+        let (mut rx, tx) = pipe().unwrap();
+        let mut output = SshOutput::new(tx);
+        assert_eq!(output.copy_from(&mut master).await.unwrap(), 5);
+        drop(output);
+
+        let mut b = vec![];
+        rx.read_to_end(&mut b).await.unwrap();
+        assert_eq!(b, b"hello");
+    }
 }
@@ -0,0 +1,56 @@
+use futures::channel::{mpsc, oneshot};
+
+use super::ssh_stream::{SshInput, SshOutput};
+use super::StreamOpenRequest;
+use crate::msg::channel_open::{ForwardedTcpip, Type};
+use crate::SshError;
+
+/// Remote port forwarding (`tcpip-forward`) request.
+///
+/// Opens `forwarded-tcpip` channels to the client.
+#[derive(Debug, Clone)]
+pub struct TcpipForward {
+    address: String,
+    port: u32,
+    tx: mpsc::UnboundedSender<StreamOpenRequest>,
+}
+
+impl TcpipForward {
+    pub(crate) fn new(
+        address: String,
+        port: u32,
+        tx: mpsc::UnboundedSender<StreamOpenRequest>,
+    ) -> Self {
+        Self { address, port, tx }
+    }
+
+    /// Address to bind.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Port to bind. `0` lets the server choose.
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Open new `forwarded-tcpip` channel to the client for a connection accepted on `port`.
+    pub async fn connect(
+        &self,
+        port: u32,
+        originator_address: &str,
+        originator_port: u32,
+    ) -> Result<(SshInput, SshOutput), SshError> {
+        let typ = Type::ForwardedTcpip(ForwardedTcpip::new(
+            self.address.clone(),
+            port,
+            originator_address.into(),
+            originator_port,
+        ));
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .unbounded_send((typ, tx))
+            .map_err(mpsc::TrySendError::into_send_error)?;
+        rx.await.map_err(SshError::any)?
+    }
+}
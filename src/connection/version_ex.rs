@@ -1,4 +1,7 @@
+use std::time::Duration;
+
 use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
 
 use crate::SshError;
 
@@ -10,6 +13,9 @@ where
 {
     let mut buf = Vec::with_capacity(MAX_BUFFER);
     loop {
+        if buf.len() >= MAX_BUFFER {
+            return Err(SshError::VersionTooLong);
+        }
         let b = io.read_u8().await?;
         buf.push(b);
         if b == b'\n' {
@@ -33,21 +39,39 @@ where
     Ok(result.to_string())
 }
 
-async fn vex_send<IO>(mut io: IO, name: &str) -> Result<String, SshError>
+async fn vex_send<IO>(mut io: IO, name: &str, pre_banner: &[String]) -> Result<String, SshError>
 where
     IO: AsyncWrite + Unpin,
 {
+    let mut buf = String::new();
+    for line in pre_banner {
+        buf.push_str(&format!("{}\r\n", line));
+    }
     let name = format!("SSH-2.0-{}", name);
-    io.write_all(format!("{}\r\n", name).as_bytes()).await?;
+    buf.push_str(&format!("{}\r\n", name));
+    io.write_all(buf.as_bytes()).await?;
     Ok(name)
 }
 
-pub(crate) async fn vex<IO>(io: IO, name: &str) -> Result<(String, String), SshError>
+pub(crate) async fn vex<IO>(
+    io: IO,
+    name: &str,
+    pre_banner: &[String],
+    timeout: Option<Duration>,
+) -> Result<(String, String), SshError>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
     let (rx, tx) = split(io);
-    let (recv, send) = tokio::try_join!(vex_recv(rx), vex_send(tx, name))?;
+    let recv = async {
+        match timeout {
+            Some(timeout) => time::timeout(timeout, vex_recv(rx))
+                .await
+                .map_err(|_| SshError::Timeout)?,
+            None => vex_recv(rx).await,
+        }
+    };
+    let (recv, send) = tokio::try_join!(recv, vex_send(tx, name, pre_banner))?;
     Ok((recv, send))
 }
 
@@ -64,7 +88,7 @@ mod tests {
             .read(b"SSH-2.0-ssh\r\n")
             .write(b"SSH-2.0-ssssh\r\n")
             .build();
-        let (r, x) = super::vex(mock, "ssssh").await.unwrap();
+        let (r, x) = super::vex(mock, "ssssh", &[], None).await.unwrap();
         assert_eq!(&r, "SSH-2.0-ssh");
         assert_eq!(&x, "SSH-2.0-ssssh");
     }
@@ -75,7 +99,7 @@ mod tests {
             .read(b"SSH-2.0-ssh\r\na")
             .write(b"SSH-2.0-ssssh\r\n")
             .build();
-        let (r, x) = super::vex(&mut mock, "ssssh").await.unwrap();
+        let (r, x) = super::vex(&mut mock, "ssssh", &[], None).await.unwrap();
         assert_eq!(&r, "SSH-2.0-ssh");
         assert_eq!(&x, "SSH-2.0-ssssh");
 
@@ -87,18 +111,18 @@ mod tests {
     #[tokio::test]
     async fn test_vex_empty() {
         let mock = Builder::new().read(b"").write(b"SSH-2.0-ssssh\r\n").build();
-        let result = super::vex(mock, "ssssh").await;
+        let result = super::vex(mock, "ssssh", &[], None).await;
         assert_err!(result);
     }
 
     #[tokio::test]
     async fn test_vex_too_long() {
         let mock = Builder::new()
-            .read(&[0; 256])
             .write(b"SSH-2.0-ssssh\r\n")
+            .read(&[0; 255])
             .build();
-        let result = super::vex(mock, "ssssh").await;
-        assert_err!(result);
+        let result = super::vex(mock, "ssssh", &[], None).await;
+        assert!(matches!(result, Err(SshError::VersionTooLong)));
     }
 
     #[tokio::test]
@@ -106,7 +130,7 @@ mod tests {
         let mock = Builder::new()
             .read_error(io::Error::new(io::ErrorKind::Other, ""))
             .build();
-        let result = super::vex(mock, "ssssh").await;
+        let result = super::vex(mock, "ssssh", &[], None).await;
         assert_err!(result);
     }
 
@@ -116,7 +140,7 @@ mod tests {
             .read(b"SSH-2.0-ssh\n")
             .write(b"SSH-2.0-ssssh\r\n")
             .build();
-        let (r, x) = super::vex(mock, "ssssh").await.unwrap();
+        let (r, x) = super::vex(mock, "ssssh", &[], None).await.unwrap();
         assert_eq!(&r, "SSH-2.0-ssh");
         assert_eq!(&x, "SSH-2.0-ssssh");
     }
@@ -124,7 +148,7 @@ mod tests {
     #[tokio::test]
     async fn test_vex_invalid_version() {
         let mock = Builder::new().read(b"S\r\n").build();
-        let result = super::vex(mock, "ssssh").await;
+        let result = super::vex(mock, "ssssh", &[], None).await;
         assert_err!(result);
     }
 
@@ -133,7 +157,31 @@ mod tests {
         let mock = Builder::new()
             .write_error(io::Error::new(io::ErrorKind::Other, ""))
             .build();
-        let result = super::vex(mock, "ssssh").await;
+        let result = super::vex(mock, "ssssh", &[], None).await;
         assert_err!(result);
     }
+
+    #[tokio::test]
+    async fn test_vex_pre_banner() {
+        let mock = Builder::new()
+            .read(b"SSH-2.0-ssh\r\n")
+            .write(b"authorized use only\r\n\r\nSSH-2.0-ssssh\r\n")
+            .build();
+        let pre_banner = ["authorized use only".to_string(), "".to_string()];
+        let (r, x) = super::vex(mock, "ssssh", &pre_banner, None).await.unwrap();
+        assert_eq!(&r, "SSH-2.0-ssh");
+        assert_eq!(&x, "SSH-2.0-ssssh");
+    }
+
+    #[tokio::test]
+    async fn test_vex_banner_timeout() {
+        let (io, mut client) = tokio::io::duplex(1024);
+        // the client sends nothing.
+        let result = super::vex(io, "ssssh", &[], Some(Duration::from_millis(100))).await;
+        assert!(matches!(result, Err(SshError::Timeout)));
+
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"SSH-2.0-ssssh\r\n");
+    }
 }
@@ -0,0 +1,71 @@
+use bytes::Bytes;
+use futures::channel::{mpsc, oneshot};
+
+use super::ssh_stream::{SshInput, SshOutput};
+use super::StreamOpenRequest;
+use crate::msg::channel_open::{Type, X11};
+use crate::SshError;
+
+/// X11 forwarding (`x11-req`) request.
+///
+/// Opens `x11` channels to the client.
+#[derive(Debug, Clone)]
+pub struct X11Forward {
+    single_connection: bool,
+    auth_protocol: String,
+    auth_cookie: Bytes,
+    screen_number: u32,
+    tx: mpsc::UnboundedSender<StreamOpenRequest>,
+}
+
+impl X11Forward {
+    pub(crate) fn new(
+        single_connection: bool,
+        auth_protocol: String,
+        auth_cookie: Bytes,
+        screen_number: u32,
+        tx: mpsc::UnboundedSender<StreamOpenRequest>,
+    ) -> Self {
+        Self {
+            single_connection,
+            auth_protocol,
+            auth_cookie,
+            screen_number,
+            tx,
+        }
+    }
+
+    /// Only a single connection should be forwarded.
+    pub fn single_connection(&self) -> bool {
+        self.single_connection
+    }
+
+    /// X11 authentication protocol. e.g. `MIT-MAGIC-COOKIE-1`
+    pub fn auth_protocol(&self) -> &str {
+        &self.auth_protocol
+    }
+
+    /// X11 authentication cookie, hex encoded.
+    pub fn auth_cookie(&self) -> &[u8] {
+        &self.auth_cookie
+    }
+
+    /// X11 screen number.
+    pub fn screen_number(&self) -> u32 {
+        self.screen_number
+    }
+
+    /// Open new `x11` channel to the client for an X11 connection from originator.
+    pub async fn connect(
+        &self,
+        originator_address: &str,
+        originator_port: u32,
+    ) -> Result<(SshInput, SshOutput), SshError> {
+        let typ = Type::X11(X11::new(originator_address.into(), originator_port));
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .unbounded_send((typ, tx))
+            .map_err(mpsc::TrySendError::into_send_error)?;
+        rx.await.map_err(SshError::any)?
+    }
+}
@@ -22,12 +22,21 @@ pub enum SshError {
     #[error("too long version identifier")]
     VersionTooLong,
 
+    #[error("invalid pre-banner line: {0:?}")]
+    InvalidPreBanner(String),
+
+    #[error("invalid identification string: {0:?}")]
+    InvalidIdentification(String),
+
     #[error(transparent)]
     UnpackError(#[from] UnpackError),
 
     #[error("too large packet length {0}")]
     TooLargePacket(usize),
 
+    #[error("too small packet length {0}")]
+    TooSmallPacket(usize),
+
     #[error("not matched {0:?}")]
     NegotiateNotMatched(String),
 
@@ -49,6 +58,18 @@ pub enum SshError {
     #[error("unexpected eof")]
     KexUnexpectedEof,
 
+    #[error("unsupported group size {0}..={1}")]
+    KexUnsupportedGroupSize(u32, u32),
+
+    #[error("invalid public value")]
+    KexInvalidPublicValue,
+
+    #[error("no matching host key {0}")]
+    NoMatchingHostKey(String),
+
+    #[error("no host keys")]
+    NoHostKeys,
+
     #[error("kex error: {0}")]
     KexError(#[source] Box<dyn Error + Send + Sync + 'static>),
 
@@ -61,21 +82,51 @@ pub enum SshError {
     #[error(transparent)]
     ChannelError(#[from] futures::channel::mpsc::SendError),
 
-    #[error("unacceptable service {0}")]
+    #[error("unacceptable service {0:?}")]
     UnacceptableService(String),
 
+    #[error("service not requested {0}")]
+    ServiceNotRequested(String),
+
+    #[error("too many channels (max {0})")]
+    TooManyChannels(u32),
+
+    #[error("not authenticated")]
+    NotAuthenticated,
+
     #[error("handler error: {0}")]
     HandlerError(#[source] Box<dyn Error + Send + Sync + 'static>),
 
+    #[error("self test failed: {0}")]
+    SelfTestFailed(String),
+
     #[error("unsupported key file format")]
     UnsupportedKeyFileFormat,
 
     #[error("timeout")]
     Timeout,
 
+    #[error("session duration exceeded")]
+    SessionDurationExceeded,
+
+    #[error("byte quota {0} exceeded")]
+    ByteQuotaExceeded(u64),
+
+    #[error("key exchange timeout")]
+    KexTimeout,
+
+    #[error("service request timeout")]
+    ServiceRequestTimeout,
+
+    #[error("rekey requested too frequently")]
+    RekeyTooFrequent,
+
     #[error("algorithm mismatch {0} != {1}")]
     AlgorithmMismatch(String, String),
 
+    #[error("channel can not be opened while the connection waits for a handler")]
+    ChannelOpenInHandler,
+
     #[error(transparent)]
     Any(Box<dyn Error + Send + Sync + 'static>),
 }
@@ -87,8 +138,11 @@ impl SshError {
             Self::InvalidVersion(..) => None,
             Self::VersionUnexpectedEof(..) => None,
             Self::VersionTooLong => None,
+            Self::InvalidPreBanner(..) => None,
+            Self::InvalidIdentification(..) => None,
             Self::UnpackError(..) => Some(ReasonCode::ProtocolError),
             Self::TooLargePacket(..) => Some(ReasonCode::ProtocolError),
+            Self::TooSmallPacket(..) => Some(ReasonCode::ProtocolError),
             Self::NegotiateNotMatched(..) => Some(ReasonCode::KeyExchangeFailed),
             Self::UnknownAlgorithm(..) => Some(ReasonCode::ProtocolError),
             Self::CompressionError(..) => Some(ReasonCode::CompressionError),
@@ -96,15 +150,29 @@ impl SshError {
             Self::MacError(..) => Some(ReasonCode::MacError),
             Self::KexUnexpectedMsg(..) => Some(ReasonCode::KeyExchangeFailed),
             Self::KexUnexpectedEof => Some(ReasonCode::KeyExchangeFailed),
+            Self::KexUnsupportedGroupSize(..) => Some(ReasonCode::KeyExchangeFailed),
+            Self::KexInvalidPublicValue => Some(ReasonCode::KeyExchangeFailed),
+            Self::NoMatchingHostKey(..) => Some(ReasonCode::KeyExchangeFailed),
+            Self::NoHostKeys => None,
             Self::KexError(..) => Some(ReasonCode::KeyExchangeFailed),
             Self::UnexpectedMsg(..) => Some(ReasonCode::ProtocolError),
             Self::NoPacketReceived => Some(ReasonCode::ProtocolError),
             Self::ChannelError(..) => Some(ReasonCode::ServiceNotAvailable),
             Self::UnacceptableService(..) => Some(ReasonCode::ServiceNotAvailable),
+            Self::ServiceNotRequested(..) => Some(ReasonCode::ProtocolError),
+            Self::TooManyChannels(..) => Some(ReasonCode::ProtocolError),
+            Self::NotAuthenticated => Some(ReasonCode::ProtocolError),
             Self::HandlerError(..) => Some(ReasonCode::ByApplication),
+            Self::SelfTestFailed(..) => None,
             Self::UnsupportedKeyFileFormat => None,
             Self::Timeout => Some(ReasonCode::ConnectionLost),
+            Self::SessionDurationExceeded => Some(ReasonCode::ByApplication),
+            Self::ByteQuotaExceeded(..) => Some(ReasonCode::ByApplication),
+            Self::KexTimeout => Some(ReasonCode::KeyExchangeFailed),
+            Self::ServiceRequestTimeout => Some(ReasonCode::ByApplication),
+            Self::RekeyTooFrequent => Some(ReasonCode::KeyExchangeFailed),
             Self::AlgorithmMismatch(..) => Some(ReasonCode::ProtocolError),
+            Self::ChannelOpenInHandler => None,
             Self::Any(..) => None,
         }
     }
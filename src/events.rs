@@ -0,0 +1,60 @@
+//! Connection lifecycle events for monitoring.
+use std::net::SocketAddr;
+
+use futures::stream::{self, Stream, StreamExt as _};
+use tokio::sync::broadcast;
+
+use crate::connection::ChannelKind;
+use crate::msg::disconnect::ReasonCode;
+
+/// Number of events buffered per subscriber. Older events are dropped for slow subscribers.
+const CAPACITY: usize = 256;
+
+/// Event observed by the server. See [`ServerBuilder::events`](crate::ServerBuilder::events).
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    /// Connection accepted from `addr`.
+    ConnectionAccepted { addr: SocketAddr },
+
+    /// Version exchange completed.
+    VersionExchanged { client_version: String },
+
+    /// User authenticated with `method`. (e.g. `publickey`)
+    Authenticated { user: String, method: String },
+
+    /// Channel `id` opened.
+    ChannelOpened { id: u32, kind: ChannelKind },
+
+    /// Channel `id` closed.
+    ChannelClosed { id: u32 },
+
+    /// Connection finished. `reason` is `None` if closed without `SSH_MSG_DISCONNECT`.
+    Disconnected { reason: Option<ReasonCode> },
+}
+
+/// Sender side of events. Nothing is sent until subscribed.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EventSender(Option<broadcast::Sender<ServerEvent>>);
+
+impl EventSender {
+    pub(crate) fn subscribe(&mut self) -> impl Stream<Item = ServerEvent> + Send + Unpin + 'static {
+        let tx = self.0.get_or_insert_with(|| broadcast::channel(CAPACITY).0);
+        stream::unfold(tx.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(broadcast::error::RecvError::Lagged(..)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed()
+    }
+
+    pub(crate) fn emit(&self, event: ServerEvent) {
+        if let Some(tx) = &self.0 {
+            // no receivers is not an error.
+            tx.send(event).ok();
+        }
+    }
+}
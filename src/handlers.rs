@@ -1,35 +1,85 @@
 //! SSH handler
 
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::error::Error as StdError;
 use std::ffi::OsString;
 use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
 
+use bytes::Bytes;
+use futures::channel::mpsc;
 use futures::future::BoxFuture;
 
-use crate::{PublicKey, SshInput, SshOutput};
+use crate::authorized_keys::AuthorizedKey;
+use crate::{
+    AgentForward, ConnectionData, DisconnectReasonCode, Negotiated, PublicKey, Signal, SshInput,
+    SshOutput, TcpipForward, X11Forward,
+};
 
 pub(crate) type HandlerError = Box<dyn StdError + Send + Sync + 'static>;
 
+/// Session channel state requested by the client before shell / exec.
+#[derive(Debug)]
+pub(crate) struct SessionState<Pty> {
+    pub(crate) env: HashMap<String, String>,
+    pub(crate) term: Option<String>,
+    pub(crate) client_can_do_flow_control: bool,
+    pub(crate) pty: Option<Pty>,
+}
+
+impl<Pty> Default for SessionState<Pty> {
+    fn default() -> Self {
+        Self {
+            env: HashMap::new(),
+            term: None,
+            client_can_do_flow_control: false,
+            pty: None,
+        }
+    }
+}
+
+/// Request which started the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestKind {
+    /// `shell`
+    Shell,
+    /// `exec` with command.
+    Exec(OsString),
+    /// `subsystem` with subsystem name.
+    Subsystem(String),
+}
+
 /// Context for SSH Session.
 pub struct SessionContext<Pty = ()> {
     stdio: Option<(SshInput, SshOutput, SshOutput)>,
-    env: HashMap<String, String>,
-    pty: Option<Pty>,
+    state: SessionState<Pty>,
+    request_kind: RequestKind,
+    channel: u32,
+    close_tx: mpsc::UnboundedSender<u32>,
+    exit_signal: Arc<Mutex<Option<Signal>>>,
+    data: ConnectionData,
 }
 
 impl<Pty> SessionContext<Pty> {
     pub(crate) fn new(
-        stdin: SshInput,
-        stdout: SshOutput,
-        stderr: SshOutput,
-        env: HashMap<String, String>,
-        pty: Option<Pty>,
+        stdio: (SshInput, SshOutput, SshOutput),
+        state: SessionState<Pty>,
+        request_kind: RequestKind,
+        channel: u32,
+        close_tx: mpsc::UnboundedSender<u32>,
+        exit_signal: Arc<Mutex<Option<Signal>>>,
+        data: ConnectionData,
     ) -> Self {
         Self {
-            stdio: Some((stdin, stdout, stderr)),
-            env,
-            pty,
+            stdio: Some(stdio),
+            state,
+            request_kind,
+            channel,
+            close_tx,
+            exit_signal,
+            data,
         }
     }
 
@@ -37,12 +87,160 @@ impl<Pty> SessionContext<Pty> {
         self.stdio.take()
     }
 
+    /// Request which started this session.
+    ///
+    /// Allows registering one handler for shell / exec / subsystem.
+    pub fn request_kind(&self) -> &RequestKind {
+        &self.request_kind
+    }
+
     pub fn env(&self) -> &HashMap<String, String> {
-        &self.env
+        &self.state.env
+    }
+
+    /// `TERM` requested by `pty-req`.
+    pub fn term(&self) -> Option<&str> {
+        self.state.term.as_deref()
+    }
+
+    /// Whether the client can do flow control, as notified by `xon-xoff`.
+    pub fn client_can_do_flow_control(&self) -> bool {
+        self.state.client_can_do_flow_control
     }
 
     pub fn take_pty(&mut self) -> Option<Pty> {
-        self.pty.take()
+        self.state.pty.take()
+    }
+
+    /// Close this channel without waiting for the handler to complete.
+    ///
+    /// Stdin reaches EOF and writing to stdout / stderr fails thereafter.
+    /// Output not yet sent to the client is discarded, and no exit status is sent.
+    pub fn close(&self) {
+        self.close_tx.unbounded_send(self.channel).ok();
+    }
+
+    /// Report `exit-signal` instead of the exit status returned by the handler.
+    pub fn set_exit_signal(&self, signal: Signal) {
+        *self.exit_signal.lock().unwrap() = Some(signal);
+    }
+
+    /// Values shared by handlers of this connection.
+    pub fn data(&self) -> &ConnectionData {
+        &self.data
+    }
+
+    /// Store `value` shared with other handlers of this connection.
+    pub fn set_data<T>(&self, value: T)
+    where
+        T: Send + Sync + 'static,
+    {
+        self.data.insert(value);
+    }
+
+    /// Copy of value of type `T` stored by handlers of this connection.
+    pub fn get_data<T>(&self) -> Option<T>
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        self.data.get()
+    }
+}
+
+/// Context for Direct TCP/IP channel.
+#[derive(Debug, Clone)]
+pub struct DirectTcpIpContext {
+    host: String,
+    port: u32,
+    originator_address: String,
+    originator_port: u32,
+}
+
+impl DirectTcpIpContext {
+    pub(crate) fn new(
+        host: String,
+        port: u32,
+        originator_address: String,
+        originator_port: u32,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            originator_address,
+            originator_port,
+        }
+    }
+
+    /// Host to connect.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Port to connect.
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Originator address as sent by the client.
+    pub fn originator_address(&self) -> &str {
+        &self.originator_address
+    }
+
+    /// Originator port as sent by the client.
+    pub fn originator_port(&self) -> u32 {
+        self.originator_port
+    }
+
+    /// Originator socket address, if the address is an IP address.
+    pub fn originator(&self) -> Option<SocketAddr> {
+        let ip = self.originator_address.parse::<IpAddr>().ok()?;
+        let port = u16::try_from(self.originator_port).ok()?;
+        Some(SocketAddr::new(ip, port))
+    }
+}
+
+/// Result of key exchange.
+#[derive(Debug, Clone)]
+pub struct KexSummary {
+    algorithm: Negotiated,
+    exchange_hash: Bytes,
+    session_id: Bytes,
+    signature: Bytes,
+}
+
+impl KexSummary {
+    pub(crate) fn new(
+        algorithm: Negotiated,
+        exchange_hash: Bytes,
+        session_id: Bytes,
+        signature: Bytes,
+    ) -> Self {
+        Self {
+            algorithm,
+            exchange_hash,
+            session_id,
+            signature,
+        }
+    }
+
+    /// Negotiated algorithms.
+    pub fn algorithm(&self) -> &Negotiated {
+        &self.algorithm
+    }
+
+    /// Exchange hash `H` of this key exchange.
+    pub fn exchange_hash(&self) -> &[u8] {
+        &self.exchange_hash
+    }
+
+    /// Session identifier. (exchange hash of the first key exchange)
+    pub fn session_id(&self) -> &[u8] {
+        &self.session_id
+    }
+
+    /// Host key signature of the exchange hash, as sent to the client.
+    pub fn signature(&self) -> &[u8] {
+        &self.signature
     }
 }
 
@@ -103,6 +301,32 @@ where
     }
 }
 
+pub trait AuthAuthorizedKeyHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        username: String,
+        publickey: PublicKey,
+    ) -> BoxFuture<'static, Result<Option<AuthorizedKey>, Self::Error>>;
+}
+
+impl<F, E> AuthAuthorizedKeyHandler for F
+where
+    F: Fn(String, PublicKey) -> BoxFuture<'static, Result<Option<AuthorizedKey>, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        username: String,
+        publickey: PublicKey,
+    ) -> BoxFuture<'static, Result<Option<AuthorizedKey>, Self::Error>> {
+        self(username, publickey)
+    }
+}
+
 pub trait AuthPasswordHandler: Send {
     type Error: Into<HandlerError> + Send + 'static;
 
@@ -263,11 +487,38 @@ where
     }
 }
 
+pub trait ChannelSubsystemHandler<Pty>: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        ctx: SessionContext<Pty>,
+        name: String,
+    ) -> BoxFuture<'static, Result<u32, Self::Error>>;
+}
+
+impl<F, E, Pty> ChannelSubsystemHandler<Pty> for F
+where
+    F: Fn(SessionContext<Pty>, String) -> BoxFuture<'static, Result<u32, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        ctx: SessionContext<Pty>,
+        name: String,
+    ) -> BoxFuture<'static, Result<u32, Self::Error>> {
+        self(ctx, name)
+    }
+}
+
 pub trait ChannelDirectTcpIpHandler: Send {
     type Error: Into<HandlerError> + Send + 'static;
 
     fn handle(
         &mut self,
+        ctx: DirectTcpIpContext,
         ingress: SshInput,
         egress: SshOutput,
     ) -> BoxFuture<'static, Result<(), Self::Error>>;
@@ -275,17 +526,166 @@ pub trait ChannelDirectTcpIpHandler: Send {
 
 impl<F, E> ChannelDirectTcpIpHandler for F
 where
-    F: Fn(SshInput, SshOutput) -> BoxFuture<'static, Result<(), E>> + Send,
+    F: Fn(DirectTcpIpContext, SshInput, SshOutput) -> BoxFuture<'static, Result<(), E>> + Send,
     E: Into<HandlerError> + Send + 'static,
 {
     type Error = E;
 
     fn handle(
         &mut self,
+        ctx: DirectTcpIpContext,
         ingress: SshInput,
         egress: SshOutput,
     ) -> BoxFuture<'static, Result<(), Self::Error>> {
-        self(ingress, egress)
+        self(ctx, ingress, egress)
+    }
+}
+
+pub trait AgentForwardHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(&mut self, agent: AgentForward) -> BoxFuture<'static, Result<bool, Self::Error>>;
+}
+
+impl<F, E> AgentForwardHandler for F
+where
+    F: Fn(AgentForward) -> BoxFuture<'static, Result<bool, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(&mut self, agent: AgentForward) -> BoxFuture<'static, Result<bool, Self::Error>> {
+        self(agent)
+    }
+}
+
+pub trait X11ForwardHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(&mut self, forward: X11Forward) -> BoxFuture<'static, Result<bool, Self::Error>>;
+}
+
+impl<F, E> X11ForwardHandler for F
+where
+    F: Fn(X11Forward) -> BoxFuture<'static, Result<bool, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(&mut self, forward: X11Forward) -> BoxFuture<'static, Result<bool, Self::Error>> {
+        self(forward)
+    }
+}
+
+pub trait TcpipForwardHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        forward: TcpipForward,
+    ) -> BoxFuture<'static, Result<Option<u32>, Self::Error>>;
+}
+
+impl<F, E> TcpipForwardHandler for F
+where
+    F: Fn(TcpipForward) -> BoxFuture<'static, Result<Option<u32>, E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        forward: TcpipForward,
+    ) -> BoxFuture<'static, Result<Option<u32>, Self::Error>> {
+        self(forward)
+    }
+}
+
+pub trait ChannelCloseHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(&mut self, channel: u32) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
+impl<F, E> ChannelCloseHandler for F
+where
+    F: Fn(u32) -> BoxFuture<'static, Result<(), E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(&mut self, channel: u32) -> BoxFuture<'static, Result<(), Self::Error>> {
+        self(channel)
+    }
+}
+
+pub trait KexDoneHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(&mut self, summary: KexSummary) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
+impl<F, E> KexDoneHandler for F
+where
+    F: Fn(KexSummary) -> BoxFuture<'static, Result<(), E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(&mut self, summary: KexSummary) -> BoxFuture<'static, Result<(), Self::Error>> {
+        self(summary)
+    }
+}
+
+pub trait ReauthHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        username: String,
+        success: bool,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
+impl<F, E> ReauthHandler for F
+where
+    F: Fn(String, bool) -> BoxFuture<'static, Result<(), E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        username: String,
+        success: bool,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        self(username, success)
+    }
+}
+
+pub trait DisconnectHandler: Send {
+    type Error: Into<HandlerError> + Send + 'static;
+
+    fn handle(
+        &mut self,
+        reason_code: DisconnectReasonCode,
+        description: String,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
+impl<F, E> DisconnectHandler for F
+where
+    F: Fn(DisconnectReasonCode, String) -> BoxFuture<'static, Result<(), E>> + Send,
+    E: Into<HandlerError> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        reason_code: DisconnectReasonCode,
+        description: String,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        self(reason_code, description)
     }
 }
 
@@ -297,6 +697,7 @@ where
 {
     auth_none: Option<Box<dyn AuthNoneHandler<Error = E>>>,
     auth_publickey: Option<Box<dyn AuthPublickeyHandler<Error = E>>>,
+    auth_authorized_key: Option<Box<dyn AuthAuthorizedKeyHandler<Error = E>>>,
     auth_publickey_signature_verified_after_accepted:
         Option<Box<dyn AuthPublickeyHandler<Error = E>>>,
     auth_password: Option<Box<dyn AuthPasswordHandler<Error = E>>>,
@@ -306,7 +707,15 @@ where
     channel_pty_request: Option<Box<dyn ChannelRequestPtyHandler<Pty, Error = E>>>,
     channel_shell: Option<Box<dyn ChannelShellHandler<Pty, Error = E>>>,
     channel_exec: Option<Box<dyn ChannelExecHandler<Pty, Error = E>>>,
+    channel_subsystem: Option<Box<dyn ChannelSubsystemHandler<Pty, Error = E>>>,
     channel_direct_tcpip: Option<Box<dyn ChannelDirectTcpIpHandler<Error = E>>>,
+    agent_forward: Option<Box<dyn AgentForwardHandler<Error = E>>>,
+    x11_forward: Option<Box<dyn X11ForwardHandler<Error = E>>>,
+    tcpip_forward: Option<Box<dyn TcpipForwardHandler<Error = E>>>,
+    channel_close: Option<Box<dyn ChannelCloseHandler<Error = E>>>,
+    kex_done: Option<Box<dyn KexDoneHandler<Error = E>>>,
+    reauth: Option<Box<dyn ReauthHandler<Error = E>>>,
+    disconnect: Option<Box<dyn DisconnectHandler<Error = E>>>,
 }
 
 impl<E, Pty> Handlers<E, Pty>
@@ -318,6 +727,7 @@ where
         Self {
             auth_none: None,
             auth_publickey: None,
+            auth_authorized_key: None,
             auth_publickey_signature_verified_after_accepted: None,
             auth_password: None,
             auth_change_password: None,
@@ -325,7 +735,15 @@ where
             channel_pty_request: None,
             channel_shell: None,
             channel_exec: None,
+            channel_subsystem: None,
             channel_direct_tcpip: None,
+            agent_forward: None,
+            x11_forward: None,
+            tcpip_forward: None,
+            channel_close: None,
+            kex_done: None,
+            reauth: None,
+            disconnect: None,
         }
     }
 
@@ -377,6 +795,49 @@ where
         self.auth_publickey = Some(Box::new(handler))
     }
 
+    /// Register Publickey user authentication method handler, returning `authorized_keys` entry.
+    ///
+    /// Return the entry of `publickey`, or `None` to reject.
+    /// Used instead of [`on_auth_publickey`](Self::on_auth_publickey) if registered,
+    /// and the following options of the entry are enforced for this connection.
+    ///
+    /// - `from="pattern-list"`: rejects unless the client address matches.
+    /// - `no-pty` (or `restrict` without `pty`): rejects pty-req.
+    /// - `no-port-forwarding` (or `restrict` without `port-forwarding`): rejects direct-tcpip
+    ///   and tcpip-forward.
+    /// - `permitopen="host:port"`: rejects direct-tcpip to other destinations.
+    /// - `permitlisten="..."`: not supported, so rejects every tcpip-forward.
+    /// - `no-agent-forwarding` (or `restrict` without `agent-forwarding`): rejects auth-agent-req.
+    /// - `no-X11-forwarding` (or `restrict` without `X11-forwarding`): rejects x11-req.
+    /// - `command="..."`: runs the command as exec, instead of the shell / exec / subsystem
+    ///   requested. The requested command is set to `SSH_ORIGINAL_COMMAND` environment.
+    ///
+    /// Other options are up to handlers.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use ssssh::authorized_keys::AuthorizedKeys;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_auth_authorized_key(|username, publickey: ssssh::PublicKey| {
+    ///     async move {
+    ///         let file = b"command=\"whoami\" ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBGr/hiKoT+ED6BGl0rYM8Ai96O/2lbnGM++zAbz578V";
+    ///         let keys = AuthorizedKeys::parse(&file[..]).await?;
+    ///         Ok(keys
+    ///             .into_iter()
+    ///             .find(|key| username == "bob" && key.publickey() == &publickey))
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn on_auth_authorized_key<H>(&mut self, handler: H)
+    where
+        H: AuthAuthorizedKeyHandler<Error = E> + 'static,
+    {
+        self.auth_authorized_key = Some(Box::new(handler))
+    }
+
     /// Register Publickey user authentication method handler.
     /// It handles When the verification of the message signature of the previously accepted public key is successful.
     ///
@@ -588,7 +1049,7 @@ where
         self.channel_exec = Some(Box::new(handler))
     }
 
-    /// Register Direct TCP/IP channel handler.
+    /// Register Subsystem channel handler. (e.g. `sftp`)
     ///
     /// If not registered, channel returns failure.
     ///
@@ -598,8 +1059,38 @@ where
     /// use ssssh::Handlers;
     /// use futures::FutureExt as _;
     /// let mut handlers = Handlers::<anyhow::Error>::new();
-    /// handlers.on_channel_direct_tcpip(|input, output| {
+    /// handlers.on_channel_subsystem(|mut ctx: ssssh::SessionContext<_>, name| {
+    ///     async move {
+    ///         let (stdin, stdout, _) = ctx.take_stdio().unwrap();
+    ///         let exit_code = do_subsystem(name, stdin, stdout).await;
+    ///         Ok(exit_code)
+    ///     }.boxed()
+    /// });
+    /// # use ssssh::{SshInput, SshOutput};
+    /// # async fn do_subsystem(_: String, _: SshInput, _: SshOutput) -> u32 {
+    /// #     0
+    /// # }
+    /// ```
+    pub fn on_channel_subsystem<H>(&mut self, handler: H)
+    where
+        H: ChannelSubsystemHandler<Pty, Error = E> + 'static,
+    {
+        self.channel_subsystem = Some(Box::new(handler))
+    }
+
+    /// Register Direct TCP/IP channel handler.
+    ///
+    /// If not registered, channel returns failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::{DirectTcpIpContext, Handlers};
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_channel_direct_tcpip(|ctx: DirectTcpIpContext, input, output| {
     ///     async move {
+    ///         log::info!("{}:{} from {:?}", ctx.host(), ctx.port(), ctx.originator());
     ///         do_proxy(input, output).await;
     ///         Ok(())
     ///     }.boxed()
@@ -615,6 +1106,217 @@ where
         self.channel_direct_tcpip = Some(Box::new(handler))
     }
 
+    /// Register Agent forwarding (`auth-agent-req@openssh.com`) request handler.
+    ///
+    /// Return `true` to accept. Accepted [`AgentForward`] opens agent connections to the client.
+    /// If not registered, request returns failure.
+    ///
+    /// The connection waits for the returned future, and [`AgentForward::connect`] fails with
+    /// [`SshError::ChannelOpenInHandler`](crate::SshError::ChannelOpenInHandler) meanwhile.
+    /// Connect from a spawned task instead.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_agent_forward(|agent: ssssh::AgentForward| {
+    ///     async move {
+    ///         tokio::spawn(async move {
+    ///             let (input, output) = agent.connect().await?;
+    ///             do_proxy(input, output).await;
+    ///             Ok::<_, ssssh::SshError>(())
+    ///         });
+    ///         Ok(true)
+    ///     }.boxed()
+    /// });
+    /// # use ssssh::{SshInput, SshOutput};
+    /// # async fn do_proxy(_: SshInput, _: SshOutput) {
+    /// # }
+    /// ```
+    pub fn on_agent_forward<H>(&mut self, handler: H)
+    where
+        H: AgentForwardHandler<Error = E> + 'static,
+    {
+        self.agent_forward = Some(Box::new(handler))
+    }
+
+    /// Register X11 forwarding (`x11-req`) request handler.
+    ///
+    /// Return `true` to accept. Accepted [`X11Forward`] opens `x11` channels to the client.
+    /// If not registered, request returns failure.
+    ///
+    /// As with [`Handlers::on_agent_forward`], connect from a spawned task.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_x11_forward(|forward: ssssh::X11Forward| {
+    ///     async move {
+    ///         println!("cookie: {:?}", forward.auth_cookie());
+    ///         tokio::spawn(async move {
+    ///             let (input, output) = forward.connect("127.0.0.1", 6010).await?;
+    ///             do_proxy(input, output).await;
+    ///             Ok::<_, ssssh::SshError>(())
+    ///         });
+    ///         Ok(true)
+    ///     }.boxed()
+    /// });
+    /// # use ssssh::{SshInput, SshOutput};
+    /// # async fn do_proxy(_: SshInput, _: SshOutput) {
+    /// # }
+    /// ```
+    pub fn on_x11_forward<H>(&mut self, handler: H)
+    where
+        H: X11ForwardHandler<Error = E> + 'static,
+    {
+        self.x11_forward = Some(Box::new(handler))
+    }
+
+    /// Register Remote port forwarding (`tcpip-forward`) request handler.
+    ///
+    /// Return bound port to accept, or `None` to reject.
+    /// Accepted [`TcpipForward`] opens `forwarded-tcpip` channels to the client.
+    /// If not registered, request returns failure.
+    ///
+    /// As with [`Handlers::on_agent_forward`], connect from a spawned task.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::{Handlers, TcpipForward};
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_tcpip_forward(|forward: TcpipForward| {
+    ///     async move {
+    ///         let listener = tokio::net::TcpListener::bind((forward.address(), 0)).await?;
+    ///         let port = listener.local_addr()?.port() as u32;
+    ///         tokio::spawn(async move {
+    ///             let (_, peer) = listener.accept().await?;
+    ///             let (input, output) = forward
+    ///                 .connect(port, &peer.ip().to_string(), peer.port() as u32)
+    ///                 .await?;
+    ///             do_proxy(input, output).await;
+    ///             Ok::<_, anyhow::Error>(())
+    ///         });
+    ///         Ok(Some(port))
+    ///     }.boxed()
+    /// });
+    /// # use ssssh::{SshInput, SshOutput};
+    /// # async fn do_proxy(_: SshInput, _: SshOutput) {
+    /// # }
+    /// ```
+    pub fn on_tcpip_forward<H>(&mut self, handler: H)
+    where
+        H: TcpipForwardHandler<Error = E> + 'static,
+    {
+        self.tcpip_forward = Some(Box::new(handler))
+    }
+
+    /// Register Channel close handler.
+    ///
+    /// Called once per channel when the client closes it,
+    /// or when the connection is torn down while the channel is still open.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_channel_close(|channel| {
+    ///     async move {
+    ///         do_cleanup(channel).await;
+    ///         Ok(())
+    ///     }.boxed()
+    /// });
+    /// # async fn do_cleanup(_: u32) {
+    /// # }
+    /// ```
+    pub fn on_channel_close<H>(&mut self, handler: H)
+    where
+        H: ChannelCloseHandler<Error = E> + 'static,
+    {
+        self.channel_close = Some(Box::new(handler))
+    }
+
+    /// Register Key exchange done handler.
+    ///
+    /// Called with [`KexSummary`] after every `SSH_MSG_NEWKEYS`, including rekeys.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::{Handlers, KexSummary};
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_kex_done(|summary: KexSummary| {
+    ///     async move {
+    ///         println!("cipher: {:?}", summary.algorithm().cipher_algorithm_c2s());
+    ///         Ok(())
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn on_kex_done<H>(&mut self, handler: H)
+    where
+        H: KexDoneHandler<Error = E> + 'static,
+    {
+        self.kex_done = Some(Box::new(handler))
+    }
+
+    /// Register Re-authentication handler.
+    ///
+    /// Called with user name and result of each user authentication after success.
+    /// Requires [`ServerBuilder::allow_reauthentication`](crate::ServerBuilder::allow_reauthentication).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_reauth(|username, success| {
+    ///     async move {
+    ///         println!("{} re-authenticated: {}", username, success);
+    ///         Ok(())
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn on_reauth<H>(&mut self, handler: H)
+    where
+        H: ReauthHandler<Error = E> + 'static,
+    {
+        self.reauth = Some(Box::new(handler))
+    }
+
+    /// Register Disconnect handler.
+    ///
+    /// Called with reason code and description when the client sends `SSH_MSG_DISCONNECT`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ssssh::Handlers;
+    /// use futures::FutureExt as _;
+    /// let mut handlers = Handlers::<anyhow::Error>::new();
+    /// handlers.on_disconnect(|reason_code, description| {
+    ///     async move {
+    ///         println!("client disconnected: {:?} {}", reason_code, description);
+    ///         Ok(())
+    ///     }.boxed()
+    /// });
+    /// ```
+    pub fn on_disconnect<H>(&mut self, handler: H)
+    where
+        H: DisconnectHandler<Error = E> + 'static,
+    {
+        self.disconnect = Some(Box::new(handler))
+    }
+
     pub(crate) fn dispatch_auth_none(
         &mut self,
         username: String,
@@ -634,6 +1336,16 @@ where
             .map(|handler| handler.handle(username, publickey))
     }
 
+    pub(crate) fn dispatch_auth_authorized_key(
+        &mut self,
+        username: String,
+        publickey: PublicKey,
+    ) -> Option<BoxFuture<'static, Result<Option<AuthorizedKey>, E>>> {
+        self.auth_authorized_key
+            .as_mut()
+            .map(|handler| handler.handle(username, publickey))
+    }
+
     pub(crate) fn dispatch_auth_publickey_signature_verified_after_accepted(
         &mut self,
         username: String,
@@ -692,45 +1404,107 @@ where
 
     pub(crate) fn dispatch_channel_shell(
         &mut self,
-        stdin: SshInput,
-        stdout: SshOutput,
-        stderr: SshOutput,
-        env: HashMap<String, String>,
-        pty: Option<Pty>,
+        ctx: SessionContext<Pty>,
     ) -> Option<BoxFuture<'static, Result<u32, E>>> {
-        if let Some(handler) = &mut self.channel_shell {
-            let ctx = SessionContext::new(stdin, stdout, stderr, env, pty);
-            Some(handler.handle(ctx))
-        } else {
-            None
-        }
+        self.channel_shell
+            .as_mut()
+            .map(|handler| handler.handle(ctx))
     }
 
     pub(crate) fn dispatch_channel_exec(
         &mut self,
-        stdin: SshInput,
-        stdout: SshOutput,
-        stderr: SshOutput,
+        ctx: SessionContext<Pty>,
         prog: OsString,
-        env: HashMap<String, String>,
-        pty: Option<Pty>,
     ) -> Option<BoxFuture<'static, Result<u32, E>>> {
-        if let Some(handler) = &mut self.channel_exec {
-            let ctx = SessionContext::new(stdin, stdout, stderr, env, pty);
-            Some(handler.handle(ctx, prog))
-        } else {
-            None
-        }
+        self.channel_exec
+            .as_mut()
+            .map(|handler| handler.handle(ctx, prog))
+    }
+
+    pub(crate) fn dispatch_channel_subsystem(
+        &mut self,
+        ctx: SessionContext<Pty>,
+        name: String,
+    ) -> Option<BoxFuture<'static, Result<u32, E>>> {
+        self.channel_subsystem
+            .as_mut()
+            .map(|handler| handler.handle(ctx, name))
     }
 
     pub(crate) fn dispatch_direct_tcpip(
         &mut self,
+        ctx: DirectTcpIpContext,
         ingress: SshInput,
         egress: SshOutput,
     ) -> Option<BoxFuture<'static, Result<(), E>>> {
         self.channel_direct_tcpip
             .as_mut()
-            .map(|handler| handler.handle(ingress, egress))
+            .map(|handler| handler.handle(ctx, ingress, egress))
+    }
+
+    pub(crate) fn dispatch_agent_forward(
+        &mut self,
+        agent: AgentForward,
+    ) -> Option<BoxFuture<'static, Result<bool, E>>> {
+        self.agent_forward
+            .as_mut()
+            .map(|handler| handler.handle(agent))
+    }
+
+    pub(crate) fn dispatch_x11_forward(
+        &mut self,
+        forward: X11Forward,
+    ) -> Option<BoxFuture<'static, Result<bool, E>>> {
+        self.x11_forward
+            .as_mut()
+            .map(|handler| handler.handle(forward))
+    }
+
+    pub(crate) fn dispatch_tcpip_forward(
+        &mut self,
+        forward: TcpipForward,
+    ) -> Option<BoxFuture<'static, Result<Option<u32>, E>>> {
+        self.tcpip_forward
+            .as_mut()
+            .map(|handler| handler.handle(forward))
+    }
+
+    pub(crate) fn dispatch_channel_close(
+        &mut self,
+        channel: u32,
+    ) -> Option<BoxFuture<'static, Result<(), E>>> {
+        self.channel_close
+            .as_mut()
+            .map(|handler| handler.handle(channel))
+    }
+
+    pub(crate) fn dispatch_kex_done(
+        &mut self,
+        summary: KexSummary,
+    ) -> Option<BoxFuture<'static, Result<(), E>>> {
+        self.kex_done
+            .as_mut()
+            .map(|handler| handler.handle(summary))
+    }
+
+    pub(crate) fn dispatch_reauth(
+        &mut self,
+        username: String,
+        success: bool,
+    ) -> Option<BoxFuture<'static, Result<(), E>>> {
+        self.reauth
+            .as_mut()
+            .map(|handler| handler.handle(username, success))
+    }
+
+    pub(crate) fn dispatch_disconnect(
+        &mut self,
+        reason_code: DisconnectReasonCode,
+        description: String,
+    ) -> Option<BoxFuture<'static, Result<(), E>>> {
+        self.disconnect
+            .as_mut()
+            .map(|handler| handler.handle(reason_code, description))
     }
 }
 
@@ -26,7 +26,7 @@ impl KexTrait for Curve25519Sha256 {
         &self,
         io: &'a mut MsgStream<IO>,
         env: Env<'a>,
-    ) -> BoxFuture<'a, Result<(Bytes, Bytes), SshError>>
+    ) -> BoxFuture<'a, Result<(Bytes, Bytes, Signature), SshError>>
     where
         IO: AsyncRead + AsyncWrite + Unpin + Send,
     {
@@ -71,12 +71,12 @@ impl KexTrait for Curve25519Sha256 {
             let kex_ecdh_reply = KexEcdhReply::new(
                 env.hostkey.publickey(),
                 server_ephemeral_public_key.copy_to_bytes(server_ephemeral_public_key.remaining()),
-                signature,
+                signature.clone(),
             );
 
             io.send(kex_ecdh_reply.into()).await?;
 
-            Ok((hash, key))
+            Ok((hash, key, signature))
         }
         .boxed()
     }
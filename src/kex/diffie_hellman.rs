@@ -1,8 +1,10 @@
+use std::cell::RefCell;
 use std::marker::PhantomData;
+use std::sync::OnceLock;
 
 use futures::future::FutureExt as _;
 use futures::sink::SinkExt as _;
-use openssl::bn::{BigNum, BigNumContext, BigNumContextRef, BigNumRef, MsbOption};
+use openssl::bn::{BigNum, BigNumContext, BigNumRef, MsbOption};
 use openssl::error::ErrorStack;
 use tokio_stream::StreamExt as _;
 
@@ -23,7 +25,8 @@ pub(crate) type DiffieHellmanGroupExchangeSha1 = DiffieHellmanGroupExchange<Sha1
 pub(crate) type DiffieHellmanGroupExchangeSha256 = DiffieHellmanGroupExchange<Sha256>;
 
 pub(crate) trait Group {
-    const P: fn() -> Result<BigNum, ErrorStack>;
+    /// Size of well-known prime, in bits.
+    const BITS: u32;
 }
 
 pub(crate) trait Sha {
@@ -34,28 +37,28 @@ pub(crate) trait Sha {
 pub(crate) enum Group1 {}
 
 impl Group for Group1 {
-    const P: fn() -> Result<BigNum, ErrorStack> = BigNum::get_rfc2409_prime_1024;
+    const BITS: u32 = 1024;
 }
 
 #[derive(Debug)]
 pub(crate) enum Group14 {}
 
 impl Group for Group14 {
-    const P: fn() -> Result<BigNum, ErrorStack> = BigNum::get_rfc3526_prime_2048;
+    const BITS: u32 = 2048;
 }
 
 #[derive(Debug)]
 pub(crate) enum Group16 {}
 
 impl Group for Group16 {
-    const P: fn() -> Result<BigNum, ErrorStack> = BigNum::get_rfc3526_prime_4096;
+    const BITS: u32 = 4096;
 }
 
 #[derive(Debug)]
 pub(crate) enum Group18 {}
 
 impl Group for Group18 {
-    const P: fn() -> Result<BigNum, ErrorStack> = BigNum::get_rfc3526_prime_8192;
+    const BITS: u32 = 8192;
 }
 
 #[derive(Debug)]
@@ -104,7 +107,7 @@ where
         &self,
         io: &'a mut MsgStream<IO>,
         env: Env<'a>,
-    ) -> BoxFuture<'a, Result<(Bytes, Bytes), SshError>>
+    ) -> BoxFuture<'a, Result<(Bytes, Bytes, Signature), SshError>>
     where
         IO: AsyncRead + AsyncWrite + Unpin + Send,
     {
@@ -124,49 +127,73 @@ where
                 None => return Err(SshError::KexUnexpectedEof),
             };
 
+            let p = prime(G::BITS)?;
+
             let e = kexdh_init.ephemeral_public_key();
             e.pack(&mut hasher);
-            let e = BigNum::from_slice(e).map_err(SshError::kex_error)?;
-
-            let p = (G::P()).map_err(SshError::kex_error)?;
+            let e = client_public(e, p)?;
             let y = gen_y()?;
             let g = get_g()?;
 
-            let mut ctx = BigNumContext::new().map_err(SshError::kex_error)?;
-
-            let f = mod_exp(&g, &y, &p, &mut ctx)?;
+            let f = mod_exp(&g, &y, p)?;
             f.pack(&mut hasher);
 
-            let k = mod_exp(&e, &y, &p, &mut ctx)?;
+            let k = mod_exp(&e, &y, p)?;
             k.pack(&mut hasher);
 
             let h = hasher.finish();
 
             let signature = env.hostkey.sign(&h);
 
-            let reply = KexEcdhReply::new(env.hostkey.publickey(), f, signature);
+            let reply = KexEcdhReply::new(env.hostkey.publickey(), f, signature.clone());
 
             io.send(reply.into()).await?;
 
-            Ok((h, k))
+            Ok((h, k, signature))
         }
         .boxed()
     }
 }
 
-fn mod_exp(
-    a: &BigNumRef,
-    p: &BigNumRef,
-    m: &BigNumRef,
-    cx: &mut BigNumContextRef,
-) -> Result<Bytes, SshError> {
+thread_local! {
+    /// Scratch space for `mod_exp`, reused across handshakes on the same thread.
+    static CTX: RefCell<Option<BigNumContext>> = const { RefCell::new(None) };
+}
+
+fn mod_exp(a: &BigNumRef, p: &BigNumRef, m: &BigNumRef) -> Result<Bytes, SshError> {
     let mut r = BigNum::new().map_err(SshError::kex_error)?;
-    r.mod_exp(a, p, m, cx).map_err(SshError::kex_error)?;
+    // never held across `.await`, so a thread local is sufficient.
+    CTX.with(|ctx| {
+        let mut ctx = ctx.borrow_mut();
+        let ctx = match &mut *ctx {
+            Some(ctx) => ctx,
+            None => ctx.insert(BigNumContext::new()?),
+        };
+        r.mod_exp(a, p, m, ctx)
+    })
+    .map_err(SshError::kex_error)?;
     let r = Mpint::new(r.to_vec());
     let mut r = r.as_ref();
     Ok(r.copy_to_bytes(r.remaining()))
 }
 
+/// Parse client's public value `e`, which must satisfy `1 < e < p - 1`.
+fn client_public(e: &[u8], p: &BigNumRef) -> Result<BigNum, SshError> {
+    // one leading zero byte is allowed; negative values are rejected.
+    if e.len() > p.num_bytes() as usize + 1 || matches!(e.first(), Some(b) if b & 0x80 != 0) {
+        return Err(SshError::KexInvalidPublicValue);
+    }
+    let e = BigNum::from_slice(e).map_err(SshError::kex_error)?;
+
+    let one = BigNum::from_u32(1).map_err(SshError::kex_error)?;
+    let mut upper = BigNum::new().map_err(SshError::kex_error)?;
+    upper.checked_sub(p, &one).map_err(SshError::kex_error)?;
+    if e <= one || e >= upper {
+        return Err(SshError::KexInvalidPublicValue);
+    }
+    Ok(e)
+}
+
 fn get_g() -> Result<BigNum, SshError> {
     BigNum::from_u32(2).map_err(SshError::kex_error)
 }
@@ -178,17 +205,55 @@ fn gen_y() -> Result<BigNum, SshError> {
     Ok(y)
 }
 
+type PrimeFn = fn() -> Result<BigNum, ErrorStack>;
+
+/// Well-known groups, largest first. Group exchange offers one of these.
+const GROUPS: [(u32, PrimeFn); 8] = [
+    (8192, BigNum::get_rfc3526_prime_8192),
+    (6144, BigNum::get_rfc3526_prime_6144),
+    (4096, BigNum::get_rfc3526_prime_4096),
+    (3072, BigNum::get_rfc3526_prime_3072),
+    (2048, BigNum::get_rfc3526_prime_2048),
+    (1536, BigNum::get_rfc3526_prime_1536),
+    (1024, BigNum::get_rfc2409_prime_1024),
+    (768, BigNum::get_rfc2409_prime_768),
+];
+
+/// Prime of a well-known group, parsed once per process.
+fn prime(bits: u32) -> Result<&'static BigNum, SshError> {
+    static PRIMES: [OnceLock<BigNum>; GROUPS.len()] = [const { OnceLock::new() }; GROUPS.len()];
+
+    let index = GROUPS
+        .iter()
+        .position(|(b, _)| *b == bits)
+        .ok_or(SshError::KexUnsupportedGroupSize(bits, bits))?;
+    if let Some(p) = PRIMES[index].get() {
+        return Ok(p);
+    }
+    let p = (GROUPS[index].1)().map_err(SshError::kex_error)?;
+    Ok(PRIMES[index].get_or_init(|| p))
+}
+
 #[derive(Debug)]
 pub(crate) struct DiffieHellmanGroupExchange<H> {
+    /// Largest group selected, in bits.
+    max_group_size: u32,
     _phantom: PhantomData<H>,
 }
 
+impl<H> DiffieHellmanGroupExchange<H> {
+    pub(crate) fn set_max_group_size(&mut self, bits: u32) {
+        self.max_group_size = bits;
+    }
+}
+
 impl<H> KexTrait for DiffieHellmanGroupExchange<H>
 where
     H: Sha,
 {
     fn new() -> Self {
         Self {
+            max_group_size: 8192,
             _phantom: PhantomData,
         }
     }
@@ -202,10 +267,11 @@ where
         &self,
         io: &'a mut MsgStream<IO>,
         env: Env<'a>,
-    ) -> BoxFuture<'a, Result<(Bytes, Bytes), SshError>>
+    ) -> BoxFuture<'a, Result<(Bytes, Bytes, Signature), SshError>>
     where
         IO: AsyncRead + AsyncWrite + Unpin + Send,
     {
+        let max_group_size = self.max_group_size;
         async move {
             let mut io = io.context::<GexMsg>();
             let mut hasher = Self::hasher();
@@ -219,39 +285,30 @@ where
             let range = match io.next().await {
                 Some(Ok(GexMsg::KexDhGexRequestOld(msg))) => {
                     msg.n().pack(&mut hasher);
-                    *msg.n()..=*msg.n()
+                    (*msg.n(), *msg.n())
                 }
                 Some(Ok(GexMsg::KexDhGexRequest(msg))) => {
                     msg.min().pack(&mut hasher);
                     msg.n().pack(&mut hasher);
                     msg.max().pack(&mut hasher);
-                    *msg.min()..=*msg.max()
+                    (*msg.min(), *msg.max())
                 }
                 Some(Ok(msg)) => return Err(SshError::KexUnexpectedMsg(format!("{:?}", msg))),
                 Some(Err(e)) => return Err(e),
                 None => return Err(SshError::KexUnexpectedEof),
             };
 
-            let p = if range.contains(&8192) {
-                BigNum::get_rfc3526_prime_8192()
-            } else if range.contains(&6144) {
-                BigNum::get_rfc3526_prime_6144()
-            } else if range.contains(&4096) {
-                BigNum::get_rfc3526_prime_4096()
-            } else if range.contains(&3072) {
-                BigNum::get_rfc3526_prime_3072()
-            } else if range.contains(&2048) {
-                BigNum::get_rfc3526_prime_2048()
-            } else if range.contains(&1536) {
-                BigNum::get_rfc3526_prime_1536()
-            } else if range.contains(&1024) {
-                BigNum::get_rfc2409_prime_1024()
-            } else if range.contains(&768) {
-                BigNum::get_rfc2409_prime_768()
-            } else {
-                todo!()
-            }
-            .map_err(SshError::kex_error)?;
+            let (min, max) = range;
+            let range = min..=max;
+            // larger groups are downgraded, or refused if `min` exceeds the limit.
+            let bits = GROUPS
+                .iter()
+                .map(|(bits, _)| *bits)
+                .find(|bits| *bits <= max_group_size && range.contains(bits));
+            let p = match bits {
+                Some(bits) => prime(bits)?,
+                None => return Err(SshError::KexUnsupportedGroupSize(min, max)),
+            };
             Mpint::new(p.to_vec()).pack(&mut hasher);
 
             let g = get_g()?;
@@ -269,26 +326,24 @@ where
 
             let e = kex_dh_gex_init.e();
             e.pack(&mut hasher);
-            let e = BigNum::from_slice(e.as_ref()).map_err(SshError::kex_error)?;
+            let e = client_public(e.as_ref(), p)?;
 
             let y = gen_y()?;
 
-            let mut ctx = BigNumContext::new().map_err(SshError::kex_error)?;
-
-            let f = mod_exp(&g, &y, &p, &mut ctx)?;
+            let f = mod_exp(&g, &y, p)?;
             f.pack(&mut hasher);
 
-            let k = mod_exp(&e, &y, &p, &mut ctx)?;
+            let k = mod_exp(&e, &y, p)?;
             k.pack(&mut hasher);
 
             let h = hasher.finish();
 
             let signature = env.hostkey.sign(&h);
 
-            let reply = KexDhGexReply::new(env.hostkey.publickey(), f, signature);
+            let reply = KexDhGexReply::new(env.hostkey.publickey(), f, signature.clone());
             io.send(reply.into()).await?;
 
-            Ok((h, k))
+            Ok((h, k, signature))
         }
         .boxed()
     }
@@ -336,4 +391,233 @@ mod tests {
         };
         assert(kex.kex(&mut io, env));
     }
+
+    #[tokio::test]
+    async fn test_gex_unsupported_group_size() {
+        use crate::msg::kex_dh_gex_request::KexDhGexRequest;
+
+        let (io, client) = tokio::io::duplex(1024 * 1024);
+        let mut io = crate::stream::msg::MsgStream::new(io);
+        let mut client = crate::stream::msg::MsgStream::new(client);
+
+        let hostkey = crate::key::Key::gen(&crate::key::Algorithm::SshEd25519).unwrap();
+        let kexinit = crate::preference::PreferenceBuilder::default()
+            .build()
+            .await
+            .unwrap()
+            .to_kexinit();
+        let kexinit = to_msg_bytes(&kexinit);
+
+        let msg = GexMsg::from(KexDhGexRequest::new(256, 512, 512));
+        client.context().send(msg).await.unwrap();
+
+        let kex = DiffieHellmanGroupExchangeSha256::new();
+        let env = Env {
+            c_version: "",
+            s_version: "",
+            c_kexinit: &kexinit,
+            s_kexinit: &kexinit,
+            hostkey: &hostkey,
+        };
+        assert!(matches!(
+            kex.kex(&mut io, env).await,
+            Err(SshError::KexUnsupportedGroupSize(256, 512))
+        ));
+    }
+
+    #[test]
+    fn test_prime_cached() {
+        for (bits, p) in &GROUPS {
+            let first = prime(*bits).unwrap();
+            assert_eq!(first.num_bits() as u32, *bits);
+            assert_eq!(*first, p().unwrap());
+            assert!(std::ptr::eq(first, prime(*bits).unwrap()));
+        }
+        assert!(prime(512).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_gex_max_group_size() {
+        use crate::msg::kex_dh_gex_request::KexDhGexRequest;
+
+        let hostkey = crate::key::Key::gen(&crate::key::Algorithm::SshEd25519).unwrap();
+        let kexinit = crate::preference::PreferenceBuilder::default()
+            .build()
+            .await
+            .unwrap()
+            .to_kexinit();
+        let kexinit = to_msg_bytes(&kexinit);
+
+        let mut kex = DiffieHellmanGroupExchangeSha256::new();
+        kex.set_max_group_size(4096);
+
+        // downgraded
+        let (io, client) = tokio::io::duplex(1024 * 1024);
+        let mut io = crate::stream::msg::MsgStream::new(io);
+        let mut client = crate::stream::msg::MsgStream::new(client);
+        let msg = GexMsg::from(KexDhGexRequest::new(2048, 8192, 8192));
+        client.context().send(msg).await.unwrap();
+
+        let env = Env {
+            c_version: "",
+            s_version: "",
+            c_kexinit: &kexinit,
+            s_kexinit: &kexinit,
+            hostkey: &hostkey,
+        };
+        let server = kex.kex(&mut io, env);
+        let client = async move {
+            let mut client = client.context::<GexMsg>();
+            match client.next().await {
+                Some(Ok(GexMsg::KexDhGexGroup(msg))) => msg,
+                x => panic!("{:?}", x),
+            }
+        };
+        let (_, group) = futures::join!(server, client);
+        let p = BigNum::from_slice(group.p().as_ref()).unwrap();
+        assert_eq!(p.num_bits(), 4096);
+
+        // refused
+        let (io, client) = tokio::io::duplex(1024 * 1024);
+        let mut io = crate::stream::msg::MsgStream::new(io);
+        let mut client = crate::stream::msg::MsgStream::new(client);
+        let msg = GexMsg::from(KexDhGexRequest::new(6144, 8192, 8192));
+        client.context().send(msg).await.unwrap();
+
+        let env = Env {
+            c_version: "",
+            s_version: "",
+            c_kexinit: &kexinit,
+            s_kexinit: &kexinit,
+            hostkey: &hostkey,
+        };
+        assert!(matches!(
+            kex.kex(&mut io, env).await,
+            Err(SshError::KexUnsupportedGroupSize(6144, 8192))
+        ));
+    }
+
+    #[tokio::test]
+    #[allow(clippy::many_single_char_names)]
+    async fn test_gex_request_old() {
+        use crate::msg::kex_dh_gex_init::KexDhGexInit;
+        use crate::msg::kex_dh_gex_request_old::KexDhGexRequestOld;
+        use crate::pack::Put as _;
+
+        let (io, client) = tokio::io::duplex(1024 * 1024);
+        let mut io = crate::stream::msg::MsgStream::new(io);
+        let mut client = crate::stream::msg::MsgStream::new(client);
+
+        let hostkey = crate::key::Key::gen(&crate::key::Algorithm::SshEd25519).unwrap();
+        let kexinit = crate::preference::PreferenceBuilder::default()
+            .build()
+            .await
+            .unwrap()
+            .to_kexinit();
+        let kexinit = to_msg_bytes(&kexinit);
+
+        let kex = DiffieHellmanGroupExchangeSha256::new();
+        let env = Env {
+            c_version: "SSH-2.0-client",
+            s_version: "SSH-2.0-server",
+            c_kexinit: &kexinit,
+            s_kexinit: &kexinit,
+            hostkey: &hostkey,
+        };
+        let server = kex.kex(&mut io, env);
+
+        let client = async {
+            let mut client = client.context::<GexMsg>();
+            client
+                .send(KexDhGexRequestOld::new(2048).into())
+                .await
+                .unwrap();
+            let group = match client.next().await {
+                Some(Ok(GexMsg::KexDhGexGroup(msg))) => msg,
+                x => panic!("{:?}", x),
+            };
+
+            let p = BigNum::from_slice(group.p().as_ref()).unwrap();
+            let g = BigNum::from_slice(group.g().as_ref()).unwrap();
+            let x = gen_y().unwrap();
+            let e = mod_exp(&g, &x, &p).unwrap();
+            client
+                .send(KexDhGexInit::new(Mpint::new(e.clone())).into())
+                .await
+                .unwrap();
+            let reply = match client.next().await {
+                Some(Ok(GexMsg::KexDhGexReply(msg))) => msg,
+                x => panic!("{:?}", x),
+            };
+
+            let f = BigNum::from_slice(reply.f()).unwrap();
+            let k = mod_exp(&f, &x, &p).unwrap();
+
+            // H = hash(V_C || V_S || I_C || I_S || K_S || n || p || g || e || f || K)
+            let mut hasher = Hasher::sha256();
+            "SSH-2.0-client".pack(&mut hasher);
+            "SSH-2.0-server".pack(&mut hasher);
+            kexinit.pack(&mut hasher);
+            kexinit.pack(&mut hasher);
+            reply.public_host_key().pack(&mut hasher);
+            2048u32.pack(&mut hasher);
+            group.p().pack(&mut hasher);
+            group.g().pack(&mut hasher);
+            Mpint::new(e).pack(&mut hasher);
+            reply.f().pack(&mut hasher);
+            k.pack(&mut hasher);
+            let h = hasher.finish();
+
+            let mut verifier = reply.public_host_key().clone().verifier().unwrap();
+            verifier.put(&h);
+            assert!(verifier.verify(reply.signature()));
+            (h, k)
+        };
+
+        let (server, client) = futures::join!(server, client);
+        let (h, k, _) = server.unwrap();
+        assert_eq!((h, k), client);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_public_value() {
+        use crate::pack::Unpack as _;
+
+        let p = prime(Group14::BITS).unwrap();
+        let mut p1 = BigNum::new().unwrap();
+        p1.checked_sub(p, &BigNum::from_u32(1).unwrap()).unwrap();
+        let too_long = vec![0x01; p.num_bytes() as usize + 2];
+
+        for e in [vec![], vec![1], p1.to_vec(), vec![0xff], too_long] {
+            let (io, client) = tokio::io::duplex(1024 * 1024);
+            let mut io = crate::stream::msg::MsgStream::new(io);
+            let mut client = crate::stream::msg::MsgStream::new(client);
+
+            let hostkey = crate::key::Key::gen(&crate::key::Algorithm::SshEd25519).unwrap();
+            let kexinit = crate::preference::PreferenceBuilder::default()
+                .build()
+                .await
+                .unwrap()
+                .to_kexinit();
+            let kexinit = to_msg_bytes(&kexinit);
+
+            let mut buf = BytesMut::new();
+            Bytes::from(e).pack(&mut buf);
+            let init = crate::msg::kex_ecdh_init::KexEcdhInit::unpack(&mut buf.freeze()).unwrap();
+            client.send(init.into()).await.unwrap();
+
+            let kex = DiffieHellmanGroup14Sha256::new();
+            let env = Env {
+                c_version: "",
+                s_version: "",
+                c_kexinit: &kexinit,
+                s_kexinit: &kexinit,
+                hostkey: &hostkey,
+            };
+            assert!(matches!(
+                kex.kex(&mut io, env).await,
+                Err(SshError::KexInvalidPublicValue)
+            ));
+        }
+    }
 }
@@ -5,7 +5,7 @@ use futures::future::BoxFuture;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::hash::Hasher;
-use crate::key::Key;
+use crate::key::{Key, Signature};
 use crate::msg::kexinit::Kexinit;
 use crate::msg::Msg;
 use crate::negotiate::{AlgorithmName, UnknownNameError};
@@ -106,11 +106,12 @@ trait KexTrait: Sized {
 
     fn hasher() -> Hasher;
 
+    /// Perform key exchange and return exchange hash, shared secret and host key signature.
     fn kex<'a, IO>(
         &self,
         io: &'a mut MsgStream<IO>,
         env: Env<'a>,
-    ) -> BoxFuture<'a, Result<(Bytes, Bytes), SshError>>
+    ) -> BoxFuture<'a, Result<(Bytes, Bytes, Signature), SshError>>
     where
         IO: AsyncRead + AsyncWrite + Unpin + Send;
 }
@@ -183,6 +184,15 @@ impl Kex {
         }
     }
 
+    /// Limit group size of group exchange. Other algorithms are not affected.
+    pub(crate) fn set_max_dh_group_size(&mut self, bits: u32) {
+        match self {
+            Self::DiffieHellmanGroupExchangeSha1(item) => item.set_max_group_size(bits),
+            Self::DiffieHellmanGroupExchangeSha256(item) => item.set_max_group_size(bits),
+            _ => {}
+        }
+    }
+
     pub(crate) async fn kex<IO>(
         &self,
         io: &mut MsgStream<IO>,
@@ -191,7 +201,7 @@ impl Kex {
         c_kexinit: &Kexinit,
         s_kexinit: &Kexinit,
         hostkey: &Key,
-    ) -> Result<(Bytes, Bytes), SshError>
+    ) -> Result<(Bytes, Bytes, Signature), SshError>
     where
         IO: AsyncRead + AsyncWrite + Unpin + Send,
     {
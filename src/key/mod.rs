@@ -58,13 +58,19 @@ impl AlgorithmName for Algorithm {
 #[derive(Debug, Clone)]
 pub(crate) struct Signature(String, Bytes);
 
-impl Pack for Signature {
-    fn pack<P: Put>(&self, buf: &mut P) {
+impl Signature {
+    /// Signature blob. (`string` algorithm name followed by `string` signature)
+    pub(crate) fn to_bytes(&self) -> Bytes {
         let mut b = BytesMut::new();
         self.0.pack(&mut b);
         self.1.pack(&mut b);
+        b.freeze()
+    }
+}
 
-        b.freeze().pack(buf)
+impl Pack for Signature {
+    fn pack<P: Put>(&self, buf: &mut P) {
+        self.to_bytes().pack(buf)
     }
 }
 
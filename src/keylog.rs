@@ -0,0 +1,105 @@
+//! Derived session keys. (`debug_keylog` feature)
+//!
+//! # Danger
+//!
+//! Anyone holding these keys can decrypt and forge the traffic of the session.
+//! Only meant for protocol bridges and debugging (e.g. decrypting a capture).
+use std::fmt;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use getset::Getters;
+
+use crate::negotiate::Algorithm;
+
+/// Keys derived after key exchange. (RFC 4253 7.2)
+#[derive(Clone, Getters)]
+pub struct SessionKeys {
+    /// Session identifier.
+    #[get = "pub"]
+    session_id: Bytes,
+
+    /// Negotiated algorithms the keys are derived for.
+    #[get = "pub"]
+    algorithm: Algorithm,
+
+    /// Initial IV client to server.
+    #[get = "pub"]
+    iv_c2s: Bytes,
+
+    /// Initial IV server to client.
+    #[get = "pub"]
+    iv_s2c: Bytes,
+
+    /// Encryption key client to server.
+    #[get = "pub"]
+    key_c2s: Bytes,
+
+    /// Encryption key server to client.
+    #[get = "pub"]
+    key_s2c: Bytes,
+
+    /// Integrity key client to server.
+    #[get = "pub"]
+    mac_key_c2s: Bytes,
+
+    /// Integrity key server to client.
+    #[get = "pub"]
+    mac_key_s2c: Bytes,
+}
+
+impl SessionKeys {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        session_id: Bytes,
+        algorithm: Algorithm,
+        iv_c2s: Bytes,
+        iv_s2c: Bytes,
+        key_c2s: Bytes,
+        key_s2c: Bytes,
+        mac_key_c2s: Bytes,
+        mac_key_s2c: Bytes,
+    ) -> Self {
+        Self {
+            session_id,
+            algorithm,
+            iv_c2s,
+            iv_s2c,
+            key_c2s,
+            key_s2c,
+            mac_key_c2s,
+            mac_key_s2c,
+        }
+    }
+}
+
+impl fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionKeys")
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Callback receiving [`SessionKeys`] on every key exchange.
+#[derive(Clone)]
+pub(crate) struct OnKeysDerived(Arc<dyn Fn(&SessionKeys) + Send + Sync>);
+
+impl OnKeysDerived {
+    pub(crate) fn new<F>(callback: F) -> Self
+    where
+        F: Fn(&SessionKeys) + Send + Sync + 'static,
+    {
+        Self(Arc::new(callback))
+    }
+
+    pub(crate) fn call(&self, keys: &SessionKeys) {
+        (self.0)(keys)
+    }
+}
+
+impl fmt::Debug for OnKeysDerived {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnKeysDerived")
+    }
+}
@@ -40,31 +40,55 @@
 //! }
 //! ```
 
+pub use auth_policy::AuthPolicy;
 pub use cipher::Algorithm as Cipher;
 pub use comp::Algorithm as Compression;
-pub use connection::{Connection, SshInput, SshOutput};
+pub use connection::{
+    AgentForward, ChannelKind, Connection, ConnectionData, ConnectionState, SshInput, SshOutput,
+    TcpipForward, X11Forward,
+};
 pub use error::SshError;
+pub use events::ServerEvent;
 pub use handlers::*;
+pub use hostkey::HostKeys;
 pub use kex::Algorithm as Kex;
 pub use key::{Algorithm as Key, PublicKey, PublicKeyParseError};
+#[cfg(feature = "debug_keylog")]
+pub use keylog::SessionKeys;
 pub use mac::Algorithm as Mac;
-pub use server::{Builder as ServerBuilder, Server};
+pub use msg::disconnect::ReasonCode as DisconnectReasonCode;
+pub use negotiate::Algorithm as Negotiated;
+pub use quirks::QuirkSet;
+pub use rng::{RandomSource, SystemRandomSource};
+pub use server::{AcceptGate, Builder as ServerBuilder, HostKeysHandle, PeerAddr, Server};
+pub use signal::{Signal, UnknownSignalError};
 
+mod auth_policy;
 pub mod authorized_keys;
 mod cipher;
 mod comp;
 mod connection;
 mod error;
+mod events;
 mod handlers;
 mod hash;
 mod hostkey;
 mod kex;
 mod key;
+#[cfg(feature = "debug_keylog")]
+mod keylog;
 mod mac;
 mod msg;
 mod negotiate;
+pub mod netconf;
 mod pack;
 mod preference;
+mod quirks;
+mod rng;
+#[cfg(feature = "fips_selftest")]
+mod selftest;
 mod server;
+mod signal;
 mod state;
 mod stream;
+pub mod util;
@@ -93,6 +93,15 @@ impl Mac {
         }
     }
 
+    pub(crate) fn algorithm(&self) -> Algorithm {
+        match self {
+            Self::None(..) => Algorithm::None,
+            Self::HmacSha256(..) => Algorithm::HmacSha256,
+            Self::HmacSha512(..) => Algorithm::HmacSha512,
+            Self::HmacSha1(..) => Algorithm::HmacSha1,
+        }
+    }
+
     pub(crate) fn len(&self) -> usize {
         match self {
             Self::None(..) => none::None::LEN,
@@ -1,4 +1,5 @@
 use derive_new::new;
+use getset::Getters;
 
 use super::*;
 
@@ -27,10 +28,12 @@ impl Unpack for DataTypeCode {
     }
 }
 
-#[derive(Debug, new)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct ChannelExtendedData {
     recipient_channel: u32,
     data_type_code: DataTypeCode,
+
+    #[get = "pub(crate)"]
     data: Bytes,
 }
 
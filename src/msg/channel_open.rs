@@ -1,8 +1,9 @@
+use derive_new::new;
 use getset::Getters;
 
 use super::*;
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct X11 {
     #[get = "pub(crate)"]
     originator_address: String,
@@ -29,7 +30,7 @@ impl Unpack for X11 {
     }
 }
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct ForwardedTcpip {
     #[get = "pub(crate)"]
     address: String,
@@ -68,7 +69,7 @@ impl Unpack for ForwardedTcpip {
     }
 }
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct DirectTcpip {
     #[get = "pub(crate)"]
     host: String,
@@ -113,10 +114,11 @@ pub(crate) enum Type {
     X11(X11),
     ForwardedTcpip(ForwardedTcpip),
     DirectTcpip(DirectTcpip),
+    AuthAgent(()),
     Unknown(String, Bytes),
 }
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct ChannelOpen {
     #[get = "pub(crate)"]
     sender_channel: u32,
@@ -142,6 +144,7 @@ impl Pack for ChannelOpen {
             Type::X11(..) => "x11",
             Type::ForwardedTcpip(..) => "forwarded-tcpip",
             Type::DirectTcpip(..) => "direct-tcpip",
+            Type::AuthAgent(..) => "auth-agent@openssh.com",
             Type::Unknown(name, _) => name.as_str(),
         };
 
@@ -155,6 +158,7 @@ impl Pack for ChannelOpen {
             Type::X11(item) => item.pack(buf),
             Type::ForwardedTcpip(item) => item.pack(buf),
             Type::DirectTcpip(item) => item.pack(buf),
+            Type::AuthAgent(..) => {}
             Type::Unknown(_, item) => {
                 buf.put(&item);
             }
@@ -173,6 +177,7 @@ impl Unpack for ChannelOpen {
             "x11" => Type::X11(Unpack::unpack(buf)?),
             "forwarded-tcpip" => Type::ForwardedTcpip(Unpack::unpack(buf)?),
             "direct-tcpip" => Type::DirectTcpip(Unpack::unpack(buf)?),
+            "auth-agent@openssh.com" => Type::AuthAgent(()),
             v => Type::Unknown(v.to_string(), buf.copy_to_bytes(buf.remaining())),
         };
 
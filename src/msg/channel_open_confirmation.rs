@@ -1,12 +1,20 @@
 use derive_new::new;
+use getset::Getters;
 
 use super::*;
 
-#[derive(Debug, new)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct ChannelOpenConfirmation {
+    #[get = "pub(crate)"]
     recipient_channel: u32,
+
+    #[get = "pub(crate)"]
     sender_channel: u32,
+
+    #[get = "pub(crate)"]
     initial_window_size: u32,
+
+    #[get = "pub(crate)"]
     maximum_packet_size: u32,
     additional_data: Bytes,
 }
@@ -1,8 +1,9 @@
 use derive_new::new;
+use getset::Getters;
 
 use super::*;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum ReasonCode {
     AdministrativeryProhibited,
     ConnectFailed,
@@ -37,11 +38,18 @@ impl Unpack for ReasonCode {
     }
 }
 
-#[derive(Debug, new)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct ChannelOpenFailure {
+    #[get = "pub(crate)"]
     recipient_channel: u32,
+
+    #[get = "pub(crate)"]
     reason_code: ReasonCode,
+
+    #[get = "pub(crate)"]
     description: String,
+
+    #[get = "pub(crate)"]
     language_tag: String,
 }
 
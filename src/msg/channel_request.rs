@@ -198,6 +198,8 @@ pub(crate) enum Type {
     Signal(String),
     ExitStatus(u32),
     ExitSignal(ExitSignal),
+    Eow(()),
+    AuthAgentReq(()),
     Unknown(String, Bytes),
 }
 
@@ -232,6 +234,8 @@ impl Pack for ChannelRequest {
             Type::Signal(..) => "signal",
             Type::ExitStatus(..) => "exit-status",
             Type::ExitSignal(..) => "exit-signal",
+            Type::Eow(..) => "eow@openssh.com",
+            Type::AuthAgentReq(..) => "auth-agent-req@openssh.com",
             Type::Unknown(name, ..) => &*name,
         }
         .pack(buf);
@@ -249,6 +253,8 @@ impl Pack for ChannelRequest {
             Type::Signal(item) => item.pack(buf),
             Type::ExitStatus(item) => item.pack(buf),
             Type::ExitSignal(item) => item.pack(buf),
+            Type::Eow(..) => {}
+            Type::AuthAgentReq(..) => {}
             Type::Unknown(_, data) => buf.put(&data),
         }
     }
@@ -272,6 +278,8 @@ impl Unpack for ChannelRequest {
             "signal" => Type::Signal(Unpack::unpack(buf)?),
             "exit-status" => Type::ExitStatus(Unpack::unpack(buf)?),
             "exit-signal" => Type::ExitSignal(Unpack::unpack(buf)?),
+            "eow@openssh.com" => Type::Eow(()),
+            "auth-agent-req@openssh.com" => Type::AuthAgentReq(()),
             x => Type::Unknown(x.into(), buf.copy_to_bytes(buf.remaining())),
         };
 
@@ -1,9 +1,11 @@
 use derive_new::new;
+use getset::Getters;
 
 use super::*;
 
-#[derive(Debug, new)]
+#[derive(Debug, new, Getters)]
 pub(crate) struct ChannelSuccess {
+    #[get = "pub(crate)"]
     recipient_channel: u32,
 }
 
@@ -1,9 +1,11 @@
 use derive_new::new;
+use getset::Getters;
 
 use super::*;
 
-#[derive(Debug)]
-pub(crate) enum ReasonCode {
+/// Disconnect reason code.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReasonCode {
     HostNotAllowedToConnect,
     ProtocolError,
     KeyExchangeFailed,
@@ -70,10 +72,38 @@ impl Unpack for ReasonCode {
     }
 }
 
-#[derive(Debug, new)]
+impl fmt::Display for ReasonCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::HostNotAllowedToConnect => "SSH_DISCONNECT_HOST_NOT_ALLOWED_TO_CONNECT",
+            Self::ProtocolError => "SSH_DISCONNECT_PROTOCOL_ERROR",
+            Self::KeyExchangeFailed => "SSH_DISCONNECT_KEY_EXCHANGE_FAILED",
+            Self::Reserved => "SSH_DISCONNECT_RESERVED",
+            Self::MacError => "SSH_DISCONNECT_MAC_ERROR",
+            Self::CompressionError => "SSH_DISCONNECT_COMPRESSION_ERROR",
+            Self::ServiceNotAvailable => "SSH_DISCONNECT_SERVICE_NOT_AVAILABLE",
+            Self::ProtocolVersionNotSupported => "SSH_DISCONNECT_PROTOCOL_VERSION_NOT_SUPPORTED",
+            Self::HostKeyNotVerifiable => "SSH_DISCONNECT_HOST_KEY_NOT_VERIFIABLE",
+            Self::ConnectionLost => "SSH_DISCONNECT_CONNECTION_LOST",
+            Self::ByApplication => "SSH_DISCONNECT_BY_APPLICATION",
+            Self::TooManyConnections => "SSH_DISCONNECT_TOO_MANY_CONNECTIONS",
+            Self::AuthCancelledByUser => "SSH_DISCONNECT_AUTH_CANCELLED_BY_USER",
+            Self::NoMoreAuthMethodsAvailable => "SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE",
+            Self::IllegalUserName => "SSH_DISCONNECT_ILLEGAL_USER_NAME",
+            Self::Unknown(v) => return write!(f, "unknown ({})", v),
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Getters, new)]
 pub(crate) struct Disconnect {
+    #[get = "pub(crate)"]
     reason_code: ReasonCode,
+
+    #[get = "pub(crate)"]
     description: String,
+
     language_tag: String,
 }
 
@@ -108,3 +138,42 @@ impl From<Disconnect> for Msg {
         Self::Disconnect(v)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reason_code() {
+        use ReasonCode::*;
+
+        // RFC 4253 11.1
+        let codes = [
+            (HostNotAllowedToConnect, 1),
+            (ProtocolError, 2),
+            (KeyExchangeFailed, 3),
+            (Reserved, 4),
+            (MacError, 5),
+            (CompressionError, 6),
+            (ServiceNotAvailable, 7),
+            (ProtocolVersionNotSupported, 8),
+            (HostKeyNotVerifiable, 9),
+            (ConnectionLost, 10),
+            (ByApplication, 11),
+            (TooManyConnections, 12),
+            (AuthCancelledByUser, 13),
+            (NoMoreAuthMethodsAvailable, 14),
+            (IllegalUserName, 15),
+            (Unknown(16), 16),
+        ];
+        for (code, n) in codes.iter() {
+            let mut buf = BytesMut::new();
+            code.pack(&mut buf);
+            assert_eq!(buf.as_ref(), &(*n as u32).to_be_bytes());
+            assert_eq!(&ReasonCode::unpack(&mut buf.freeze()).unwrap(), code);
+        }
+
+        assert_eq!(ByApplication.to_string(), "SSH_DISCONNECT_BY_APPLICATION");
+        assert_eq!(Unknown(99).to_string(), "unknown (99)");
+    }
+}
@@ -1,10 +1,14 @@
+use derive_new::new;
 use getset::Getters;
 
 use super::*;
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct TcpipForward {
+    #[get = "pub(crate)"]
     address_to_bind: String,
+
+    #[get = "pub(crate)"]
     port_number_to_bind: u32,
 }
 
@@ -56,10 +60,11 @@ impl Unpack for CancelTcpipForward {
 pub(crate) enum Type {
     TcpipForward(TcpipForward),
     CancelTcpipForward(CancelTcpipForward),
+    NoMoreSessions(()),
     Unknown(String, Bytes),
 }
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct GlobalRequest {
     #[get = "pub(crate)"]
     want_reply: bool,
@@ -77,6 +82,7 @@ impl Pack for GlobalRequest {
         match &self.typ {
             Type::TcpipForward(..) => "tcpip-forward",
             Type::CancelTcpipForward(..) => "cancel-tcpip-forward",
+            Type::NoMoreSessions(..) => "no-more-sessions@openssh.com",
             Type::Unknown(t, ..) => &*t,
         }
         .pack(buf);
@@ -86,6 +92,7 @@ impl Pack for GlobalRequest {
         match &self.typ {
             Type::TcpipForward(x) => x.pack(buf),
             Type::CancelTcpipForward(x) => x.pack(buf),
+            Type::NoMoreSessions(..) => {}
             Type::Unknown(_, x) => buf.put(&x),
         }
     }
@@ -98,6 +105,7 @@ impl Unpack for GlobalRequest {
         let typ = match &*typ {
             "tcpip-forward" => Type::TcpipForward(Unpack::unpack(buf)?),
             "cancel-tcpip-forward" => Type::CancelTcpipForward(Unpack::unpack(buf)?),
+            "no-more-sessions@openssh.com" => Type::NoMoreSessions(()),
             x => Type::Unknown(x.to_string(), buf.copy_to_bytes(buf.remaining())),
         };
 
@@ -1,12 +1,13 @@
 //! SSH_MSG_KEX_DH_GEX_INIT
 //!
 //! [Diffie-Hellman Group Exchange for](https://tools.ietf.org/html/rfc4419)
+use derive_new::new;
 use getset::Getters;
 
 use super::*;
 use crate::pack::Mpint;
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct KexDhGexInit {
     #[get = "pub(crate)"]
     e: Mpint,
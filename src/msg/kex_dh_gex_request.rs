@@ -1,11 +1,12 @@
 //! SSH_MSG_KEX_DH_GEX_REQUEST
 //!
 //! [Diffie-Hellman Group Exchange for](https://tools.ietf.org/html/rfc4419)
+use derive_new::new;
 use getset::Getters;
 
 use super::*;
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct KexDhGexRequest {
     #[get = "pub(crate)"]
     min: u32,
@@ -1,11 +1,14 @@
 use derive_new::new;
+use getset::Getters;
 
 use super::*;
 use crate::pack::NameList;
 
-#[derive(Debug, new)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct UserauthFailure {
+    #[get = "pub(crate)"]
     authentications: NameList,
+    #[get = "pub(crate)"]
     partial_success: bool,
 }
 
@@ -1,9 +1,14 @@
+use derive_new::new;
 use getset::Getters;
 
 use super::*;
 use crate::key::{PublicKey as Pk, Signature};
+use crate::pack::unpack_limited;
 
-#[derive(Debug, Getters)]
+/// Maximum length of key blob and signature. Larger ones are rejected before being read.
+const MAXIMUM_KEY_FIELD_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Getters, new)]
 pub(crate) struct Publickey {
     #[get = "pub(crate)"]
     algorithm: String,
@@ -30,9 +35,9 @@ impl Unpack for Publickey {
     fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
         let has_signature = bool::unpack(buf)?;
         let algorithm = Unpack::unpack(buf)?;
-        let blob = Unpack::unpack(buf)?;
+        let blob = unpack_limited(buf, MAXIMUM_KEY_FIELD_SIZE)?;
         let signature = if has_signature {
-            Some(Unpack::unpack(buf)?)
+            Some(unpack_limited(buf, MAXIMUM_KEY_FIELD_SIZE)?)
         } else {
             None
         };
@@ -112,10 +117,10 @@ impl Pack for Hostbased {
 impl Unpack for Hostbased {
     fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
         let algorithm = Unpack::unpack(buf)?;
-        let client_hostkey = Unpack::unpack(buf)?;
+        let client_hostkey = unpack_limited(buf, MAXIMUM_KEY_FIELD_SIZE)?;
         let client_hostname = Unpack::unpack(buf)?;
         let user_name = Unpack::unpack(buf)?;
-        let signature = Unpack::unpack(buf)?;
+        let signature = unpack_limited(buf, MAXIMUM_KEY_FIELD_SIZE)?;
 
         Ok(Self {
             algorithm,
@@ -136,6 +141,19 @@ pub(crate) enum Method {
     Unknown(String, Bytes),
 }
 
+impl Method {
+    /// Method name.
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            Self::None => "none",
+            Self::Publickey(..) => "publickey",
+            Self::Password(..) => "password",
+            Self::Hostbased(..) => "hostbased",
+            Self::Unknown(name, ..) => name,
+        }
+    }
+}
+
 impl Pack for Method {
     fn pack<P: Put>(&self, buf: &mut P) {
         match self {
@@ -173,7 +191,7 @@ impl Unpack for Method {
     }
 }
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Getters, new)]
 pub(crate) struct UserauthRequest {
     #[get = "pub(crate)"]
     user_name: String,
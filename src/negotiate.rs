@@ -3,11 +3,13 @@ use std::str::FromStr;
 
 use derive_builder::Builder;
 use getset::Getters;
+use log::warn;
 use thiserror::Error;
 
 use crate::msg::kexinit::Kexinit;
 use crate::pack::NameList;
 use crate::preference::Preference;
+use crate::quirks::QuirkSet;
 use crate::SshError;
 use crate::{cipher, comp, kex, key, mac};
 
@@ -25,34 +27,41 @@ pub(crate) trait AlgorithmName:
     }
 }
 
-#[derive(Debug, Builder, Getters)]
-pub(crate) struct Algorithm {
-    #[get = "pub(crate)"]
+/// Negotiated algorithms.
+#[derive(Debug, Clone, Builder, Getters)]
+pub struct Algorithm {
+    #[get = "pub"]
     kex_algorithm: kex::Algorithm,
-    #[get = "pub(crate)"]
+    #[get = "pub"]
     server_host_key_algorithm: key::Algorithm,
-    #[get = "pub(crate)"]
+    #[get = "pub"]
     cipher_algorithm_c2s: cipher::Algorithm,
-    #[get = "pub(crate)"]
+    #[get = "pub"]
     cipher_algorithm_s2c: cipher::Algorithm,
-    #[get = "pub(crate)"]
+    #[get = "pub"]
     mac_algorithm_c2s: mac::Algorithm,
-    #[get = "pub(crate)"]
+    #[get = "pub"]
     mac_algorithm_s2c: mac::Algorithm,
-    #[get = "pub(crate)"]
+    #[get = "pub"]
     compression_algorithm_c2s: comp::Algorithm,
-    #[get = "pub(crate)"]
+    #[get = "pub"]
     compression_algorithm_s2c: comp::Algorithm,
 }
 
-fn decide<N>(l: &[N], r: &NameList) -> Result<N, SshError>
+/// First algorithm in client list `r` also in server list `l`, or the other way around if
+/// `prefer_server`.
+fn decide<N>(l: &[N], r: &NameList, prefer_server: bool) -> Result<N, SshError>
 where
     N: AlgorithmName,
 {
-    let found = r
-        .iter()
-        .flat_map(|r| l.iter().filter(move |l| r.as_str() == l.as_ref()))
-        .next();
+    let found = if prefer_server {
+        l.iter()
+            .find(|l| r.iter().any(|r| r.as_str() == l.as_ref()))
+    } else {
+        r.iter()
+            .flat_map(|r| l.iter().filter(move |l| r.as_str() == l.as_ref()))
+            .next()
+    };
 
     found.map(ToOwned::to_owned).ok_or_else(|| {
         SshError::NegotiateNotMatched(r.iter().map(AsRef::as_ref).collect::<Vec<_>>().join(","))
@@ -62,49 +71,86 @@ where
 pub(crate) fn negotiate(
     c_kexinit: &Kexinit,
     preference: &Preference,
+    quirks: &QuirkSet,
 ) -> Result<Algorithm, SshError> {
     let mut builder = AlgorithmBuilder::default();
+    let prefer_server = *preference.prefer_server_algorithms();
 
-    let kex_algorithm = decide(preference.kex_algorithms(), c_kexinit.kex_algorithms())?;
+    let kex_algorithm = decide(
+        preference.kex_algorithms(),
+        c_kexinit.kex_algorithms(),
+        false,
+    )?;
     builder.kex_algorithm(kex_algorithm);
 
+    // Always client order, not hostkeys insertion order nor `prefer_server`:
+    // clients put algorithms of keys in known_hosts first.
     let server_host_key_algorithm = decide(
         &preference.hostkeys().names(),
         c_kexinit.server_host_key_algorithms(),
+        false,
     )?;
     builder.server_host_key_algorithm(server_host_key_algorithm);
 
+    let cipher_algorithms = preference.cipher_algorithms_for(quirks);
+    let mac_algorithms = preference.mac_algorithms_for(quirks);
+
     let cipher_algorithm_c2s = decide(
-        preference.cipher_algorithms(),
+        &cipher_algorithms,
         c_kexinit.cipher_algorithms_c2s(),
+        prefer_server,
     )?;
     builder.cipher_algorithm_c2s(cipher_algorithm_c2s);
 
     let cipher_algorithm_s2c = decide(
-        preference.cipher_algorithms(),
+        &cipher_algorithms,
         c_kexinit.cipher_algorithms_s2c(),
+        prefer_server,
     )?;
     builder.cipher_algorithm_s2c(cipher_algorithm_s2c);
 
-    let mac_algorithm_c2s = decide(preference.mac_algorithms(), c_kexinit.mac_algorithms_c2s())?;
+    let mac_algorithm_c2s = decide(
+        &mac_algorithms,
+        c_kexinit.mac_algorithms_c2s(),
+        prefer_server,
+    )?;
     builder.mac_algorithm_c2s(mac_algorithm_c2s);
 
-    let mac_algorithm_s2c = decide(preference.mac_algorithms(), c_kexinit.mac_algorithms_s2c())?;
+    let mac_algorithm_s2c = decide(
+        &mac_algorithms,
+        c_kexinit.mac_algorithms_s2c(),
+        prefer_server,
+    )?;
     builder.mac_algorithm_s2c(mac_algorithm_s2c);
 
     let compression_algorithm_c2s = decide(
         preference.compression_algorithms(),
         c_kexinit.compression_algorithms_c2s(),
+        false,
     )?;
     builder.compression_algorithm_c2s(compression_algorithm_c2s);
 
     let compression_algorithm_s2c = decide(
         preference.compression_algorithms(),
         c_kexinit.compression_algorithms_s2c(),
+        false,
     )?;
     builder.compression_algorithm_s2c(compression_algorithm_s2c);
 
-    Ok(builder.build().unwrap())
+    let algorithm = builder.build().unwrap();
+    let plaintext = [
+        algorithm.cipher_algorithm_c2s() == &cipher::Algorithm::None,
+        algorithm.cipher_algorithm_s2c() == &cipher::Algorithm::None,
+        algorithm.mac_algorithm_c2s() == &mac::Algorithm::None,
+        algorithm.mac_algorithm_s2c() == &mac::Algorithm::None,
+    ];
+    if plaintext.iter().any(|p| *p) {
+        warn!(
+            "INSECURE: negotiated `none` cipher / MAC. traffic is NOT protected. {:?}",
+            algorithm
+        );
+    }
+    Ok(algorithm)
 }
 
 #[cfg(test)]
@@ -119,25 +165,33 @@ mod tests {
     fn test_decide() {
         use mac::Algorithm::*;
 
-        let r = decide(&[HmacSha1], &list(["hmac-sha1"]));
+        let r = decide(&[HmacSha1], &list(["hmac-sha1"]), false);
         assert_eq!(r.unwrap(), HmacSha1);
 
-        let r = decide(&[HmacSha1], &list(["hmac-sha2-256"]));
+        let r = decide(&[HmacSha1], &list(["hmac-sha2-256"]), false);
         assert!(matches!(r, Err(SshError::NegotiateNotMatched(..))));
 
-        let r = decide(&[] as &[mac::Algorithm], &list([]));
+        let r = decide(&[] as &[mac::Algorithm], &list([]), false);
         assert!(matches!(r, Err(SshError::NegotiateNotMatched(..))));
 
-        let r = decide(&[HmacSha1], &list(["hmac-sha2-256", "hmac-sha1"]));
+        let r = decide(&[HmacSha1], &list(["hmac-sha2-256", "hmac-sha1"]), false);
         assert_eq!(r.unwrap(), HmacSha1);
 
         let r = decide(
             &[HmacSha1, HmacSha256],
             &list(["hmac-sha2-256", "hmac-sha1"]),
+            false,
         );
         assert_eq!(r.unwrap(), HmacSha256);
 
-        let r = decide(&[HmacSha1], &list(["hmac-sha2-256", "none"]));
+        let r = decide(
+            &[HmacSha1, HmacSha256],
+            &list(["hmac-sha2-256", "hmac-sha1"]),
+            true,
+        );
+        assert_eq!(r.unwrap(), HmacSha1);
+
+        let r = decide(&[HmacSha1], &list(["hmac-sha2-256", "none"]), false);
         assert!(matches!(r, Err(SshError::NegotiateNotMatched(..))));
     }
 
@@ -164,6 +218,203 @@ mod tests {
             .await
             .unwrap();
 
-        negotiate(&c_kexinit, &preference).unwrap();
+        negotiate(&c_kexinit, &preference, &Default::default()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_empty_list() {
+        use bytes::BytesMut;
+
+        use crate::msg::kexinit::Kexinit;
+        use crate::pack::{Pack, Unpack};
+
+        let c_kexinit = crate::msg::kexinit::KexinitBuilder::default()
+            .cookie(0)
+            .kex_algorithms(list(["curve25519-sha256"]))
+            .server_host_key_algorithms(list(["ssh-ed25519"]))
+            .cipher_algorithms_c2s(list(["aes256-ctr"]))
+            .cipher_algorithms_s2c(list(["aes256-ctr"]))
+            .mac_algorithms_c2s(list([]))
+            .mac_algorithms_s2c(list(["hmac-sha2-256"]))
+            .compression_algorithms_c2s(list(["none"]))
+            .compression_algorithms_s2c(list(["none"]))
+            .languages_c2s(list([]))
+            .languages_s2c(list([]))
+            .first_kex_packet_follows(false)
+            .build()
+            .unwrap();
+        let mut buf = BytesMut::new();
+        c_kexinit.pack(&mut buf);
+        let c_kexinit = Kexinit::unpack(&mut buf.freeze()).unwrap();
+        assert_eq!(c_kexinit.mac_algorithms_c2s().iter().count(), 0);
+
+        let preference = crate::preference::PreferenceBuilder::default()
+            .build()
+            .await
+            .unwrap();
+
+        let r = negotiate(&c_kexinit, &preference, &Default::default());
+        assert!(matches!(r, Err(SshError::NegotiateNotMatched(..))));
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_prefer_server() {
+        use cipher::Algorithm::*;
+
+        let c_kexinit = crate::msg::kexinit::KexinitBuilder::default()
+            .cookie(0)
+            .kex_algorithms(list(["curve25519-sha256"]))
+            .server_host_key_algorithms(list(["ssh-ed25519"]))
+            .cipher_algorithms_c2s(list(["aes128-ctr", "aes256-ctr"]))
+            .cipher_algorithms_s2c(list(["aes128-ctr", "aes256-ctr"]))
+            .mac_algorithms_c2s(list(["hmac-sha2-256"]))
+            .mac_algorithms_s2c(list(["hmac-sha2-256"]))
+            .compression_algorithms_c2s(list(["none"]))
+            .compression_algorithms_s2c(list(["none"]))
+            .languages_c2s(list([""]))
+            .languages_s2c(list([""]))
+            .first_kex_packet_follows(false)
+            .build()
+            .unwrap();
+
+        let mut builder = crate::preference::PreferenceBuilder::default();
+        builder
+            .add_cipher_algorithm(Aes256Ctr)
+            .add_cipher_algorithm(Aes128Ctr);
+
+        let preference = builder.build().await.unwrap();
+        let algorithm = negotiate(&c_kexinit, &preference, &Default::default()).unwrap();
+        assert_eq!(algorithm.cipher_algorithm_c2s(), &Aes128Ctr);
+        assert_eq!(algorithm.cipher_algorithm_s2c(), &Aes128Ctr);
+
+        builder.prefer_server_algorithms(true);
+        let preference = builder.build().await.unwrap();
+        let algorithm = negotiate(&c_kexinit, &preference, &Default::default()).unwrap();
+        assert_eq!(algorithm.cipher_algorithm_c2s(), &Aes256Ctr);
+        assert_eq!(algorithm.cipher_algorithm_s2c(), &Aes256Ctr);
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_quirks() {
+        use crate::quirks::QuirkSet;
+
+        let c_kexinit = crate::msg::kexinit::KexinitBuilder::default()
+            .cookie(0)
+            .kex_algorithms(list(["curve25519-sha256"]))
+            .server_host_key_algorithms(list(["ssh-ed25519"]))
+            .cipher_algorithms_c2s(list(["aes128-ctr", "aes256-ctr"]))
+            .cipher_algorithms_s2c(list(["aes128-ctr", "aes256-ctr"]))
+            .mac_algorithms_c2s(list(["hmac-sha2-256"]))
+            .mac_algorithms_s2c(list(["hmac-sha2-256"]))
+            .compression_algorithms_c2s(list(["none"]))
+            .compression_algorithms_s2c(list(["none"]))
+            .languages_c2s(list([""]))
+            .languages_s2c(list([""]))
+            .first_kex_packet_follows(false)
+            .build()
+            .unwrap();
+
+        let preference = crate::preference::PreferenceBuilder::default()
+            .build()
+            .await
+            .unwrap();
+
+        let mut quirks = QuirkSet::new();
+        quirks.disable_cipher(cipher::Algorithm::Aes128Ctr);
+        let algorithm = negotiate(&c_kexinit, &preference, &quirks).unwrap();
+        assert_eq!(
+            algorithm.cipher_algorithm_c2s(),
+            &cipher::Algorithm::Aes256Ctr
+        );
+        assert_eq!(
+            algorithm.cipher_algorithm_s2c(),
+            &cipher::Algorithm::Aes256Ctr
+        );
+
+        quirks.disable_mac(mac::Algorithm::HmacSha256);
+        let r = negotiate(&c_kexinit, &preference, &quirks);
+        assert!(matches!(r, Err(SshError::NegotiateNotMatched(..))));
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_hostkey_client_order() {
+        let c_kexinit = crate::msg::kexinit::KexinitBuilder::default()
+            .cookie(0)
+            .kex_algorithms(list(["curve25519-sha256"]))
+            .server_host_key_algorithms(list(["ssh-ed25519", "ssh-rsa"]))
+            .cipher_algorithms_c2s(list(["aes256-ctr"]))
+            .cipher_algorithms_s2c(list(["aes256-ctr"]))
+            .mac_algorithms_c2s(list(["hmac-sha2-256"]))
+            .mac_algorithms_s2c(list(["hmac-sha2-256"]))
+            .compression_algorithms_c2s(list(["none"]))
+            .compression_algorithms_s2c(list(["none"]))
+            .languages_c2s(list([""]))
+            .languages_s2c(list([""]))
+            .first_kex_packet_follows(false)
+            .build()
+            .unwrap();
+
+        let mut builder = crate::preference::PreferenceBuilder::default();
+        builder
+            .hostkeys_from_bytes(include_bytes!("../tests/rsa"))
+            .hostkeys_from_bytes(include_bytes!("../tests/ed25519"));
+        let preference = builder.build().await.unwrap();
+        assert_eq!(
+            preference.hostkeys().names(),
+            vec![key::Algorithm::SshRsa, key::Algorithm::SshEd25519]
+        );
+
+        let algorithm = negotiate(&c_kexinit, &preference, &Default::default()).unwrap();
+        assert_eq!(
+            algorithm.server_host_key_algorithm(),
+            &key::Algorithm::SshEd25519
+        );
+
+        builder.prefer_server_algorithms(true);
+        let preference = builder.build().await.unwrap();
+        let algorithm = negotiate(&c_kexinit, &preference, &Default::default()).unwrap();
+        assert_eq!(
+            algorithm.server_host_key_algorithm(),
+            &key::Algorithm::SshEd25519
+        );
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_none() {
+        let c_kexinit = crate::msg::kexinit::KexinitBuilder::default()
+            .cookie(0)
+            .kex_algorithms(list(["curve25519-sha256"]))
+            .server_host_key_algorithms(list(["ssh-ed25519"]))
+            .cipher_algorithms_c2s(list(["none"]))
+            .cipher_algorithms_s2c(list(["none"]))
+            .mac_algorithms_c2s(list(["none"]))
+            .mac_algorithms_s2c(list(["none"]))
+            .compression_algorithms_c2s(list(["none"]))
+            .compression_algorithms_s2c(list(["none"]))
+            .languages_c2s(list([""]))
+            .languages_s2c(list([""]))
+            .first_kex_packet_follows(false)
+            .build()
+            .unwrap();
+
+        let mut builder = crate::preference::PreferenceBuilder::default();
+        let preference = builder.build().await.unwrap();
+        let r = negotiate(&c_kexinit, &preference, &Default::default());
+        assert!(matches!(r, Err(SshError::NegotiateNotMatched(..))));
+
+        // explicitly added is not enough.
+        builder
+            .add_cipher_algorithm(cipher::Algorithm::None)
+            .add_mac_algorithm(mac::Algorithm::None);
+        let preference = builder.build().await.unwrap();
+        let r = negotiate(&c_kexinit, &preference, &Default::default());
+        assert!(matches!(r, Err(SshError::NegotiateNotMatched(..))));
+
+        let mut builder = crate::preference::PreferenceBuilder::default();
+        builder.insecure_allow_plaintext_for_testing();
+        let preference = builder.build().await.unwrap();
+        let algorithm = negotiate(&c_kexinit, &preference, &Default::default()).unwrap();
+        assert_eq!(algorithm.cipher_algorithm_c2s(), &cipher::Algorithm::None);
+        assert_eq!(algorithm.mac_algorithm_s2c(), &mac::Algorithm::None);
     }
 }
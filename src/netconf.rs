@@ -0,0 +1,237 @@
+//! NETCONF message framing over the `netconf` subsystem. (RFC 6242)
+//!
+//! # Example
+//!
+//! ```
+//! use futures::FutureExt as _;
+//! use ssssh::{Handlers, SessionContext};
+//! use ssssh::netconf::{Framer, Framing};
+//!
+//! let mut handlers = Handlers::<anyhow::Error>::new();
+//! handlers.on_channel_subsystem(|mut ctx: SessionContext, name: String| {
+//!     async move {
+//!         if name != "netconf" {
+//!             return Ok(1);
+//!         }
+//!         let (stdin, stdout, _) = ctx.take_stdio().unwrap();
+//!         let mut framer = Framer::new(stdin, stdout);
+//!         framer.write_message(b"<hello/>").await?;
+//!         if framer.read_message().await?.is_none() {
+//!             return Ok(0);
+//!         }
+//!         // both peers advertised base:1.1
+//!         framer.set_framing(Framing::Chunked);
+//!         while let Some(rpc) = framer.read_message().await? {
+//!             framer.write_message(&rpc).await?;
+//!         }
+//!         Ok(0)
+//!     }
+//!     .boxed()
+//! });
+//! ```
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt as _, AsyncReadExt as _};
+use tokio::io::{AsyncWrite, AsyncWriteExt as _};
+
+const END_OF_MESSAGE: &[u8] = b"]]>]]>";
+
+/// Longest chunk header: `#` 10 digits LF.
+const MAX_CHUNK_HEADER: u64 = 12;
+
+/// Message framing mechanism. (RFC 6242 4.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing {
+    /// `]]>]]>` delimited. (base:1.0, and always for `<hello>`)
+    #[default]
+    EndOfMessage,
+
+    /// Chunked framing. (base:1.1)
+    Chunked,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read and write NETCONF messages on subsystem input / output.
+///
+/// Starts with [`Framing::EndOfMessage`] for `<hello>` exchange.
+#[derive(Debug)]
+pub struct Framer<R, W> {
+    input: R,
+    output: W,
+    framing: Framing,
+}
+
+impl<R, W> Framer<R, W>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input,
+            output,
+            framing: Framing::default(),
+        }
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Switch framing, after both peers advertised `urn:ietf:params:netconf:base:1.1`.
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// Read next message. Returns `None` on EOF between messages.
+    pub async fn read_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.framing {
+            Framing::EndOfMessage => self.read_end_of_message().await,
+            Framing::Chunked => self.read_chunked().await,
+        }
+    }
+
+    /// Write `message` and flush.
+    pub async fn write_message(&mut self, message: &[u8]) -> io::Result<()> {
+        match self.framing {
+            Framing::EndOfMessage => {
+                self.output.write_all(message).await?;
+                self.output.write_all(END_OF_MESSAGE).await?;
+            }
+            Framing::Chunked => {
+                // chunk-size must be at least 1.
+                if !message.is_empty() {
+                    let header = format!("\n#{}\n", message.len());
+                    self.output.write_all(header.as_bytes()).await?;
+                    self.output.write_all(message).await?;
+                }
+                self.output.write_all(b"\n##\n").await?;
+            }
+        }
+        self.output.flush().await
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.input, self.output)
+    }
+
+    async fn read_end_of_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut message = vec![];
+        loop {
+            if self.input.read_until(b'>', &mut message).await? == 0 {
+                return if message.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(io::ErrorKind::UnexpectedEof.into())
+                };
+            }
+            if message.ends_with(END_OF_MESSAGE) {
+                message.truncate(message.len() - END_OF_MESSAGE.len());
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    async fn read_chunked(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut message = vec![];
+        loop {
+            let mut lf = [0; 1];
+            if self.input.read(&mut lf).await? == 0 && message.is_empty() {
+                return Ok(None);
+            }
+            if lf != *b"\n" {
+                return Err(invalid_data("invalid chunk header"));
+            }
+
+            let mut header = vec![];
+            (&mut self.input)
+                .take(MAX_CHUNK_HEADER)
+                .read_until(b'\n', &mut header)
+                .await?;
+            let size = match &header[..] {
+                b"##\n" => return Ok(Some(message)),
+                [b'#', size @ .., b'\n'] => parse_chunk_size(size)?,
+                _ => return Err(invalid_data("invalid chunk header")),
+            };
+
+            // not to allocate by untrusted size at once.
+            let n = (&mut self.input)
+                .take(size)
+                .read_to_end(&mut message)
+                .await?;
+            if n as u64 != size {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+
+/// `chunk-size = 1*DIGIT1 0*DIGIT`, up to 4294967295.
+fn parse_chunk_size(size: &[u8]) -> io::Result<u64> {
+    let valid = matches!(size, [b'1'..=b'9', ..]) && size.iter().all(u8::is_ascii_digit);
+    let size = std::str::from_utf8(size)
+        .ok()
+        .filter(|_| valid)
+        .and_then(|size| size.parse::<u32>().ok());
+    match size {
+        Some(size) => Ok(size.into()),
+        None => Err(invalid_data("invalid chunk size")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_chunked() {
+        let mut framer = Framer::new(&b""[..], vec![]);
+        framer.set_framing(Framing::Chunked);
+        framer
+            .write_message(b"<rpc message-id=\"102\"/>")
+            .await
+            .unwrap();
+        framer.write_message(b"").await.unwrap();
+        let (_, output) = framer.into_inner();
+        assert_eq!(output, b"\n#23\n<rpc message-id=\"102\"/>\n##\n\n##\n");
+
+        // multiple chunks. (RFC 6242 4.2)
+        let input = b"\n#4\n<rpc\n#18\n message-id=\"102\"\n\n#3\n/>\n\n##\n\n#1\nx\n##\n";
+        let mut framer = Framer::new(&input[..], vec![]);
+        framer.set_framing(Framing::Chunked);
+        assert_eq!(
+            framer.read_message().await.unwrap().unwrap(),
+            b"<rpc message-id=\"102\"\n/>\n"
+        );
+        assert_eq!(framer.read_message().await.unwrap().unwrap(), b"x");
+        assert_eq!(framer.read_message().await.unwrap(), None);
+
+        for input in &[
+            &b"\n#0\n\n##\n"[..],
+            b"\n#01\nx\n##\n",
+            b"\n#4294967296\nx\n##\n",
+            b"\n#5\nx\n##\n",
+            b"#1\nx\n##\n",
+        ] {
+            let mut framer = Framer::new(*input, vec![]);
+            framer.set_framing(Framing::Chunked);
+            assert!(framer.read_message().await.is_err(), "{:?}", input);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_end_of_message() {
+        let mut framer = Framer::new(&b"<hello/>]]>]]><a>]]></a>]]>]]>"[..], vec![]);
+        assert_eq!(framer.read_message().await.unwrap().unwrap(), b"<hello/>");
+        assert_eq!(framer.read_message().await.unwrap().unwrap(), b"<a>]]></a>");
+        assert_eq!(framer.read_message().await.unwrap(), None);
+
+        framer.write_message(b"<hello/>").await.unwrap();
+        let (_, output) = framer.into_inner();
+        assert_eq!(output, b"<hello/>]]>]]>");
+
+        let mut framer = Framer::new(&b"<hello/>]]>"[..], vec![]);
+        assert!(framer.read_message().await.is_err());
+    }
+}
@@ -12,6 +12,9 @@ pub enum UnpackError {
 
     #[error(transparent)]
     FromUtf8Error(#[from] FromUtf8Error),
+
+    #[error("too large field length {0}")]
+    TooLarge(usize),
 }
 
 pub(crate) trait Put {
@@ -32,6 +35,22 @@ pub(crate) trait Unpack: Sized {
     fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError>;
 }
 
+/// Unpack string encoded item, rejecting more than `max` bytes before reading its content.
+pub(crate) fn unpack_limited<T: Unpack, B: Buf>(buf: &mut B, max: usize) -> Result<T, UnpackError> {
+    let len = u32::unpack(buf)? as usize;
+    if len > max {
+        return Err(UnpackError::TooLarge(len));
+    }
+    if buf.remaining() < len {
+        return Err(UnpackError::UnexpectedEof);
+    }
+
+    let mut item = BytesMut::with_capacity(4 + len);
+    (len as u32).pack(&mut item);
+    item.extend_from_slice(&buf.copy_to_bytes(len));
+    T::unpack(&mut item.freeze())
+}
+
 impl Pack for bool {
     fn pack<P: Put>(&self, buf: &mut P) {
         (if *self { 1u8 } else { 0u8 }).pack(buf);
@@ -189,6 +208,10 @@ impl Pack for NameList {
 impl Unpack for NameList {
     fn unpack<B: Buf>(buf: &mut B) -> Result<Self, UnpackError> {
         let s = String::unpack(buf)?;
+        // `""` is an empty list, not a list of an empty name.
+        if s.is_empty() {
+            return Ok(Self(vec![]));
+        }
         let s = s.split(',').map(Into::into).collect();
         Ok(Self(s))
     }
@@ -379,6 +402,16 @@ mod tests {
 
         let r = NameList::unpack(&mut b.freeze()).unwrap();
         assert_eq!(r, NameList(vec!["a".into(), "b".into()]));
+
+        let mut b = BytesMut::new();
+        Vec::<String>::new()
+            .into_iter()
+            .collect::<NameList>()
+            .pack(&mut b);
+        assert_eq!(&*b, &[0, 0, 0, 0][..]);
+
+        let r = NameList::unpack(&mut b.freeze()).unwrap();
+        assert_eq!(r.iter().count(), 0);
     }
 
     #[test]
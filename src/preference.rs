@@ -1,15 +1,25 @@
+use std::net::IpAddr;
 use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
+use futures::stream::Stream;
 use getset::Getters;
+use ipnet::IpNet;
 
+use crate::auth_policy::AuthPolicy;
 use crate::cipher;
 use crate::comp;
+use crate::events::{EventSender, ServerEvent};
 use crate::hostkey::{HostKeys, HostKeysBuilder};
 use crate::kex;
+#[cfg(feature = "debug_keylog")]
+use crate::keylog::OnKeysDerived;
 use crate::mac;
 use crate::msg::kexinit::{Kexinit, KexinitBuilder};
 use crate::negotiate::AlgorithmName;
+use crate::quirks::QuirkSet;
+use crate::rng::Rng;
 use crate::SshError;
 
 #[derive(Debug, Default)]
@@ -19,8 +29,47 @@ pub(crate) struct PreferenceBuilder {
     cipher_algorithms: Vec<cipher::Algorithm>,
     mac_algorithms: Vec<mac::Algorithm>,
     compression_algorithms: Vec<comp::Algorithm>,
+    removed_kex_algorithms: Vec<kex::Algorithm>,
+    removed_cipher_algorithms: Vec<cipher::Algorithm>,
+    removed_mac_algorithms: Vec<mac::Algorithm>,
     name: Option<String>,
+    raw_identification: Option<String>,
+    pre_banner: Vec<String>,
+    banner_timeout: Option<Duration>,
     timeout: Option<Duration>,
+    timeout_description: Option<String>,
+    max_session_duration: Option<Duration>,
+    byte_quota: Option<u64>,
+    kex_timeout: Option<Duration>,
+    service_request_timeout: Option<Duration>,
+    min_rekey_interval: Option<Duration>,
+    max_dh_group_size: Option<u32>,
+    auth_failure_delay: Option<Duration>,
+    auth_failure_jitter: Option<Duration>,
+    auth_policy: Option<AuthPolicy>,
+    max_total_channels: Option<u32>,
+    session_window: Option<(u32, u32)>,
+    direct_tcpip_window: Option<(u32, u32)>,
+    max_pending_handshakes: Option<usize>,
+    tcp_user_timeout: Option<Duration>,
+    max_user_name_length: Option<usize>,
+    client_quirks: Option<fn(&str) -> QuirkSet>,
+    abort_connection_on_panic: Option<bool>,
+    lenient_channel_requests: Option<bool>,
+    allow_pty_without_handler: Option<bool>,
+    motd: Option<String>,
+    prefer_server_algorithms: Option<bool>,
+    insecure_allow_plaintext: Option<bool>,
+    allow_reauthentication: Option<bool>,
+    outbound_queue_len: Option<usize>,
+    language_tag: Option<String>,
+    accept_env: Vec<String>,
+    allow_cidrs: Vec<IpNet>,
+    deny_cidrs: Vec<IpNet>,
+    rng: Rng,
+    events: EventSender,
+    #[cfg(feature = "debug_keylog")]
+    on_keys_derived: Option<OnKeysDerived>,
 }
 
 impl PreferenceBuilder {
@@ -44,44 +93,274 @@ impl PreferenceBuilder {
         self
     }
 
+    pub(crate) fn remove_kex_algorithm(&mut self, name: kex::Algorithm) -> &mut Self {
+        self.removed_kex_algorithms.push(name);
+        self
+    }
+
+    pub(crate) fn remove_cipher_algorithm(&mut self, name: cipher::Algorithm) -> &mut Self {
+        self.removed_cipher_algorithms.push(name);
+        self
+    }
+
+    pub(crate) fn remove_mac_algorithm(&mut self, name: mac::Algorithm) -> &mut Self {
+        self.removed_mac_algorithms.push(name);
+        self
+    }
+
+    /// Remove SHA-1 based key exchange / MAC and 1024-bit `group1`.
+    pub(crate) fn disable_weak_algorithms(&mut self) -> &mut Self {
+        self.remove_kex_algorithm(kex::Algorithm::DiffieHellmanGroup1Sha1)
+            .remove_kex_algorithm(kex::Algorithm::DiffieHellmanGroup14Sha1)
+            .remove_kex_algorithm(kex::Algorithm::DiffieHellmanGroupExchangeSha1)
+            .remove_mac_algorithm(mac::Algorithm::HmacSha1)
+    }
+
+    pub(crate) fn insecure_allow_plaintext_for_testing(&mut self) -> &mut Self {
+        self.insecure_allow_plaintext = Some(true);
+        self
+    }
+
     pub(crate) fn name(&mut self, name: &str) -> &mut Self {
         self.name = Some(name.to_string());
         self
     }
 
+    pub(crate) fn raw_identification(&mut self, identification: String) -> &mut Self {
+        self.raw_identification = Some(identification);
+        self
+    }
+
+    pub(crate) fn pre_banner(&mut self, lines: Vec<String>) -> &mut Self {
+        self.pre_banner = lines;
+        self
+    }
+
+    pub(crate) fn banner_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.banner_timeout = Some(timeout);
+        self
+    }
+
     pub(crate) fn timeout(&mut self, timeout: Duration) -> &mut Self {
         self.timeout = Some(timeout);
         self
     }
 
+    pub(crate) fn timeout_description(&mut self, description: &str) -> &mut Self {
+        self.timeout_description = Some(description.to_string());
+        self
+    }
+
+    pub(crate) fn max_session_duration(&mut self, duration: Duration) -> &mut Self {
+        self.max_session_duration = Some(duration);
+        self
+    }
+
+    pub(crate) fn byte_quota(&mut self, quota: u64) -> &mut Self {
+        self.byte_quota = Some(quota);
+        self
+    }
+
+    pub(crate) fn kex_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.kex_timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn service_request_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.service_request_timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn min_rekey_interval(&mut self, interval: Duration) -> &mut Self {
+        self.min_rekey_interval = Some(interval);
+        self
+    }
+
+    pub(crate) fn max_dh_group_size(&mut self, bits: u32) -> &mut Self {
+        self.max_dh_group_size = Some(bits);
+        self
+    }
+
+    pub(crate) fn auth_failure_delay(&mut self, delay: Duration) -> &mut Self {
+        self.auth_failure_delay = Some(delay);
+        self
+    }
+
+    pub(crate) fn auth_failure_jitter(&mut self, jitter: Duration) -> &mut Self {
+        self.auth_failure_jitter = Some(jitter);
+        self
+    }
+
+    pub(crate) fn auth_policy(&mut self, policy: AuthPolicy) -> &mut Self {
+        self.auth_policy = Some(policy);
+        self
+    }
+
+    pub(crate) fn max_total_channels(&mut self, max: u32) -> &mut Self {
+        self.max_total_channels = Some(max);
+        self
+    }
+
+    pub(crate) fn session_window(
+        &mut self,
+        window_size: u32,
+        maximum_packet_size: u32,
+    ) -> &mut Self {
+        self.session_window = Some((window_size, maximum_packet_size));
+        self
+    }
+
+    pub(crate) fn direct_tcpip_window(
+        &mut self,
+        window_size: u32,
+        maximum_packet_size: u32,
+    ) -> &mut Self {
+        self.direct_tcpip_window = Some((window_size, maximum_packet_size));
+        self
+    }
+
+    pub(crate) fn max_pending_handshakes(&mut self, max: usize) -> &mut Self {
+        self.max_pending_handshakes = Some(max);
+        self
+    }
+
+    pub(crate) fn tcp_user_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.tcp_user_timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn max_user_name_length(&mut self, len: usize) -> &mut Self {
+        self.max_user_name_length = Some(len);
+        self
+    }
+
+    pub(crate) fn client_quirks(&mut self, quirks: fn(&str) -> QuirkSet) -> &mut Self {
+        self.client_quirks = Some(quirks);
+        self
+    }
+
+    pub(crate) fn abort_connection_on_panic(&mut self, abort: bool) -> &mut Self {
+        self.abort_connection_on_panic = Some(abort);
+        self
+    }
+
+    pub(crate) fn lenient_channel_requests(&mut self, lenient: bool) -> &mut Self {
+        self.lenient_channel_requests = Some(lenient);
+        self
+    }
+
+    pub(crate) fn allow_pty_without_handler(&mut self, allow: bool) -> &mut Self {
+        self.allow_pty_without_handler = Some(allow);
+        self
+    }
+
+    pub(crate) fn motd(&mut self, motd: String) -> &mut Self {
+        self.motd = Some(motd);
+        self
+    }
+
+    pub(crate) fn prefer_server_algorithms(&mut self, prefer: bool) -> &mut Self {
+        self.prefer_server_algorithms = Some(prefer);
+        self
+    }
+
+    pub(crate) fn allow_reauthentication(&mut self, allow: bool) -> &mut Self {
+        self.allow_reauthentication = Some(allow);
+        self
+    }
+
+    pub(crate) fn outbound_queue_len(&mut self, len: usize) -> &mut Self {
+        self.outbound_queue_len = Some(len);
+        self
+    }
+
+    pub(crate) fn language_tag(&mut self, tag: &str) -> &mut Self {
+        self.language_tag = Some(tag.to_string());
+        self
+    }
+
+    pub(crate) fn accept_env<I, S>(&mut self, patterns: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.accept_env = patterns.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub(crate) fn allow_cidrs<I>(&mut self, cidrs: I) -> &mut Self
+    where
+        I: IntoIterator<Item = IpNet>,
+    {
+        self.allow_cidrs = cidrs.into_iter().collect();
+        self
+    }
+
+    pub(crate) fn deny_cidrs<I>(&mut self, cidrs: I) -> &mut Self
+    where
+        I: IntoIterator<Item = IpNet>,
+    {
+        self.deny_cidrs = cidrs.into_iter().collect();
+        self
+    }
+
+    pub(crate) fn rng(&mut self, rng: Rng) -> &mut Self {
+        self.rng = rng;
+        self
+    }
+
+    pub(crate) fn events(&mut self) -> impl Stream<Item = ServerEvent> + Send + Unpin + 'static {
+        self.events.subscribe()
+    }
+
+    #[cfg(feature = "debug_keylog")]
+    pub(crate) fn on_keys_derived(&mut self, callback: OnKeysDerived) -> &mut Self {
+        self.on_keys_derived = Some(callback);
+        self
+    }
+
     pub(crate) fn hostkeys_from_path<P: AsRef<Path>>(&mut self, file: P) -> &mut Self {
         self.hostkeys.load_from_file(file);
         self
     }
 
+    pub(crate) fn hostkeys_from_bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.hostkeys.load_from_bytes(data);
+        self
+    }
+
     pub(crate) fn hostkeys_generate(&mut self) -> &mut Self {
         self.hostkeys.generate();
         self
     }
 
     pub(crate) async fn build(&self) -> Result<Preference, SshError> {
-        let kex_algorithms = if self.kex_algorithms.is_empty() {
+        let mut kex_algorithms = if self.kex_algorithms.is_empty() {
             kex::Algorithm::defaults()
         } else {
             self.kex_algorithms.clone()
         };
+        kex_algorithms.retain(|name| !self.removed_kex_algorithms.contains(name));
 
-        let cipher_algorithms = if self.cipher_algorithms.is_empty() {
+        let mut cipher_algorithms = if self.cipher_algorithms.is_empty() {
             cipher::Algorithm::defaults()
         } else {
             self.cipher_algorithms.clone()
         };
-
-        let mac_algorithms = if self.mac_algorithms.is_empty() {
+        let mut mac_algorithms = if self.mac_algorithms.is_empty() {
             mac::Algorithm::defaults()
         } else {
             self.mac_algorithms.clone()
         };
+        // `none` is never negotiated by accident, even if added explicitly.
+        cipher_algorithms.retain(|name| name != &cipher::Algorithm::None);
+        mac_algorithms.retain(|name| name != &mac::Algorithm::None);
+        if self.insecure_allow_plaintext.unwrap_or(false) {
+            cipher_algorithms.push(cipher::Algorithm::None);
+            mac_algorithms.push(mac::Algorithm::None);
+        }
+        cipher_algorithms.retain(|name| !self.removed_cipher_algorithms.contains(name));
+        mac_algorithms.retain(|name| !self.removed_mac_algorithms.contains(name));
 
         let compression_algorithms = if self.compression_algorithms.is_empty() {
             comp::Algorithm::defaults()
@@ -89,8 +368,64 @@ impl PreferenceBuilder {
             self.compression_algorithms.clone()
         };
 
-        let name = self.name.clone().unwrap_or_else(|| "sssh".into());
+        let name = match &self.raw_identification {
+            Some(identification) => {
+                // 255 bytes including CR LF. (RFC 4253 4.2)
+                let name = identification
+                    .strip_prefix("SSH-2.0-")
+                    .filter(|_| identification.len() + 2 <= 255)
+                    .filter(|name| !name.contains(['\0', '\r', '\n']));
+                match name {
+                    Some(name) => name.to_string(),
+                    None => return Err(SshError::InvalidIdentification(identification.clone())),
+                }
+            }
+            None => self.name.clone().unwrap_or_else(|| "sssh".into()),
+        };
+        let pre_banner = self.pre_banner.clone();
+        let banner_timeout = self.banner_timeout;
+        let invalid = pre_banner
+            .iter()
+            .find(|line| line.starts_with("SSH-") || line.contains(['\r', '\n']));
+        if let Some(line) = invalid {
+            return Err(SshError::InvalidPreBanner(line.clone()));
+        }
         let timeout = self.timeout;
+        let timeout_description = self
+            .timeout_description
+            .clone()
+            .unwrap_or_else(|| "timeout".into());
+        let max_session_duration = self.max_session_duration;
+        let byte_quota = self.byte_quota;
+        let kex_timeout = self.kex_timeout;
+        let service_request_timeout = self.service_request_timeout;
+        let min_rekey_interval = self.min_rekey_interval;
+        let max_dh_group_size = self.max_dh_group_size;
+        let auth_failure_delay = self.auth_failure_delay;
+        let auth_failure_jitter = self.auth_failure_jitter;
+        let auth_policy = self.auth_policy.clone();
+        let max_total_channels = self.max_total_channels;
+        let session_window = self.session_window;
+        let direct_tcpip_window = self.direct_tcpip_window;
+        let max_pending_handshakes = self.max_pending_handshakes;
+        let tcp_user_timeout = self.tcp_user_timeout;
+        let max_user_name_length = self.max_user_name_length.unwrap_or(256);
+        let client_quirks = self.client_quirks;
+        let abort_connection_on_panic = self.abort_connection_on_panic.unwrap_or(false);
+        let lenient_channel_requests = self.lenient_channel_requests.unwrap_or(false);
+        let allow_pty_without_handler = self.allow_pty_without_handler.unwrap_or(false);
+        let motd = self.motd.clone();
+        let prefer_server_algorithms = self.prefer_server_algorithms.unwrap_or(false);
+        let allow_reauthentication = self.allow_reauthentication.unwrap_or(false);
+        let outbound_queue_len = self.outbound_queue_len.unwrap_or(64);
+        let language_tag = self.language_tag.clone().unwrap_or_default();
+        let accept_env = self.accept_env.clone();
+        let allow_cidrs = self.allow_cidrs.clone();
+        let deny_cidrs = self.deny_cidrs.clone();
+        let rng = self.rng.clone();
+        let events = self.events.clone();
+        #[cfg(feature = "debug_keylog")]
+        let on_keys_derived = self.on_keys_derived.clone();
 
         let mut hostkeys = self.hostkeys.build().await?;
         if hostkeys.names().is_empty() {
@@ -99,23 +434,57 @@ impl PreferenceBuilder {
 
         Ok(Preference {
             kex_algorithms,
-            hostkeys,
+            hostkeys: Arc::new(hostkeys),
             cipher_algorithms,
             mac_algorithms,
             compression_algorithms,
             name,
+            pre_banner,
+            banner_timeout,
             timeout,
+            timeout_description,
+            max_session_duration,
+            byte_quota,
+            kex_timeout,
+            service_request_timeout,
+            min_rekey_interval,
+            max_dh_group_size,
+            auth_failure_delay,
+            auth_failure_jitter,
+            auth_policy,
+            max_total_channels,
+            session_window,
+            direct_tcpip_window,
+            max_pending_handshakes,
+            tcp_user_timeout,
+            max_user_name_length,
+            client_quirks,
+            abort_connection_on_panic,
+            lenient_channel_requests,
+            allow_pty_without_handler,
+            motd,
+            prefer_server_algorithms,
+            allow_reauthentication,
+            outbound_queue_len,
+            language_tag,
+            accept_env,
+            allow_cidrs,
+            deny_cidrs,
+            rng,
+            events,
+            #[cfg(feature = "debug_keylog")]
+            on_keys_derived,
         })
     }
 }
 
-#[derive(Debug, Getters)]
+#[derive(Debug, Clone, Getters)]
 pub(crate) struct Preference {
     #[get = "pub(crate)"]
     kex_algorithms: Vec<kex::Algorithm>,
 
     #[get = "pub(crate)"]
-    hostkeys: HostKeys,
+    hostkeys: Arc<HostKeys>,
 
     #[get = "pub(crate)"]
     cipher_algorithms: Vec<cipher::Algorithm>,
@@ -129,20 +498,174 @@ pub(crate) struct Preference {
     #[get = "pub(crate)"]
     name: String,
 
+    #[get = "pub(crate)"]
+    pre_banner: Vec<String>,
+
+    #[get = "pub(crate)"]
+    banner_timeout: Option<Duration>,
+
     #[get = "pub(crate)"]
     timeout: Option<Duration>,
+
+    #[get = "pub(crate)"]
+    timeout_description: String,
+
+    #[get = "pub(crate)"]
+    max_session_duration: Option<Duration>,
+
+    #[get = "pub(crate)"]
+    byte_quota: Option<u64>,
+
+    #[get = "pub(crate)"]
+    kex_timeout: Option<Duration>,
+
+    #[get = "pub(crate)"]
+    service_request_timeout: Option<Duration>,
+
+    #[get = "pub(crate)"]
+    min_rekey_interval: Option<Duration>,
+
+    #[get = "pub(crate)"]
+    max_dh_group_size: Option<u32>,
+
+    #[get = "pub(crate)"]
+    auth_failure_delay: Option<Duration>,
+
+    #[get = "pub(crate)"]
+    auth_failure_jitter: Option<Duration>,
+
+    #[get = "pub(crate)"]
+    auth_policy: Option<AuthPolicy>,
+
+    #[get = "pub(crate)"]
+    max_total_channels: Option<u32>,
+
+    #[get = "pub(crate)"]
+    session_window: Option<(u32, u32)>,
+
+    #[get = "pub(crate)"]
+    direct_tcpip_window: Option<(u32, u32)>,
+
+    #[get = "pub(crate)"]
+    max_pending_handshakes: Option<usize>,
+
+    #[get = "pub(crate)"]
+    tcp_user_timeout: Option<Duration>,
+
+    #[get = "pub(crate)"]
+    max_user_name_length: usize,
+
+    client_quirks: Option<fn(&str) -> QuirkSet>,
+
+    #[get = "pub(crate)"]
+    abort_connection_on_panic: bool,
+
+    #[get = "pub(crate)"]
+    lenient_channel_requests: bool,
+
+    #[get = "pub(crate)"]
+    allow_pty_without_handler: bool,
+
+    #[get = "pub(crate)"]
+    motd: Option<String>,
+
+    #[get = "pub(crate)"]
+    prefer_server_algorithms: bool,
+
+    #[get = "pub(crate)"]
+    allow_reauthentication: bool,
+
+    #[get = "pub(crate)"]
+    outbound_queue_len: usize,
+
+    #[get = "pub(crate)"]
+    language_tag: String,
+
+    accept_env: Vec<String>,
+
+    allow_cidrs: Vec<IpNet>,
+
+    deny_cidrs: Vec<IpNet>,
+
+    #[get = "pub(crate)"]
+    rng: Rng,
+
+    #[get = "pub(crate)"]
+    events: EventSender,
+
+    #[cfg(feature = "debug_keylog")]
+    #[get = "pub(crate)"]
+    on_keys_derived: Option<OnKeysDerived>,
 }
 
-fn generate_cookie() -> u128 {
-    use ring::rand::{SecureRandom as _, SystemRandom};
+fn generate_cookie(rng: &Rng) -> u128 {
     let mut cookie = 0u128.to_ne_bytes();
-    SystemRandom::new().fill(&mut cookie).unwrap();
+    rng.fill(&mut cookie).unwrap();
     u128::from_ne_bytes(cookie)
 }
 
+/// Match `text` against `pattern` with `*` and `?`. (case sensitive)
+fn env_pattern_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.split_first(), text.split_first()) {
+        (None, _) => text.is_empty(),
+        (Some((b'*', rest)), _) => (0..=text.len()).any(|n| env_pattern_match(rest, &text[n..])),
+        (Some((b'?', rest)), Some((_, text))) => env_pattern_match(rest, text),
+        (Some((p, rest)), Some((c, text))) if p == c => env_pattern_match(rest, text),
+        _ => false,
+    }
+}
+
 impl Preference {
+    /// Whether environment variable `name` requested by the client is accepted.
+    pub(crate) fn accepts_env(&self, name: &str) -> bool {
+        self.accept_env
+            .iter()
+            .any(|pattern| env_pattern_match(pattern.as_bytes(), name.as_bytes()))
+    }
+
+    /// Test remote address against allow / deny CIDR list.
+    ///
+    /// Deny list takes precedence. Empty allow list permits any address.
+    pub(crate) fn permits(&self, addr: &IpAddr) -> bool {
+        if self.deny_cidrs.iter().any(|net| net.contains(addr)) {
+            return false;
+        }
+        self.allow_cidrs.is_empty() || self.allow_cidrs.iter().any(|net| net.contains(addr))
+    }
+
+    /// Whether allow / deny CIDR list is configured.
+    pub(crate) fn restricts_address(&self) -> bool {
+        !self.allow_cidrs.is_empty() || !self.deny_cidrs.is_empty()
+    }
+
+    /// Workarounds for the client identified by `c_version`.
+    pub(crate) fn quirks_for(&self, c_version: &str) -> QuirkSet {
+        self.client_quirks
+            .map(|quirks| quirks(c_version))
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn cipher_algorithms_for(&self, quirks: &QuirkSet) -> Vec<cipher::Algorithm> {
+        let mut names = self.cipher_algorithms.clone();
+        names.retain(|name| !quirks.disabled_ciphers().contains(name));
+        names
+    }
+
+    pub(crate) fn mac_algorithms_for(&self, quirks: &QuirkSet) -> Vec<mac::Algorithm> {
+        let mut names = self.mac_algorithms.clone();
+        names.retain(|name| !quirks.disabled_macs().contains(name));
+        names
+    }
+
+    #[cfg(test)]
     pub(crate) fn to_kexinit(&self) -> Kexinit {
-        let cookie = generate_cookie();
+        self.to_kexinit_for(&QuirkSet::default())
+    }
+
+    pub(crate) fn to_kexinit_for(&self, quirks: &QuirkSet) -> Kexinit {
+        let cookie = generate_cookie(&self.rng);
+        let cipher_algorithms = self.cipher_algorithms_for(quirks);
+        let mac_algorithms = self.mac_algorithms_for(quirks);
 
         KexinitBuilder::default()
             .cookie(cookie)
@@ -160,25 +683,25 @@ impl Preference {
                     .collect(),
             )
             .cipher_algorithms_c2s(
-                self.cipher_algorithms
+                cipher_algorithms
                     .iter()
                     .map(AlgorithmName::to_string)
                     .collect(),
             )
             .cipher_algorithms_s2c(
-                self.cipher_algorithms
+                cipher_algorithms
                     .iter()
                     .map(AlgorithmName::to_string)
                     .collect(),
             )
             .mac_algorithms_c2s(
-                self.mac_algorithms
+                mac_algorithms
                     .iter()
                     .map(AlgorithmName::to_string)
                     .collect(),
             )
             .mac_algorithms_s2c(
-                self.mac_algorithms
+                mac_algorithms
                     .iter()
                     .map(AlgorithmName::to_string)
                     .collect(),
@@ -197,8 +720,189 @@ impl Preference {
             )
             .languages_c2s(Vec::<String>::new().into_iter().collect())
             .languages_s2c(Vec::<String>::new().into_iter().collect())
+            // Every supported key exchange starts with a client packet (`*_INIT` / `*_REQUEST`),
+            // so the server never has a guessed packet to send.
             .first_kex_packet_follows(false)
             .build()
             .unwrap()
     }
+
+    /// Same preference with `hostkeys` replaced.
+    pub(crate) fn with_hostkeys(&self, hostkeys: HostKeys) -> Self {
+        Self {
+            hostkeys: Arc::new(hostkeys),
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_permits() {
+        let preference = PreferenceBuilder::default()
+            .allow_cidrs(vec!["10.0.0.0/8".parse().unwrap()])
+            .deny_cidrs(vec!["10.1.0.0/16".parse().unwrap()])
+            .build()
+            .await
+            .unwrap();
+
+        assert!(preference.permits(&"10.0.0.1".parse().unwrap()));
+        assert!(!preference.permits(&"10.1.0.1".parse().unwrap()));
+        assert!(!preference.permits(&"127.0.0.1".parse().unwrap()));
+
+        let preference = PreferenceBuilder::default().build().await.unwrap();
+        assert!(preference.permits(&"127.0.0.1".parse().unwrap()));
+        assert!(preference.permits(&"::1".parse().unwrap()));
+
+        // set again replaces, not merges.
+        let preference = PreferenceBuilder::default()
+            .allow_cidrs(vec!["10.0.0.0/8".parse().unwrap()])
+            .allow_cidrs(vec!["192.168.0.0/16".parse().unwrap()])
+            .build()
+            .await
+            .unwrap();
+        assert!(!preference.permits(&"10.0.0.1".parse().unwrap()));
+        assert!(preference.permits(&"192.168.0.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_accept_env() {
+        let preference = PreferenceBuilder::default().build().await.unwrap();
+        assert!(!preference.accepts_env("LANG"));
+
+        let preference = PreferenceBuilder::default()
+            .accept_env(vec!["LANG", "LC_*"])
+            .build()
+            .await
+            .unwrap();
+        assert!(preference.accepts_env("LANG"));
+        assert!(preference.accepts_env("LC_ALL"));
+        assert!(!preference.accepts_env("lang"));
+        assert!(!preference.accepts_env("LD_PRELOAD"));
+    }
+
+    #[tokio::test]
+    async fn test_disable_weak_algorithms() {
+        let kexinit = PreferenceBuilder::default()
+            .disable_weak_algorithms()
+            .build()
+            .await
+            .unwrap()
+            .to_kexinit();
+
+        let kex_algorithms = kexinit
+            .kex_algorithms()
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        assert!(!kex_algorithms.contains(&"diffie-hellman-group1-sha1"));
+        assert!(!kex_algorithms.contains(&"diffie-hellman-group-exchange-sha1"));
+        assert!(kex_algorithms.contains(&"curve25519-sha256"));
+        for macs in &[kexinit.mac_algorithms_c2s(), kexinit.mac_algorithms_s2c()] {
+            let macs = macs.iter().map(String::as_str).collect::<Vec<_>>();
+            assert!(!macs.contains(&"hmac-sha1"));
+            assert!(macs.contains(&"hmac-sha2-256"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_seeded_rng_cookie() {
+        use rand_chacha::rand_core::SeedableRng as _;
+        use rand_chacha::ChaCha20Rng;
+
+        let mut cookies = vec![];
+        for _ in 0..2 {
+            let preference = PreferenceBuilder::default()
+                .rng(Rng::new(ChaCha20Rng::seed_from_u64(1)))
+                .build()
+                .await
+                .unwrap();
+            cookies.push(*preference.to_kexinit().cookie());
+        }
+        assert_eq!(cookies[0], cookies[1]);
+
+        let preference = PreferenceBuilder::default().build().await.unwrap();
+        assert_ne!(*preference.to_kexinit().cookie(), cookies[0]);
+    }
+
+    #[tokio::test]
+    async fn test_random_source() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use crate::rng::{RandomSource, SystemRandomSource};
+
+        let preference = PreferenceBuilder::default().build().await.unwrap();
+        assert_ne!(
+            *preference.to_kexinit().cookie(),
+            *preference.to_kexinit().cookie()
+        );
+
+        struct Counting(SystemRandomSource, Arc<AtomicUsize>);
+
+        impl RandomSource for Counting {
+            fn fill(&self, dest: &mut [u8]) -> Result<(), SshError> {
+                self.1.fetch_add(dest.len(), Ordering::SeqCst);
+                self.0.fill(dest)
+            }
+        }
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let source = Counting(SystemRandomSource, count.clone());
+        let preference = PreferenceBuilder::default()
+            .rng(Rng::from_source(source))
+            .build()
+            .await
+            .unwrap();
+        let (first, second) = (preference.to_kexinit(), preference.to_kexinit());
+        assert_ne!(first.cookie(), second.cookie());
+        assert_eq!(count.load(Ordering::SeqCst), 32);
+    }
+
+    #[tokio::test]
+    async fn test_client_quirks() {
+        let preference = PreferenceBuilder::default()
+            .client_quirks(|version| {
+                let mut quirks = QuirkSet::new();
+                if version.starts_with("SSH-2.0-BuggySSH") {
+                    quirks.disable_cipher(cipher::Algorithm::Aes128Ctr);
+                }
+                quirks
+            })
+            .build()
+            .await
+            .unwrap();
+
+        let quirks = preference.quirks_for("SSH-2.0-BuggySSH_1.0");
+        assert_eq!(quirks.disabled_ciphers(), &[cipher::Algorithm::Aes128Ctr]);
+        let kexinit = preference.to_kexinit_for(&quirks);
+        let names = kexinit
+            .cipher_algorithms_c2s()
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        assert!(!names.contains(&"aes128-ctr"));
+        assert!(names.contains(&"aes256-ctr"));
+
+        let quirks = preference.quirks_for("SSH-2.0-OpenSSH_8.4");
+        assert_eq!(quirks, QuirkSet::default());
+        let kexinit = preference.to_kexinit_for(&quirks);
+        let names = kexinit
+            .cipher_algorithms_s2c()
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        assert!(names.contains(&"aes128-ctr"));
+
+        assert_eq!(
+            PreferenceBuilder::default()
+                .build()
+                .await
+                .unwrap()
+                .quirks_for("SSH-2.0-BuggySSH_1.0"),
+            QuirkSet::default()
+        );
+    }
 }
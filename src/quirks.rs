@@ -0,0 +1,53 @@
+//! Client specific workarounds.
+
+use crate::cipher;
+use crate::mac;
+
+/// Workarounds applied to a connection, decided from the client identification string.
+///
+/// # Example
+///
+/// ```
+/// use ssssh::{Cipher, QuirkSet, ServerBuilder};
+///
+/// let mut builder = ServerBuilder::default();
+/// builder.client_quirks(|version| {
+///     let mut quirks = QuirkSet::new();
+///     if version.starts_with("SSH-2.0-BuggySSH") {
+///         quirks.disable_cipher(Cipher::Aes256Ctr);
+///     }
+///     quirks
+/// });
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QuirkSet {
+    disabled_ciphers: Vec<cipher::Algorithm>,
+    disabled_macs: Vec<mac::Algorithm>,
+}
+
+impl QuirkSet {
+    /// No workaround.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Neither offer nor accept cipher `name` for this client.
+    pub fn disable_cipher(&mut self, name: cipher::Algorithm) -> &mut Self {
+        self.disabled_ciphers.push(name);
+        self
+    }
+
+    /// Neither offer nor accept MAC `name` for this client.
+    pub fn disable_mac(&mut self, name: mac::Algorithm) -> &mut Self {
+        self.disabled_macs.push(name);
+        self
+    }
+
+    pub(crate) fn disabled_ciphers(&self) -> &[cipher::Algorithm] {
+        &self.disabled_ciphers
+    }
+
+    pub(crate) fn disabled_macs(&self) -> &[mac::Algorithm] {
+        &self.disabled_macs
+    }
+}
@@ -0,0 +1,75 @@
+//! Random number source for padding and KEXINIT cookie.
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use rand_core::{CryptoRng, RngCore};
+use ring::rand::{SecureRandom as _, SystemRandom};
+
+use crate::SshError;
+
+/// Source of random bytes for packet padding and KEXINIT cookie.
+///
+/// Must be cryptographically secure. Wrap [`SystemRandomSource`] to monitor the default source.
+pub trait RandomSource: Send + Sync {
+    /// Fill `dest` with random bytes.
+    fn fill(&self, dest: &mut [u8]) -> Result<(), SshError>;
+}
+
+/// Operating system CSPRNG. (default)
+#[derive(Debug, Clone, Default)]
+pub struct SystemRandomSource;
+
+impl RandomSource for SystemRandomSource {
+    fn fill(&self, dest: &mut [u8]) -> Result<(), SshError> {
+        SystemRandom::new().fill(dest).map_err(SshError::any)
+    }
+}
+
+/// [`RandomSource`] backed by [`RngCore`].
+struct CryptoRngSource<R>(Mutex<R>);
+
+impl<R> RandomSource for CryptoRngSource<R>
+where
+    R: RngCore + CryptoRng + Send,
+{
+    fn fill(&self, dest: &mut [u8]) -> Result<(), SshError> {
+        self.0.lock().unwrap().fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Injected random source or system RNG (default).
+#[derive(Clone, Default)]
+pub(crate) struct Rng(Option<Arc<dyn RandomSource>>);
+
+impl Rng {
+    pub(crate) fn new<R>(rng: R) -> Self
+    where
+        R: RngCore + CryptoRng + Send + 'static,
+    {
+        Self::from_source(CryptoRngSource(Mutex::new(rng)))
+    }
+
+    pub(crate) fn from_source<S>(source: S) -> Self
+    where
+        S: RandomSource + 'static,
+    {
+        Self(Some(Arc::new(source)))
+    }
+
+    pub(crate) fn fill(&self, dest: &mut [u8]) -> Result<(), SshError> {
+        match &self.0 {
+            Some(source) => source.fill(dest),
+            None => SystemRandomSource.fill(dest),
+        }
+    }
+}
+
+impl fmt::Debug for Rng {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(..) => f.write_str("Rng(Injected)"),
+            None => f.write_str("Rng(System)"),
+        }
+    }
+}
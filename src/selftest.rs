@@ -0,0 +1,184 @@
+//! Known-answer tests for ciphers and MACs. (`fips_selftest` feature)
+#[cfg(test)]
+use std::cell::RefCell;
+use std::sync::OnceLock;
+
+use bytes::Bytes;
+
+use crate::cipher::{Algorithm as CipherAlgorithm, Cipher};
+use crate::mac::{Algorithm as MacAlgorithm, Mac};
+use crate::SshError;
+
+/// Cipher test vector. (key, counter block, plain text, cipher text)
+pub(crate) type CipherVector = (
+    CipherAlgorithm,
+    &'static str,
+    &'static str,
+    &'static str,
+    &'static str,
+);
+
+/// MAC test vector. (key, data, tag)
+///
+/// First 4 bytes of data are used as the sequence number.
+pub(crate) type MacVector = (MacAlgorithm, &'static str, &'static str, &'static str);
+
+/// [RFC 3686](https://tools.ietf.org/html/rfc3686#section-6) test vectors #1, #4, #7.
+pub(crate) const CIPHER_VECTORS: &[CipherVector] = &[
+    (
+        CipherAlgorithm::Aes128Ctr,
+        "ae6852f8121067cc4bf7a5765577f39e",
+        "00000030000000000000000000000001",
+        "53696e676c6520626c6f636b206d7367",
+        "e4095d4fb7a7b3792d6175a3261311b8",
+    ),
+    (
+        CipherAlgorithm::Aes192Ctr,
+        "16af5b145fc9f579c175f93e3bfb0eed863d06ccfdb78515",
+        "0000004836733c147d6d93cb00000001",
+        "53696e676c6520626c6f636b206d7367",
+        "4b55384fe259c9c84e7935a003cbe928",
+    ),
+    (
+        CipherAlgorithm::Aes256Ctr,
+        "776beff2851db06f4c8a0542c8696f6c6a81af1eec96b4d37fc1d689e6c1c104",
+        "00000060db5672c97aa8f0b200000001",
+        "53696e676c6520626c6f636b206d7367",
+        "145ad01dbf824ec7560863dc71e3e0c0",
+    ),
+];
+
+/// [RFC 4231](https://tools.ietf.org/html/rfc4231#section-4.2) and
+/// [RFC 2202](https://tools.ietf.org/html/rfc2202#section-3) test case 1.
+pub(crate) const MAC_VECTORS: &[MacVector] = &[
+    (
+        MacAlgorithm::HmacSha256,
+        "0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b",
+        "4869205468657265",
+        "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+    ),
+    (
+        MacAlgorithm::HmacSha512,
+        "0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b",
+        "4869205468657265",
+        "87aa7cdea5ef619d4ff0b4241a1d6cb02379f4e2ce4ec2787ad0b30545e17cde\
+         daa833b7d6b8a702038b274eaea3f4e4be9d914eeb61f1702e696c203a126854",
+    ),
+    (
+        MacAlgorithm::HmacSha1,
+        "0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b",
+        "4869205468657265",
+        "b617318655057264e28bc0b6fb378c8ef146be00",
+    ),
+];
+
+fn unhex(s: &str) -> Bytes {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn failed<A: AsRef<str>>(name: &A) -> SshError {
+    SshError::SelfTestFailed(name.as_ref().into())
+}
+
+/// Run known-answer tests against given vectors.
+pub(crate) fn run_with(ciphers: &[CipherVector], macs: &[MacVector]) -> Result<(), SshError> {
+    for (name, key, iv, plain, expect) in ciphers {
+        let (key, iv) = (unhex(key), unhex(iv));
+        let mut buf = unhex(plain).to_vec();
+        Cipher::new_for_encrypt(name, &key, &iv)?.update(&mut buf)?;
+        if buf != unhex(expect) {
+            return Err(failed(name));
+        }
+        Cipher::new_for_decrypt(name, &key, &iv)?.update(&mut buf)?;
+        if buf != unhex(plain) {
+            return Err(failed(name));
+        }
+    }
+
+    for (name, key, data, expect) in macs {
+        let data = unhex(data);
+        let (seq, data) = data.split_at(4);
+        let seq = u32::from_be_bytes([seq[0], seq[1], seq[2], seq[3]]);
+        let tag = Mac::new(name, &unhex(key)).sign(seq, data)?;
+        if tag != unhex(expect) {
+            return Err(failed(name));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Vectors used instead of the built-in ones, bypassing the cache.
+    static OVERRIDE: RefCell<Option<(Vec<CipherVector>, Vec<MacVector>)>> = RefCell::new(None);
+}
+
+/// Run known-answer tests once per process.
+pub(crate) fn run() -> Result<(), SshError> {
+    #[cfg(test)]
+    {
+        let result = OVERRIDE.with(|o| o.borrow().as_ref().map(|(c, m)| run_with(c, m)));
+        if let Some(result) = result {
+            return result;
+        }
+    }
+
+    static RESULT: OnceLock<Result<(), String>> = OnceLock::new();
+
+    let result = RESULT.get_or_init(|| {
+        run_with(CIPHER_VECTORS, MAC_VECTORS).map_err(|e| {
+            log::error!("{}", e);
+            e.to_string()
+        })
+    });
+    result.clone().map_err(SshError::SelfTestFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selftest() {
+        run().unwrap();
+    }
+
+    #[test]
+    fn test_tampered_cipher_vector() {
+        let mut ciphers = CIPHER_VECTORS.to_vec();
+        ciphers[0].4 = "e4095d4fb7a7b3792d6175a3261311b9";
+        assert!(matches!(
+            run_with(&ciphers, MAC_VECTORS),
+            Err(SshError::SelfTestFailed(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_build_fails_on_tampered_vector() {
+        use crate::server::BuildError;
+
+        let mut ciphers = CIPHER_VECTORS.to_vec();
+        ciphers[1].4 = "4b55384fe259c9c84e7935a003cbe929";
+        OVERRIDE.with(|o| *o.borrow_mut() = Some((ciphers, MAC_VECTORS.to_vec())));
+        let result = crate::ServerBuilder::default().build("127.0.0.1:0").await;
+        OVERRIDE.with(|o| *o.borrow_mut() = None);
+
+        assert!(matches!(
+            result,
+            Err(BuildError::SshError(SshError::SelfTestFailed(..)))
+        ));
+    }
+
+    #[test]
+    fn test_tampered_mac_vector() {
+        let mut macs = MAC_VECTORS.to_vec();
+        macs[0].2 = "4869205468657266";
+        assert!(matches!(
+            run_with(CIPHER_VECTORS, &macs),
+            Err(SshError::SelfTestFailed(..))
+        ));
+    }
+}
@@ -1,19 +1,34 @@
 use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::os::unix::io::{FromRawFd, RawFd};
 use std::path::Path;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 use std::task::{Context, Poll};
 use std::time::Duration;
 
 use futures::ready;
+use futures::stream::TryStreamExt;
+use futures::task::AtomicWaker;
+use ipnet::IpNet;
+use log::debug;
+use rand_core::{CryptoRng, RngCore};
 use thiserror::Error;
 use tokio::io;
-use tokio::net::{lookup_host, TcpListener, TcpStream, ToSocketAddrs};
+use tokio::net::{lookup_host, TcpListener, TcpSocket, TcpStream, ToSocketAddrs};
 use tokio_stream::wrappers::TcpListenerStream;
 use tokio_stream::Stream;
 
+use crate::auth_policy::AuthPolicy;
 use crate::connection::{Accept, Connection};
+use crate::events::ServerEvent;
+use crate::hostkey::HostKeys;
+#[cfg(feature = "debug_keylog")]
+use crate::keylog::{OnKeysDerived, SessionKeys};
 use crate::preference::{Preference, PreferenceBuilder};
+use crate::quirks::QuirkSet;
+use crate::rng::{RandomSource, Rng};
 use crate::SshError;
 
 #[derive(Debug, Error)]
@@ -32,6 +47,8 @@ pub enum BuildError {
 #[derive(Debug, Default)]
 pub struct Builder {
     preference: PreferenceBuilder,
+    reuse_address: Option<bool>,
+    reuse_port: Option<bool>,
 }
 
 impl Builder {
@@ -55,16 +72,89 @@ impl Builder {
         self
     }
 
+    /// Exclude key exchange algorithm from defaults or added ones.
+    pub fn remove_kex(&mut self, name: crate::Kex) -> &mut Self {
+        self.preference.remove_kex_algorithm(name);
+        self
+    }
+
+    /// Exclude cipher algorithm from defaults or added ones.
+    pub fn remove_cipher(&mut self, name: crate::Cipher) -> &mut Self {
+        self.preference.remove_cipher_algorithm(name);
+        self
+    }
+
+    /// Exclude MAC algorithm from defaults or added ones.
+    pub fn remove_mac(&mut self, name: crate::Mac) -> &mut Self {
+        self.preference.remove_mac_algorithm(name);
+        self
+    }
+
+    /// Exclude SHA-1 based key exchange and MAC algorithms, and `diffie-hellman-group1-sha1`.
+    pub fn disable_weak_algorithms(&mut self) -> &mut Self {
+        self.preference.disable_weak_algorithms();
+        self
+    }
+
+    /// Choose cipher and MAC algorithms in server preference order. (default: `false`)
+    ///
+    /// Otherwise the first algorithm of the client which the server supports is chosen.
+    pub fn prefer_server_algorithms(&mut self, prefer: bool) -> &mut Self {
+        self.preference.prefer_server_algorithms(prefer);
+        self
+    }
+
+    /// Allow `none` cipher and MAC, for protocol conformance testing only.
+    ///
+    /// Traffic including passwords is sent in plaintext if the client offers `none`.
+    /// Every connection negotiated so is logged as a warning.
+    /// Otherwise `none` is never negotiated, even if added by
+    /// [`add_cipher_algorithm`](Self::add_cipher_algorithm).
+    pub fn insecure_allow_plaintext_for_testing(&mut self) -> &mut Self {
+        self.preference.insecure_allow_plaintext_for_testing();
+        self
+    }
+
     pub fn name(&mut self, name: &str) -> &mut Self {
         self.preference.name(name);
         self
     }
 
+    /// Send `identification` verbatim as the identification string, instead of composing it
+    /// from [`name`](Self::name). (RFC 4253 4.2)
+    ///
+    /// Advanced: meant for conformance tests against clients. Must start with `SSH-2.0-`,
+    /// be at most 255 bytes including CR LF, and contain no NUL / CR / LF.
+    pub fn raw_identification(&mut self, identification: String) -> &mut Self {
+        self.preference.raw_identification(identification);
+        self
+    }
+
+    /// Lines sent before the identification string. (RFC 4253 4.2)
+    ///
+    /// Lines must not start with `SSH-` nor contain CR / LF.
+    pub fn pre_banner(&mut self, lines: Vec<String>) -> &mut Self {
+        self.preference.pre_banner(lines);
+        self
+    }
+
+    /// Disconnect when the client identification string is not received within this duration.
+    pub fn banner_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.preference.banner_timeout(timeout);
+        self
+    }
+
     pub fn hostkeys_from_path<P: AsRef<Path>>(&mut self, file: P) -> &mut Self {
         self.preference.hostkeys_from_path(file);
         self
     }
 
+    /// Load host keys from content of openssh private key file. (e.g. fetched from secret store)
+    pub fn hostkeys_from_bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.preference.hostkeys_from_bytes(data);
+        self
+    }
+
     pub fn generate_hostkeys(&mut self) -> &mut Self {
         self.preference.hostkeys_generate();
         self
@@ -75,6 +165,288 @@ impl Builder {
         self
     }
 
+    /// Description of disconnect message sent on idle timeout. (default: `"timeout"`)
+    pub fn timeout_description(&mut self, description: &str) -> &mut Self {
+        self.preference.timeout_description(description);
+        self
+    }
+
+    /// Disconnect session when elapsed this duration regardless of activity.
+    pub fn max_session_duration(&mut self, duration: Duration) -> &mut Self {
+        self.preference.max_session_duration(duration);
+        self
+    }
+
+    /// Disconnect when bytes sent and received over the connection exceed this.
+    ///
+    /// Counts packets on the wire, including key exchange.
+    pub fn byte_quota(&mut self, quota: u64) -> &mut Self {
+        self.preference.byte_quota(quota);
+        self
+    }
+
+    /// Disconnect when key exchange is not completed within this duration.
+    ///
+    /// Measured from sending `SSH_MSG_KEXINIT` to receiving `SSH_MSG_NEWKEYS`.
+    pub fn kex_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.preference.kex_timeout(timeout);
+        self
+    }
+
+    /// Disconnect when `SSH_MSG_SERVICE_REQUEST` is not received within this duration.
+    ///
+    /// Measured from the end of the first key exchange. Usually shorter than
+    /// [`timeout`](Self::timeout), to drop clients idling before authentication.
+    pub fn service_request_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.preference.service_request_timeout(timeout);
+        self
+    }
+
+    /// Disconnect when the client starts a key exchange earlier than this after the last one.
+    pub fn min_rekey_interval(&mut self, interval: Duration) -> &mut Self {
+        self.preference.min_rekey_interval(interval);
+        self
+    }
+
+    /// Largest group selected by Diffie-Hellman group exchange, in bits. (default: `8192`)
+    ///
+    /// Larger requests are downgraded, or refused when the client's minimum exceeds this.
+    /// Combine with [`min_rekey_interval`](Self::min_rekey_interval) to limit how often
+    /// a client can make the server generate one.
+    pub fn max_dh_group_size(&mut self, bits: u32) -> &mut Self {
+        self.preference.max_dh_group_size(bits);
+        self
+    }
+
+    /// Wait this duration before sending each `SSH_MSG_USERAUTH_FAILURE`.
+    ///
+    /// Slows online password guessing. Only the connection itself waits.
+    pub fn auth_failure_delay(&mut self, delay: Duration) -> &mut Self {
+        self.preference.auth_failure_delay(delay);
+        self
+    }
+
+    /// Add random duration up to this to [`auth_failure_delay`](Self::auth_failure_delay).
+    pub fn auth_failure_jitter(&mut self, jitter: Duration) -> &mut Self {
+        self.preference.auth_failure_jitter(jitter);
+        self
+    }
+
+    /// Methods required to complete user authentication. (default: any single method)
+    ///
+    /// e.g. publickey and then password, by `AuthPolicy::all`.
+    pub fn auth_policy(&mut self, policy: AuthPolicy) -> &mut Self {
+        self.preference.auth_policy(policy);
+        self
+    }
+
+    /// Disconnect when the client opens more channels than this over the connection lifetime.
+    pub fn max_total_channels(&mut self, max: u32) -> &mut Self {
+        self.preference.max_total_channels(max);
+        self
+    }
+
+    /// Initial window size and maximum packet size advertised for session channels.
+    /// (default: same as the client's)
+    ///
+    /// e.g. small window for interactive sessions.
+    pub fn session_window(&mut self, window_size: u32, maximum_packet_size: u32) -> &mut Self {
+        self.preference
+            .session_window(window_size, maximum_packet_size);
+        self
+    }
+
+    /// Initial window size and maximum packet size advertised for direct-tcpip channels.
+    /// (default: same as the client's)
+    ///
+    /// e.g. large window for bulk transfer.
+    pub fn direct_tcpip_window(&mut self, window_size: u32, maximum_packet_size: u32) -> &mut Self {
+        self.preference
+            .direct_tcpip_window(window_size, maximum_packet_size);
+        self
+    }
+
+    /// Stop accepting while this many connections are before version exchange completed.
+    ///
+    /// Connections hold the slot until [`Connection::accept`] completes or they are dropped.
+    pub fn max_pending_handshakes(&mut self, max: usize) -> &mut Self {
+        self.preference.max_pending_handshakes(max);
+        self
+    }
+
+    /// Set `TCP_USER_TIMEOUT` of accepted connections. Linux only, ignored elsewhere.
+    ///
+    /// The kernel drops the connection when sent data stays unacknowledged for this duration,
+    /// detecting dead peers much earlier than `SO_KEEPALIVE`.
+    pub fn tcp_user_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.preference.tcp_user_timeout(timeout);
+        self
+    }
+
+    /// Fail user authentication requests with a longer user name or service name. (default: `256`)
+    ///
+    /// Names containing control characters are always rejected.
+    pub fn max_user_name_length(&mut self, len: usize) -> &mut Self {
+        self.preference.max_user_name_length(len);
+        self
+    }
+
+    /// Decide workarounds from the client identification string, after version exchange.
+    ///
+    /// See [`QuirkSet`].
+    pub fn client_quirks(&mut self, quirks: fn(&str) -> QuirkSet) -> &mut Self {
+        self.preference.client_quirks(quirks);
+        self
+    }
+
+    /// Disconnect when a handler panics. (default: `false`)
+    ///
+    /// Otherwise only the channel is closed, with exit status 255.
+    pub fn abort_connection_on_panic(&mut self, abort: bool) -> &mut Self {
+        self.preference.abort_connection_on_panic(abort);
+        self
+    }
+
+    /// Reply success to unknown channel requests. (default: `false`)
+    pub fn lenient_channel_requests(&mut self, lenient: bool) -> &mut Self {
+        self.preference.lenient_channel_requests(lenient);
+        self
+    }
+
+    /// Reply success to `pty-req` without pty request handler. (default: `false`)
+    ///
+    /// Some clients abort on failure instead of continuing without pty.
+    /// The session then starts with [`SessionContext::term`](crate::SessionContext::term) but no pty.
+    pub fn allow_pty_without_handler(&mut self, allow: bool) -> &mut Self {
+        self.preference.allow_pty_without_handler(allow);
+        self
+    }
+
+    /// Message written to stdout of each `shell` session, before any output of the handler.
+    ///
+    /// Not written for `exec` and `subsystem`.
+    pub fn motd(&mut self, motd: &str) -> &mut Self {
+        self.preference.motd(motd.to_string());
+        self
+    }
+
+    /// Process user authentication requests after success. (default: `false`)
+    ///
+    /// Otherwise such requests are ignored. Results are passed to
+    /// [`Handlers::on_reauth`](crate::Handlers::on_reauth).
+    pub fn allow_reauthentication(&mut self, allow: bool) -> &mut Self {
+        self.preference.allow_reauthentication(allow);
+        self
+    }
+
+    /// Number of outbound messages queued before handler output blocks. (default: `64`)
+    pub fn outbound_queue_len(&mut self, len: usize) -> &mut Self {
+        self.preference.outbound_queue_len(len);
+        self
+    }
+
+    /// Language tag of human readable messages. (default: `""`)
+    pub fn language_tag(&mut self, tag: &str) -> &mut Self {
+        self.preference.language_tag(tag);
+        self
+    }
+
+    /// Accept `env` requests only for variables matching these patterns. (default: none)
+    ///
+    /// Patterns may contain `*` and `?`, as `AcceptEnv` of OpenSSH. Requests for other
+    /// variables fail, and are not seen by handlers nor [`spawn_process`](crate::util::spawn_process).
+    pub fn accept_env<I, S>(&mut self, patterns: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.preference.accept_env(patterns);
+        self
+    }
+
+    /// Stream of lifecycle events of every connection of servers built after this.
+    ///
+    /// Events are dropped for a subscriber which falls too far behind.
+    pub fn events(&mut self) -> impl Stream<Item = ServerEvent> + Send + Unpin + 'static {
+        self.preference.events()
+    }
+
+    /// Use `rng` for packet padding and KEXINIT cookie instead of the system RNG.
+    ///
+    /// Intended for reproducible tests.
+    pub fn rng<R>(&mut self, rng: R) -> &mut Self
+    where
+        R: RngCore + CryptoRng + Send + 'static,
+    {
+        self.preference.rng(Rng::new(rng));
+        self
+    }
+
+    /// Use `source` for packet padding and KEXINIT cookie instead of the system RNG.
+    ///
+    /// e.g. to audit or monitor the entropy source.
+    pub fn random_source<S>(&mut self, source: S) -> &mut Self
+    where
+        S: RandomSource + 'static,
+    {
+        self.preference.rng(Rng::from_source(source));
+        self
+    }
+
+    /// Call `callback` with the derived session keys after every key exchange.
+    /// (`debug_keylog` feature)
+    ///
+    /// # Danger
+    ///
+    /// The keys allow anyone to decrypt and forge the session traffic.
+    /// Only for protocol bridges and debugging; never enable in production.
+    #[cfg(feature = "debug_keylog")]
+    pub fn on_keys_derived<F>(&mut self, callback: F) -> &mut Self
+    where
+        F: Fn(&SessionKeys) + Send + Sync + 'static,
+    {
+        self.preference
+            .on_keys_derived(OnKeysDerived::new(callback));
+        self
+    }
+
+    /// Accept connections only from these networks. Replaces networks set before.
+    ///
+    /// Networks are [`IpNet`](https://docs.rs/ipnet/2/ipnet/enum.IpNet.html) of the `ipnet`
+    /// crate, version 2. If not specified, any address is accepted unless denied by
+    /// [`Builder::deny_cidrs`].
+    pub fn allow_cidrs<I>(&mut self, cidrs: I) -> &mut Self
+    where
+        I: IntoIterator<Item = IpNet>,
+    {
+        self.preference.allow_cidrs(cidrs);
+        self
+    }
+
+    /// Drop connections from these networks before version exchange.
+    /// Replaces networks set before.
+    ///
+    /// See [`Builder::allow_cidrs`].
+    pub fn deny_cidrs<I>(&mut self, cidrs: I) -> &mut Self
+    where
+        I: IntoIterator<Item = IpNet>,
+    {
+        self.preference.deny_cidrs(cidrs);
+        self
+    }
+
+    /// Set `SO_REUSEADDR` on the listening socket. (default: `true`)
+    pub fn reuse_address(&mut self, reuse: bool) -> &mut Self {
+        self.reuse_address = Some(reuse);
+        self
+    }
+
+    /// Set `SO_REUSEPORT` on the listening socket. (default: `false`)
+    pub fn reuse_port(&mut self, reuse: bool) -> &mut Self {
+        self.reuse_port = Some(reuse);
+        self
+    }
+
     pub async fn build<A>(
         &self,
         addr: A,
@@ -82,45 +454,341 @@ impl Builder {
     where
         A: ToSocketAddrs,
     {
-        let preference = self.preference.build().await?;
-        let preference = Arc::new(preference);
+        let preference = self.build_preference().await?;
 
         let addr = lookup_host(addr).await?.next();
         if let Some(addr) = addr {
-            let io = TcpListener::bind(addr).await?;
-            Ok(Server {
-                io: TcpListenerStream::new(io),
-                preference,
-                _stream: PhantomData,
-            })
+            let socket = if addr.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            };
+            socket.set_reuseaddr(self.reuse_address.unwrap_or(true))?;
+            socket.set_reuseport(self.reuse_port.unwrap_or(false))?;
+            socket.bind(addr)?;
+            let io = socket.listen(1024)?;
+            Ok(Server::new(io, preference))
         } else {
             Err(BuildError::Unresolved)
         }
     }
+
+    /// Build server on already listening socket. e.g. socket activated by systemd.
+    ///
+    /// Socket options like [`Builder::reuse_address`] are not applied.
+    pub async fn build_from_std(
+        &self,
+        listener: std::net::TcpListener,
+    ) -> Result<Server<TcpListenerStream, TcpStream>, BuildError> {
+        let preference = self.build_preference().await?;
+
+        listener.set_nonblocking(true)?;
+        let io = TcpListener::from_std(listener)?;
+        Ok(Server::new(io, preference))
+    }
+
+    /// Build server on listening socket `fd`. See [`Builder::build_from_std`].
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open listening TCP socket, and is owned by the server afterwards.
+    pub async unsafe fn build_from_raw_fd(
+        &self,
+        fd: RawFd,
+    ) -> Result<Server<TcpListenerStream, TcpStream>, BuildError> {
+        self.build_from_std(std::net::TcpListener::from_raw_fd(fd))
+            .await
+    }
+
+    async fn build_preference(&self) -> Result<Arc<Preference>, BuildError> {
+        #[cfg(feature = "fips_selftest")]
+        crate::selftest::run()?;
+
+        let preference = self.preference.build().await?;
+        Ok(Arc::new(preference))
+    }
+}
+
+/// Stream which knows its remote address.
+pub trait PeerAddr {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Apply [`Builder::tcp_user_timeout`]. Does nothing by default.
+    fn set_tcp_user_timeout(&self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl PeerAddr for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_tcp_user_timeout(&self, timeout: Duration) -> io::Result<()> {
+        socket2::SockRef::from(self).set_tcp_user_timeout(Some(timeout))
+    }
+}
+
+#[derive(Debug, Default)]
+struct AcceptGateInner {
+    paused: AtomicBool,
+    waker: AtomicWaker,
+}
+
+/// Handle to pause / resume accepting connections of [`Server`].
+#[derive(Debug, Clone, Default)]
+pub struct AcceptGate {
+    inner: Arc<AcceptGateInner>,
+}
+
+impl AcceptGate {
+    /// Stop accepting new connections. Established connections are unaffected.
+    pub fn pause(&self) {
+        self.inner.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resume accepting new connections.
+    pub fn resume(&self) {
+        self.inner.paused.store(false, Ordering::SeqCst);
+        self.inner.waker.wake();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.inner.paused.load(Ordering::SeqCst)
+    }
+
+    fn poll_open(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.is_paused() {
+            return Poll::Ready(());
+        }
+        self.inner.waker.register(cx.waker());
+        if self.is_paused() {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+/// Handle to replace host keys of [`Server`] while it is being polled.
+#[derive(Debug, Clone)]
+pub struct HostKeysHandle {
+    preference: Arc<RwLock<Arc<Preference>>>,
+}
+
+impl HostKeysHandle {
+    fn new(preference: Arc<Preference>) -> Self {
+        Self {
+            preference: Arc::new(RwLock::new(preference)),
+        }
+    }
+
+    /// Use `hostkeys` for connections accepted after this.
+    ///
+    /// Established connections keep their keys. Fails if `hostkeys` is empty.
+    pub fn set(&self, hostkeys: HostKeys) -> Result<(), SshError> {
+        if hostkeys.names().is_empty() {
+            return Err(SshError::NoHostKeys);
+        }
+        let mut preference = self.preference.write().unwrap();
+        *preference = Arc::new(preference.with_hostkeys(hostkeys));
+        Ok(())
+    }
+
+    fn preference(&self) -> Arc<Preference> {
+        self.preference.read().unwrap().clone()
+    }
+}
+
+#[derive(Debug)]
+struct HandshakeLimitInner {
+    available: AtomicUsize,
+    waker: AtomicWaker,
+}
+
+/// Slots for connections in version exchange. See [`Builder::max_pending_handshakes`].
+#[derive(Debug, Clone)]
+struct HandshakeLimit {
+    inner: Arc<HandshakeLimitInner>,
+}
+
+impl HandshakeLimit {
+    fn new(max: usize) -> Self {
+        let inner = HandshakeLimitInner {
+            available: AtomicUsize::new(max),
+            waker: AtomicWaker::new(),
+        };
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    fn try_acquire(&self) -> Option<HandshakePermit> {
+        self.inner
+            .available
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .ok()
+            .map(|_| HandshakePermit {
+                inner: self.inner.clone(),
+            })
+    }
+
+    fn poll_acquire(&self, cx: &mut Context<'_>) -> Poll<HandshakePermit> {
+        if let Some(permit) = self.try_acquire() {
+            return Poll::Ready(permit);
+        }
+        self.inner.waker.register(cx.waker());
+        match self.try_acquire() {
+            Some(permit) => Poll::Ready(permit),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Slot of [`HandshakeLimit`], released on drop.
+#[derive(Debug)]
+pub(crate) struct HandshakePermit {
+    inner: Arc<HandshakeLimitInner>,
+}
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        self.inner.available.fetch_add(1, Ordering::SeqCst);
+        self.inner.waker.wake();
+    }
 }
 
 /// SSH server instance.
 #[derive(Debug)]
 pub struct Server<L, S> {
     io: L,
-    preference: Arc<Preference>,
+    hostkeys: HostKeysHandle,
+    gate: AcceptGate,
+    handshake_limit: Option<HandshakeLimit>,
     _stream: PhantomData<S>,
 }
 
+impl Server<TcpListenerStream, TcpStream> {
+    fn new(io: TcpListener, preference: Arc<Preference>) -> Self {
+        let handshake_limit = preference.max_pending_handshakes().map(HandshakeLimit::new);
+        Self {
+            io: TcpListenerStream::new(io),
+            hostkeys: HostKeysHandle::new(preference),
+            gate: AcceptGate::default(),
+            handshake_limit,
+            _stream: PhantomData,
+        }
+    }
+}
+
+impl<L, S> Server<L, S>
+where
+    L: Stream<Item = io::Result<S>> + Unpin,
+    S: io::AsyncRead + io::AsyncWrite + PeerAddr + Unpin,
+{
+    /// Stop accepting new connections until [`Server::resume`].
+    ///
+    /// Pending connections are left in the listen backlog.
+    pub fn pause(&self) {
+        self.gate.pause()
+    }
+
+    /// Resume accepting new connections.
+    pub fn resume(&self) {
+        self.gate.resume()
+    }
+
+    /// Handle to pause / resume while the server is being polled.
+    pub fn gate(&self) -> AcceptGate {
+        self.gate.clone()
+    }
+
+    /// Replace host keys for connections accepted after this.
+    ///
+    /// Established connections keep their keys. Fails if `hostkeys` is empty.
+    pub fn set_host_keys(&self, hostkeys: HostKeys) -> Result<(), SshError> {
+        self.hostkeys.set(hostkeys)
+    }
+
+    /// Handle to replace host keys while the server is being polled.
+    pub fn hostkeys_handle(&self) -> HostKeysHandle {
+        self.hostkeys.clone()
+    }
+
+    /// Stream of incoming connections.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use futures::prelude::*;
+    /// use ssssh::ServerBuilder;
+    ///
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut server = ServerBuilder::default().build("[::1]:2222").await?;
+    /// let mut incoming = server.incoming().take(2);
+    /// while let Some(connection) = incoming.next().await {
+    ///     let connection = connection?.accept().await?;
+    ///     // ...
+    /// #   drop(connection);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn incoming(
+        &mut self,
+    ) -> impl Stream<Item = Result<Connection<Accept<S>>, SshError>> + Unpin + '_ {
+        TryStreamExt::map_err(self, SshError::from)
+    }
+}
+
 impl<L, S> Stream for Server<L, S>
 where
     L: Stream<Item = io::Result<S>> + Unpin,
-    S: io::AsyncRead + io::AsyncWrite + Unpin,
+    S: io::AsyncRead + io::AsyncWrite + PeerAddr + Unpin,
 {
     type Item = io::Result<Connection<Accept<S>>>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
-        let result = ready!(Pin::new(&mut this.io).poll_next(cx));
-        if let Some(stream) = result {
-            Poll::Ready(Some(Ok(Connection::new(stream?, this.preference.clone()))))
-        } else {
-            Poll::Ready(None)
+        loop {
+            ready!(this.gate.poll_open(cx));
+            let permit = match &this.handshake_limit {
+                Some(limit) => Some(ready!(limit.poll_acquire(cx))),
+                None => None,
+            };
+            let result = ready!(Pin::new(&mut this.io).poll_next(cx));
+            if let Some(stream) = result {
+                let stream = stream?;
+                let preference = this.hostkeys.preference();
+                let peer_addr = stream.peer_addr();
+                match &peer_addr {
+                    Ok(addr) if !preference.permits(&addr.ip()) => {
+                        debug!("connection from {} denied.", addr);
+                        continue;
+                    }
+                    Ok(addr) => {
+                        let event = ServerEvent::ConnectionAccepted { addr: *addr };
+                        preference.events().emit(event);
+                    }
+                    // fail closed if the address can not be checked.
+                    Err(err) if preference.restricts_address() => {
+                        debug!("connection from unknown address denied: {}", err);
+                        continue;
+                    }
+                    _ => {}
+                }
+                if let Some(timeout) = preference.tcp_user_timeout() {
+                    if let Err(err) = stream.set_tcp_user_timeout(*timeout) {
+                        debug!("failed to set TCP_USER_TIMEOUT: {}", err);
+                    }
+                }
+                let peer_addr = peer_addr.ok();
+                let connection = Connection::new(stream, preference, permit, peer_addr);
+                return Poll::Ready(Some(Ok(connection)));
+            } else {
+                return Poll::Ready(None);
+            }
         }
     }
 }
@@ -129,6 +797,12 @@ where
 mod tests {
     use super::*;
 
+    impl PeerAddr for tokio_test::io::Mock {
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Err(io::ErrorKind::Other.into())
+        }
+    }
+
     #[tokio::test]
     async fn test_incorrect_hostkey() {
         let err = Builder::default()
@@ -138,12 +812,69 @@ mod tests {
         assert!(err.is_err())
     }
 
+    #[tokio::test]
+    async fn test_invalid_pre_banner() {
+        let err = Builder::default()
+            .pre_banner(vec!["notice".into(), "SSH-2.0-fake".into()])
+            .build("[::1]:0")
+            .await;
+        assert!(matches!(
+            err,
+            Err(BuildError::SshError(SshError::InvalidPreBanner(..)))
+        ));
+    }
+
     #[tokio::test]
     async fn test_incorrect_listen_addr() {
         let err = Builder::default().build("").await;
         assert!(err.is_err())
     }
 
+    #[tokio::test]
+    async fn test_build_from_std() {
+        use futures::prelude::*;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Builder::default().build_from_std(listener).await.unwrap();
+
+        let client = TcpStream::connect(addr).await.unwrap();
+        let connection = server.next().await.unwrap().unwrap();
+        assert_eq!(
+            connection.remote_ip().unwrap(),
+            client.local_addr().unwrap()
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_tcp_user_timeout() {
+        use futures::prelude::*;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        // shares the socket with the accepted stream.
+        let probe = stream.try_clone().unwrap();
+        let stream = TcpStream::from_std(stream).unwrap();
+
+        let mut preference = PreferenceBuilder::default();
+        preference.tcp_user_timeout(Duration::from_secs(30));
+        let mut server = Server {
+            io: futures::stream::iter(vec![Ok(stream)]),
+            hostkeys: HostKeysHandle::new(Arc::new(preference.build().await.unwrap())),
+            gate: AcceptGate::default(),
+            handshake_limit: None,
+            _stream: PhantomData,
+        };
+        let _connection = server.next().await.unwrap().unwrap();
+        assert_eq!(
+            socket2::SockRef::from(&probe).tcp_user_timeout().unwrap(),
+            Some(Duration::from_secs(30))
+        );
+    }
+
     #[tokio::test]
     async fn test_end() {
         use futures::prelude::*;
@@ -151,7 +882,11 @@ mod tests {
         let stream = futures::stream::empty::<io::Result<tokio_test::io::Mock>>();
         let mut server = Server {
             io: stream,
-            preference: Arc::new(PreferenceBuilder::default().build().await.unwrap()),
+            hostkeys: HostKeysHandle::new(Arc::new(
+                PreferenceBuilder::default().build().await.unwrap(),
+            )),
+            gate: AcceptGate::default(),
+            handshake_limit: None,
             _stream: PhantomData,
         };
         assert!(server.next().await.is_none())
@@ -165,9 +900,263 @@ mod tests {
         let stream = futures::stream::once(async { err }.boxed());
         let mut server = Server {
             io: stream,
-            preference: Arc::new(PreferenceBuilder::default().build().await.unwrap()),
+            hostkeys: HostKeysHandle::new(Arc::new(
+                PreferenceBuilder::default().build().await.unwrap(),
+            )),
+            gate: AcceptGate::default(),
+            handshake_limit: None,
             _stream: PhantomData,
         };
         assert!(server.next().await.unwrap().is_err())
     }
+
+    #[tokio::test]
+    async fn test_unknown_peer_addr_denied() {
+        use futures::prelude::*;
+
+        let stream = futures::stream::iter(
+            (0..2).map(|_| Ok::<_, io::Error>(tokio_test::io::Builder::new().build())),
+        );
+        let mut preference = PreferenceBuilder::default();
+        preference.deny_cidrs(vec!["10.0.0.0/8".parse().unwrap()]);
+        let mut server = Server {
+            io: stream,
+            hostkeys: HostKeysHandle::new(Arc::new(preference.build().await.unwrap())),
+            gate: AcceptGate::default(),
+            handshake_limit: None,
+            _stream: PhantomData,
+        };
+        assert!(server.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_incoming_take() {
+        use futures::prelude::*;
+
+        let stream = futures::stream::iter(
+            (0..3).map(|_| Ok::<_, io::Error>(tokio_test::io::Builder::new().build())),
+        );
+        let mut server = Server {
+            io: stream,
+            hostkeys: HostKeysHandle::new(Arc::new(
+                PreferenceBuilder::default().build().await.unwrap(),
+            )),
+            gate: AcceptGate::default(),
+            handshake_limit: None,
+            _stream: PhantomData,
+        };
+        let connections = server.incoming().take(2).collect::<Vec<_>>().await;
+        assert_eq!(connections.len(), 2);
+        assert!(connections.iter().all(Result::is_ok));
+        assert!(server.next().await.unwrap().is_ok());
+        assert!(server.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_set_host_keys() {
+        use futures::prelude::*;
+
+        use crate::key::Algorithm;
+
+        let stream = futures::stream::iter(
+            (0..2).map(|_| Ok::<_, io::Error>(tokio_test::io::Builder::new().build())),
+        );
+        let mut server = Server {
+            io: stream,
+            hostkeys: HostKeysHandle::new(Arc::new(
+                PreferenceBuilder::default().build().await.unwrap(),
+            )),
+            gate: AcceptGate::default(),
+            handshake_limit: None,
+            _stream: PhantomData,
+        };
+        let publickey = |connection: &Connection<Accept<_>>| {
+            connection
+                .preference()
+                .hostkeys()
+                .lookup(&Algorithm::SshEd25519)
+                .unwrap()
+                .publickey()
+        };
+
+        let handle = server.hostkeys_handle();
+        let mut incoming = server.incoming();
+        let first = incoming.next().await.unwrap().unwrap();
+
+        assert!(matches!(
+            handle.set(HostKeys::new()),
+            Err(SshError::NoHostKeys)
+        ));
+        // from another task, while `server` is borrowed.
+        let mut hostkeys = HostKeys::new();
+        hostkeys.load("tests/ed25519").await.unwrap();
+        tokio::spawn(async move { handle.set(hostkeys) })
+            .await
+            .unwrap()
+            .unwrap();
+        let second = incoming.next().await.unwrap().unwrap();
+
+        let mut expected = HostKeys::new();
+        expected.load("tests/ed25519").await.unwrap();
+        let expected = expected.lookup(&Algorithm::SshEd25519).unwrap().publickey();
+        assert_eq!(publickey(&second), expected);
+        assert_ne!(publickey(&first), expected);
+        assert!(second
+            .preference()
+            .hostkeys()
+            .lookup(&Algorithm::SshRsa)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rebind() {
+        let mut builder = Builder::default();
+        builder.reuse_address(true);
+
+        use futures::prelude::*;
+
+        let mut server = builder.build("127.0.0.1:0").await.unwrap();
+        let addr = server.io.as_ref().local_addr().unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        // close accepted connection first to leave it in TIME_WAIT.
+        drop(server.next().await.unwrap().unwrap());
+        drop(server);
+        drop(client);
+
+        builder.build(addr).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_raw_identification() {
+        use futures::prelude::*;
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let identification = "SSH-2.0-odd_1.0-x y\tz";
+        let mut server = Builder::default()
+            .raw_identification(identification.into())
+            .build("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = server.io.as_ref().local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+            let mut buf = vec![0; identification.len() + 2];
+            client.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+
+        let connection = server.next().await.unwrap().unwrap();
+        let connection = connection.accept().await.unwrap();
+        assert_eq!(client.await.unwrap(), b"SSH-2.0-odd_1.0-x y\tz\r\n");
+        drop(connection);
+
+        for identification in &["SSH-1.99-old", "SSH-2.0-nul\0", &"SSH-2.0-".repeat(32)] {
+            let err = Builder::default()
+                .raw_identification(identification.to_string())
+                .build("[::1]:0")
+                .await;
+            assert!(matches!(
+                err,
+                Err(BuildError::SshError(SshError::InvalidIdentification(..)))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reject() {
+        use futures::prelude::*;
+        use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader};
+
+        use crate::msg::disconnect::ReasonCode;
+        use crate::msg::Msg;
+        use crate::stream::msg::MsgStream;
+
+        let mut server = Builder::default().build("127.0.0.1:0").await.unwrap();
+        let addr = server.io.as_ref().local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut client = BufReader::new(TcpStream::connect(addr).await.unwrap());
+            client.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+            let mut line = String::new();
+            client.read_line(&mut line).await.unwrap();
+            assert!(line.starts_with("SSH-2.0-"));
+
+            let mut client = MsgStream::new(client);
+            let msg = client.next().await;
+            assert!(client.next().await.is_none());
+            msg
+        });
+
+        let connection = server.next().await.unwrap().unwrap();
+        connection.reject("not allowed").await.unwrap();
+        match client.await.unwrap() {
+            Some(Ok(Msg::Disconnect(msg))) => {
+                assert!(matches!(
+                    msg.reason_code(),
+                    ReasonCode::HostNotAllowedToConnect
+                ));
+                assert_eq!(msg.description(), "not allowed");
+            }
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_pending_handshakes() {
+        use futures::prelude::*;
+        use tokio::time::timeout;
+
+        let mut server = Builder::default()
+            .max_pending_handshakes(2)
+            .build("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = server.io.as_ref().local_addr().unwrap();
+
+        // never send banner.
+        let mut clients = vec![];
+        for _ in 0..3 {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+        }
+
+        let first = server.next().await.unwrap().unwrap();
+        let _second = server.next().await.unwrap().unwrap();
+        let third = timeout(Duration::from_millis(200), server.next()).await;
+        assert!(third.is_err());
+
+        drop(first);
+        let third = timeout(Duration::from_secs(1), server.next()).await;
+        assert!(matches!(third, Ok(Some(Ok(..)))));
+    }
+
+    #[tokio::test]
+    async fn test_pause_resume() {
+        use futures::prelude::*;
+        use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader};
+        use tokio::time::timeout;
+
+        let mut server = Builder::default().build("127.0.0.1:0").await.unwrap();
+        let addr = server.io.as_ref().local_addr().unwrap();
+        server.pause();
+
+        let client = tokio::spawn(async move {
+            let mut client = BufReader::new(TcpStream::connect(addr).await.unwrap());
+            client.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+            let mut line = String::new();
+            client.read_line(&mut line).await.unwrap();
+            line
+        });
+
+        let gate = server.gate();
+        let accepted = timeout(Duration::from_millis(200), server.next()).await;
+        assert!(accepted.is_err());
+
+        gate.resume();
+        let connection = server.next().await.unwrap().unwrap();
+        let connection = connection.accept().await.unwrap();
+        assert!(client.await.unwrap().starts_with("SSH-2.0-"));
+        drop(connection);
+    }
 }
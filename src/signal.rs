@@ -0,0 +1,89 @@
+//! Signal names for `exit-signal`.
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// Signal names defined in RFC 4254 section 6.10.
+const NAMES: &[&str] = &[
+    "ABRT", "ALRM", "FPE", "HUP", "ILL", "INT", "KILL", "PIPE", "QUIT", "SEGV", "TERM", "USR1",
+    "USR2",
+];
+
+#[derive(Debug, thiserror::Error)]
+#[error("unknown signal name {0:?}")]
+pub struct UnknownSignalError(String);
+
+/// Signal reported to the client by `exit-signal`.
+///
+/// Parsed from either the bare name (`INT`) or the `SIG` prefixed name (`SIGINT`).
+///
+/// # Example
+///
+/// ```
+/// use ssssh::Signal;
+///
+/// let signal = "SIGINT".parse::<Signal>().unwrap();
+/// assert_eq!(signal.name(), "INT");
+/// assert!("SIGCHLD".parse::<Signal>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Signal {
+    name: &'static str,
+}
+
+impl Signal {
+    /// Name without `SIG` prefix, as sent on the wire.
+    pub fn name(&self) -> &str {
+        self.name
+    }
+}
+
+impl FromStr for Signal {
+    type Err = UnknownSignalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bare = s.strip_prefix("SIG").unwrap_or(s);
+        NAMES
+            .iter()
+            .find(|name| **name == bare)
+            .map(|name| Self { name })
+            .ok_or_else(|| UnknownSignalError(s.to_string()))
+    }
+}
+
+impl TryFrom<&str> for Signal {
+    type Error = UnknownSignalError;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(feature = "nix")]
+impl TryFrom<nix::sys::signal::Signal> for Signal {
+    type Error = UnknownSignalError;
+
+    fn try_from(signal: nix::sys::signal::Signal) -> Result<Self, Self::Error> {
+        signal.as_str().parse()
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("SIGINT".parse::<Signal>().unwrap().name(), "INT");
+        assert_eq!("TERM".parse::<Signal>().unwrap().name(), "TERM");
+        assert!("SIGCHLD".parse::<Signal>().is_err());
+        assert!("SIG".parse::<Signal>().is_err());
+        assert!("int".parse::<Signal>().is_err());
+    }
+}
@@ -6,6 +6,8 @@ use getset::{Getters, MutGetters};
 use crate::cipher::Cipher;
 use crate::comp::Compression;
 use crate::kex::Kex;
+#[cfg(feature = "debug_keylog")]
+use crate::keylog::SessionKeys;
 use crate::mac::Mac;
 use crate::negotiate::Algorithm;
 use crate::pack::{Mpint, Pack, Put};
@@ -15,6 +17,9 @@ use crate::SshError;
 pub(crate) struct OneWayState {
     seq: Wrapping<u32>,
 
+    /// Bytes of packets on the wire, including length, padding and MAC.
+    bytes: u64,
+
     #[get = "pub(crate)"]
     #[get_mut = "pub(crate)"]
     cipher: Cipher,
@@ -30,12 +35,14 @@ impl OneWayState {
     fn new() -> Self {
         Self {
             seq: Wrapping(0),
+            bytes: 0,
             cipher: Cipher::new_none(),
             mac: Mac::new_none(),
             comp: Compression::new_none(),
         }
     }
 
+    /// Sequence number of the current packet. Wraps around after 2^32 - 1. (RFC 4253 6.4)
     pub(crate) fn get_and_inc_seq(&mut self) -> u32 {
         let r = self.seq;
         self.seq += Wrapping(1);
@@ -45,6 +52,29 @@ impl OneWayState {
     pub(crate) fn seq(&self) -> u32 {
         self.seq.0
     }
+
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub(crate) fn add_bytes(&mut self, n: usize) {
+        self.bytes = self.bytes.saturating_add(n as u64);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_seq(&mut self, seq: u32) {
+        self.seq = Wrapping(seq);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_mac(&mut self, mac: Mac) {
+        self.mac = mac;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_cipher(&mut self, cipher: Cipher) {
+        self.cipher = cipher;
+    }
 }
 
 fn compute_hash(
@@ -87,6 +117,9 @@ pub(crate) struct State {
     #[get = "pub(crate)"]
     #[get_mut = "pub(crate)"]
     stoc: OneWayState,
+
+    #[cfg(feature = "debug_keylog")]
+    keys: Option<SessionKeys>,
 }
 
 impl State {
@@ -95,6 +128,8 @@ impl State {
             session_id: None,
             ctos: OneWayState::new(),
             stoc: OneWayState::new(),
+            #[cfg(feature = "debug_keylog")]
+            keys: None,
         }
     }
 
@@ -102,6 +137,11 @@ impl State {
         self.session_id.as_ref().unwrap()
     }
 
+    #[cfg(test)]
+    pub(crate) fn set_session_id(&mut self, session_id: Bytes) {
+        self.session_id = Some(session_id);
+    }
+
     pub(crate) fn change_key(
         &mut self,
         hash: &Bytes,
@@ -137,9 +177,29 @@ impl State {
         self.ctos.comp = Compression::new(algorithm.compression_algorithm_c2s());
         self.stoc.comp = Compression::new(algorithm.compression_algorithm_s2c());
 
+        #[cfg(feature = "debug_keylog")]
+        {
+            self.keys = Some(SessionKeys::new(
+                session_id.clone(),
+                algorithm.clone(),
+                iv_ctos,
+                iv_stoc,
+                key_ctos,
+                key_stoc,
+                intk_ctos,
+                intk_stoc,
+            ));
+        }
+
         self.session_id = Some(session_id.clone());
         Ok(())
     }
+
+    /// Take keys derived by last `change_key`.
+    #[cfg(feature = "debug_keylog")]
+    pub(crate) fn take_keys(&mut self) -> Option<SessionKeys> {
+        self.keys.take()
+    }
 }
 
 #[cfg(test)]
@@ -152,4 +212,49 @@ mod tests {
 
         assert::<State>();
     }
+
+    #[cfg(feature = "debug_keylog")]
+    #[test]
+    fn test_keys_derived() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::keylog::OnKeysDerived;
+        use crate::negotiate::AlgorithmBuilder;
+        use crate::{cipher, comp, kex, key, mac};
+
+        let algorithm = AlgorithmBuilder::default()
+            .kex_algorithm(kex::Algorithm::Curve25519Sha256)
+            .server_host_key_algorithm(key::Algorithm::SshEd25519)
+            .cipher_algorithm_c2s(cipher::Algorithm::Aes256Ctr)
+            .cipher_algorithm_s2c(cipher::Algorithm::Aes256Ctr)
+            .mac_algorithm_c2s(mac::Algorithm::HmacSha256)
+            .mac_algorithm_s2c(mac::Algorithm::HmacSha256)
+            .compression_algorithm_c2s(comp::Algorithm::None)
+            .compression_algorithm_s2c(comp::Algorithm::None)
+            .build()
+            .unwrap();
+        let kex = Kex::new(algorithm.kex_algorithm());
+
+        let received = Arc::new(Mutex::new(None));
+        let r = received.clone();
+        let callback = OnKeysDerived::new(move |keys| *r.lock().unwrap() = Some(keys.clone()));
+
+        let mut state = State::new();
+        let hash = Bytes::from_static(&[1; 32]);
+        state
+            .change_key(&hash, &Bytes::from_static(&[2; 32]), &kex, &algorithm)
+            .unwrap();
+        callback.call(&state.take_keys().unwrap());
+        assert!(state.take_keys().is_none());
+
+        let keys = received.lock().unwrap().take().unwrap();
+        assert_eq!(keys.session_id(), &hash);
+        assert_eq!(keys.iv_c2s().len(), 16);
+        assert_eq!(keys.iv_s2c().len(), 16);
+        assert_eq!(keys.key_c2s().len(), 32);
+        assert_eq!(keys.key_s2c().len(), 32);
+        assert_eq!(keys.mac_key_c2s().len(), 32);
+        assert_eq!(keys.mac_key_s2c().len(), 32);
+        assert_ne!(keys.key_c2s(), keys.key_s2c());
+    }
 }
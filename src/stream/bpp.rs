@@ -9,14 +9,32 @@ use bytes::{Buf as _, BufMut as _, Bytes, BytesMut};
 use futures::ready;
 use futures::sink::Sink;
 use futures::stream::Stream;
-use ring::rand::{SecureRandom, SystemRandom};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
+use crate::rng::Rng;
 use crate::state::{OneWayState, State};
 use crate::SshError;
 
 pub(crate) const MAXIMUM_PACKET_SIZE: usize = 35000;
 
+/// MAC verification failure, with context for debugging middlebox / prefix truncation issues.
+///
+/// Contains no key material.
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "verification failed at seq {seq} (packet length {packet_length}, mac length {mac_length}, \
+     cipher {cipher}, mac {mac}, strict kex {strict_kex})"
+)]
+struct MacFailure {
+    seq: u32,
+    packet_length: usize,
+    mac_length: usize,
+    cipher: String,
+    mac: String,
+    /// `kex-strict-s-v00@openssh.com`. Not supported yet, so sequence numbers are never reset.
+    strict_kex: bool,
+}
+
 fn pad_len(len: usize, bs: usize) -> usize {
     const MINIMUM_PAD_SIZE: usize = 4;
 
@@ -31,7 +49,8 @@ fn pad_len(len: usize, bs: usize) -> usize {
 #[derive(Debug)]
 enum DecryptState {
     FillFirst,
-    FillRemaining(usize),
+    /// Packet length, and bytes already decrypted.
+    FillRemaining(usize, usize),
 }
 
 #[derive(Debug)]
@@ -41,16 +60,18 @@ pub(crate) struct BppStream<IO> {
     rxstate: DecryptState,
     rxbuf: BytesMut,
     txbuf: BytesMut,
+    rng: Rng,
 }
 
 impl<IO> BppStream<IO> {
-    pub(crate) fn new(io: IO) -> Self {
+    pub(crate) fn new(io: IO, rng: Rng) -> Self {
         Self {
             state: State::new(),
             io,
             rxstate: DecryptState::FillFirst,
             rxbuf: BytesMut::with_capacity(MAXIMUM_PACKET_SIZE),
             txbuf: BytesMut::with_capacity(MAXIMUM_PACKET_SIZE),
+            rng,
         }
     }
 
@@ -103,33 +124,53 @@ fn next_payload(
     loop {
         match txstate {
             DecryptState::FillFirst => {
-                if buf.remaining() < 4 {
+                // decrypt the whole first block only, so that the cipher never sees
+                // a partial block however the input is split.
+                let block_size = state.cipher().block_size();
+                if buf.remaining() < block_size {
                     return Poll::Pending;
                 }
 
-                state.cipher_mut().update(&mut buf[..4])?;
+                state.cipher_mut().update(&mut buf[..block_size])?;
                 let len = (&buf[..4]).get_u32() as usize;
                 if len + 4 + mac_length > MAXIMUM_PACKET_SIZE {
                     return Poll::Ready(Err(SshError::TooLargePacket(len + 4 + mac_length)));
                 }
-                *txstate = DecryptState::FillRemaining(len);
+                if len + 4 < block_size {
+                    return Poll::Ready(Err(SshError::TooSmallPacket(len + 4)));
+                }
+                *txstate = DecryptState::FillRemaining(len, block_size);
             }
-            DecryptState::FillRemaining(len) => {
+            DecryptState::FillRemaining(len, decrypted) => {
                 if buf.remaining() < 4 + *len + mac_length {
                     return Poll::Pending;
                 }
 
                 let pkt_and_mac = &mut buf[..(4 + *len + mac_length)];
-                state.cipher_mut().update(&mut pkt_and_mac[4..(4 + *len)])?;
+                state
+                    .cipher_mut()
+                    .update(&mut pkt_and_mac[*decrypted..(4 + *len)])?;
                 let pkt = &pkt_and_mac[..(4 + *len)];
                 let mac = &pkt_and_mac[(*len + 4)..];
                 let seq = state.get_and_inc_seq();
-                state.mac().verify(seq, &pkt[..(*len + 4)], &mac)?;
+                if let Err(err) = state.mac().verify(seq, &pkt[..(*len + 4)], &mac) {
+                    let failure = MacFailure {
+                        seq,
+                        packet_length: *len,
+                        mac_length,
+                        cipher: state.cipher().algorithm().as_ref().into(),
+                        mac: state.mac().algorithm().as_ref().into(),
+                        strict_kex: false,
+                    };
+                    log::debug!("{}: {}", failure, err);
+                    return Poll::Ready(Err(SshError::mac_error(failure)));
+                }
 
                 let pad = pkt[4] as usize;
                 let payload = &pkt[(1 + 4)..(*len + 4 - pad)];
                 let payload = state.comp().decompress(payload)?;
 
+                state.add_bytes(4 + *len + mac_length);
                 consume(buf, 4 + *len + mac_length);
                 *txstate = DecryptState::FillFirst;
                 return Poll::Ready(Ok(payload));
@@ -184,6 +225,7 @@ where
         let Self {
             ref mut txbuf,
             ref mut state,
+            ref rng,
             ..
         } = self.get_mut();
         let state = state.stoc_mut();
@@ -195,7 +237,7 @@ where
         let len = len + padding_length + 1;
 
         let mut pad = vec![0; padding_length];
-        SystemRandom::new().fill(&mut pad).map_err(SshError::any)?;
+        rng.fill(&mut pad)?;
 
         let mut buf = txbuf.split();
 
@@ -211,6 +253,7 @@ where
 
         buf.put_slice(&sign);
 
+        state.add_bytes(buf.len());
         txbuf.unsplit(buf);
 
         Ok(())
@@ -245,4 +288,128 @@ mod tests {
 
         assert::<BppStream<tokio::net::TcpStream>>();
     }
+
+    #[tokio::test]
+    async fn test_seq_wraparound() {
+        use futures::sink::SinkExt as _;
+        use futures::stream::StreamExt as _;
+
+        use crate::mac::{self, Mac};
+
+        let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+        let mut server = BppStream::new(server_io, Rng::default());
+        let mut client = BppStream::new(client_io, Rng::default());
+
+        let name = mac::Algorithm::HmacSha256;
+        let key = vec![7; Mac::len_by_name(&name)];
+        let tx = server.state_mut().stoc_mut();
+        tx.set_mac(Mac::new(&name, &key));
+        tx.set_seq(u32::MAX - 1);
+        let rx = client.state_mut().ctos_mut();
+        rx.set_mac(Mac::new(&name, &key));
+        rx.set_seq(u32::MAX - 1);
+
+        for data in &[&b"first"[..], b"second", b"third"] {
+            server.send(data).await.unwrap();
+            assert_eq!(&client.next().await.unwrap().unwrap()[..], *data);
+        }
+        assert_eq!(server.state().stoc().seq(), 1);
+        assert_eq!(client.state().ctos().seq(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_split_read() {
+        use bytes::Bytes;
+        use futures::sink::SinkExt as _;
+        use futures::stream::StreamExt as _;
+        use tokio::io::AsyncReadExt as _;
+
+        use crate::cipher::{self, Cipher};
+        use crate::mac::{self, Mac};
+
+        let (server_io, mut client_io) = tokio::io::duplex(64 * 1024);
+        let mut server = BppStream::new(server_io, Rng::default());
+
+        let name = cipher::Algorithm::Aes128Ctr;
+        let key = Bytes::from(vec![1; Cipher::key_length_by_name(&name)]);
+        let iv = Bytes::from(vec![2; Cipher::block_size_by_name(&name)]);
+        let tx = server.state_mut().stoc_mut();
+        tx.set_cipher(Cipher::new_for_encrypt(&name, &key, &iv).unwrap());
+        tx.set_mac(Mac::new(&mac::Algorithm::HmacSha256, &[7; 32]));
+
+        // spans chunks of aes update.
+        let data = [&b"a"[..], &[b'b'; 100], &[b'c'; 5000]];
+        for d in &data {
+            server.send(d).await.unwrap();
+        }
+        drop(server);
+        let mut wire = vec![];
+        client_io.read_to_end(&mut wire).await.unwrap();
+
+        // deliver a byte at a time.
+        let mut mock = tokio_test::io::Builder::new();
+        for b in &wire {
+            mock.read(std::slice::from_ref(b));
+        }
+        let mut client = BppStream::new(mock.build(), Rng::default());
+        let rx = client.state_mut().ctos_mut();
+        rx.set_cipher(Cipher::new_for_decrypt(&name, &key, &iv).unwrap());
+        rx.set_mac(Mac::new(&mac::Algorithm::HmacSha256, &[7; 32]));
+
+        for d in &data {
+            assert_eq!(&client.next().await.unwrap().unwrap()[..], *d);
+        }
+        assert!(client.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mac_failure() {
+        use futures::sink::SinkExt as _;
+        use futures::stream::StreamExt as _;
+
+        use crate::mac::{self, Mac};
+
+        let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+        let mut server = BppStream::new(server_io, Rng::default());
+        let mut client = BppStream::new(client_io, Rng::default());
+
+        let name = mac::Algorithm::HmacSha256;
+        let tx = server.state_mut().stoc_mut();
+        tx.set_mac(Mac::new(&name, &[7; 32]));
+        tx.set_seq(41);
+        let rx = client.state_mut().ctos_mut();
+        rx.set_mac(Mac::new(&name, &[8; 32]));
+        rx.set_seq(41);
+
+        server.send(b"corrupted").await.unwrap();
+        match client.next().await {
+            Some(Err(err @ SshError::MacError(..))) => {
+                let msg = err.to_string();
+                assert!(msg.contains("seq 41"), "{}", msg);
+                assert!(msg.contains("mac hmac-sha2-256"), "{}", msg);
+                assert!(msg.contains("strict kex false"), "{}", msg);
+            }
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_too_large_packet() {
+        use futures::stream::StreamExt as _;
+        use tokio::io::AsyncWriteExt as _;
+
+        let (server_io, mut client_io) = tokio::io::duplex(64 * 1024);
+        let mut server = BppStream::new(server_io, Rng::default());
+
+        // first block of multi-megabyte packet only. payload is never sent.
+        client_io
+            .write_all(&(4 * 1024 * 1024u32).to_be_bytes())
+            .await
+            .unwrap();
+        client_io.write_all(&[0; 4]).await.unwrap();
+        match server.next().await {
+            Some(Err(SshError::TooLargePacket(..))) => {}
+            x => panic!("{:?}", x),
+        }
+    }
 }
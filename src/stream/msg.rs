@@ -12,6 +12,7 @@ use tokio::io::{AsyncRead, AsyncWrite};
 use super::bpp::BppStream;
 use crate::msg::{ContextualMsg, Msg};
 use crate::pack::{Pack, Unpack};
+use crate::rng::Rng;
 use crate::SshError;
 
 #[derive(Debug)]
@@ -27,9 +28,14 @@ impl<IO> MsgStream<IO>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
+    #[cfg(test)]
     pub(crate) fn new(io: IO) -> Self {
+        Self::with_rng(io, Rng::default())
+    }
+
+    pub(crate) fn with_rng(io: IO, rng: Rng) -> Self {
         Self {
-            io: BppStream::new(io),
+            io: BppStream::new(io, rng),
             txbuf: BytesMut::new(),
         }
     }
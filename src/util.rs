@@ -0,0 +1,387 @@
+//! Utilities for handler implementations.
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+
+use futures::future::{self, Either};
+use futures::ready;
+use tokio::io::AsyncWrite;
+use tokio::process::Command;
+
+use crate::{SessionContext, SshOutput};
+
+/// Run `command` with session's stdin / stdout / stderr and return its exit code.
+///
+/// Environment variables requested by the client and accepted by
+/// [`ServerBuilder::accept_env`](crate::ServerBuilder::accept_env), and `TERM` from `pty-req`,
+/// are passed to the process unless already set on `command`.
+///
+/// Exit code is `255` if the process is terminated by a signal.
+///
+/// # Example
+///
+/// ```
+/// use futures::FutureExt as _;
+/// use ssssh::Handlers;
+/// use ssssh::util::spawn_process;
+/// use tokio::process::Command;
+///
+/// let mut handlers = Handlers::<anyhow::Error>::new();
+/// handlers.on_channel_exec(|ctx: ssssh::SessionContext<_>, prog| {
+///     let mut command = Command::new("sh");
+///     command.arg("-c").arg(prog);
+///     spawn_process(ctx, command).boxed()
+/// });
+/// ```
+pub fn spawn_process<Pty, E>(
+    mut ctx: SessionContext<Pty>,
+    mut command: Command,
+) -> impl Future<Output = Result<u32, E>> + Send + 'static
+where
+    E: From<io::Error> + Send + 'static,
+{
+    let stdio = ctx.take_stdio();
+    let explicit = command
+        .as_std()
+        .get_envs()
+        .map(|(key, _)| key.to_owned())
+        .collect::<Vec<_>>();
+    let term = ctx.term().map(|term| ("TERM", term));
+    let envs = ctx
+        .env()
+        .iter()
+        .map(|(key, val)| (key.as_str(), val.as_str()))
+        .chain(term)
+        .filter(|(key, _)| !explicit.iter().any(|e| e == key));
+    command.envs(envs);
+
+    async move {
+        let (mut stdin, mut stdout, mut stderr) =
+            stdio.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut child_stdin = child.stdin.take().unwrap();
+        let mut child_stdout = child.stdout.take().unwrap();
+        let mut child_stderr = child.stderr.take().unwrap();
+
+        let input = async move {
+            match tokio::io::copy(&mut stdin, &mut child_stdin).await {
+                // process exited before consuming all input.
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+                Err(e) => Err(e),
+                Ok(..) => Ok(()), // drop child_stdin for EOF
+            }
+        };
+        let output = future::try_join3(
+            child.wait(),
+            tokio::io::copy(&mut child_stdout, &mut stdout),
+            tokio::io::copy(&mut child_stderr, &mut stderr),
+        );
+        futures::pin_mut!(input, output);
+
+        let (status, ..) = match future::select(input, output).await {
+            Either::Left((result, output)) => {
+                result?;
+                output.await?
+            }
+            Either::Right((result, _)) => result?,
+        };
+        Ok(status.code().unwrap_or(255) as u32)
+    }
+}
+
+fn poll_write_pending<W>(
+    writer: &mut W,
+    pending: &mut Vec<u8>,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>>
+where
+    W: AsyncWrite + Unpin,
+{
+    while !pending.is_empty() {
+        let n = ready!(Pin::new(&mut *writer).poll_write(cx, pending))?;
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        pending.drain(..n);
+    }
+    Poll::Ready(Ok(()))
+}
+
+/// Writer returned by [`tee`].
+#[derive(Debug)]
+pub struct Tee<W> {
+    output: SshOutput,
+    sink: W,
+    pending: Vec<u8>,
+}
+
+impl<W> Tee<W> {
+    /// Unwrap channel output and secondary sink.
+    pub fn into_inner(self) -> (SshOutput, W) {
+        (self.output, self.sink)
+    }
+}
+
+impl<W> Tee<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    /// Write bytes accepted by channel output but not yet by sink.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        poll_write_pending(&mut self.sink, &mut self.pending, cx)
+    }
+}
+
+impl<W> AsyncWrite for Tee<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+
+        let n = ready!(Pin::new(&mut this.output).poll_write(cx, buf))?;
+        let written = match Pin::new(&mut this.sink).poll_write(cx, &buf[..n]) {
+            Poll::Ready(result) => result?,
+            Poll::Pending => 0,
+        };
+        this.pending.extend_from_slice(&buf[written..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        ready!(Pin::new(&mut this.output).poll_flush(cx))?;
+        Pin::new(&mut this.sink).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        ready!(Pin::new(&mut this.output).poll_shutdown(cx))?;
+        Pin::new(&mut this.sink).poll_shutdown(cx)
+    }
+}
+
+/// Write to both channel `output` and `sink`.
+///
+/// Every byte accepted by `output` is written to `sink` too.
+///
+/// # Example
+///
+/// ```
+/// use futures::FutureExt as _;
+/// use ssssh::Handlers;
+/// use ssssh::util::tee;
+/// use tokio::io::AsyncWriteExt as _;
+///
+/// let mut handlers = Handlers::<anyhow::Error>::new();
+/// handlers.on_channel_shell(|mut ctx: ssssh::SessionContext<_>| {
+///     async move {
+///         let log = tokio::fs::File::create("/tmp/session.log").await?;
+///         let (_, stdout, _) = ctx.take_stdio().unwrap();
+///         let mut stdout = tee(stdout, log);
+///         stdout.write_all(b"hello\n").await?;
+///         stdout.shutdown().await?;
+///         Ok(0)
+///     }
+///     .boxed()
+/// });
+/// ```
+pub fn tee<W>(output: SshOutput, sink: W) -> Tee<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    Tee {
+        output,
+        sink,
+        pending: vec![],
+    }
+}
+
+/// Writer returned by [`broadcast`].
+#[derive(Debug)]
+pub struct Broadcast {
+    outputs: Vec<(SshOutput, Vec<u8>)>,
+}
+
+impl Broadcast {
+    /// Unwrap channel outputs.
+    pub fn into_inner(self) -> Vec<SshOutput> {
+        self.outputs.into_iter().map(|(output, _)| output).collect()
+    }
+
+    /// Write bytes accepted by the first output but not yet by the others.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        for (output, pending) in &mut self.outputs {
+            ready!(poll_write_pending(output, pending, cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Broadcast {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+
+        let ((first, _), rest) = match this.outputs.split_first_mut() {
+            Some(outputs) => outputs,
+            None => return Poll::Ready(Ok(buf.len())),
+        };
+        let n = ready!(Pin::new(first).poll_write(cx, buf))?;
+        for (output, pending) in rest {
+            let written = match Pin::new(output).poll_write(cx, &buf[..n]) {
+                Poll::Ready(result) => result?,
+                Poll::Pending => 0,
+            };
+            pending.extend_from_slice(&buf[written..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        for (output, _) in &mut this.outputs {
+            ready!(Pin::new(output).poll_flush(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        for (output, _) in &mut this.outputs {
+            ready!(Pin::new(output).poll_shutdown(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Write to every channel in `outputs`. e.g. shared terminal.
+///
+/// Writing fails if any of the outputs fails.
+///
+/// # Example
+///
+/// ```
+/// use ssssh::SshOutput;
+/// use ssssh::util::broadcast;
+/// use tokio::io::AsyncWriteExt as _;
+///
+/// async fn announce(outputs: Vec<SshOutput>) -> std::io::Result<()> {
+///     let mut output = broadcast(outputs);
+///     output.write_all(b"server is going down\r\n").await
+/// }
+/// ```
+pub fn broadcast(outputs: Vec<SshOutput>) -> Broadcast {
+    Broadcast {
+        outputs: outputs.into_iter().map(|output| (output, vec![])).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use futures::channel::mpsc;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    use super::*;
+    use crate::handlers::{RequestKind, SessionState};
+    use crate::SshInput;
+
+    #[tokio::test]
+    async fn test_spawn_process() {
+        let (stdin, stdin_tx) = tokio_pipe::pipe().unwrap();
+        let (mut stdout_rx, stdout) = tokio_pipe::pipe().unwrap();
+        let (_stderr_rx, stderr) = tokio_pipe::pipe().unwrap();
+        drop(stdin_tx);
+
+        let ctx = SessionContext::<()>::new(
+            (
+                SshInput::new(stdin),
+                SshOutput::new(stdout),
+                SshOutput::new(stderr),
+            ),
+            SessionState::default(),
+            RequestKind::Exec("/bin/echo hello".into()),
+            0,
+            mpsc::unbounded().0,
+            Default::default(),
+            Default::default(),
+        );
+        let mut command = Command::new("/bin/echo");
+        command.arg("hello");
+        let status = spawn_process::<_, io::Error>(ctx, command).await.unwrap();
+        assert_eq!(status, 0);
+
+        let mut buf = String::new();
+        stdout_rx.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "hello\n");
+    }
+
+    #[tokio::test]
+    async fn test_tee() {
+        let (mut output_rx, output) = tokio_pipe::pipe().unwrap();
+
+        let mut writer = tee(SshOutput::new(output), vec![]);
+        let data = (0..64 * 1024).map(|n| n as u8).collect::<Vec<_>>();
+        let write = async {
+            writer.write_all(&data).await.unwrap();
+            writer.shutdown().await.unwrap();
+            let (output, sink) = writer.into_inner();
+            drop(output); // EOF
+            sink
+        };
+        let mut received = vec![];
+        let read = output_rx.read_to_end(&mut received);
+        let (sink, n) = tokio::join!(write, read);
+        n.unwrap();
+
+        assert_eq!(received, data);
+        assert_eq!(sink, data);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast() {
+        let (mut rx1, tx1) = tokio_pipe::pipe().unwrap();
+        let (mut rx2, tx2) = tokio_pipe::pipe().unwrap();
+
+        let mut writer = broadcast(vec![SshOutput::new(tx1), SshOutput::new(tx2)]);
+        let data = (0..256 * 1024).map(|n| n as u8).collect::<Vec<_>>();
+        let write = async {
+            writer.write_all(&data).await.unwrap();
+            writer.flush().await.unwrap();
+            drop(writer); // EOF
+        };
+        let (mut received1, mut received2) = (vec![], vec![]);
+        let (_, r1, r2) = tokio::join!(
+            write,
+            rx1.read_to_end(&mut received1),
+            rx2.read_to_end(&mut received2)
+        );
+        r1.unwrap();
+        r2.unwrap();
+
+        assert_eq!(received1, data);
+        assert_eq!(received2, data);
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::future::ok;
+use futures::prelude::*;
+use tokio::process::Command;
+
+use ssssh::{Handlers, ServerBuilder};
+
+#[tokio::test]
+async fn test_close() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    let mut server = ServerBuilder::default().build("[::1]:2222").await.unwrap();
+
+    let closed = Arc::new(AtomicUsize::new(0));
+
+    let mut handlers = Handlers::<anyhow::Error>::new();
+    handlers.on_auth_none(|_| ok(true).boxed());
+    handlers.on_channel_shell(|_| ok(0).boxed());
+    let c = closed.clone();
+    handlers.on_channel_close(move |_| {
+        c.fetch_add(1, Ordering::SeqCst);
+        ok(()).boxed()
+    });
+
+    let proc = Command::new("ssh")
+        .env_clear()
+        .arg("-oStrictHostKeyChecking=no")
+        .arg("-oUserKnownHostsFile=/dev/null")
+        .arg("-p2222")
+        .arg("::1")
+        .stdout(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::inherit())
+        .spawn()
+        .unwrap();
+
+    let connection = server.try_next().await.unwrap().unwrap();
+    let connection = connection.accept().await.unwrap();
+    connection.run(handlers).await.unwrap();
+
+    let output = proc.wait_with_output().await.unwrap();
+    assert!(output.status.success());
+    assert_eq!(closed.load(Ordering::SeqCst), 1);
+}
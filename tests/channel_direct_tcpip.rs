@@ -23,7 +23,7 @@ async fn exec() {
 
     let mut handlers = Handlers::<anyhow::Error>::new();
     handlers.on_auth_none(|_| ok(true).boxed());
-    handlers.on_channel_direct_tcpip(|mut input, mut output| {
+    handlers.on_channel_direct_tcpip(|_, mut input, mut output| {
         async move {
             tokio::io::copy(&mut input, &mut output).await.unwrap();
             Ok(())
@@ -20,7 +20,11 @@ async fn exec() {
     nix::unistd::lseek(input_fd, 0, nix::unistd::Whence::SeekSet).unwrap();
     let input = unsafe { Stdio::from_raw_fd(input_fd) };
 
-    let mut server = ServerBuilder::default().build("[::1]:2222").await.unwrap();
+    let mut server = ServerBuilder::default()
+        .accept_env(vec!["LANG"])
+        .build("[::1]:2222")
+        .await
+        .unwrap();
 
     let mut handlers = Handlers::<anyhow::Error>::new();
     handlers.on_auth_none(|_| ok(true).boxed());
@@ -20,7 +20,11 @@ async fn shell() {
     nix::unistd::lseek(input_fd, 0, nix::unistd::Whence::SeekSet).unwrap();
     let input = unsafe { Stdio::from_raw_fd(input_fd) };
 
-    let mut server = ServerBuilder::default().build("[::1]:2222").await.unwrap();
+    let mut server = ServerBuilder::default()
+        .accept_env(vec!["LANG"])
+        .build("[::1]:2222")
+        .await
+        .unwrap();
 
     let mut handlers = Handlers::<anyhow::Error, ()>::new();
     handlers.on_auth_none(|_| ok(true).boxed());
@@ -71,7 +75,11 @@ async fn shell() {
 async fn pty_alloc_failed() {
     simple_logger::SimpleLogger::new().init().ok();
 
-    let mut server = ServerBuilder::default().build("[::1]:2223").await.unwrap();
+    let mut server = ServerBuilder::default()
+        .accept_env(vec!["LANG"])
+        .build("[::1]:2223")
+        .await
+        .unwrap();
 
     let mut handlers = Handlers::<anyhow::Error>::new();
     handlers.on_auth_none(|_| ok(true).boxed());
@@ -115,7 +123,11 @@ async fn pty_alloc_failed() {
 async fn pty_alloc_failed2() {
     simple_logger::SimpleLogger::new().init().ok();
 
-    let mut server = ServerBuilder::default().build("[::1]:2224").await.unwrap();
+    let mut server = ServerBuilder::default()
+        .accept_env(vec!["LANG"])
+        .build("[::1]:2224")
+        .await
+        .unwrap();
 
     let mut handlers = Handlers::<anyhow::Error, ()>::new();
     handlers.on_auth_none(|_| ok(true).boxed());
@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+use futures::prelude::*;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time;
+
+use ssssh::ServerBuilder;
+
+#[tokio::test]
+async fn test_deny() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    let mut server = ServerBuilder::default()
+        .deny_cidrs(vec!["127.0.0.0/8".parse().unwrap()])
+        .build("127.0.0.1:2222")
+        .await
+        .unwrap();
+
+    let mut client = TcpStream::connect("127.0.0.1:2222").await.unwrap();
+
+    let accepted = time::timeout(Duration::from_millis(500), server.next()).await;
+    assert!(accepted.is_err());
+
+    let mut buf = vec![];
+    let n = client.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(n, 0);
+}
@@ -0,0 +1,52 @@
+use std::sync::{Arc, Mutex};
+
+use futures::future::ok;
+use futures::prelude::*;
+use tokio::process::Command;
+
+use ssssh::{Cipher, Handlers, ServerBuilder};
+
+#[tokio::test]
+async fn test_kex_done() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    let mut server = ServerBuilder::default().build("[::1]:2222").await.unwrap();
+
+    let negotiated = Arc::new(Mutex::new(vec![]));
+
+    let mut handlers = Handlers::<anyhow::Error>::new();
+    handlers.on_auth_none(|_| ok(true).boxed());
+    handlers.on_channel_shell(|_| ok(0).boxed());
+    let n = negotiated.clone();
+    handlers.on_kex_done(move |summary| {
+        n.lock().unwrap().push(summary);
+        ok(()).boxed()
+    });
+
+    let proc = Command::new("ssh")
+        .env_clear()
+        .arg("-oStrictHostKeyChecking=no")
+        .arg("-oUserKnownHostsFile=/dev/null")
+        .arg("-oCiphers=aes192-ctr")
+        .arg("-p2222")
+        .arg("::1")
+        .stdout(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::inherit())
+        .spawn()
+        .unwrap();
+
+    let connection = server.try_next().await.unwrap().unwrap();
+    let connection = connection.accept().await.unwrap();
+    connection.run(handlers).await.unwrap();
+
+    let output = proc.wait_with_output().await.unwrap();
+    assert!(output.status.success());
+
+    let negotiated = negotiated.lock().unwrap();
+    assert_eq!(negotiated.len(), 1);
+    let algorithm = negotiated[0].algorithm();
+    assert_eq!(algorithm.cipher_algorithm_c2s(), &Cipher::Aes192Ctr);
+    assert_eq!(algorithm.cipher_algorithm_s2c(), &Cipher::Aes192Ctr);
+    assert_eq!(negotiated[0].exchange_hash(), negotiated[0].session_id());
+    assert!(!negotiated[0].signature().is_empty());
+}
@@ -15,7 +15,7 @@ async fn test_close() {
 
     let mut handlers = Handlers::<anyhow::Error>::new();
     handlers.on_auth_none(|_| ok(true).boxed());
-    handlers.on_channel_direct_tcpip(|_, mut output: SshOutput| {
+    handlers.on_channel_direct_tcpip(|_, _, mut output: SshOutput| {
         async move {
             output.shutdown().await.unwrap();
             Ok(())
@@ -0,0 +1,77 @@
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::future::ok;
+use futures::{FutureExt, TryStreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+
+use ssssh::{Handlers, ServerBuilder};
+
+#[tokio::test]
+async fn rekey_by_client() {
+    simple_logger::SimpleLogger::new().init().ok();
+
+    let mut server = ServerBuilder::default().build("[::1]:2222").await.unwrap();
+
+    let mut handlers = Handlers::<anyhow::Error>::new();
+    handlers.on_auth_none(|_| ok(true).boxed());
+    handlers.on_channel_exec(|mut ctx: ssssh::SessionContext, _| {
+        let (mut stdin, mut stdout, _) = ctx.take_stdio().unwrap();
+        async move {
+            for n in 0..200 {
+                stdout.write_all(&[n as u8; 1024]).await?;
+            }
+            // exit after the client closes stdin, not in the middle of its rekey.
+            tokio::io::copy(&mut stdin, &mut tokio::io::sink()).await?;
+            Ok(0)
+        }
+        .boxed()
+    });
+
+    let kex_count = Arc::new(AtomicUsize::new(0));
+    let c = kex_count.clone();
+    handlers.on_kex_done(move |_| {
+        c.fetch_add(1, Ordering::SeqCst);
+        ok(()).boxed()
+    });
+
+    // client rekeys after every 64KiB.
+    let mut proc = Command::new("ssh")
+        .env_clear()
+        .arg("-oStrictHostKeyChecking=no")
+        .arg("-oUserKnownHostsFile=/dev/null")
+        .arg("-oRekeyLimit=64K")
+        .arg("-p2222")
+        .arg("-q")
+        .arg("::1")
+        .arg("cat")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .unwrap();
+    let stdin = proc.stdin.take().unwrap();
+    let mut stdout = proc.stdout.take().unwrap();
+
+    let client = async move {
+        let mut output = vec![0; 200 * 1024];
+        stdout.read_exact(&mut output).await.unwrap();
+        drop(stdin);
+        stdout.read_to_end(&mut output).await.unwrap();
+        (proc.wait().await.unwrap(), output)
+    };
+
+    let connection = server.try_next().await.unwrap().unwrap();
+    let connection = connection.accept().await.unwrap();
+    let (result, (status, output)) = tokio::join!(connection.run(handlers), client);
+    result.unwrap();
+
+    assert!(status.success());
+    let expected = (0..200)
+        .flat_map(|n| vec![n as u8; 1024])
+        .collect::<Vec<_>>();
+    assert_eq!(output, expected);
+    assert!(kex_count.load(Ordering::SeqCst) > 1);
+}